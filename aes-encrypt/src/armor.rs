@@ -0,0 +1,257 @@
+use std::io::{self, BufRead, Read, Write};
+
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+
+pub const BEGIN: &str = "-----BEGIN AES-ENCRYPT MESSAGE-----";
+pub const END: &str = "-----END AES-ENCRYPT MESSAGE-----";
+/// Prefix of the single-line tokens written by `encrypt-string`
+pub const TOKEN_PREFIX: &str = "aes-encrypt:";
+
+// 48 bytes encode to a 64-column line
+const LINE_BYTES: usize = 48;
+// CRC-24 from OpenPGP (RFC 4880, section 6.1)
+const CRC24_INIT: u32 = 0xB704CE;
+const CRC24_POLY: u32 = 0x1864CFB;
+
+fn crc24(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= (byte as u32) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x1000000 != 0 {
+                crc ^= CRC24_POLY;
+            }
+        }
+    }
+    crc & 0xFFFFFF
+}
+
+fn checksum_line(crc: u32) -> String {
+    format!("={}", STANDARD.encode(&crc.to_be_bytes()[1..]))
+}
+
+/// Whether the data starts like an armored message.
+pub fn is_armored(data: &[u8]) -> bool {
+    data.trim_ascii_start().starts_with(BEGIN.as_bytes())
+}
+
+/// Whether the data starts like a token from `encrypt-string`.
+pub fn is_token(data: &[u8]) -> bool {
+    data.trim_ascii_start().starts_with(TOKEN_PREFIX.as_bytes())
+}
+
+pub fn encode_token(data: &[u8]) -> String {
+    format!("{}{}", TOKEN_PREFIX, URL_SAFE_NO_PAD.encode(data))
+}
+
+pub fn decode_token(token: &str) -> anyhow::Result<Vec<u8>> {
+    let body = token
+        .trim()
+        .strip_prefix(TOKEN_PREFIX)
+        .ok_or_else(|| anyhow::anyhow!("Not an aes-encrypt token"))?;
+    URL_SAFE_NO_PAD
+        .decode(body)
+        .map_err(|e| anyhow::anyhow!("Invalid token: {}", e))
+}
+
+/// Writes everything given to it as an armored message: the BEGIN line, base64 in 64-column lines, a CRC-24
+/// checksum line and the END line. Call `finish` to write the tail; dropping the writer leaves it unterminated.
+pub struct ArmorWriter<W: Write> {
+    inner: W,
+    pending: Vec<u8>,
+    crc: u32,
+}
+
+impl<W: Write> ArmorWriter<W> {
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        writeln!(inner, "{}", BEGIN)?;
+        Ok(Self { inner, pending: Vec::with_capacity(LINE_BYTES), crc: CRC24_INIT })
+    }
+
+    fn write_line(&mut self) -> io::Result<()> {
+        writeln!(self.inner, "{}", STANDARD.encode(&self.pending))?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.pending.is_empty() {
+            self.write_line()?;
+        }
+        writeln!(self.inner, "{}", checksum_line(self.crc))?;
+        writeln!(self.inner, "{}", END)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ArmorWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.crc = crc24(self.crc, buf);
+        for chunk in buf.chunks(LINE_BYTES) {
+            let take = chunk.len().min(LINE_BYTES - self.pending.len());
+            self.pending.extend_from_slice(&chunk[..take]);
+            if self.pending.len() == LINE_BYTES {
+                self.write_line()?;
+                self.pending.extend_from_slice(&chunk[take..]);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads the binary message back out of an armored one, line by line. The checksum is compared when the
+/// END line is reached, so a damaged message fails there at the latest.
+pub struct ArmorReader<R: BufRead> {
+    inner: R,
+    line: String,
+    decoded: Vec<u8>,
+    position: usize,
+    crc: u32,
+    begun: bool,
+    done: bool,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid armor: {}", message))
+}
+
+impl<R: BufRead> ArmorReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, line: String::new(), decoded: Vec::new(), position: 0, crc: CRC24_INIT, begun: false, done: false }
+    }
+
+    fn next_line(&mut self) -> io::Result<()> {
+        self.line.clear();
+        if self.inner.read_line(&mut self.line)? == 0 {
+            return Err(invalid("the message is truncated"));
+        }
+        Ok(())
+    }
+
+    /// Decodes the next line of the body into `decoded`, or finishes at the checksum.
+    fn refill(&mut self) -> io::Result<()> {
+        loop {
+            self.next_line()?;
+            let line = self.line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if !self.begun {
+                if line != BEGIN {
+                    return Err(invalid("missing BEGIN line"));
+                }
+                self.begun = true;
+                continue;
+            }
+
+            if line.starts_with('=') {
+                let expected = checksum_line(self.crc);
+                if line != expected {
+                    return Err(invalid("checksum mismatch; the message was damaged in transit"));
+                }
+                self.next_line()?;
+                if self.line.trim() != END {
+                    return Err(invalid("missing END line"));
+                }
+                self.done = true;
+                return Ok(());
+            }
+            if line == END {
+                return Err(invalid("missing checksum line"));
+            }
+
+            self.decoded = STANDARD.decode(line).map_err(|e| invalid(&e.to_string()))?;
+            self.position = 0;
+            self.crc = crc24(self.crc, &self.decoded);
+            return Ok(());
+        }
+    }
+}
+
+impl<R: BufRead> Read for ArmorReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.decoded.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.refill()?;
+        }
+
+        let count = buf.len().min(self.decoded.len() - self.position);
+        buf[..count].copy_from_slice(&self.decoded[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn armor(data: &[u8]) -> String {
+        let mut writer = ArmorWriter::new(Vec::new()).unwrap();
+        // Uneven writes, so lines have to be assembled across calls
+        for chunk in data.chunks(7) {
+            writer.write_all(chunk).unwrap();
+        }
+        String::from_utf8(writer.finish().unwrap()).unwrap()
+    }
+
+    fn dearmor(text: &str) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        ArmorReader::new(text.as_bytes()).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn test_crc24_check_value() {
+        assert_eq!(crc24(CRC24_INIT, b"123456789"), 0x21CF02);
+    }
+
+    #[test]
+    fn test_armor_round_trip() {
+        for size in [0, 1, 47, 48, 49, 1000] {
+            let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            let text = armor(&data);
+
+            assert!(is_armored(text.as_bytes()));
+            assert!(text.lines().all(|line| line.len() <= 64 || line == BEGIN || line == END));
+            assert_eq!(dearmor(&text).unwrap(), data);
+            // Survives a trip through a Windows editor and indentation in a YAML block
+            let reformatted: String = text.lines().map(|line| format!("  {}\r\n", line)).collect();
+            assert_eq!(dearmor(&reformatted).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_damaged_armor_is_rejected() {
+        let text = armor(&[1u8; 100]);
+
+        let damaged = text.replacen("AQEB", "AQEC", 1);
+        assert!(dearmor(&damaged).is_err());
+
+        let truncated = text.replace(END, "");
+        assert!(dearmor(&truncated).is_err());
+
+        let without_checksum: String = text.lines().filter(|line| !line.starts_with('=')).map(|line| format!("{}\n", line)).collect();
+        assert!(dearmor(&without_checksum).is_err());
+    }
+
+    #[test]
+    fn test_token_round_trip() {
+        let token = encode_token(b"\xff\xfe secret");
+
+        assert!(is_token(token.as_bytes()));
+        assert!(!token.contains(['\n', '+', '/', '=']));
+        assert_eq!(decode_token(&format!("{}\n", token)).unwrap(), b"\xff\xfe secret");
+        assert!(decode_token("AESENC").is_err());
+    }
+}
@@ -1,56 +1,490 @@
-use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce, aead::Aead};
+use std::io::{self, Read, Write};
+
+use aes_gcm::{
+    Aes256Gcm, KeyInit,
+    aead::{
+        Aead, Payload,
+        generic_array::GenericArray,
+        stream::{DecryptorBE32, EncryptorBE32},
+    },
+};
+use aes_gcm_siv::Aes256GcmSiv;
 use anyhow::Ok;
+use chacha20poly1305::XChaCha20Poly1305;
 use rand::Rng;
 
+use crate::{
+    envelope::{self, Identity, Recipient},
+    header::{self, Header, Kdf, Stanza},
+};
+
 // The GCM in AES256Gcm stands for Galois/Counter Mode, which is a mode of operation for symmetric key cryptographic block ciphers.
 pub const KEY_SIZE: usize = 32;
 
+/// Plaintext bytes per segment; every segment but the last is exactly this long.
+pub const DEFAULT_SEGMENT_SIZE: u32 = 64 * 1024;
+
+// STREAM uses 5 bytes of the nonce for the segment counter and the last-segment flag
+const STREAM_NONCE_OVERHEAD: usize = 5;
+const TAG_SIZE: usize = 16;
+
+/// The AEAD used for the data. All three take a 256-bit key and produce a 16-byte tag.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Cipher {
+    #[default]
+    #[value(name = "aes-256-gcm")]
+    Aes256Gcm,
+    /// Nonce-misuse resistant: a repeated nonce only reveals whether two messages were equal
+    #[value(name = "aes-256-gcm-siv")]
+    Aes256GcmSiv,
+    /// 192-bit nonces, and fast without AES hardware
+    #[value(name = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// The id stored in file headers. Never reuse one for a different cipher.
+    pub fn id(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::Aes256GcmSiv => 2,
+            Cipher::XChaCha20Poly1305 => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> anyhow::Result<Self> {
+        match id {
+            1 => Ok(Cipher::Aes256Gcm),
+            2 => Ok(Cipher::Aes256GcmSiv),
+            3 => Ok(Cipher::XChaCha20Poly1305),
+            _ => Err(anyhow::anyhow!("Unsupported cipher: {}", id)),
+        }
+    }
+
+    pub fn nonce_size(self) -> usize {
+        match self {
+            Cipher::Aes256Gcm | Cipher::Aes256GcmSiv => 12,
+            Cipher::XChaCha20Poly1305 => 24,
+        }
+    }
+
+    /// Bytes of random nonce prefix in a stream header; STREAM fills in the rest per segment.
+    pub fn nonce_prefix_size(self) -> usize {
+        self.nonce_size() - STREAM_NONCE_OVERHEAD
+    }
+}
+
+enum Aead256 {
+    Aes256Gcm(Aes256Gcm),
+    Aes256GcmSiv(Aes256GcmSiv),
+    XChaCha20Poly1305(XChaCha20Poly1305),
+}
+
+impl Aead256 {
+    fn new(cipher: Cipher, key: &[u8; KEY_SIZE]) -> Self {
+        let key = GenericArray::from_slice(key);
+        match cipher {
+            Cipher::Aes256Gcm => Aead256::Aes256Gcm(Aes256Gcm::new(key)),
+            Cipher::Aes256GcmSiv => Aead256::Aes256GcmSiv(Aes256GcmSiv::new(key)),
+            Cipher::XChaCha20Poly1305 => Aead256::XChaCha20Poly1305(XChaCha20Poly1305::new(key)),
+        }
+    }
+}
+
+// Runs `$body` with `$aead` bound to the concrete cipher, so generic AEAD and STREAM code can be written once
+macro_rules! with_aead {
+    ($aead256:expr, $aead:ident => $body:expr) => {
+        match $aead256 {
+            Aead256::Aes256Gcm($aead) => $body,
+            Aead256::Aes256GcmSiv($aead) => $body,
+            Aead256::XChaCha20Poly1305($aead) => $body,
+        }
+    };
+}
+
+/// Seals or opens one segment; the flag marks the last one, after which the closure must not be called.
+type SegmentFn<'a> = Box<dyn FnMut(Payload<'_, '_>, bool) -> aes_gcm::aead::Result<Vec<u8>> + 'a>;
+
 pub struct Encryptor {
-    cipher: Aes256Gcm,
+    aead: Aead256,
+    cipher: Cipher,
+    // Recorded in stream headers so decryption can rebuild the key
+    kdf: Kdf,
+    // The key wrapped for each recipient when it is a random data key
+    recipients: Vec<Stanza>,
 }
 
 impl Encryptor {
+    /// AES-256-GCM with a raw key.
     pub fn new(key: &[u8; KEY_SIZE]) -> Self {
-        let key = Key::<Aes256Gcm>::from_slice(key);
-        let cipher = Aes256Gcm::new(key);
+        Self::with_cipher(key, Cipher::default())
+    }
+
+    /// Streams record the key's ID, so decryption can check it has the right key or look it up in a keyring.
+    pub fn with_cipher(key: &[u8; KEY_SIZE], cipher: Cipher) -> Self {
+        let kdf = Kdf::Key { key_id: envelope::key_id(key) };
+        Self { aead: Aead256::new(cipher, key), cipher, kdf, recipients: Vec::new() }
+    }
 
-        Self { cipher }
+    /// Derives the key from `password` with Argon2id and a fresh salt.
+    pub fn with_password(password: &[u8], cipher: Cipher) -> anyhow::Result<Self> {
+        Self::from_password(password, Kdf::argon2id(), cipher)
+    }
+
+    /// Derives the key from `password` with the parameters in `kdf`, e.g. those read from a header.
+    pub fn from_password(password: &[u8], kdf: Kdf, cipher: Cipher) -> anyhow::Result<Self> {
+        let key = kdf.derive_key(password)?;
+        let mut encryptor = Self::with_cipher(&key, cipher);
+
+        encryptor.kdf = kdf;
+        Ok(encryptor)
+    }
+
+    /// Envelope encryption: a random data key, wrapped for each of `recipients` in the stream header.
+    /// Any one of them can decrypt, and recipients can be changed later with `envelope::rewrap`.
+    pub fn for_recipients(recipients: &[Recipient], cipher: Cipher) -> anyhow::Result<Self> {
+        if recipients.is_empty() {
+            return Err(anyhow::anyhow!("At least one recipient is needed"));
+        }
+
+        let data_key = envelope::data_key();
+        let mut encryptor = Self::with_cipher(&data_key, cipher);
+        // Recipients find the data key through their stanzas; a key ID would only let someone confirm a guess
+        encryptor.kdf = Kdf::None;
+        encryptor.recipients = recipients.iter().map(|recipient| recipient.wrap(&data_key)).collect::<anyhow::Result<_>>()?;
+        Ok(encryptor)
+    }
+
+    /// Unwraps the data key of an envelope-encrypted stream with `identity`.
+    pub fn with_identity(identity: &Identity, header: &Header) -> anyhow::Result<Self> {
+        let data_key = identity.unwrap(&header.recipients)?;
+        Ok(Self::with_cipher(&data_key, header.cipher))
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
     }
 
     pub fn encrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
-        // Generate random 12-byte nonce
-        let mut rng = rand::thread_rng();
-        let mut nonce_bytes = [0u8; 12];
-        rng.fill(&mut nonce_bytes);
-        // nonce is number used only once
-        let nonce = Nonce::from_slice(&nonce_bytes);
+        self.encrypt_with_aad(data, &[])
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.decrypt_with_aad(data, &[])
+    }
 
-        // Encrypt data
-        let ciphertext = self
-            .cipher
-            .encrypt(nonce, data)
+    /// Encrypts `data` and binds `aad` (e.g. a file name or tenant ID) into the tag. `aad` isn't stored;
+    /// decryption only succeeds when given the same bytes.
+    pub fn encrypt_with_aad(&self, data: &[u8], aad: &[u8]) -> anyhow::Result<Vec<u8>> {
+        // Generate a random nonce of the cipher's size
+        let mut nonce_bytes = vec![0u8; self.cipher.nonce_size()];
+        rand::thread_rng().fill(nonce_bytes.as_mut_slice());
+
+        // nonce is number used only once
+        let payload = Payload { msg: data, aad };
+        let ciphertext = with_aead!(&self.aead, aead => aead.encrypt(GenericArray::from_slice(&nonce_bytes), payload))
             .map_err(|e| anyhow::anyhow!("Encryption error: {:?}", e))?;
 
         // Combine nonce and ciphertext
-        let mut result = Vec::with_capacity(nonce_bytes.len() + ciphertext.len());
-        result.extend_from_slice(&nonce_bytes);
+        let mut result = nonce_bytes;
         result.extend_from_slice(&ciphertext);
 
         Ok(result)
     }
 
-    pub fn decrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
-        if data.len() < 12 {
+    pub fn decrypt_with_aad(&self, data: &[u8], aad: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let nonce_size = self.cipher.nonce_size();
+        if data.len() < nonce_size {
             return Err(anyhow::anyhow!("Invalid encrypted data"));
         }
 
         // Split nonce and ciphertext
-        let (nonce_bytes, ciphertext) = data.split_at(12);
-        let nonce = Nonce::from_slice(nonce_bytes);
+        let (nonce_bytes, ciphertext) = data.split_at(nonce_size);
+        let payload = Payload { msg: ciphertext, aad };
 
-        // Decrypt data
-        self.cipher
-            .decrypt(nonce, ciphertext)
+        with_aead!(&self.aead, aead => aead.decrypt(GenericArray::from_slice(nonce_bytes), payload))
             .map_err(|e| anyhow::anyhow!("Decryption error: {:?}", e))
     }
+
+    /// Encrypts everything `reader` yields into `writer` without holding more than one segment in memory.
+    /// `aad` is bound into every segment like in `encrypt_with_aad`. Returns the number of plaintext bytes encrypted.
+    pub fn encrypt_stream(
+        &self,
+        reader: impl Read,
+        mut writer: impl Write,
+        segment_size: u32,
+        aad: &[u8],
+    ) -> anyhow::Result<u64> {
+        header::check_segment_size(segment_size)?;
+
+        let mut nonce_prefix = vec![0u8; self.cipher.nonce_prefix_size()];
+        rand::thread_rng().fill(nonce_prefix.as_mut_slice());
+
+        let mut header = Header::new(self.kdf.clone(), self.cipher, segment_size, nonce_prefix);
+        header.recipients = self.recipients.clone();
+        header.write(&mut writer)?;
+
+        let mut seal = self.stream_sealer(&header.nonce_prefix);
+        let segment_aad = segment_aad(&header, aad);
+        let mut total = 0;
+
+        for_each_segment(reader, segment_size as usize, |segment, last| {
+            total += segment.len() as u64;
+            let ciphertext = seal(Payload { msg: segment, aad: &segment_aad }, last)
+                .map_err(|e| anyhow::anyhow!("Encryption error: {:?}", e))?;
+            writer.write_all(&ciphertext)?;
+            Ok(())
+        })?;
+
+        writer.flush()?;
+        Ok(total)
+    }
+
+    /// Decrypts a stream written by `encrypt_stream` with the same `aad`. Fails if any segment was modified,
+    /// dropped, reordered, or the stream was cut short. Segments before the damage may already have been written.
+    pub fn decrypt_stream(&self, mut reader: impl Read, writer: impl Write, aad: &[u8]) -> anyhow::Result<u64> {
+        let header = Header::read(&mut reader)?;
+        self.decrypt_stream_with_header(&header, reader, writer, aad)
+    }
+
+    /// Like `decrypt_stream`, for callers that had to read the header first, e.g. to find out how to derive the key.
+    pub fn decrypt_stream_with_header(
+        &self,
+        header: &Header,
+        reader: impl Read,
+        mut writer: impl Write,
+        aad: &[u8],
+    ) -> anyhow::Result<u64> {
+        if header.cipher != self.cipher {
+            return Err(anyhow::anyhow!("This file was encrypted with {:?}, not {:?}", header.cipher, self.cipher));
+        }
+
+        let mut open = self.stream_opener(&header.nonce_prefix);
+        let segment_aad = segment_aad(header, aad);
+        let mut total = 0;
+
+        for_each_segment(reader, header.segment_size as usize + TAG_SIZE, |segment, last| {
+            // A stream truncated at a segment boundary ends in a segment that wasn't sealed as the last one
+            let plaintext = open(Payload { msg: segment, aad: &segment_aad }, last).map_err(|_| {
+                anyhow::anyhow!("Decryption error: wrong key, password or associated data, or a segment is corrupted, out of order or missing")
+            })?;
+
+            total += plaintext.len() as u64;
+            writer.write_all(&plaintext)?;
+            Ok(())
+        })?;
+
+        writer.flush()?;
+        Ok(total)
+    }
+
+    fn stream_sealer(&self, nonce_prefix: &[u8]) -> SegmentFn<'_> {
+        with_aead!(&self.aead, aead => {
+            // `encrypt_last` consumes the encryptor, which also stops anything being sealed after it
+            let mut stream = Some(EncryptorBE32::from_aead(aead.clone(), GenericArray::from_slice(nonce_prefix)));
+            Box::new(move |payload: Payload<'_, '_>, last: bool| {
+                if last {
+                    stream.take().expect("segment after the last one").encrypt_last(payload)
+                } else {
+                    stream.as_mut().expect("segment after the last one").encrypt_next(payload)
+                }
+            })
+        })
+    }
+
+    fn stream_opener(&self, nonce_prefix: &[u8]) -> SegmentFn<'_> {
+        with_aead!(&self.aead, aead => {
+            let mut stream = Some(DecryptorBE32::from_aead(aead.clone(), GenericArray::from_slice(nonce_prefix)));
+            Box::new(move |payload: Payload<'_, '_>, last: bool| {
+                if last {
+                    stream.take().expect("segment after the last one").decrypt_last(payload)
+                } else {
+                    stream.as_mut().expect("segment after the last one").decrypt_next(payload)
+                }
+            })
+        })
+    }
+}
+
+/// The header is authenticated with every segment, so it can't be swapped or edited; caller AAD follows it.
+fn segment_aad(header: &Header, aad: &[u8]) -> Vec<u8> {
+    [header.as_bytes(), aad].concat()
+}
+
+/// Splits `reader` into `size`-byte segments and calls `f` with each one and whether it is the last.
+/// One byte of lookahead decides "last", so an input that is an exact multiple of `size` ends in a
+/// full segment rather than an empty one. Empty input yields a single empty segment.
+fn for_each_segment(
+    mut reader: impl Read,
+    size: usize,
+    mut f: impl FnMut(&[u8], bool) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut buffer = vec![0u8; size + 1];
+    let mut filled = 0;
+
+    loop {
+        filled += fill(&mut reader, &mut buffer[filled..])?;
+        let last = filled <= size;
+        f(&buffer[..filled.min(size)], last)?;
+        if last {
+            return Ok(());
+        }
+
+        // Carry the lookahead byte into the next segment
+        buffer[0] = buffer[size];
+        filled = 1;
+    }
+}
+
+/// Reads until `buffer` is full or the reader is exhausted.
+fn fill(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Result::Ok(0) => break,
+            Result::Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Result::Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEGMENT: u32 = 16;
+    // magic, version, KDF id and key ID, cipher id, segment size, nonce prefix, recipient count
+    const HEADER_SIZE: usize = 6 + 1 + 1 + 8 + 1 + 4 + 7 + 2;
+
+    fn encryptor() -> Encryptor {
+        Encryptor::new(&[7u8; KEY_SIZE])
+    }
+
+    fn seal(data: &[u8]) -> Vec<u8> {
+        let mut sealed = Vec::new();
+        encryptor().encrypt_stream(data, &mut sealed, SEGMENT, &[]).unwrap();
+        sealed
+    }
+
+    fn open(sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut opened = Vec::new();
+        encryptor().decrypt_stream(sealed, &mut opened, &[])?;
+        Ok(opened)
+    }
+
+    #[test]
+    fn test_stream_round_trips_at_segment_boundaries() {
+        for len in [0usize, 1, 15, 16, 17, 32, 100] {
+            let data: Vec<u8> = (0..len as u8).collect();
+            let sealed = seal(&data);
+
+            let segments = len.max(1).div_ceil(SEGMENT as usize);
+            assert_eq!(sealed.len(), HEADER_SIZE + len + segments * TAG_SIZE);
+            assert_eq!(open(&sealed).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_truncation_is_detected() {
+        let sealed = seal(&[1u8; 40]);
+        let segment = SEGMENT as usize + TAG_SIZE;
+
+        // Cut at a segment boundary and inside a segment
+        assert!(open(&sealed[..HEADER_SIZE + segment]).is_err());
+        assert!(open(&sealed[..sealed.len() - 1]).is_err());
+        assert!(open(&sealed[..HEADER_SIZE]).is_err());
+    }
+
+    #[test]
+    fn test_reordered_segments_are_detected() {
+        let mut sealed = seal(&[1u8; 48]);
+        let segment = SEGMENT as usize + TAG_SIZE;
+        let first = HEADER_SIZE;
+
+        let (a, b) = sealed[first..first + 2 * segment].split_at_mut(segment);
+        a.swap_with_slice(b);
+
+        assert!(open(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_tampered_header_is_detected() {
+        let mut sealed = seal(b"attack at dawn");
+        sealed[HEADER_SIZE - 1] ^= 1;
+
+        assert!(open(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_legacy_format_is_not_a_stream() {
+        let legacy = encryptor().encrypt(b"hello").unwrap();
+
+        assert!(!header::is_header(&legacy));
+        assert_eq!(encryptor().decrypt(&legacy).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_password_streams_carry_their_kdf() {
+        let kdf = Kdf::argon2id_with_cost(8, 1, 1);
+        let encryptor = Encryptor::from_password(b"hunter2", kdf.clone(), Cipher::Aes256Gcm).unwrap();
+        let mut sealed = Vec::new();
+        encryptor.encrypt_stream(&b"secret"[..], &mut sealed, SEGMENT, &[]).unwrap();
+
+        let header = Header::read(sealed.as_slice()).unwrap();
+        assert_eq!(header.kdf, kdf);
+
+        let mut opened = Vec::new();
+        let decryptor = Encryptor::from_password(b"hunter2", header.kdf.clone(), header.cipher).unwrap();
+        decryptor.decrypt_stream(sealed.as_slice(), &mut opened, &[]).unwrap();
+        assert_eq!(opened, b"secret");
+
+        let wrong = Encryptor::from_password(b"hunter3", header.kdf, header.cipher).unwrap();
+        assert!(wrong.decrypt_stream(sealed.as_slice(), &mut Vec::new(), &[]).is_err());
+    }
+
+    #[test]
+    fn test_every_cipher_round_trips_and_is_recorded() {
+        let data: Vec<u8> = (0..100).collect();
+        for cipher in [Cipher::Aes256Gcm, Cipher::Aes256GcmSiv, Cipher::XChaCha20Poly1305] {
+            let encryptor = Encryptor::with_cipher(&[7u8; KEY_SIZE], cipher);
+            let mut sealed = Vec::new();
+            encryptor.encrypt_stream(data.as_slice(), &mut sealed, SEGMENT, &[]).unwrap();
+
+            let header = Header::read(sealed.as_slice()).unwrap();
+            assert_eq!(header.cipher, cipher);
+            assert_eq!(header.nonce_prefix.len(), cipher.nonce_prefix_size());
+
+            let mut opened = Vec::new();
+            encryptor.decrypt_stream(sealed.as_slice(), &mut opened, &[]).unwrap();
+            assert_eq!(opened, data);
+
+            let sealed = encryptor.encrypt(b"hello").unwrap();
+            assert_eq!(sealed.len(), cipher.nonce_size() + 5 + TAG_SIZE);
+            assert_eq!(encryptor.decrypt(&sealed).unwrap(), b"hello");
+        }
+
+        // The same key under another cipher must not open the stream
+        let mut sealed = Vec::new();
+        let siv = Encryptor::with_cipher(&[7u8; KEY_SIZE], Cipher::Aes256GcmSiv);
+        siv.encrypt_stream(&b"hello"[..], &mut sealed, SEGMENT, &[]).unwrap();
+        assert!(open(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_associated_data_must_match() {
+        let sealed = encryptor().encrypt_with_aad(b"payroll", b"tenant-42").unwrap();
+        assert_eq!(encryptor().decrypt_with_aad(&sealed, b"tenant-42").unwrap(), b"payroll");
+        assert!(encryptor().decrypt_with_aad(&sealed, b"tenant-43").is_err());
+        assert!(encryptor().decrypt(&sealed).is_err());
+
+        let mut sealed = Vec::new();
+        encryptor().encrypt_stream(&[1u8; 40][..], &mut sealed, SEGMENT, b"report.pdf").unwrap();
+        let mut opened = Vec::new();
+        encryptor().decrypt_stream(sealed.as_slice(), &mut opened, b"report.pdf").unwrap();
+        assert_eq!(opened, [1u8; 40]);
+        assert!(encryptor().decrypt_stream(sealed.as_slice(), &mut Vec::new(), b"other.pdf").is_err());
+        assert!(open(&sealed).is_err());
+    }
 }
@@ -0,0 +1,420 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    crypto::{DEFAULT_SEGMENT_SIZE, Encryptor},
+    header::Header,
+    hex,
+};
+
+/// Appended to the name of every encrypted file.
+pub const ENCRYPTED_EXTENSION: &str = ".enc";
+
+/// The encrypted manifest in the root of an encrypted directory. Encrypted files all end in `.enc`, so it can't
+/// clash with one.
+pub const MANIFEST_NAME: &str = ".aes-encrypt-manifest";
+
+const MANIFEST_MAGIC: &str = "aes-encrypt manifest v1";
+
+// Every file is authenticated together with its path, so encrypted files can't be renamed or swapped unnoticed
+const FILE_AAD_PREFIX: &[u8] = b"file:";
+const MANIFEST_AAD: &[u8] = b"manifest";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Relative to the directory root, `/`-separated
+    pub path: String,
+    pub size: u64,
+    pub sha256: [u8; 32],
+}
+
+/// Every file in an encrypted directory with the size and hash of its plaintext.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// One `sha256 size path` line per file, after a line naming the format.
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{}\n", MANIFEST_MAGIC);
+        for entry in &self.entries {
+            let _ = writeln!(text, "{} {} {}", hex::encode(&entry.sha256), entry.size, entry.path);
+        }
+        text
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut lines = text.lines();
+        if lines.next() != Some(MANIFEST_MAGIC) {
+            return Err(anyhow::anyhow!("Invalid manifest: unknown format"));
+        }
+
+        let mut entries = Vec::new();
+        for line in lines {
+            let invalid = || anyhow::anyhow!("Invalid manifest line: {}", line);
+            let mut fields = line.splitn(3, ' ');
+            let (Some(sha256), Some(size), Some(path)) = (fields.next(), fields.next(), fields.next()) else {
+                return Err(invalid());
+            };
+            let sha256 = hex::decode(sha256).ok_or_else(invalid)?;
+            let size = size.parse().map_err(|_| invalid())?;
+            check_relative_path(path)?;
+            entries.push(ManifestEntry { path: path.to_string(), size, sha256 });
+        }
+        Ok(Self { entries })
+    }
+}
+
+/// What `verify_dir` found wrong with one file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub path: String,
+    pub message: String,
+}
+
+/// Encrypts every regular file under `input` into the same layout under `output`, using up to `jobs` threads,
+/// and writes an encrypted manifest. Symlinks and special files are skipped.
+pub fn encrypt_dir(encryptor: &Encryptor, input: &Path, output: &Path, jobs: usize) -> anyhow::Result<Manifest> {
+    // Checked before anything is created, so a rejected call leaves no empty directory behind
+    if resolve(output)?.starts_with(input.canonicalize()?) {
+        return Err(anyhow::anyhow!("The output directory must not be inside the input directory"));
+    }
+    fs::create_dir_all(output)?;
+
+    let files = list_files(input)?;
+    let entries = parallel_map(&files, jobs, |path| {
+        let target = output.join(format!("{}{}", path, ENCRYPTED_EXTENSION));
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut reader = Hashing::new(BufReader::new(File::open(input.join(path))?));
+        let writer = BufWriter::new(File::create(&target)?);
+        let size = encryptor
+            .encrypt_stream(&mut reader, writer, DEFAULT_SEGMENT_SIZE, &file_aad(path))
+            .map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+        Ok(ManifestEntry { path: path.clone(), size, sha256: reader.finish() })
+    })?;
+
+    let manifest = Manifest { entries };
+    let writer = BufWriter::new(File::create(output.join(MANIFEST_NAME))?);
+    encryptor.encrypt_stream(manifest.to_text().as_bytes(), writer, DEFAULT_SEGMENT_SIZE, MANIFEST_AAD)?;
+    Ok(manifest)
+}
+
+/// The manifest's header, to find out how to build the encryptor for a directory. All files share its key.
+pub fn manifest_header(dir: &Path) -> anyhow::Result<Header> {
+    let path = dir.join(MANIFEST_NAME);
+    let file = File::open(&path).map_err(|e| anyhow::anyhow!("Failed to open {}: {}", path.display(), e))?;
+    Header::read(BufReader::new(file))
+}
+
+pub fn read_manifest(encryptor: &Encryptor, dir: &Path) -> anyhow::Result<Manifest> {
+    let mut text = Vec::new();
+    let reader = BufReader::new(File::open(dir.join(MANIFEST_NAME))?);
+    encryptor
+        .decrypt_stream(reader, &mut text, MANIFEST_AAD)
+        .map_err(|e| anyhow::anyhow!("{}: {}", MANIFEST_NAME, e))?;
+    Manifest::parse(&String::from_utf8(text)?)
+}
+
+/// Decrypts a directory written by `encrypt_dir` into `output`, checking every file against the manifest.
+pub fn decrypt_dir(encryptor: &Encryptor, input: &Path, output: &Path, jobs: usize) -> anyhow::Result<Manifest> {
+    let manifest = read_manifest(encryptor, input)?;
+
+    parallel_map(&manifest.entries, jobs, |entry| {
+        let target = output.join(&entry.path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut writer = Hashing::new(BufWriter::new(File::create(&target)?));
+        let result = decrypt_entry(encryptor, input, entry, &mut writer).and_then(|_| check_entry(entry, writer));
+        if result.is_err() {
+            let _ = fs::remove_file(&target);
+        }
+        result.map_err(|e| anyhow::anyhow!("{}: {}", entry.path, e))
+    })?;
+
+    Ok(manifest)
+}
+
+/// Authenticates every file of an encrypted directory and checks it against the manifest, without writing any
+/// plaintext. Also reports encrypted files the manifest doesn't list. Returns the manifest and what's wrong.
+pub fn verify_dir(encryptor: &Encryptor, dir: &Path, jobs: usize) -> anyhow::Result<(Manifest, Vec<Problem>)> {
+    let manifest = read_manifest(encryptor, dir)?;
+
+    let mut problems: Vec<Problem> = parallel_map(&manifest.entries, jobs, |entry| {
+        let mut writer = Hashing::new(io::sink());
+        let result = decrypt_entry(encryptor, dir, entry, &mut writer).and_then(|_| check_entry(entry, writer));
+        Ok(result.err().map(|e| Problem { path: entry.path.clone(), message: e.to_string() }))
+    })?
+    .into_iter()
+    .flatten()
+    .collect();
+
+    for path in list_files(dir)? {
+        let Some(path) = path.strip_suffix(ENCRYPTED_EXTENSION) else { continue };
+        if !manifest.entries.iter().any(|entry| entry.path == path) {
+            problems.push(Problem { path: path.to_string(), message: "not in the manifest".to_string() });
+        }
+    }
+
+    Ok((manifest, problems))
+}
+
+fn decrypt_entry(encryptor: &Encryptor, dir: &Path, entry: &ManifestEntry, writer: impl Write) -> anyhow::Result<()> {
+    let path = dir.join(format!("{}{}", entry.path, ENCRYPTED_EXTENSION));
+    let file = File::open(&path).map_err(|e| anyhow::anyhow!("Failed to open {}: {}", path.display(), e))?;
+    encryptor.decrypt_stream(BufReader::new(file), writer, &file_aad(&entry.path))?;
+    Ok(())
+}
+
+fn check_entry<W: Write>(entry: &ManifestEntry, mut hashed: Hashing<W>) -> anyhow::Result<()> {
+    hashed.flush()?;
+    let size = hashed.len;
+    if size != entry.size {
+        return Err(anyhow::anyhow!("size is {} bytes, the manifest says {}", size, entry.size));
+    }
+    if hashed.finish() != entry.sha256 {
+        return Err(anyhow::anyhow!("content doesn't match the manifest hash"));
+    }
+    Ok(())
+}
+
+fn file_aad(path: &str) -> Vec<u8> {
+    [FILE_AAD_PREFIX, path.as_bytes()].concat()
+}
+
+/// Relative, `/`-separated paths of the regular files under `root`, sorted.
+fn list_files(root: &Path) -> anyhow::Result<Vec<String>> {
+    let mut files = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(root.join(&dir))? {
+            let entry = entry?;
+            let relative = dir.join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(relative);
+            } else if file_type.is_file() {
+                let name = relative.to_str().ok_or_else(|| anyhow::anyhow!("File name isn't UTF-8: {}", relative.display()))?;
+                let name = name.replace(std::path::MAIN_SEPARATOR, "/");
+                // The manifest is line-based
+                if name.contains('\n') {
+                    return Err(anyhow::anyhow!("File name contains a newline: {:?}", name));
+                }
+                files.push(name);
+            }
+        }
+    }
+
+    files.retain(|name| name != MANIFEST_NAME);
+    files.sort();
+    Ok(files)
+}
+
+/// `path` with its deepest existing ancestor canonicalized, so paths that don't exist yet can be compared too.
+fn resolve(path: &Path) -> anyhow::Result<PathBuf> {
+    let path = std::path::absolute(path)?;
+    for ancestor in path.ancestors() {
+        if ancestor.exists() {
+            let rest = path.strip_prefix(ancestor)?;
+            return Ok(ancestor.canonicalize()?.join(rest));
+        }
+    }
+    Ok(path)
+}
+
+/// Manifest paths are joined onto the output directory, so they must stay inside it.
+fn check_relative_path(path: &str) -> anyhow::Result<()> {
+    let bad = path.is_empty() || path.split('/').any(|part| part.is_empty() || part == "." || part == ".." || part.contains('\\'));
+    if bad || Path::new(path).is_absolute() {
+        return Err(anyhow::anyhow!("Invalid manifest path: {:?}", path));
+    }
+    Ok(())
+}
+
+/// Runs `f` on each item using up to `jobs` threads and returns the results in order. No new items are started
+/// after one fails.
+fn parallel_map<T: Sync, R: Send>(
+    items: &[T],
+    jobs: usize,
+    f: impl Fn(&T) -> anyhow::Result<R> + Sync,
+) -> anyhow::Result<Vec<R>> {
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results = Mutex::new((0..items.len()).map(|_| None).collect::<Vec<_>>());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            scope.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= items.len() || failed.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let result = f(&items[i]);
+                    failed.fetch_or(result.is_err(), Ordering::Relaxed);
+                    results.lock().unwrap()[i] = Some(result);
+                }
+            });
+        }
+    });
+
+    // Items are handed out in order, so every slot before a failure is filled
+    results.into_inner().unwrap().into_iter().flatten().collect()
+}
+
+/// Hashes and counts everything read or written through it.
+struct Hashing<T> {
+    inner: T,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<T> Hashing<T> {
+    fn new(inner: T) -> Self {
+        Self { inner, hasher: Sha256::new(), len: 0 }
+    }
+
+    fn finish(self) -> [u8; 32] {
+        self.hasher.finalize().into()
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::KEY_SIZE;
+
+    /// A fresh directory under the system temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("aes-encrypt-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn encryptor() -> Encryptor {
+        Encryptor::new(&[7u8; KEY_SIZE])
+    }
+
+    fn sample_tree(root: &Path) {
+        fs::create_dir_all(root.join("docs/nested")).unwrap();
+        fs::write(root.join("readme.txt"), "hello").unwrap();
+        fs::write(root.join("docs/report.bin"), vec![3u8; 200_000]).unwrap();
+        fs::write(root.join("docs/nested/empty"), "").unwrap();
+    }
+
+    #[test]
+    fn test_directory_round_trips_with_layout() {
+        let temp = TempDir::new("round-trip");
+        let (plain, sealed, opened) = (temp.0.join("plain"), temp.0.join("sealed"), temp.0.join("opened"));
+        sample_tree(&plain);
+
+        let manifest = encrypt_dir(&encryptor(), &plain, &sealed, 4).unwrap();
+        let paths: Vec<&str> = manifest.entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["docs/nested/empty", "docs/report.bin", "readme.txt"]);
+        assert!(sealed.join("docs/report.bin.enc").is_file());
+
+        assert_eq!(decrypt_dir(&encryptor(), &sealed, &opened, 4).unwrap(), manifest);
+        assert_eq!(fs::read(opened.join("readme.txt")).unwrap(), b"hello");
+        assert_eq!(fs::read(opened.join("docs/report.bin")).unwrap(), vec![3u8; 200_000]);
+        assert_eq!(fs::read(opened.join("docs/nested/empty")).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_verify_reports_tampered_swapped_missing_and_extra_files() {
+        let temp = TempDir::new("verify");
+        let (plain, sealed) = (temp.0.join("plain"), temp.0.join("sealed"));
+        sample_tree(&plain);
+        encrypt_dir(&encryptor(), &plain, &sealed, 2).unwrap();
+
+        let (_, problems) = verify_dir(&encryptor(), &sealed, 2).unwrap();
+        assert_eq!(problems, vec![]);
+
+        // Same-size files swapped by name fail because each is bound to its path
+        fs::write(plain.join("other.txt"), "howdy").unwrap();
+        encrypt_dir(&encryptor(), &plain, &temp.0.join("again"), 1).unwrap();
+        fs::copy(temp.0.join("again/other.txt.enc"), sealed.join("readme.txt.enc")).unwrap();
+        fs::copy(temp.0.join("again/other.txt.enc"), sealed.join("stray.txt.enc")).unwrap();
+        fs::remove_file(sealed.join("docs/nested/empty.enc")).unwrap();
+        let mut report = fs::read(sealed.join("docs/report.bin.enc")).unwrap();
+        report[1000] ^= 1;
+        fs::write(sealed.join("docs/report.bin.enc"), report).unwrap();
+
+        let (_, problems) = verify_dir(&encryptor(), &sealed, 2).unwrap();
+        let paths: Vec<&str> = problems.iter().map(|problem| problem.path.as_str()).collect();
+        assert_eq!(paths, ["docs/nested/empty", "docs/report.bin", "readme.txt", "stray.txt"]);
+    }
+
+    #[test]
+    fn test_output_inside_input_is_rejected_before_creating_it() {
+        let temp = TempDir::new("nested-output");
+        let plain = temp.0.join("plain");
+        sample_tree(&plain);
+
+        for output in [plain.join("sealed"), plain.join("docs/../new/sealed"), plain.clone()] {
+            assert!(encrypt_dir(&encryptor(), &plain, &output, 1).is_err(), "{}", output.display());
+        }
+        assert!(!plain.join("sealed").exists());
+        assert!(!plain.join("new").exists());
+        assert_eq!(list_files(&plain).unwrap(), ["docs/nested/empty", "docs/report.bin", "readme.txt"]);
+    }
+
+    #[test]
+    fn test_manifest_rejects_paths_outside_the_directory() {
+        let line = |path: &str| format!("{}\n{} 1 {}\n", MANIFEST_MAGIC, "00".repeat(32), path);
+
+        assert!(Manifest::parse(&line("docs/a.txt")).is_ok());
+        for path in ["../a.txt", "/etc/passwd", "docs/../../a", "docs//a", "a\\..\\b"] {
+            assert!(Manifest::parse(&line(path)).is_err(), "{}", path);
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use std::io::{self, Read, Write};
+
+use hkdf::Hkdf;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::{
+    crypto::{Cipher, Encryptor, KEY_SIZE},
+    header::{Header, KEY_ID_SIZE, KeyId, Stanza, StanzaKind},
+};
+
+// Domain separation, so a key file and a public key with the same bytes get different IDs
+const KEY_ID_CONTEXT: &[u8] = b"aes-encrypt key id";
+const X25519_ID_CONTEXT: &[u8] = b"aes-encrypt x25519 id";
+const X25519_WRAP_INFO: &[u8] = b"aes-encrypt x25519 wrap";
+
+/// Someone a data key can be wrapped for.
+#[derive(Clone)]
+pub enum Recipient {
+    /// A shared symmetric key file
+    Key(Zeroizing<[u8; KEY_SIZE]>),
+    /// Anyone holding the matching X25519 secret key
+    X25519(PublicKey),
+}
+
+impl Recipient {
+    pub fn key_id(&self) -> KeyId {
+        match self {
+            Recipient::Key(key) => key_id(key),
+            Recipient::X25519(public) => fingerprint(X25519_ID_CONTEXT, public.as_bytes()),
+        }
+    }
+
+    pub fn wrap(&self, data_key: &[u8; KEY_SIZE]) -> anyhow::Result<Stanza> {
+        let key_id = self.key_id();
+        let (kind, kek) = match self {
+            Recipient::Key(key) => (StanzaKind::Key, key.clone()),
+            Recipient::X25519(public) => {
+                let ephemeral = StaticSecret::random_from_rng(rand::thread_rng());
+                let ephemeral_public = PublicKey::from(&ephemeral);
+                let kek = x25519_kek(ephemeral.diffie_hellman(public), &ephemeral_public, public)?;
+                (StanzaKind::X25519 { ephemeral: ephemeral_public.to_bytes() }, kek)
+            }
+        };
+
+        let wrapped = Encryptor::with_cipher(&kek, Cipher::XChaCha20Poly1305)
+            .encrypt_with_aad(data_key, &Stanza::aad(&key_id, &kind))?;
+        Ok(Stanza { key_id, kind, wrapped_key: wrapped.try_into().expect("wrapped key has a fixed size") })
+    }
+}
+
+/// A key file that can open stanzas: either a symmetric key or an X25519 secret key. Both are 32 random
+/// bytes, so which one it is only shows when a stanza's key ID matches.
+pub struct Identity {
+    key: Zeroizing<[u8; KEY_SIZE]>,
+}
+
+impl Identity {
+    pub fn new(key: Zeroizing<[u8; KEY_SIZE]>) -> Self {
+        Self { key }
+    }
+
+    fn as_key(&self) -> Recipient {
+        Recipient::Key(self.key.clone())
+    }
+
+    fn secret(&self) -> StaticSecret {
+        StaticSecret::from(*self.key)
+    }
+
+    /// Finds the stanza meant for this identity and returns the data key inside.
+    pub fn unwrap(&self, stanzas: &[Stanza]) -> anyhow::Result<Zeroizing<[u8; KEY_SIZE]>> {
+        let key_id = self.as_key().key_id();
+        let secret = self.secret();
+        let public = PublicKey::from(&secret);
+        let x25519_id = Recipient::X25519(public).key_id();
+
+        let stanza = stanzas
+            .iter()
+            .find(|stanza| match stanza.kind {
+                StanzaKind::Key => stanza.key_id == key_id,
+                StanzaKind::X25519 { .. } => stanza.key_id == x25519_id,
+            })
+            .ok_or_else(|| anyhow::anyhow!("This file isn't encrypted for the given key"))?;
+
+        let kek = match &stanza.kind {
+            StanzaKind::Key => self.key.clone(),
+            StanzaKind::X25519 { ephemeral } => {
+                let ephemeral = PublicKey::from(*ephemeral);
+                x25519_kek(secret.diffie_hellman(&ephemeral), &ephemeral, &public)?
+            }
+        };
+        let data_key = Encryptor::with_cipher(&kek, Cipher::XChaCha20Poly1305)
+            .decrypt_with_aad(&stanza.wrapped_key, &Stanza::aad(&stanza.key_id, &stanza.kind))
+            .map_err(|_| anyhow::anyhow!("Decryption error: the wrapped data key is corrupted"))?;
+        Ok(Zeroizing::new(data_key.try_into().expect("data key has a fixed size")))
+    }
+}
+
+/// A fresh random data key for one stream.
+pub fn data_key() -> Zeroizing<[u8; KEY_SIZE]> {
+    let mut key = Zeroizing::new([0u8; KEY_SIZE]);
+    rand::thread_rng().fill(key.as_mut());
+    key
+}
+
+/// Copies an envelope-encrypted stream, adding and removing recipients. Only the header changes; the
+/// segments are copied as they are. Returns the number of recipients in the new header.
+pub fn rewrap(
+    identity: &Identity,
+    add: &[Recipient],
+    remove: &[KeyId],
+    mut reader: impl Read,
+    mut writer: impl Write,
+) -> anyhow::Result<usize> {
+    let mut header = Header::read(&mut reader)?;
+    if header.recipients.is_empty() {
+        return Err(anyhow::anyhow!("This file has no recipients to rewrap; it was encrypted directly with a key or password"));
+    }
+    let data_key = identity.unwrap(&header.recipients)?;
+
+    header.recipients.retain(|stanza| !remove.contains(&stanza.key_id));
+    for recipient in add {
+        let key_id = recipient.key_id();
+        if !header.recipients.iter().any(|stanza| stanza.key_id == key_id) {
+            header.recipients.push(recipient.wrap(&data_key)?);
+        }
+    }
+    if header.recipients.is_empty() {
+        return Err(anyhow::anyhow!("Refusing to remove every recipient"));
+    }
+
+    header.write(&mut writer)?;
+    io::copy(&mut reader, &mut writer)?;
+    writer.flush()?;
+    Ok(header.recipients.len())
+}
+
+/// The ID of a symmetric key, as recorded in headers and keyrings.
+pub fn key_id(key: &[u8; KEY_SIZE]) -> KeyId {
+    fingerprint(KEY_ID_CONTEXT, key)
+}
+
+fn fingerprint(context: &[u8], key: &[u8]) -> KeyId {
+    let digest = Sha256::new().chain_update(context).chain_update(key).finalize();
+    digest[..KEY_ID_SIZE].try_into().unwrap()
+}
+
+/// Derives the key-encryption key from an X25519 exchange, bound to both public keys.
+fn x25519_kek(shared: SharedSecret, ephemeral: &PublicKey, recipient: &PublicKey) -> anyhow::Result<Zeroizing<[u8; KEY_SIZE]>> {
+    if !shared.was_contributory() {
+        return Err(anyhow::anyhow!("Invalid X25519 public key"));
+    }
+
+    let salt = [ephemeral.as_bytes().as_slice(), recipient.as_bytes()].concat();
+    let mut kek = Zeroizing::new([0u8; KEY_SIZE]);
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(X25519_WRAP_INFO, kek.as_mut())
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    Ok(kek)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::WRAPPED_KEY_SIZE;
+
+    fn x25519_identity() -> (Identity, Recipient) {
+        let identity = Identity::new(data_key());
+        let public = PublicKey::from(&identity.secret());
+        (identity, Recipient::X25519(public))
+    }
+
+    fn seal_for(recipients: &[Recipient], data: &[u8]) -> Vec<u8> {
+        let mut sealed = Vec::new();
+        let encryptor = Encryptor::for_recipients(recipients, Cipher::Aes256Gcm).unwrap();
+        encryptor.encrypt_stream(data, &mut sealed, 16, &[]).unwrap();
+        sealed
+    }
+
+    fn open_as(identity: &Identity, sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut reader = sealed;
+        let header = Header::read(&mut reader)?;
+        let encryptor = Encryptor::with_identity(identity, &header)?;
+        let mut opened = Vec::new();
+        encryptor.decrypt_stream_with_header(&header, reader, &mut opened, &[])?;
+        Ok(opened)
+    }
+
+    #[test]
+    fn test_every_recipient_can_decrypt() {
+        let shared = Identity::new(data_key());
+        let (alice, alice_public) = x25519_identity();
+        let (bob, bob_public) = x25519_identity();
+        let (mallory, _) = x25519_identity();
+
+        let sealed = seal_for(&[shared.as_key(), alice_public, bob_public], b"quarterly numbers");
+
+        for identity in [&shared, &alice, &bob] {
+            assert_eq!(open_as(identity, &sealed).unwrap(), b"quarterly numbers");
+        }
+        assert!(open_as(&mallory, &sealed).is_err());
+    }
+
+    #[test]
+    fn test_rewrap_changes_recipients_and_keeps_segments() {
+        let (alice, alice_public) = x25519_identity();
+        let (bob, bob_public) = x25519_identity();
+        let data = [7u8; 100];
+        let sealed = seal_for(std::slice::from_ref(&alice_public), &data);
+
+        let mut rewrapped = Vec::new();
+        let count = rewrap(&alice, &[bob_public], &[alice_public.key_id()], sealed.as_slice(), &mut rewrapped).unwrap();
+
+        assert_eq!(count, 1);
+        assert_eq!(open_as(&bob, &rewrapped).unwrap(), data);
+        assert!(open_as(&alice, &rewrapped).is_err());
+        // One stanza swapped for another of the same size, segments untouched
+        assert_eq!(rewrapped.len(), sealed.len());
+        assert_eq!(rewrapped[rewrapped.len() - 200..], sealed[sealed.len() - 200..]);
+    }
+
+    #[test]
+    fn test_rewrap_refuses_to_drop_every_recipient() {
+        let (alice, alice_public) = x25519_identity();
+        let sealed = seal_for(std::slice::from_ref(&alice_public), b"data");
+
+        assert!(rewrap(&alice, &[], &[alice_public.key_id()], sealed.as_slice(), Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_tampered_stanza_is_rejected() {
+        let (alice, alice_public) = x25519_identity();
+        let mut sealed = seal_for(&[alice_public], b"data");
+        let mut header = Header::read(sealed.as_slice()).unwrap();
+        let stanza_end = header.as_bytes().len() + 2 + 1 + KEY_ID_SIZE + 32 + WRAPPED_KEY_SIZE;
+
+        sealed[stanza_end - 1] ^= 1;
+        assert!(open_as(&alice, &sealed).is_err());
+
+        // Swapping in a data key wrapped for the same recipient doesn't open the segments either
+        header.recipients = vec![Recipient::X25519(PublicKey::from(&alice.secret())).wrap(&data_key()).unwrap()];
+        let mut forged = Vec::new();
+        header.write(&mut forged).unwrap();
+        forged.extend_from_slice(&sealed[stanza_end..]);
+        assert!(open_as(&alice, &forged).is_err());
+    }
+}
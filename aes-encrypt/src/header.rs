@@ -0,0 +1,385 @@
+use std::io::{Read, Write};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::Rng;
+use zeroize::Zeroizing;
+
+use crate::crypto::{Cipher, KEY_SIZE};
+
+// Marks the self-describing format, so `decrypt` can tell it apart from a bare nonce || ciphertext
+pub const MAGIC: &[u8; 6] = b"AESENC";
+
+pub const VERSION: u8 = 1;
+
+const KDF_NONE: u8 = 0;
+const KDF_ARGON2ID: u8 = 1;
+const KDF_KEY: u8 = 2;
+
+const RECIPIENT_KEY: u8 = 1;
+const RECIPIENT_X25519: u8 = 2;
+
+pub const SALT_SIZE: usize = 16;
+pub const KEY_ID_SIZE: usize = 8;
+// XChaCha20-Poly1305 nonce, data key and tag
+pub const WRAPPED_KEY_SIZE: usize = 24 + KEY_SIZE + 16;
+
+// RFC 9106's second recommended Argon2id cost: 64 MiB, 3 passes, 4 lanes
+const DEFAULT_ARGON2_MEMORY_KIB: u32 = 64 * 1024;
+const DEFAULT_ARGON2_ITERATIONS: u32 = 3;
+const DEFAULT_ARGON2_PARALLELISM: u32 = 4;
+
+// Refuse headers asking for absurd buffers or work. Decryption reads the Argon2 cost from the file, so a crafted
+// one can ask for at most 4 times the default in each
+const MAX_SEGMENT_SIZE: u32 = 16 * 1024 * 1024;
+const MAX_ARGON2_MEMORY_KIB: u32 = 4 * DEFAULT_ARGON2_MEMORY_KIB;
+const MAX_ARGON2_ITERATIONS: u32 = 4 * DEFAULT_ARGON2_ITERATIONS;
+const MAX_ARGON2_PARALLELISM: u32 = 4 * DEFAULT_ARGON2_PARALLELISM;
+const MAX_RECIPIENTS: u16 = 1024;
+
+/// Short fingerprint of a key, so decryption can find the key, or the stanza it can open.
+pub type KeyId = [u8; KEY_ID_SIZE];
+
+/// How the key was obtained, so decryption can repeat it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Kdf {
+    /// The key came from a key file, or is a data key wrapped for the recipients
+    None,
+    /// A key file or keyring key, identified by its fingerprint
+    Key { key_id: KeyId },
+    Argon2id { salt: [u8; SALT_SIZE], memory_kib: u32, iterations: u32, parallelism: u32 },
+}
+
+impl Kdf {
+    /// Argon2id with a fresh salt and the default cost.
+    pub fn argon2id() -> Self {
+        Self::argon2id_with_cost(DEFAULT_ARGON2_MEMORY_KIB, DEFAULT_ARGON2_ITERATIONS, DEFAULT_ARGON2_PARALLELISM)
+    }
+
+    pub fn argon2id_with_cost(memory_kib: u32, iterations: u32, parallelism: u32) -> Self {
+        let mut salt = [0u8; SALT_SIZE];
+        rand::thread_rng().fill(&mut salt);
+        Kdf::Argon2id { salt, memory_kib, iterations, parallelism }
+    }
+
+    /// Stretches `password` into a cipher key. Only meaningful for password-based KDFs.
+    pub fn derive_key(&self, password: &[u8]) -> anyhow::Result<Zeroizing<[u8; KEY_SIZE]>> {
+        let Kdf::Argon2id { salt, memory_kib, iterations, parallelism } = self else {
+            return Err(anyhow::anyhow!("This file was encrypted with a key, not a password"));
+        };
+
+        let params = Params::new(*memory_kib, *iterations, *parallelism, Some(KEY_SIZE))
+            .map_err(|e| anyhow::anyhow!("Invalid Argon2 parameters: {}", e))?;
+        let mut key = Zeroizing::new([0u8; KEY_SIZE]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(password, salt, key.as_mut())
+            .map_err(|e| anyhow::anyhow!("Key derivation error: {}", e))?;
+        Ok(key)
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Kdf::None => out.push(KDF_NONE),
+            Kdf::Key { key_id } => {
+                out.push(KDF_KEY);
+                out.extend_from_slice(key_id);
+            }
+            Kdf::Argon2id { salt, memory_kib, iterations, parallelism } => {
+                out.push(KDF_ARGON2ID);
+                out.extend_from_slice(salt);
+                out.extend_from_slice(&memory_kib.to_be_bytes());
+                out.extend_from_slice(&iterations.to_be_bytes());
+                out.extend_from_slice(&parallelism.to_be_bytes());
+            }
+        }
+    }
+
+    fn read(fields: &mut Fields<impl Read>) -> anyhow::Result<Self> {
+        match fields.u8()? {
+            KDF_NONE => Ok(Kdf::None),
+            KDF_KEY => Ok(Kdf::Key { key_id: fields.array()? }),
+            KDF_ARGON2ID => {
+                let salt = fields.array()?;
+                let memory_kib = fields.u32()?;
+                let iterations = fields.u32()?;
+                let parallelism = fields.u32()?;
+                if memory_kib > MAX_ARGON2_MEMORY_KIB
+                    || iterations > MAX_ARGON2_ITERATIONS
+                    || parallelism > MAX_ARGON2_PARALLELISM
+                {
+                    return Err(anyhow::anyhow!("Invalid encrypted data: Argon2 cost too high"));
+                }
+                Ok(Kdf::Argon2id { salt, memory_kib, iterations, parallelism })
+            }
+            id => Err(anyhow::anyhow!("Unsupported key derivation function: {}", id)),
+        }
+    }
+}
+
+/// The data key wrapped for one recipient of an envelope-encrypted stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stanza {
+    pub key_id: KeyId,
+    pub kind: StanzaKind,
+    /// The data key sealed with XChaCha20-Poly1305: nonce || ciphertext || tag
+    pub wrapped_key: [u8; WRAPPED_KEY_SIZE],
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StanzaKind {
+    /// Wrapped directly under a symmetric key file
+    Key,
+    /// Wrapped under a key agreed between an ephemeral key and the recipient's X25519 public key
+    X25519 { ephemeral: [u8; 32] },
+}
+
+impl Stanza {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&Self::aad(&self.key_id, &self.kind));
+        out.extend_from_slice(&self.wrapped_key);
+    }
+
+    fn read(fields: &mut Fields<impl Read>) -> anyhow::Result<Self> {
+        let kind = fields.u8()?;
+        let key_id = fields.array()?;
+        let kind = match kind {
+            RECIPIENT_KEY => StanzaKind::Key,
+            RECIPIENT_X25519 => StanzaKind::X25519 { ephemeral: fields.array()? },
+            id => return Err(anyhow::anyhow!("Unsupported recipient type: {}", id)),
+        };
+        Ok(Self { key_id, kind, wrapped_key: fields.array()? })
+    }
+
+    /// Everything in front of the wrapped key, which is authenticated along with it.
+    pub fn aad(key_id: &KeyId, kind: &StanzaKind) -> Vec<u8> {
+        let mut aad = Vec::new();
+        match kind {
+            StanzaKind::Key => aad.push(RECIPIENT_KEY),
+            StanzaKind::X25519 { .. } => aad.push(RECIPIENT_X25519),
+        }
+        aad.extend_from_slice(key_id);
+        if let StanzaKind::X25519 { ephemeral } = kind {
+            aad.extend_from_slice(ephemeral);
+        }
+        aad
+    }
+}
+
+/// The header in front of every stream. Its exact bytes, up to the nonce prefix, are authenticated with each segment.
+///
+/// magic || version || KDF id || KDF params || cipher id || segment size (u32 BE) || nonce prefix
+/// || recipient count (u16 BE) || recipients
+///
+/// The recipients are left out of the authenticated bytes so they can be rewritten without touching the segments.
+/// Each wrapped data key is authenticated on its own, and only the right data key opens the segments.
+#[derive(Clone, Debug)]
+pub struct Header {
+    pub kdf: Kdf,
+    pub cipher: Cipher,
+    pub segment_size: u32,
+    pub nonce_prefix: Vec<u8>,
+    /// Empty unless the data key is random and wrapped per recipient, in which case `kdf` is `None`
+    pub recipients: Vec<Stanza>,
+    bytes: Vec<u8>,
+}
+
+impl Header {
+    pub fn new(kdf: Kdf, cipher: Cipher, segment_size: u32, nonce_prefix: Vec<u8>) -> Self {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        kdf.write(&mut bytes);
+        bytes.push(cipher.id());
+        bytes.extend_from_slice(&segment_size.to_be_bytes());
+        bytes.extend_from_slice(&nonce_prefix);
+
+        Self { kdf, cipher, segment_size, nonce_prefix, recipients: Vec::new(), bytes }
+    }
+
+    /// Reads a header, leaving `reader` at the first segment.
+    pub fn read(reader: impl Read) -> anyhow::Result<Self> {
+        let mut fields = Fields { reader, bytes: Vec::new() };
+
+        let magic: [u8; 6] = fields.array()?;
+        if &magic != MAGIC {
+            return Err(anyhow::anyhow!("Invalid encrypted data: unknown format"));
+        }
+
+        let version = fields.u8()?;
+        if version != VERSION {
+            return Err(anyhow::anyhow!("Unsupported format version: {}", version));
+        }
+        let kdf = Kdf::read(&mut fields)?;
+        let cipher = Cipher::from_id(fields.u8()?)?;
+
+        let segment_size = fields.u32()?;
+        check_segment_size(segment_size)?;
+        let nonce_prefix = fields.bytes(cipher.nonce_prefix_size())?;
+        let bytes = std::mem::take(&mut fields.bytes);
+
+        let count = fields.u16()?;
+        if count > MAX_RECIPIENTS || (count > 0 && kdf != Kdf::None) {
+            return Err(anyhow::anyhow!("Invalid encrypted data: bad recipient list"));
+        }
+        let mut recipients = Vec::new();
+        for _ in 0..count {
+            recipients.push(Stanza::read(&mut fields)?);
+        }
+
+        Ok(Self { kdf, cipher, segment_size, nonce_prefix, recipients, bytes })
+    }
+
+    /// Writes the header as `read` expects it.
+    pub fn write(&self, mut writer: impl Write) -> anyhow::Result<()> {
+        let mut out = self.bytes.clone();
+        out.extend_from_slice(&(self.recipients.len() as u16).to_be_bytes());
+        for stanza in &self.recipients {
+            stanza.write(&mut out);
+        }
+        writer.write_all(&out)?;
+        Ok(())
+    }
+
+    /// The authenticated part of the header.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// True when `data` starts like something this format wrote.
+pub fn is_header(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+pub fn check_segment_size(segment_size: u32) -> anyhow::Result<()> {
+    if segment_size == 0 || segment_size > MAX_SEGMENT_SIZE {
+        return Err(anyhow::anyhow!("Invalid segment size: {}", segment_size));
+    }
+    Ok(())
+}
+
+/// Reads header fields while keeping the raw bytes, which become the segments' associated data.
+struct Fields<R> {
+    reader: R,
+    bytes: Vec<u8>,
+}
+
+impl<R: Read> Fields<R> {
+    fn bytes(&mut self, len: usize) -> anyhow::Result<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        self.reader
+            .read_exact(&mut buf)
+            .map_err(|_| anyhow::anyhow!("Invalid encrypted data: header is truncated"))?;
+        self.bytes.extend_from_slice(&buf);
+        Ok(buf)
+    }
+
+    fn array<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_round_trips() {
+        let kdf = Kdf::argon2id_with_cost(8, 1, 1);
+        let header = Header::new(kdf.clone(), Cipher::XChaCha20Poly1305, 4096, vec![9; 19]);
+
+        let read = Header::read(to_vec(&header).as_slice()).unwrap();
+
+        assert_eq!(read.kdf, kdf);
+        assert_eq!(read.segment_size, 4096);
+        assert_eq!(read.cipher, Cipher::XChaCha20Poly1305);
+        assert_eq!(read.nonce_prefix, vec![9; 19]);
+        assert_eq!(read.recipients, vec![]);
+        assert_eq!(read.as_bytes(), header.as_bytes());
+    }
+
+    #[test]
+    fn test_recipients_round_trip_outside_the_authenticated_bytes() {
+        let mut header = Header::new(Kdf::None, Cipher::Aes256Gcm, 4096, vec![0; 7]);
+        let authenticated = header.as_bytes().to_vec();
+        header.recipients = vec![
+            Stanza { key_id: [1; KEY_ID_SIZE], kind: StanzaKind::Key, wrapped_key: [2; WRAPPED_KEY_SIZE] },
+            Stanza {
+                key_id: [3; KEY_ID_SIZE],
+                kind: StanzaKind::X25519 { ephemeral: [4; 32] },
+                wrapped_key: [5; WRAPPED_KEY_SIZE],
+            },
+        ];
+
+        let mut bytes = to_vec(&header);
+        bytes.extend_from_slice(b"segments");
+        let mut reader = bytes.as_slice();
+        let read = Header::read(&mut reader).unwrap();
+
+        assert_eq!(read.recipients, header.recipients);
+        assert_eq!(read.as_bytes(), authenticated);
+        assert_eq!(reader, b"segments");
+    }
+
+    #[test]
+    fn test_unknown_versions_and_ids_are_rejected() {
+        let header = Header::new(Kdf::None, Cipher::Aes256Gcm, 4096, vec![0; 7]);
+        let kdf_at = MAGIC.len() + 1;
+
+        let mut bytes = to_vec(&header);
+        bytes[MAGIC.len()] = 99;
+        assert!(Header::read(bytes.as_slice()).is_err());
+
+        let mut bytes = to_vec(&header);
+        bytes[kdf_at] = 99;
+        assert!(Header::read(bytes.as_slice()).is_err());
+
+        let mut bytes = to_vec(&header);
+        bytes[kdf_at + 1] = 99;
+        assert!(Header::read(bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_excessive_argon2_cost_is_rejected() {
+        let read = |memory_kib, iterations, parallelism| {
+            let kdf = Kdf::argon2id_with_cost(memory_kib, iterations, parallelism);
+            Header::read(to_vec(&Header::new(kdf, Cipher::Aes256Gcm, 4096, vec![0; 7])).as_slice())
+        };
+
+        assert!(read(DEFAULT_ARGON2_MEMORY_KIB, DEFAULT_ARGON2_ITERATIONS, DEFAULT_ARGON2_PARALLELISM).is_ok());
+        assert!(read(MAX_ARGON2_MEMORY_KIB, MAX_ARGON2_ITERATIONS, MAX_ARGON2_PARALLELISM).is_ok());
+        assert!(read(MAX_ARGON2_MEMORY_KIB + 1, 1, 1).is_err());
+        assert!(read(8, MAX_ARGON2_ITERATIONS + 1, 1).is_err());
+        assert!(read(8, 1, MAX_ARGON2_PARALLELISM + 1).is_err());
+        assert!(read(8, 1, u32::MAX).is_err());
+    }
+
+    #[test]
+    fn test_derivation_depends_on_password_and_salt() {
+        let kdf = Kdf::argon2id_with_cost(8, 1, 1);
+
+        let key = kdf.derive_key(b"correct horse").unwrap();
+        assert_eq!(*key, *kdf.derive_key(b"correct horse").unwrap());
+        assert_ne!(*key, *kdf.derive_key(b"battery staple").unwrap());
+        assert_ne!(*key, *Kdf::argon2id_with_cost(8, 1, 1).derive_key(b"correct horse").unwrap());
+        assert!(Kdf::None.derive_key(b"correct horse").is_err());
+        assert!(Kdf::Key { key_id: [0; KEY_ID_SIZE] }.derive_key(b"correct horse").is_err());
+    }
+
+    fn to_vec(header: &Header) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        header.write(&mut bytes).unwrap();
+        bytes
+    }
+}
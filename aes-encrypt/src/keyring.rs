@@ -0,0 +1,359 @@
+use std::{
+    fmt::{self, Write as _},
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use zeroize::Zeroizing;
+
+use crate::{
+    crypto::{Cipher, DEFAULT_SEGMENT_SIZE, Encryptor, KEY_SIZE},
+    envelope,
+    header::{self, KeyId},
+    hex,
+};
+
+const KEYRING_MAGIC: &str = "aes-encrypt keyring v1";
+
+// Binds the encrypted keyring to its purpose, so a protected keyring can't be passed off as a data file
+const KEYRING_AAD: &[u8] = b"keyring";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyStatus {
+    /// Used for new encryptions
+    Active,
+    /// Only kept to decrypt what it encrypted before
+    Retired,
+}
+
+impl KeyStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            KeyStatus::Active => "active",
+            KeyStatus::Retired => "retired",
+        }
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        match text {
+            "active" => Some(KeyStatus::Active),
+            "retired" => Some(KeyStatus::Retired),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for KeyStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+pub struct KeyEntry {
+    /// The key's fingerprint, the same ID that streams encrypted with it record
+    pub id: KeyId,
+    pub name: String,
+    /// Seconds since the Unix epoch
+    pub created: u64,
+    pub status: KeyStatus,
+    key: Zeroizing<[u8; KEY_SIZE]>,
+}
+
+impl KeyEntry {
+    pub fn encryptor(&self, cipher: Cipher) -> Encryptor {
+        Encryptor::with_cipher(&self.key, cipher)
+    }
+
+    pub fn id_hex(&self) -> String {
+        hex::encode(&self.id)
+    }
+
+    /// The creation time as `YYYY-MM-DD HH:MM` in UTC.
+    pub fn created_utc(&self) -> String {
+        // Days to a civil date, after Howard Hinnant's `civil_from_days`
+        let days = (self.created / 86_400) as i64 + 719_468;
+        let (era, day_of_era) = (days.div_euclid(146_097), days.rem_euclid(146_097));
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+        let year = era * 400 + year_of_era + i64::from(month <= 2);
+
+        let minutes = self.created % 86_400 / 60;
+        format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, minutes / 60, minutes % 60)
+    }
+}
+
+/// Named keys in one file. The file can be protected with a password, in which case it is stored in the
+/// stream format and the password is needed to load and save it.
+#[derive(Default)]
+pub struct Keyring {
+    keys: Vec<KeyEntry>,
+    // Re-encrypts the keyring on save; `None` for a plaintext keyring
+    protection: Option<Encryptor>,
+}
+
+impl Keyring {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads a keyring, calling `password` only if the file is protected.
+    pub fn load(path: &Path, password: impl FnOnce() -> anyhow::Result<Zeroizing<String>>) -> anyhow::Result<Self> {
+        let data = Zeroizing::new(
+            fs::read(path).map_err(|e| anyhow::anyhow!("Failed to read keyring {}: {}", path.display(), e))?,
+        );
+        Self::from_bytes(&data, password)
+    }
+
+    pub fn from_bytes(data: &[u8], password: impl FnOnce() -> anyhow::Result<Zeroizing<String>>) -> anyhow::Result<Self> {
+        if !header::is_header(data) {
+            return Self::parse(data);
+        }
+
+        let mut reader = data;
+        let header = header::Header::read(&mut reader)?;
+        let encryptor = Encryptor::from_password(password()?.as_bytes(), header.kdf.clone(), header.cipher)?;
+        let mut text = Zeroizing::new(Vec::new());
+        encryptor
+            .decrypt_stream_with_header(&header, reader, &mut *text, KEYRING_AAD)
+            .map_err(|_| anyhow::anyhow!("Wrong keyring password, or the keyring is corrupted"))?;
+
+        let mut keyring = Self::parse(&text)?;
+        keyring.protection = Some(encryptor);
+        Ok(keyring)
+    }
+
+    /// Writes the keyring, readable only by the owner.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let data = self.to_bytes()?;
+        // Write beside the keyring and rename over it, so a failed save can't lose keys
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        std::io::Write::write_all(&mut options.open(&temp)?, &data)?;
+        fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        let text = self.to_text();
+        let Some(encryptor) = &self.protection else {
+            return Ok(Zeroizing::new(text.as_bytes().to_vec()));
+        };
+
+        let mut data = Zeroizing::new(Vec::new());
+        encryptor.encrypt_stream(text.as_bytes(), &mut *data, DEFAULT_SEGMENT_SIZE, KEYRING_AAD)?;
+        Ok(data)
+    }
+
+    /// Protects the keyring with `password` from the next save on.
+    pub fn protect(&mut self, password: &[u8]) -> anyhow::Result<()> {
+        self.protection = Some(Encryptor::with_password(password, Cipher::default())?);
+        Ok(())
+    }
+
+    pub fn is_protected(&self) -> bool {
+        self.protection.is_some()
+    }
+
+    pub fn keys(&self) -> &[KeyEntry] {
+        &self.keys
+    }
+
+    /// Adds an active key under a new name.
+    pub fn add(&mut self, name: &str, key: Zeroizing<[u8; KEY_SIZE]>) -> anyhow::Result<&KeyEntry> {
+        check_name(name)?;
+        let id = envelope::key_id(&key);
+        if self.keys.iter().any(|entry| entry.name == name) {
+            return Err(anyhow::anyhow!("The keyring already has a key named {}", name));
+        }
+        if let Some(entry) = self.get(&id) {
+            return Err(anyhow::anyhow!("The keyring already has this key, as {}", entry.name));
+        }
+
+        let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        self.keys.push(KeyEntry { id, name: name.to_string(), created, status: KeyStatus::Active, key });
+        Ok(self.keys.last().unwrap())
+    }
+
+    /// Stops `name_or_id` being used for new encryptions. It still decrypts.
+    pub fn retire(&mut self, name_or_id: &str) -> anyhow::Result<&KeyEntry> {
+        let entry = self
+            .keys
+            .iter_mut()
+            .find(|entry| entry.name == name_or_id || entry.id_hex() == name_or_id)
+            .ok_or_else(|| anyhow::anyhow!("No key named {} in the keyring", name_or_id))?;
+        entry.status = KeyStatus::Retired;
+        Ok(entry)
+    }
+
+    pub fn get(&self, id: &KeyId) -> Option<&KeyEntry> {
+        self.keys.iter().find(|entry| entry.id == *id)
+    }
+
+    /// The key to encrypt with: the named one, or else the newest active key.
+    pub fn active(&self, name: Option<&str>) -> anyhow::Result<&KeyEntry> {
+        let entry = match name {
+            Some(name) => self.keys.iter().find(|entry| entry.name == name),
+            None => self.keys.iter().filter(|entry| entry.status == KeyStatus::Active).max_by_key(|entry| entry.created),
+        };
+        let entry = entry.ok_or_else(|| match name {
+            Some(name) => anyhow::anyhow!("No key named {} in the keyring", name),
+            None => anyhow::anyhow!("The keyring has no active key"),
+        })?;
+
+        if entry.status != KeyStatus::Active {
+            return Err(anyhow::anyhow!("Key {} is retired", entry.name));
+        }
+        Ok(entry)
+    }
+
+    /// One `id created status key name` line per key, after a line naming the format.
+    fn to_text(&self) -> Zeroizing<String> {
+        let mut text = Zeroizing::new(format!("{}\n", KEYRING_MAGIC));
+        for entry in &self.keys {
+            let key = Zeroizing::new(hex::encode(entry.key.as_ref()));
+            let _ = writeln!(text, "{} {} {} {} {}", entry.id_hex(), entry.created, entry.status, *key, entry.name);
+        }
+        text
+    }
+
+    fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let text = std::str::from_utf8(data).map_err(|_| anyhow::anyhow!("Invalid keyring"))?;
+        let mut lines = text.lines();
+        if lines.next() != Some(KEYRING_MAGIC) {
+            return Err(anyhow::anyhow!("Invalid keyring: unknown format"));
+        }
+
+        let mut keys = Vec::new();
+        for (number, line) in lines.enumerate() {
+            // Lines hold key material, so errors only give their position
+            let invalid = || anyhow::anyhow!("Invalid keyring entry {}", number + 1);
+            let fields: Vec<&str> = line.splitn(5, ' ').collect();
+            let [id, created, status, key, name] = fields[..] else {
+                return Err(invalid());
+            };
+
+            let key = Zeroizing::new(hex::decode(key).ok_or_else(invalid)?);
+            let id = hex::decode(id).ok_or_else(invalid)?;
+            if id != envelope::key_id(&key) {
+                return Err(invalid());
+            }
+            keys.push(KeyEntry {
+                id,
+                name: name.to_string(),
+                created: created.parse().map_err(|_| invalid())?,
+                status: KeyStatus::parse(status).ok_or_else(invalid)?,
+                key,
+            });
+        }
+        Ok(Self { keys, protection: None })
+    }
+}
+
+fn check_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || name.contains('\n') {
+        return Err(anyhow::anyhow!("Invalid key name: {:?}", name));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::{Header, Kdf};
+
+    fn no_password() -> anyhow::Result<Zeroizing<String>> {
+        panic!("the keyring isn't protected")
+    }
+
+    fn keyring() -> Keyring {
+        let mut keyring = Keyring::new();
+        keyring.add("2024", Zeroizing::new([1u8; KEY_SIZE])).unwrap();
+        keyring.add("2025 rotation", Zeroizing::new([2u8; KEY_SIZE])).unwrap();
+        keyring
+    }
+
+    #[test]
+    fn test_keyring_round_trips() {
+        let mut keyring = keyring();
+        keyring.retire("2024").unwrap();
+
+        let read = Keyring::from_bytes(&keyring.to_bytes().unwrap(), no_password).unwrap();
+
+        let summary: Vec<(&str, KeyStatus)> = read.keys().iter().map(|entry| (entry.name.as_str(), entry.status)).collect();
+        assert_eq!(summary, [("2024", KeyStatus::Retired), ("2025 rotation", KeyStatus::Active)]);
+        assert_eq!(read.keys()[0].key, keyring.keys()[0].key);
+        assert_eq!(read.keys()[1].created, keyring.keys()[1].created);
+    }
+
+    #[test]
+    fn test_protected_keyring_needs_the_password() {
+        let mut keyring = keyring();
+        // `protect` with a cheap KDF
+        keyring.protection = Some(Encryptor::from_password(b"hunter2", Kdf::argon2id_with_cost(8, 1, 1), Cipher::default()).unwrap());
+        let data = keyring.to_bytes().unwrap();
+
+        assert!(!String::from_utf8_lossy(&data).contains(KEYRING_MAGIC));
+        let read = Keyring::from_bytes(&data, || Ok(Zeroizing::new("hunter2".to_string()))).unwrap();
+        assert!(read.is_protected());
+        assert_eq!(read.keys().len(), 2);
+        assert!(Keyring::from_bytes(&data, || Ok(Zeroizing::new("hunter3".to_string()))).is_err());
+    }
+
+    #[test]
+    fn test_streams_record_the_key_id() {
+        let keyring = keyring();
+        let entry = keyring.active(Some("2025 rotation")).unwrap();
+        let mut sealed = Vec::new();
+        entry.encryptor(Cipher::Aes256Gcm).encrypt_stream(&b"secret"[..], &mut sealed, 16, &[]).unwrap();
+
+        let header = Header::read(sealed.as_slice()).unwrap();
+        let Kdf::Key { key_id } = header.kdf else { panic!("expected a key ID") };
+
+        let mut opened = Vec::new();
+        let found = keyring.get(&key_id).unwrap();
+        found.encryptor(header.cipher).decrypt_stream(sealed.as_slice(), &mut opened, &[]).unwrap();
+        assert_eq!(found.name, "2025 rotation");
+        assert_eq!(opened, b"secret");
+    }
+
+    #[test]
+    fn test_retired_keys_are_not_used_to_encrypt() {
+        let mut keyring = keyring();
+        assert_eq!(keyring.active(None).unwrap().name, "2025 rotation");
+
+        keyring.retire("2025 rotation").unwrap();
+        assert_eq!(keyring.active(None).unwrap().name, "2024");
+        assert!(keyring.active(Some("2025 rotation")).is_err());
+
+        let id = keyring.keys()[0].id_hex();
+        keyring.retire(&id).unwrap();
+        assert!(keyring.active(None).is_err());
+    }
+
+    #[test]
+    fn test_creation_dates_are_shown_in_utc() {
+        let mut keyring = keyring();
+        keyring.keys[0].created = 1_700_000_000;
+        keyring.keys[1].created = 951_782_400;
+
+        assert_eq!(keyring.keys()[0].created_utc(), "2023-11-14 22:13");
+        assert_eq!(keyring.keys()[1].created_utc(), "2000-02-29 00:00");
+    }
+
+    #[test]
+    fn test_duplicate_names_and_keys_are_rejected() {
+        let mut keyring = keyring();
+
+        assert!(keyring.add("2024", Zeroizing::new([3u8; KEY_SIZE])).is_err());
+        assert!(keyring.add("copy", Zeroizing::new([1u8; KEY_SIZE])).is_err());
+    }
+}
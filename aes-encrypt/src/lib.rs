@@ -0,0 +1,7 @@
+pub mod armor;
+pub mod crypto;
+pub mod directory;
+pub mod envelope;
+pub mod header;
+pub mod keyring;
+pub mod hex;
@@ -1,42 +1,205 @@
-mod crypto;
-
-use std::{fs, path::PathBuf};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Cursor, Read, Write},
+    path::{Path, PathBuf},
+    thread,
+};
 
 use anyhow::Ok;
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use rand::Rng;
-use zeroize::Zeroize;
+use zeroize::{Zeroize, Zeroizing};
+
+use aes_encrypt::{
+    armor::{self, ArmorReader, ArmorWriter},
+    crypto, directory,
+    envelope::{self, Identity, Recipient},
+    header::{self, Header, Kdf},
+    hex,
+    keyring::Keyring,
+};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+// Read instead of prompting when set, for scripts and pipelines without a terminal
+const PASSWORD_ENV: &str = "AES_ENCRYPT_PASSWORD";
+const KEYRING_PASSWORD_ENV: &str = "AES_ENCRYPT_KEYRING_PASSWORD";
 
 #[derive(Parser)]
 #[command(name = "aes-encrypt")]
-#[command(about = "Encrypt data using AES256-GCM")]
-struct CLI {
+#[command(about = "Encrypt data using AES-256-GCM, AES-256-GCM-SIV or XChaCha20-Poly1305")]
+struct Cli {
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand)]
 enum Commands {
+    /// Encrypt a file or stdin; reads and writes in segments, so input size isn't limited by memory.
+    /// A directory is encrypted file by file into the same layout, with an encrypted manifest
     Encrypt {
+        /// Input file or directory; stdin when omitted or `-`
         #[arg(short, long)]
-        input: PathBuf,
+        input: Option<PathBuf>,
+        /// Output file, or directory when the input is one; stdout when omitted or `-`
         #[arg(short, long)]
-        output: PathBuf,
+        output: Option<PathBuf>,
+        #[command(flatten)]
+        keys: EncryptKeys,
+        /// Context such as a file name or tenant ID that decryption must repeat; authenticated, not stored.
+        /// Not used for directories, where each file is bound to its path instead
+        #[arg(long)]
+        aad: Option<String>,
+        /// Write a PEM-style text block instead of binary, for pasting into configs, mail or chat
         #[arg(short, long)]
-        key_file: PathBuf,
+        armor: bool,
+        /// Files to encrypt at once when the input is a directory; defaults to the number of CPUs
+        #[arg(short, long)]
+        jobs: Option<usize>,
     },
+    /// Encrypt a value read from stdin into a single-line token, for secrets kept in config files.
+    /// `decrypt` turns the token back into the value
+    EncryptString {
+        #[command(flatten)]
+        keys: EncryptKeys,
+        /// Context that decryption must repeat; authenticated, not stored
+        #[arg(long)]
+        aad: Option<String>,
+    },
+    /// Decrypt a stream written by `encrypt`, or a file from older versions (nonce || ciphertext).
+    /// Armored input and `encrypt-string` tokens are recognized automatically
     Decrypt {
+        /// Input file or encrypted directory; stdin when omitted or `-`
         #[arg(short, long)]
-        input: PathBuf,
+        input: Option<PathBuf>,
+        /// Output file, or directory when the input is one; stdout when omitted or `-`
         #[arg(short, long)]
-        output: PathBuf,
+        output: Option<PathBuf>,
+        /// Key file or X25519 secret key; password-encrypted files prompt instead
+        #[arg(short, long)]
+        key_file: Option<PathBuf>,
+        /// Keyring to find the key in, for files encrypted with a key
+        #[arg(long)]
+        keyring: Option<PathBuf>,
+        /// The associated data given to `encrypt`, if any
+        #[arg(long)]
+        aad: Option<String>,
+        /// Files to decrypt at once when the input is a directory; defaults to the number of CPUs
+        #[arg(short, long)]
+        jobs: Option<usize>,
+    },
+    /// Check that a file or encrypted directory decrypts and matches its manifest, without writing plaintext
+    Verify {
+        /// Input file or encrypted directory; stdin when omitted or `-`
+        #[arg(short, long)]
+        input: Option<PathBuf>,
+        /// Key file or X25519 secret key; password-encrypted files prompt instead
+        #[arg(short, long)]
+        key_file: Option<PathBuf>,
+        /// Keyring to find the key in, for files encrypted with a key
+        #[arg(long)]
+        keyring: Option<PathBuf>,
+        /// The associated data given to `encrypt`, if any
+        #[arg(long)]
+        aad: Option<String>,
+        /// Files to check at once when the input is a directory; defaults to the number of CPUs
+        #[arg(short, long)]
+        jobs: Option<usize>,
+    },
+    /// Add or remove recipients of a file encrypted with `--recipient`/`--recipient-key`, leaving the data as is
+    Rewrap {
+        /// Input file; stdin when omitted or `-`
+        #[arg(short, long)]
+        input: Option<PathBuf>,
+        /// Output file; stdout when omitted or `-`
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Key file or X25519 secret key of a current recipient
         #[arg(short, long)]
         key_file: PathBuf,
+        /// X25519 public key to add; repeatable
+        #[arg(short, long)]
+        recipient: Vec<PathBuf>,
+        /// Symmetric key file to add; repeatable
+        #[arg(long)]
+        recipient_key: Vec<PathBuf>,
+        /// Public key or key file of a recipient to remove; repeatable
+        #[arg(long)]
+        remove: Vec<PathBuf>,
+    },
+    /// Manage the named keys in a keyring file
+    Key {
+        #[command(subcommand)]
+        command: KeyCommands,
     },
     GenerateKey {
         #[arg(short, long)]
         output: PathBuf,
     },
+    /// Generate an X25519 key pair: the secret key in OUTPUT and the public key in OUTPUT.pub
+    GenerateKeypair {
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+}
+
+/// Which key encrypts: a key file, a password, recipients or a keyring.
+#[derive(Args)]
+struct EncryptKeys {
+    #[arg(short, long, required_unless_present_any = ["password", "recipient", "recipient_key", "keyring"])]
+    key_file: Option<PathBuf>,
+    /// Derive the key from a password (Argon2id) instead of reading a key file
+    #[arg(short, long, conflicts_with = "key_file")]
+    password: bool,
+    /// Encrypt a random data key for the holder of this X25519 public key; repeatable
+    #[arg(short, long, conflicts_with_all = ["key_file", "password"])]
+    recipient: Vec<PathBuf>,
+    /// Encrypt a random data key for the holders of this symmetric key file; repeatable
+    #[arg(long, conflicts_with_all = ["key_file", "password"])]
+    recipient_key: Vec<PathBuf>,
+    /// Encrypt with the newest active key in this keyring
+    #[arg(long, conflicts_with_all = ["key_file", "password", "recipient", "recipient_key"])]
+    keyring: Option<PathBuf>,
+    /// Use this keyring key instead of the newest active one
+    #[arg(long, requires = "keyring")]
+    key_name: Option<String>,
+    #[arg(short, long, value_enum, default_value_t)]
+    cipher: crypto::Cipher,
+}
+
+#[derive(Subcommand)]
+enum KeyCommands {
+    /// List the keys with their IDs, creation dates and status
+    List {
+        #[arg(long)]
+        keyring: PathBuf,
+    },
+    /// Add a key, creating the keyring if it doesn't exist
+    Add {
+        #[arg(long)]
+        keyring: PathBuf,
+        #[arg(short, long)]
+        name: String,
+        /// Import this key file instead of generating a new key
+        #[arg(long)]
+        import: Option<PathBuf>,
+        /// Protect the keyring with a password, if it isn't already
+        #[arg(long)]
+        protect: bool,
+    },
+    /// Stop using a key for new encryptions; it still decrypts
+    Retire {
+        #[arg(long)]
+        keyring: PathBuf,
+        /// Name or ID of the key
+        key: String,
+    },
+}
+
+/// Where decryption may find the key; the header decides which is used.
+#[derive(Clone, Copy)]
+struct KeySource<'a> {
+    key_file: Option<&'a Path>,
+    keyring: Option<&'a Path>,
 }
 
 fn read_key(key_file: &std::path::Path) -> anyhow::Result<[u8; 32]> {
@@ -45,42 +208,360 @@ fn read_key(key_file: &std::path::Path) -> anyhow::Result<[u8; 32]> {
         .map_err(|_| anyhow::anyhow!("Invalid key length"))
 }
 
+fn key_encryptor(key_file: Option<&Path>, cipher: crypto::Cipher) -> anyhow::Result<crypto::Encryptor> {
+    let key_file = key_file.ok_or_else(|| anyhow::anyhow!("This file needs a key file (--key-file)"))?;
+    let mut key: [u8; 32] = read_key(key_file)?;
+    let encryptor = crypto::Encryptor::with_cipher(&key, cipher);
+    key.zeroize();
+    Ok(encryptor)
+}
+
+fn encryptor(keys: &EncryptKeys) -> anyhow::Result<crypto::Encryptor> {
+    let cipher = keys.cipher;
+    if keys.password {
+        crypto::Encryptor::with_password(read_password(PASSWORD_ENV, "Password: ", true)?.as_bytes(), cipher)
+    } else if !keys.recipient.is_empty() || !keys.recipient_key.is_empty() {
+        crypto::Encryptor::for_recipients(&read_recipients(&keys.recipient, &keys.recipient_key)?, cipher)
+    } else if let Some(keyring) = &keys.keyring {
+        Ok(load_keyring(keyring)?.active(keys.key_name.as_deref())?.encryptor(cipher))
+    } else {
+        key_encryptor(keys.key_file.as_deref(), cipher)
+    }
+}
+
+fn read_identity(key_file: Option<&Path>) -> anyhow::Result<Identity> {
+    let key_file = key_file.ok_or_else(|| anyhow::anyhow!("This file needs a key file (--key-file)"))?;
+    Ok(Identity::new(Zeroizing::new(read_key(key_file)?)))
+}
+
+fn read_recipients(public_keys: &[PathBuf], key_files: &[PathBuf]) -> anyhow::Result<Vec<Recipient>> {
+    let mut recipients = Vec::new();
+    for path in public_keys {
+        recipients.push(Recipient::X25519(PublicKey::from(read_key(path)?)));
+    }
+    for path in key_files {
+        recipients.push(Recipient::Key(Zeroizing::new(read_key(path)?)));
+    }
+    Ok(recipients)
+}
+
+fn read_password(env: &str, prompt: &str, confirm: bool) -> anyhow::Result<Zeroizing<String>> {
+    let password = match std::env::var(env) {
+        Result::Ok(password) => Zeroizing::new(password),
+        Err(_) => {
+            let password = Zeroizing::new(rpassword::prompt_password(prompt)?);
+            if confirm && *Zeroizing::new(rpassword::prompt_password("Confirm password: ")?) != *password {
+                return Err(anyhow::anyhow!("Passwords do not match"));
+            }
+            password
+        }
+    };
+
+    if password.is_empty() {
+        return Err(anyhow::anyhow!("Password must not be empty"));
+    }
+    Ok(password)
+}
+
+fn load_keyring(path: &Path) -> anyhow::Result<Keyring> {
+    Keyring::load(path, || read_password(KEYRING_PASSWORD_ENV, "Keyring password: ", false))
+}
+
+fn is_dir(path: &Option<PathBuf>) -> bool {
+    !is_std(path) && path.as_deref().unwrap().is_dir()
+}
+
+/// The output directory for a directory input; stdout can't take one.
+fn output_dir(output: &Option<PathBuf>) -> anyhow::Result<&Path> {
+    if is_std(output) {
+        return Err(anyhow::anyhow!("An output directory (--output) is needed when the input is a directory"));
+    }
+    Ok(output.as_deref().unwrap())
+}
+
+fn jobs(jobs: Option<usize>) -> usize {
+    jobs.unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+}
+
+fn is_std(path: &Option<PathBuf>) -> bool {
+    path.as_deref().is_none_or(|path| path == Path::new("-"))
+}
+
+fn open_input(input: &Option<PathBuf>) -> anyhow::Result<Box<dyn Read>> {
+    if is_std(input) {
+        return Ok(Box::new(io::stdin().lock()));
+    }
+    let path = input.as_deref().unwrap();
+    let file = File::open(path).map_err(|e| anyhow::anyhow!("Failed to open {}: {}", path.display(), e))?;
+    Ok(Box::new(BufReader::new(file)))
+}
+
+/// Runs `write` against the output, removing a half-written output file if it fails.
+fn with_output(output: &Option<PathBuf>, write: impl FnOnce(&mut dyn Write) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    if is_std(output) {
+        return write(&mut io::stdout().lock());
+    }
+
+    let path = output.as_deref().unwrap();
+    let mut file = BufWriter::new(File::create(path)?);
+    let result = write(&mut file).and_then(|_| Ok(file.flush()?));
+    if result.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    result
+}
+
+/// Decrypts armored messages and `encrypt-string` tokens as well as binary input, telling them apart by how
+/// they start.
+fn decrypt(keys: KeySource, aad: &[u8], mut reader: impl Read, writer: &mut dyn Write) -> anyhow::Result<()> {
+    // Armor and tokens may be indented or follow blank lines. The whitespace is kept rather than skipped, since
+    // binary input can start with the same bytes
+    let mut start = Vec::new();
+    let mut byte = [0u8; 1];
+    while start.last().is_none_or(u8::is_ascii_whitespace) && reader.read(&mut byte)? == 1 {
+        start.push(byte[0]);
+    }
+    (&mut reader).take(armor::BEGIN.len() as u64 - 1).read_to_end(&mut start)?;
+    let armored = armor::is_armored(&start);
+    let token = armor::is_token(&start);
+    let mut reader = Cursor::new(start).chain(reader);
+
+    if armored {
+        return decrypt_binary(keys, aad, ArmorReader::new(BufReader::new(reader)), writer);
+    }
+    if token {
+        let mut text = String::new();
+        reader.read_to_string(&mut text)?;
+        return decrypt_binary(keys, aad, armor::decode_token(&text)?.as_slice(), writer);
+    }
+    decrypt_binary(keys, aad, reader, writer)
+}
+
+/// Decrypts the streaming format, falling back to the whole-file legacy format when the magic is missing.
+/// The header says whether the key comes from the key file or a password, and which cipher was used.
+fn decrypt_binary(keys: KeySource, aad: &[u8], mut reader: impl Read, writer: &mut dyn Write) -> anyhow::Result<()> {
+    let mut magic = Vec::new();
+    (&mut reader).take(header::MAGIC.len() as u64).read_to_end(&mut magic)?;
+
+    if !header::is_header(&magic) {
+        let mut data = magic;
+        reader.read_to_end(&mut data)?;
+        writer.write_all(&key_encryptor(keys.key_file, crypto::Cipher::Aes256Gcm)?.decrypt_with_aad(&data, aad)?)?;
+        return Ok(());
+    }
+
+    let mut reader = Cursor::new(magic).chain(reader);
+    let header = Header::read(&mut reader)?;
+    header_encryptor(keys, &header)?.decrypt_stream_with_header(&header, reader, writer, aad)?;
+    Ok(())
+}
+
+/// Builds the decryptor a header asks for: from the key file or keyring, a password, or a data key wrapped
+/// for the key file.
+fn header_encryptor(keys: KeySource, header: &Header) -> anyhow::Result<crypto::Encryptor> {
+    match &header.kdf {
+        Kdf::None if !header.recipients.is_empty() => crypto::Encryptor::with_identity(&read_identity(keys.key_file)?, header),
+        Kdf::None => key_encryptor(keys.key_file, header.cipher),
+        Kdf::Key { key_id } => {
+            if let (None, Some(keyring)) = (keys.key_file, keys.keyring) {
+                let keyring = load_keyring(keyring)?;
+                let entry = keyring
+                    .get(key_id)
+                    .ok_or_else(|| anyhow::anyhow!("Key {} isn't in the keyring", hex::encode(key_id)))?;
+                return Ok(entry.encryptor(header.cipher));
+            }
+
+            let key_file = keys.key_file.ok_or_else(|| {
+                anyhow::anyhow!("This file was encrypted with key {}; pass its key file (--key-file) or a keyring (--keyring)", hex::encode(key_id))
+            })?;
+            let key = Zeroizing::new(read_key(key_file)?);
+            if envelope::key_id(&key) != *key_id {
+                return Err(anyhow::anyhow!("This file was encrypted with key {}, not {}", hex::encode(key_id), key_file.display()));
+            }
+            Ok(crypto::Encryptor::with_cipher(&key, header.cipher))
+        }
+        kdf => crypto::Encryptor::from_password(read_password(PASSWORD_ENV, "Password: ", false)?.as_bytes(), kdf.clone(), header.cipher),
+    }
+}
+
+fn key_command(command: KeyCommands) -> anyhow::Result<()> {
+    match command {
+        KeyCommands::List { keyring } => {
+            let keyring = load_keyring(&keyring)?;
+            let width = keyring.keys().iter().map(|entry| entry.name.len()).max().unwrap_or(0).max(4);
+
+            println!("{:<16}  {:<width$}  {:<16}  STATUS", "ID", "NAME", "CREATED (UTC)");
+            for entry in keyring.keys() {
+                println!("{}  {:<width$}  {}  {}", entry.id_hex(), entry.name, entry.created_utc(), entry.status);
+            }
+        }
+        KeyCommands::Add {
+            keyring: path,
+            name,
+            import,
+            protect,
+        } => {
+            let mut keyring = if path.exists() { load_keyring(&path)? } else { Keyring::new() };
+            if protect && !keyring.is_protected() {
+                keyring.protect(read_password(KEYRING_PASSWORD_ENV, "New keyring password: ", true)?.as_bytes())?;
+            }
+
+            let key = match import {
+                Some(key_file) => Zeroizing::new(read_key(&key_file)?),
+                None => envelope::data_key(),
+            };
+            let id = keyring.add(&name, key)?.id_hex();
+            keyring.save(&path)?;
+
+            println!("Added key {} ({})", name, id);
+        }
+        KeyCommands::Retire { keyring: path, key } => {
+            let mut keyring = load_keyring(&path)?;
+            let entry = keyring.retire(&key)?;
+            let (name, id) = (entry.name.clone(), entry.id_hex());
+            keyring.save(&path)?;
+
+            println!("Retired key {} ({})", name, id);
+        }
+    }
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
-    let cli = CLI::parse();
+    let cli = Cli::parse();
 
     match cli.command {
         Commands::Encrypt {
             input,
             output,
-            key_file,
+            keys,
+            aad,
+            armor,
+            jobs: job_count,
         } => {
-            let mut key: [u8; 32] = read_key(&key_file)?;
+            let encryptor = encryptor(&keys)?;
 
-            let data = fs::read(&input)?;
-            let encryptor = crypto::Encryptor::new(&key);
-            let encrypted = encryptor.encrypt(&data)?;
-            fs::write(output, encrypted)?;
+            if is_dir(&input) {
+                if aad.is_some() {
+                    return Err(anyhow::anyhow!("--aad isn't supported for directories; each file is bound to its path"));
+                }
+                if armor {
+                    return Err(anyhow::anyhow!("--armor isn't supported for directories"));
+                }
+                let manifest = directory::encrypt_dir(&encryptor, input.as_deref().unwrap(), output_dir(&output)?, jobs(job_count))?;
+                eprintln!("Encrypted {} files", manifest.entries.len());
+                return Ok(());
+            }
 
-            println!("Data encrypted successfully");
+            let aad = aad.unwrap_or_default();
+            let reader = open_input(&input)?;
+            with_output(&output, |writer| {
+                if !armor {
+                    encryptor.encrypt_stream(reader, writer, crypto::DEFAULT_SEGMENT_SIZE, aad.as_bytes())?;
+                    return Ok(());
+                }
+                let mut armored = ArmorWriter::new(writer)?;
+                encryptor.encrypt_stream(reader, &mut armored, crypto::DEFAULT_SEGMENT_SIZE, aad.as_bytes())?;
+                armored.finish()?;
+                Ok(())
+            })?;
 
-            key.zeroize();
+            // stdout may be carrying the ciphertext, so report on stderr
+            eprintln!("Data encrypted successfully");
+        }
+        Commands::EncryptString { keys, aad } => {
+            let encryptor = encryptor(&keys)?;
+            let mut value = Zeroizing::new(Vec::new());
+            io::stdin().read_to_end(&mut value)?;
+            // `echo secret |` and here-strings add a newline that isn't part of the value
+            if value.ends_with(b"\n") {
+                value.pop();
+                if value.ends_with(b"\r") {
+                    value.pop();
+                }
+            }
+
+            let mut sealed = Vec::new();
+            encryptor.encrypt_stream(value.as_slice(), &mut sealed, crypto::DEFAULT_SEGMENT_SIZE, aad.unwrap_or_default().as_bytes())?;
+            println!("{}", armor::encode_token(&sealed));
         }
         Commands::Decrypt {
             input,
             output,
             key_file,
+            keyring,
+            aad,
+            jobs: job_count,
         } => {
-            let mut key: [u8; 32] = read_key(&key_file)?;
+            let keys = KeySource { key_file: key_file.as_deref(), keyring: keyring.as_deref() };
+            if is_dir(&input) {
+                let input = input.as_deref().unwrap();
+                let encryptor = header_encryptor(keys, &directory::manifest_header(input)?)?;
+                let manifest = directory::decrypt_dir(&encryptor, input, output_dir(&output)?, jobs(job_count))?;
+                eprintln!("Decrypted {} files", manifest.entries.len());
+                return Ok(());
+            }
 
-            let data = fs::read(&input)?;
-            let encryptor = crypto::Encryptor::new(&key);
-            let decrypted = encryptor.decrypt(&data)?;
-            fs::write(output, decrypted)?;
+            let aad = aad.unwrap_or_default();
+            let reader = open_input(&input)?;
+            with_output(&output, |writer| decrypt(keys, aad.as_bytes(), reader, writer))?;
 
-            println!("Data decrypted successfully");
+            eprintln!("Data decrypted successfully");
+        }
+        Commands::Verify {
+            input,
+            key_file,
+            keyring,
+            aad,
+            jobs: job_count,
+        } => {
+            let keys = KeySource { key_file: key_file.as_deref(), keyring: keyring.as_deref() };
+            if !is_dir(&input) {
+                let aad = aad.unwrap_or_default();
+                decrypt(keys, aad.as_bytes(), open_input(&input)?, &mut io::sink())?;
+                eprintln!("OK");
+                return Ok(());
+            }
 
-            key.zeroize();
+            let input = input.as_deref().unwrap();
+            let encryptor = header_encryptor(keys, &directory::manifest_header(input)?)?;
+            let (manifest, problems) = directory::verify_dir(&encryptor, input, jobs(job_count))?;
+            for problem in &problems {
+                println!("{}: {}", problem.path, problem.message);
+            }
+            if !problems.is_empty() {
+                return Err(anyhow::anyhow!("Verification failed: {} problem(s) in {} files", problems.len(), manifest.entries.len()));
+            }
+            eprintln!("Verified {} files", manifest.entries.len());
         }
+        Commands::Rewrap {
+            input,
+            output,
+            key_file,
+            recipient,
+            recipient_key,
+            remove,
+        } => {
+            let identity = read_identity(Some(&key_file))?;
+            let add = read_recipients(&recipient, &recipient_key)?;
+            // A file to remove may be a public key or a key file; drop whichever kind of recipient it matches
+            let mut remove_ids = Vec::new();
+            for path in &remove {
+                let key = read_key(path)?;
+                remove_ids.push(Recipient::X25519(PublicKey::from(key)).key_id());
+                remove_ids.push(Recipient::Key(Zeroizing::new(key)).key_id());
+            }
+
+            let reader = open_input(&input)?;
+            let mut count = 0;
+            with_output(&output, |writer| {
+                count = envelope::rewrap(&identity, &add, &remove_ids, reader, writer)?;
+                Ok(())
+            })?;
+
+            eprintln!("Rewrapped for {} recipient(s)", count);
+        }
+        Commands::Key { command } => key_command(command)?,
         Commands::GenerateKey { output } => {
             let mut key = [0u8; 32];
 
@@ -91,7 +572,55 @@ fn main() -> anyhow::Result<()> {
 
             key.zeroize();
         }
+        Commands::GenerateKeypair { output } => {
+            let secret = StaticSecret::random_from_rng(rand::thread_rng());
+            let mut public_path = output.clone().into_os_string();
+            public_path.push(".pub");
+
+            fs::write(&output, secret.as_bytes())?;
+            fs::write(&public_path, PublicKey::from(&secret).as_bytes())?;
+
+            println!("Key pair generated successfully");
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_file(name: &str) -> (PathBuf, crypto::Encryptor) {
+        let key = [7u8; 32];
+        let path = std::env::temp_dir().join(format!("aes-encrypt-{}-{}.key", name, std::process::id()));
+        fs::write(&path, key).unwrap();
+        (path, crypto::Encryptor::new(&key))
+    }
+
+    fn decrypt_text(key_file: &Path, text: &str) -> anyhow::Result<Vec<u8>> {
+        let keys = KeySource { key_file: Some(key_file), keyring: None };
+        let mut plaintext = Vec::new();
+        decrypt(keys, b"", text.as_bytes(), &mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn test_decrypt_indented_armor_and_token() {
+        let (path, encryptor) = key_file("indented");
+        let mut armored = ArmorWriter::new(Vec::new()).unwrap();
+        encryptor.encrypt_stream(&b"database password"[..], &mut armored, crypto::DEFAULT_SEGMENT_SIZE, b"").unwrap();
+        let armored = String::from_utf8(armored.finish().unwrap()).unwrap();
+        let mut sealed = Vec::new();
+        encryptor.encrypt_stream(&b"api token"[..], &mut sealed, crypto::DEFAULT_SEGMENT_SIZE, b"").unwrap();
+        let token = armor::encode_token(&sealed);
+
+        // As pasted into a YAML block, after a blank line
+        let indented: String = armored.lines().map(|line| format!("      {}\n", line)).collect();
+        assert_eq!(decrypt_text(&path, &format!("\n\n{}", indented)).unwrap(), b"database password");
+        assert_eq!(decrypt_text(&path, &format!("\n    {}\n", token)).unwrap(), b"api token");
+        assert_eq!(decrypt_text(&path, &armored).unwrap(), b"database password");
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use crate::formats::ArchiveFormat;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+use time::OffsetDateTime;
+
+/// Exit code when the file isn't an archive, or its index can't be read.
+pub const EXIT_INVALID: i32 = 2;
+/// Exit code when the archive opens but some of its contents are damaged.
+pub const EXIT_CORRUPT: i32 = 3;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryStatus {
+    /// Read back in full; for ZIP, its CRC32 matched too
+    #[default]
+    Ok,
+    /// Encrypted and no password given, so its contents couldn't be checked
+    Unverified,
+    Corrupt,
+}
+
+/// What `analyze` found out about one entry. Fields are `None` where the format doesn't record them, or the
+/// entry is too damaged to tell.
+#[derive(Debug, Default, Serialize)]
+pub struct EntryReport {
+    pub name: String,
+    pub kind: &'static str,
+    pub method: Option<String>,
+    pub size: Option<u64>,
+    pub compressed_size: Option<u64>,
+    pub ratio: Option<f64>,
+    pub crc32: Option<String>,
+    pub modified: Option<String>,
+    pub mode: Option<String>,
+    pub encrypted: bool,
+    /// The cipher of an encrypted entry, like `AES-256` or `ZipCrypto`
+    pub encryption: Option<String>,
+    pub zip64: bool,
+    pub status: EntryStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub path: String,
+    pub format: Option<&'static str>,
+    /// Whether the file is an archive whose entries could be listed at all
+    pub valid: bool,
+    /// Why the archive is invalid, or damage that isn't tied to one entry, like a truncated TAR stream
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub file_size: u64,
+    pub entries: Vec<EntryReport>,
+}
+
+impl Report {
+    pub fn corrupt_entries(&self) -> usize {
+        self.entries.iter().filter(|e| e.status == EntryStatus::Corrupt).count()
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        if !self.valid {
+            EXIT_INVALID
+        } else if self.error.is_some() || self.corrupt_entries() > 0 {
+            EXIT_CORRUPT
+        } else {
+            0
+        }
+    }
+
+    pub fn print(&self) {
+        let format = match self.format {
+            Some(format) => format,
+            None => {
+                println!("Not a recognized archive");
+                return;
+            }
+        };
+        println!("Format: {}", format);
+        if !self.valid {
+            println!("Not a valid {} archive: {}", format, self.error.as_deref().unwrap_or("unknown error"));
+            return;
+        }
+
+        let size: u64 = self.entries.iter().filter_map(|e| e.size).sum();
+        println!("Number of files: {}", self.entries.len());
+        println!("Total size: {} bytes, archive {} bytes ({})", size, self.file_size, percent(ratio(self.file_size, size)));
+        println!(
+            "{:<10} {:<8} {:>12} {:>12} {:>7} {:<8} {:<19} {:<4} NAME",
+            "STATUS", "METHOD", "SIZE", "COMPRESSED", "RATIO", "CRC32", "MODIFIED", "MODE"
+        );
+        for entry in &self.entries {
+            let mut name = entry.name.clone();
+            if let Some(encryption) = &entry.encryption {
+                name.push_str(&format!(" [{}]", encryption));
+            }
+            if entry.zip64 {
+                name.push_str(" [zip64]");
+            }
+            println!(
+                "{:<10} {:<8} {:>12} {:>12} {:>7} {:<8} {:<19} {:<4} {}",
+                format!("{:?}", entry.status).to_lowercase(),
+                entry.method.as_deref().unwrap_or("-"),
+                entry.size.map_or("-".to_string(), |s| s.to_string()),
+                entry.compressed_size.map_or("-".to_string(), |s| s.to_string()),
+                percent(entry.ratio),
+                entry.crc32.as_deref().unwrap_or("-"),
+                entry.modified.as_deref().unwrap_or("-"),
+                entry.mode.as_deref().unwrap_or("-"),
+                name
+            );
+        }
+
+        for entry in self.entries.iter().filter(|e| e.error.is_some()) {
+            println!("{}: {}", entry.name, entry.error.as_deref().unwrap_or_default());
+        }
+        if let Some(error) = &self.error {
+            println!("{}", error);
+        }
+        match self.corrupt_entries() {
+            0 if self.error.is_none() => println!("Archive OK"),
+            0 => println!("Archive is corrupt"),
+            n => println!("Archive is corrupt: {} damaged entries", n),
+        }
+    }
+}
+
+/// Opens and reads through an archive, checking every entry it can; encrypted ones need `password`.
+pub fn analyze(path: &Path, password: Option<&str>) -> Result<Report> {
+    let file_size = fs::metadata(path)
+        .with_context(|| format!("Failed to open '{}'", path.display()))?
+        .len();
+    let mut report = Report {
+        path: path.display().to_string(),
+        format: None,
+        valid: false,
+        error: None,
+        file_size,
+        entries: Vec::new(),
+    };
+
+    match ArchiveFormat::detect(path)? {
+        Some(format) => {
+            report.format = Some(format.description());
+            format.analyze(path, &mut report, password)?;
+        }
+        None => report.error = Some("Not a recognized archive".to_string()),
+    }
+    Ok(report)
+}
+
+/// Compressed size as a fraction of the original; `None` for empty entries.
+pub fn ratio(compressed: u64, size: u64) -> Option<f64> {
+    (size > 0).then(|| compressed as f64 / size as f64)
+}
+
+fn percent(ratio: Option<f64>) -> String {
+    ratio.map_or("-".to_string(), |r| format!("{:.1}%", r * 100.0))
+}
+
+/// A UTC timestamp as `YYYY-MM-DD HH:MM:SS`.
+pub fn timestamp(time: SystemTime) -> String {
+    let t = OffsetDateTime::from(time);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        t.year(),
+        u8::from(t.month()),
+        t.day(),
+        t.hour(),
+        t.minute(),
+        t.second()
+    )
+}
+
+/// Permission bits in octal, without the file type.
+pub fn mode(mode: u32) -> String {
+    format!("{:04o}", mode & 0o7777)
+}
@@ -0,0 +1,326 @@
+use crate::walk::{self, EntryKind, Filter};
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Instant;
+use zip::CompressionMethod;
+
+/// A ZIP compression method at one level; `None` is the method's default.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Candidate {
+    pub method: CompressionMethod,
+    pub level: Option<i64>,
+}
+
+const fn candidate(method: CompressionMethod, level: i64) -> Candidate {
+    Candidate { method, level: Some(level) }
+}
+
+const STORED: Candidate = Candidate { method: CompressionMethod::Stored, level: None };
+
+/// Everything `bench` measures.
+pub const BENCH_CANDIDATES: &[Candidate] = &[
+    STORED,
+    candidate(CompressionMethod::Deflated, 1),
+    candidate(CompressionMethod::Deflated, 6),
+    candidate(CompressionMethod::Deflated, 9),
+    candidate(CompressionMethod::Bzip2, 1),
+    candidate(CompressionMethod::Bzip2, 9),
+    candidate(CompressionMethod::Zstd, 1),
+    candidate(CompressionMethod::Zstd, 3),
+    candidate(CompressionMethod::Zstd, 9),
+    candidate(CompressionMethod::Zstd, 19),
+];
+
+/// What `--auto` tries on each file's sample; the slow, rarely-winning levels are left out.
+const AUTO_CANDIDATES: &[Candidate] = &[
+    candidate(CompressionMethod::Deflated, 6),
+    candidate(CompressionMethod::Deflated, 9),
+    candidate(CompressionMethod::Bzip2, 9),
+    candidate(CompressionMethod::Zstd, 3),
+    candidate(CompressionMethod::Zstd, 19),
+];
+
+// A sample is up to three chunks: the start, middle and end of the file
+const SAMPLE_CHUNK: u64 = 64 * 1024;
+// Compressing to more than this fraction of the original isn't worth the decompression cost
+const WORTHWHILE: f64 = 0.98;
+// With `--auto speed`, the fastest method that gets at least this far below the original wins
+const SPEED_TARGET: f64 = 0.9;
+// With `--auto ratio`, results this close to the smallest count as a tie and the faster one wins
+const RATIO_TIE: f64 = 1.01;
+
+/// What `--auto` optimizes for when picking a method per file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum Objective {
+    /// The smallest output
+    #[default]
+    Ratio,
+    /// The fastest method that still compresses meaningfully
+    Speed,
+}
+
+impl Candidate {
+    pub fn label(&self) -> String {
+        let name = match self.method {
+            CompressionMethod::Stored => "stored",
+            CompressionMethod::Deflated => "deflate",
+            CompressionMethod::Bzip2 => "bzip2",
+            CompressionMethod::Zstd => "zstd",
+            _ => "other",
+        };
+        match self.level {
+            Some(level) => format!("{} -{}", name, level),
+            None => name.to_string(),
+        }
+    }
+
+    /// Compresses `reader` the way a ZIP entry with this method and level would be, returning the compressed size.
+    pub fn compress(&self, reader: &mut dyn Read) -> io::Result<u64> {
+        let mut sink = CountingSink(0);
+        let level = self.level.unwrap_or(6) as u32;
+        match self.method {
+            CompressionMethod::Deflated => {
+                let mut encoder = flate2::write::DeflateEncoder::new(&mut sink, flate2::Compression::new(level));
+                io::copy(reader, &mut encoder)?;
+                encoder.finish()?;
+            }
+            CompressionMethod::Bzip2 => {
+                let mut encoder = bzip2::write::BzEncoder::new(&mut sink, bzip2::Compression::new(level));
+                io::copy(reader, &mut encoder)?;
+                encoder.finish()?;
+            }
+            CompressionMethod::Zstd => {
+                let mut encoder = zstd::Encoder::new(&mut sink, level as i32)?;
+                io::copy(reader, &mut encoder)?;
+                encoder.finish()?;
+            }
+            _ => {
+                io::copy(reader, &mut sink)?;
+            }
+        }
+        Ok(sink.0)
+    }
+}
+
+struct CountingSink(u64);
+
+impl Write for CountingSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// One candidate's result over a whole corpus.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Measurement {
+    pub method: String,
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    pub seconds: f64,
+    /// Peak resident memory while compressing, beyond what the process used before; `None` where the
+    /// platform doesn't report it
+    pub memory_bytes: Option<u64>,
+}
+
+impl Measurement {
+    pub fn ratio(&self) -> f64 {
+        if self.input_bytes == 0 { 1.0 } else { self.output_bytes as f64 / self.input_bytes as f64 }
+    }
+
+    pub fn throughput(&self) -> f64 {
+        self.input_bytes as f64 / self.seconds.max(f64::EPSILON)
+    }
+}
+
+/// Reads the start, middle and end of a file, or all of it if it's small.
+fn sample(path: &Path) -> Result<Vec<u8>> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open '{}'", path.display()))?;
+    let size = file.metadata()?.len();
+    let mut sample = Vec::new();
+    if size <= 3 * SAMPLE_CHUNK {
+        file.read_to_end(&mut sample)?;
+        return Ok(sample);
+    }
+    for offset in [0, size / 2 - SAMPLE_CHUNK / 2, size - SAMPLE_CHUNK] {
+        file.seek(SeekFrom::Start(offset))?;
+        (&mut file).take(SAMPLE_CHUNK).read_to_end(&mut sample)?;
+    }
+    Ok(sample)
+}
+
+/// Picks a method for one file by compressing a sample of it with each auto candidate.
+pub fn choose(path: &Path, objective: Objective) -> Result<Candidate> {
+    let sample = sample(path)?;
+    if sample.is_empty() {
+        return Ok(STORED);
+    }
+
+    let mut results = Vec::new();
+    for candidate in AUTO_CANDIDATES {
+        let start = Instant::now();
+        let size = candidate.compress(&mut sample.as_slice())?;
+        results.push((*candidate, size as f64 / sample.len() as f64, start.elapsed()));
+    }
+
+    let best_ratio = results.iter().map(|r| r.1).fold(f64::INFINITY, f64::min);
+    let fastest = |limit: f64| {
+        results
+            .iter()
+            .filter(|r| r.1 <= limit)
+            .min_by_key(|r| r.2)
+            .map(|r| r.0)
+    };
+    let choice = match objective {
+        Objective::Ratio => fastest(best_ratio * RATIO_TIE),
+        Objective::Speed => fastest(SPEED_TARGET).or_else(|| fastest(best_ratio * RATIO_TIE)),
+    };
+
+    Ok(match choice {
+        Some(candidate) if best_ratio < WORTHWHILE => candidate,
+        _ => STORED,
+    })
+}
+
+fn corpus(inputs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let entries = walk::collect(inputs, &Filter::default(), true)?;
+    Ok(entries.into_iter().filter(|e| e.kind == EntryKind::File).map(|e| e.path).collect())
+}
+
+/// Runs every candidate over the inputs, each in a fresh process so one method's memory doesn't hide another's.
+pub fn bench(inputs: &[PathBuf]) -> Result<Vec<Measurement>> {
+    if corpus(inputs)?.is_empty() {
+        bail!("No files to benchmark");
+    }
+
+    let exe = std::env::current_exe()?;
+    let mut measurements = Vec::new();
+    for (index, candidate) in BENCH_CANDIDATES.iter().enumerate() {
+        eprintln!("Measuring {}...", candidate.label());
+        let output = Command::new(&exe)
+            .arg("bench-worker")
+            .arg("--candidate")
+            .arg(index.to_string())
+            .args(inputs)
+            .output()
+            .with_context(|| "Failed to start benchmark process")?;
+        if !output.status.success() {
+            bail!("Benchmark of {} failed: {}", candidate.label(), String::from_utf8_lossy(&output.stderr).trim());
+        }
+        measurements.push(serde_json::from_slice(&output.stdout)?);
+    }
+    Ok(measurements)
+}
+
+/// The `bench-worker` side of `bench`: measures one candidate and prints the result as JSON.
+pub fn bench_worker(index: usize, inputs: &[PathBuf]) -> Result<()> {
+    let candidate = BENCH_CANDIDATES.get(index).with_context(|| format!("No candidate {}", index))?;
+    let files = corpus(inputs)?;
+
+    let baseline = reset_peak_memory();
+    let start = Instant::now();
+    let (mut input_bytes, mut output_bytes) = (0, 0);
+    for path in &files {
+        let mut file = File::open(path).with_context(|| format!("Failed to open '{}'", path.display()))?;
+        input_bytes += file.metadata()?.len();
+        output_bytes += candidate.compress(&mut file)?;
+    }
+    let seconds = start.elapsed().as_secs_f64();
+
+    let measurement = Measurement {
+        method: candidate.label(),
+        input_bytes,
+        output_bytes,
+        seconds,
+        memory_bytes: baseline.zip(memory_status("VmHWM:")).map(|(before, peak)| peak.saturating_sub(before)),
+    };
+    println!("{}", serde_json::to_string(&measurement)?);
+    Ok(())
+}
+
+/// Resets the peak resident size to the current one and returns it (Linux only).
+fn reset_peak_memory() -> Option<u64> {
+    fs::write("/proc/self/clear_refs", "5").ok()?;
+    memory_status("VmRSS:")
+}
+
+fn memory_status(field: &str) -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with(field))?;
+    let kilobytes: u64 = line[field.len()..].trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+pub fn print_table(measurements: &[Measurement]) {
+    println!(
+        "{:<12} {:>8} {:>14} {:>14} {:>10}",
+        "METHOD", "RATIO", "SIZE", "THROUGHPUT", "MEMORY"
+    );
+    for m in measurements {
+        let memory = m.memory_bytes.map_or("-".to_string(), |bytes| format!("{:.1} MiB", bytes as f64 / MIB));
+        println!(
+            "{:<12} {:>7.1}% {:>14} {:>9.1} MB/s {:>10}",
+            m.method,
+            m.ratio() * 100.0,
+            m.output_bytes,
+            m.throughput() / 1e6,
+            memory
+        );
+    }
+}
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates_match_zip_output() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8 ^ (i / 1000) as u8).collect();
+
+        for candidate in BENCH_CANDIDATES {
+            let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+            let options = zip::write::SimpleFileOptions::default()
+                .compression_method(candidate.method)
+                .compression_level(candidate.level);
+            zip.start_file("data", options).unwrap();
+            zip.write_all(&data).unwrap();
+            let mut archive = zip::ZipArchive::new(zip.finish().unwrap()).unwrap();
+
+            let measured = candidate.compress(&mut data.as_slice()).unwrap();
+            assert_eq!(archive.by_index(0).unwrap().compressed_size(), measured, "{}", candidate.label());
+        }
+    }
+
+    #[test]
+    fn test_choose_stores_incompressible_data() {
+        let path = std::env::temp_dir().join(format!("bench-test-{}", std::process::id()));
+        // splitmix64 output doesn't compress
+        let mut state = 0u64;
+        let noise: Vec<u8> = (0..50_000)
+            .map(|_| {
+                state = state.wrapping_add(0x9E3779B97F4A7C15);
+                let mut z = (state ^ (state >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+                (z ^ (z >> 31)) as u8
+            })
+            .collect();
+        fs::write(&path, &noise).unwrap();
+        assert_eq!(choose(&path, Objective::Ratio).unwrap(), STORED);
+
+        fs::write(&path, "abc".repeat(10_000)).unwrap();
+        assert_ne!(choose(&path, Objective::Speed).unwrap(), STORED);
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,78 +1,289 @@
-use anyhow::{Context, Result};
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::Path;
+use crate::formats::{ArchiveFormat, Compare, MethodChoice, Rewrite, WriterOptions};
+use crate::progress::Progress;
+use crate::walk::{Entry, EntryKind};
+use anyhow::{Context, Result, bail};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
 use zip::CompressionMethod;
-use zip::write::FileOptions;
 
 pub fn compress(
-    source_file: &str,
-    zip_file: &str,
-    compression_method: CompressionMethod,
+    entries: &[Entry],
+    archive_file: &str,
+    format: ArchiveFormat,
+    method: Option<MethodChoice>,
+    password: Option<Zeroizing<String>>,
+    jobs: usize,
 ) -> Result<()> {
-    println!("Compressing file '{}' to '{}'", source_file, zip_file);
+    format.check_options(method, password.is_some())?;
+    let encrypted = password.is_some();
+    let parallel = thread_pool(jobs)?;
+    println!("Compressing {} entries to '{}'", entries.len(), archive_file);
 
-    // Open the source file for reading
-    let mut source: File = File::open(source_file)
-        .with_context(|| format!("Failed to open source file '{}'", source_file))?;
+    let archive_path: &Path = Path::new(archive_file);
+    let to_write = without_archive(entries, archive_path)?;
 
-    // Read the source file contents into a buffer
-    let mut buffer: Vec<u8> = Vec::new();
-    source
-        .read_to_end(&mut buffer)
-        .with_context(|| "Failed to read source file contents")?;
+    let options = WriterOptions {
+        password,
+        parallel,
+        spool_dir: spool_dir(archive_path)?,
+    };
+    let progress = Progress::new(to_write.iter().map(|entry| entry.size).sum(), parallel);
+    // An existing archive is only replaced once the new one is complete
+    replace_archive(archive_path, |file| {
+        let mut writer = format.writer(file, method, options)?;
+        writer.add_all(&to_write, &progress)?;
+        writer.finish()
+    })?;
+    progress.finish();
 
-    // Create the ZIP file
-    let zip_path: &Path = Path::new(zip_file);
-    let zip_file_handle: File = File::create(&zip_path)
-        .with_context(|| format!("Failed to create ZIP file '{}'", zip_file))?;
+    println!("Compressed {} entries to '{}'", to_write.len(), archive_file);
+    match (format, method) {
+        (ArchiveFormat::Zip, Some(MethodChoice::Auto(objective))) => {
+            println!("Compression method: chosen per file for {:?}", objective)
+        }
+        (ArchiveFormat::Zip, Some(MethodChoice::Fixed(method))) => println!("Compression method: {:?}", method),
+        (ArchiveFormat::Zip, None) => println!("Compression method: {:?}", CompressionMethod::Deflated),
+        _ => println!("Format: {}", format.description()),
+    }
+    if encrypted {
+        println!("Encryption: AES-256");
+    }
+    if parallel {
+        print_throughput(&progress);
+    }
 
-    // Configure the ZIP writer
-    let mut zip: zip::ZipWriter<File> = zip::ZipWriter::new(zip_file_handle);
-    let options: FileOptions<'_, ()> = FileOptions::default()
-        .compression_method(compression_method)
-        .unix_permissions(0o644);
+    Ok(())
+}
 
-    // Start writing the source file to the ZIP archive
-    zip.start_file(source_file, options)
-        .with_context(|| "Failed to add file to ZIP archive")?;
+/// Brings an existing ZIP up to date with `entries`, compressing only what's new or changed and copying the
+/// rest as it is. Creates the archive if it doesn't exist yet.
+pub fn update(
+    entries: &[Entry],
+    archive_file: &str,
+    format: ArchiveFormat,
+    method: Option<MethodChoice>,
+    password: Option<Zeroizing<String>>,
+    jobs: usize,
+    compare: Compare,
+) -> Result<()> {
+    let archive_path = Path::new(archive_file);
+    if !archive_path.exists() {
+        return compress(entries, archive_file, format, method, password, jobs);
+    }
+    check_zip(archive_path, format, "--update")?;
+    format.check_options(method, password.is_some())?;
+    let parallel = thread_pool(jobs)?;
 
-    // Write the buffer to the ZIP archive
-    zip.write_all(&buffer)
-        .with_context(|| "Failed to write file contents to ZIP archive")?;
+    let to_write = without_archive(entries, archive_path)?;
+    let rewrite = Rewrite::update(archive_path, &to_write, compare, password.as_deref().map(String::as_str))?;
+    if !rewrite.changes() {
+        println!("'{}' is up to date, {} entries unchanged", archive_file, rewrite.unchanged);
+        return Ok(());
+    }
 
-    // Finalize the ZIP archive
-    zip.finish()
-        .with_context(|| "Failed to finalize ZIP archive")?;
+    let options = WriterOptions {
+        password,
+        parallel,
+        spool_dir: spool_dir(archive_path)?,
+    };
+    let progress = Progress::new(rewrite.bytes(), parallel);
+    let choice = method.unwrap_or(MethodChoice::Fixed(CompressionMethod::Deflated));
+    replace_archive(archive_path, |file| rewrite.write(archive_path, file, choice, options, &progress))?;
+    progress.finish();
 
-    println!("File '{}' compressed to '{}'", source_file, zip_file);
-    println!("Compression method: {:?}", compression_method);
+    println!(
+        "Updated '{}': {} added, {} replaced, {} unchanged",
+        archive_file, rewrite.added, rewrite.replaced, rewrite.unchanged
+    );
+    if parallel {
+        print_throughput(&progress);
+    }
+    Ok(())
+}
 
+/// Removes entries matching `patterns` from a ZIP, along with everything in matching directories. The other
+/// entries are copied as they are.
+pub fn delete(archive_path: &Path, patterns: &[String]) -> Result<()> {
+    check_zip(archive_path, ArchiveFormat::Zip, "delete")?;
+    let rewrite = Rewrite::delete(archive_path, patterns)?;
+
+    // Nothing is compressed, so the method doesn't matter
+    let options = WriterOptions {
+        password: None,
+        parallel: false,
+        spool_dir: spool_dir(archive_path)?,
+    };
+    let choice = MethodChoice::Fixed(CompressionMethod::Deflated);
+    replace_archive(archive_path, |file| {
+        rewrite.write(archive_path, file, choice, options, &Progress::new(0, false))
+    })?;
+
+    println!(
+        "Deleted {} entries from '{}', {} left",
+        rewrite.deleted,
+        archive_path.display(),
+        rewrite.unchanged
+    );
     Ok(())
 }
 
-pub fn analyze_zip_file(path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let file = File::open(path)?;
-
-    match zip::ZipArchive::new(file) {
-        Ok(mut archive) => {
-            println!("Valid ZIP archive detected");
-            println!("Number of files: {}", archive.len());
-
-            // Iterate through files to check compression methods
-            for i in 0..archive.len() {
-                let file = archive.by_index(i)?;
-                println!(
-                    "File: {} - Compression: {:?}",
-                    file.name(),
-                    file.compression()
-                );
-            }
-            Ok(())
+/// Sets up the rayon pool for `--jobs`, returning whether to compress in parallel.
+fn thread_pool(jobs: usize) -> Result<bool> {
+    let parallel = jobs != 1;
+    if parallel {
+        // 0 lets rayon use every core
+        rayon::ThreadPoolBuilder::new().num_threads(jobs).build_global()?;
+    }
+    Ok(parallel)
+}
+
+/// The archive may be written inside a directory being compressed; don't add it to itself.
+fn without_archive<'a>(entries: &'a [Entry], archive_path: &Path) -> Result<Vec<&'a Entry>> {
+    let archive_canonical = canonical_archive(archive_path)?;
+    let mut to_write = Vec::with_capacity(entries.len());
+    for entry in entries {
+        if entry.kind == EntryKind::File && fs::canonicalize(&entry.path)? == archive_canonical {
+            continue;
         }
-        Err(_) => {
-            println!("Not a valid ZIP archive");
-            Ok(())
+        to_write.push(entry);
+    }
+    Ok(to_write)
+}
+
+/// Large entries are spooled next to the archive, on the same disk.
+fn spool_dir(archive_path: &Path) -> Result<PathBuf> {
+    let archive_canonical = canonical_archive(archive_path)?;
+    Ok(archive_canonical.parent().unwrap_or(Path::new(".")).to_path_buf())
+}
+
+/// The archive's absolute path, whether or not it exists yet.
+fn canonical_archive(archive_path: &Path) -> Result<PathBuf> {
+    let name = archive_path
+        .file_name()
+        .with_context(|| format!("Invalid archive path '{}'", archive_path.display()))?;
+    let parent = archive_path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let parent = fs::canonicalize(parent).with_context(|| format!("Failed to open '{}'", parent.display()))?;
+    Ok(parent.join(name))
+}
+
+fn check_zip(archive_path: &Path, format: ArchiveFormat, action: &str) -> Result<()> {
+    let detected = ArchiveFormat::detect(archive_path)
+        .with_context(|| format!("Failed to open '{}'", archive_path.display()))?;
+    if format != ArchiveFormat::Zip || detected != Some(ArchiveFormat::Zip) {
+        bail!(
+            "{} only works on ZIP archives; '{}' is {}",
+            action,
+            archive_path.display(),
+            detected.map_or("not a recognized archive", ArchiveFormat::description)
+        );
+    }
+    Ok(())
+}
+
+/// Writes the archive to a temporary file next to it and moves that into place once complete, so a failure
+/// leaves an existing archive untouched and no partial one behind.
+fn replace_archive(archive_path: &Path, write: impl FnOnce(File) -> Result<()>) -> Result<()> {
+    let name = archive_path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = archive_path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+    let file = File::create(&temp).with_context(|| format!("Failed to create '{}'", temp.display()))?;
+
+    let result = write(file).and_then(|()| {
+        if let Ok(metadata) = fs::metadata(archive_path) {
+            fs::set_permissions(&temp, metadata.permissions())?;
         }
+        fs::rename(&temp, archive_path)
+            .with_context(|| format!("Failed to replace '{}'", archive_path.display()))
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+fn print_throughput(progress: &Progress) {
+    println!(
+        "Read {} bytes in {:.1}s on {} threads ({:.1} MB/s)",
+        progress.done(),
+        progress.elapsed().as_secs_f64(),
+        rayon::current_num_threads(),
+        progress.throughput() / 1e6
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_compress_tree_with_filters() {
+        use crate::walk::{self, Filter};
+        use std::io::Read;
+
+        let dir = std::env::temp_dir().join(format!("compress-test-{}", std::process::id()));
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(tree.join("empty")).unwrap();
+        std::fs::create_dir_all(tree.join("build")).unwrap();
+        std::fs::write(tree.join("notes.txt"), "keep me").unwrap();
+        std::fs::write(tree.join("image.png"), "not included").unwrap();
+        std::fs::write(tree.join("build").join("out.txt"), "excluded").unwrap();
+        std::os::unix::fs::symlink("notes.txt", tree.join("link.txt")).unwrap();
+
+        // Written into the tree being compressed, which mustn't add it to itself
+        let archive = tree.join("tree.txt");
+        std::fs::write(&archive, "an older archive").unwrap();
+
+        let names = |include: &[String], exclude: &[String]| {
+            let filter = Filter::new(include, exclude).unwrap();
+            let entries = walk::collect(std::slice::from_ref(&tree), &filter, false).unwrap();
+            compress(&entries, archive.to_str().unwrap(), ArchiveFormat::Zip, None, None, 1).unwrap();
+            let zip = zip::ZipArchive::new(File::open(&archive).unwrap()).unwrap();
+            let mut names: Vec<String> = zip.file_names().map(String::from).collect();
+            names.sort();
+            names
+        };
+
+        // Directories left empty by --include are dropped
+        let include = ["*.txt".to_string()];
+        let exclude = ["build".to_string()];
+        assert_eq!(names(&include, &exclude), ["tree/", "tree/link.txt", "tree/notes.txt"]);
+        let exclude = ["build".to_string(), "*.png".to_string()];
+        assert_eq!(names(&[], &exclude), ["tree/", "tree/empty/", "tree/link.txt", "tree/notes.txt"]);
+
+        let mut zip = zip::ZipArchive::new(File::open(&archive).unwrap()).unwrap();
+        assert!(zip.by_name("tree/link.txt").unwrap().is_symlink());
+        let mut notes = String::new();
+        zip.by_name("tree/notes.txt").unwrap().read_to_string(&mut notes).unwrap();
+        assert_eq!(notes, "keep me");
+        assert_eq!(std::fs::read_dir(&tree).unwrap().count(), 6, "no temporary files left");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_compress_keeps_existing_archive() {
+        let dir = std::env::temp_dir().join(format!("compress-fail-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let archive = dir.join("out.zip");
+        std::fs::write(&archive, "existing archive").unwrap();
+        let missing = Entry {
+            path: dir.join("missing.txt"),
+            name: "missing.txt".to_string(),
+            kind: EntryKind::File,
+            size: 10,
+            mode: 0o644,
+            modified: SystemTime::now(),
+        };
+
+        assert!(compress(std::slice::from_ref(&missing), archive.to_str().unwrap(), ArchiveFormat::Zip, None, None, 1).is_err());
+        assert_eq!(std::fs::read_to_string(&archive).unwrap(), "existing archive");
+        let fresh = dir.join("fresh.tar.gz");
+        assert!(compress(&[missing], fresh.to_str().unwrap(), ArchiveFormat::TarGz, None, None, 1).is_err());
+        assert!(!fresh.exists());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1, "no temporary files left");
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
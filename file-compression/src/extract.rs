@@ -0,0 +1,424 @@
+use crate::walk::EntryKind;
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// What to do when an entry's destination already exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum OverwritePolicy {
+    /// Keep the existing file
+    #[default]
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Extract next to it as `name (1).ext`
+    Rename,
+}
+
+/// An archive entry as extraction sees it, whatever the archive format.
+pub struct Item {
+    pub name: String,
+    pub kind: EntryKind,
+    pub mode: Option<u32>,
+    pub modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub extracted: usize,
+    pub skipped: usize,
+    pub renamed: usize,
+}
+
+/// Writes items under a root directory. Directory permissions and times are applied by `finish`, after
+/// their contents are written. With `list_only` nothing is written and each planned action is printed.
+pub struct Extractor {
+    root: PathBuf,
+    overwrite: OverwritePolicy,
+    list_only: bool,
+    directories: Vec<(PathBuf, Option<u32>, Option<SystemTime>)>,
+    checker: Checker,
+    summary: Summary,
+}
+
+impl Extractor {
+    pub fn new(root: &Path, overwrite: OverwritePolicy, list_only: bool) -> Result<Self> {
+        if !list_only {
+            fs::create_dir_all(root)
+                .with_context(|| format!("Failed to create output directory '{}'", root.display()))?;
+        }
+        Ok(Extractor {
+            root: root.to_path_buf(),
+            overwrite,
+            list_only,
+            directories: Vec::new(),
+            checker: Checker::default(),
+            summary: Summary::default(),
+        })
+    }
+
+    pub fn entry(&mut self, item: &Item, data: &mut dyn Read) -> Result<()> {
+        // Symlinks already on disk count too, whether this archive made them or not
+        let relative = self.checker.check_in(item, Some(&self.root))?;
+
+        let mut destination = self.root.join(&relative);
+        let existing = fs::symlink_metadata(&destination).ok();
+
+        if item.kind == EntryKind::Directory {
+            if existing.as_ref().is_some_and(|m| !m.is_dir()) {
+                bail!("Can't create directory '{}': a file is in the way", destination.display());
+            }
+            self.report("extract", &item.name, None);
+            if !self.list_only {
+                fs::create_dir_all(&destination)?;
+                self.directories.push((destination, item.mode, item.modified));
+            }
+            self.summary.extracted += 1;
+            return Ok(());
+        }
+
+        if let Some(existing) = existing {
+            match self.overwrite {
+                OverwritePolicy::Skip => {
+                    self.report("skip", &item.name, None);
+                    self.summary.skipped += 1;
+                    return Ok(());
+                }
+                OverwritePolicy::Overwrite => {
+                    if existing.is_dir() {
+                        bail!("Can't overwrite directory '{}' with a file", destination.display());
+                    }
+                    self.report("replace", &item.name, None);
+                    self.summary.extracted += 1;
+                    if !self.list_only {
+                        fs::remove_file(&destination)?;
+                    }
+                    return self.write(item, &destination, data);
+                }
+                OverwritePolicy::Rename => {
+                    destination = unused_name(&destination);
+                    let renamed = destination.strip_prefix(&self.root).unwrap_or(&destination).display().to_string();
+                    self.report("rename", &item.name, Some(&renamed));
+                    self.summary.renamed += 1;
+                    return self.write(item, &destination, data);
+                }
+            }
+        }
+
+        self.report("extract", &item.name, None);
+        self.summary.extracted += 1;
+        self.write(item, &destination, data)
+    }
+
+    /// Applies directory permissions and times, deepest first, and returns the counts.
+    pub fn finish(mut self) -> Result<Summary> {
+        self.directories.sort_by(|a, b| b.0.cmp(&a.0));
+        for (path, mode, modified) in &self.directories {
+            if let Some(modified) = modified {
+                // Best effort: not every platform can open a directory to set its time
+                if let Ok(dir) = File::open(path) {
+                    let _ = dir.set_modified(*modified);
+                }
+            }
+            set_mode(path, *mode)?;
+        }
+        Ok(self.summary)
+    }
+
+    fn write(&mut self, item: &Item, destination: &Path, data: &mut dyn Read) -> Result<()> {
+        if self.list_only {
+            return Ok(());
+        }
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        match &item.kind {
+            EntryKind::Symlink(target) => symlink(target, destination)
+                .with_context(|| format!("Failed to create symlink '{}'", destination.display()))?,
+            _ => {
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(destination)
+                    .with_context(|| format!("Failed to create '{}'", destination.display()))?;
+                io::copy(data, &mut file)
+                    .with_context(|| format!("Failed to extract '{}'", item.name))?;
+                if let Some(modified) = item.modified {
+                    file.set_modified(modified)?;
+                }
+                set_mode(destination, item.mode)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn report(&self, action: &str, name: &str, renamed: Option<&str>) {
+        if self.list_only {
+            match renamed {
+                Some(renamed) => println!("{:<8} {} -> {}", action, name, renamed),
+                None => println!("{:<8} {}", action, name),
+            }
+        }
+    }
+}
+
+/// Turns an entry name into a relative path that stays under the output directory. Absolute paths, drive
+/// letters and `..` are rejected rather than cleaned up, since they only appear in broken or hostile archives.
+pub fn safe_path(name: &str) -> Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for part in name.split(['/', '\\']) {
+        match part {
+            "" | "." => {}
+            ".." => bail!("Unsafe entry '{}': goes outside the output directory", name),
+            _ if part.contains(':') => bail!("Unsafe entry '{}': contains a drive or stream name", name),
+            _ => path.push(part),
+        }
+    }
+    if name.starts_with(['/', '\\']) {
+        bail!("Unsafe entry '{}': absolute path", name);
+    }
+    if path.as_os_str().is_empty() {
+        bail!("Invalid entry name '{}'", name);
+    }
+    Ok(path)
+}
+
+/// Checks items' names, and their targets if they're symlinks, without writing anything. Remembers the symlinks
+/// it has seen, so no later item is written through one and no later target leads out through one.
+#[derive(Default)]
+pub struct Checker {
+    links: HashSet<PathBuf>,
+}
+
+impl Checker {
+    /// Returns the item's path relative to the output directory.
+    pub fn check(&mut self, item: &Item) -> Result<PathBuf> {
+        self.check_in(item, None)
+    }
+
+    /// Like `check`, also treating symlinks already under `root` as seen.
+    fn check_in(&mut self, item: &Item, root: Option<&Path>) -> Result<PathBuf> {
+        let relative = safe_path(&item.name)?;
+        let is_link = |path: &Path| {
+            self.links.contains(path)
+                || root.is_some_and(|root| {
+                    fs::symlink_metadata(root.join(path)).is_ok_and(|m| m.file_type().is_symlink())
+                })
+        };
+
+        let mut parent = PathBuf::new();
+        for component in relative.parent().into_iter().flat_map(Path::components) {
+            parent.push(component);
+            if is_link(&parent) {
+                bail!("Refusing to extract '{}' through symlink '{}'", item.name, parent.display());
+            }
+        }
+        if let EntryKind::Symlink(target) = &item.kind {
+            check_link_target(&relative, target, &is_link)?;
+            self.links.insert(relative.clone());
+        }
+        Ok(relative)
+    }
+}
+
+/// A symlink may only point at something inside the output directory, so later entries can't be written
+/// through it to somewhere else. The target is resolved without following links, so it can't pass through
+/// one; pointing at one is fine, since that one was checked the same way.
+fn check_link_target(link: &Path, target: &Path, is_link: &dyn Fn(&Path) -> bool) -> Result<()> {
+    let mut resolved = link.parent().map(Path::to_path_buf).unwrap_or_default();
+    for component in target.components() {
+        if !resolved.as_os_str().is_empty() && is_link(&resolved) {
+            bail!(
+                "Unsafe symlink '{}' -> '{}': goes through symlink '{}'",
+                link.display(),
+                target.display(),
+                resolved.display()
+            );
+        }
+        match component {
+            Component::Normal(part) => resolved.push(part),
+            Component::CurDir => {}
+            Component::ParentDir if resolved.pop() => {}
+            _ => bail!("Unsafe symlink '{}' -> '{}': points outside the output directory", link.display(), target.display()),
+        }
+    }
+    Ok(())
+}
+
+/// `report.pdf` -> `report (1).pdf`, `report (2).pdf`, ... whichever doesn't exist yet.
+fn unused_name(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, extension)))
+        .find(|candidate| fs::symlink_metadata(candidate).is_err())
+        .unwrap()
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: Option<u32>) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    // Only the permission bits; setuid, setgid and sticky bits from an archive aren't trusted
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: Option<u32>) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    // Without unix symlinks, keep the target as the file's contents like git does
+    fs::write(link, target.to_string_lossy().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_safe_path_rejects_traversal() {
+        assert_eq!(safe_path("a/./b/c.txt").unwrap(), Path::new("a/b/c.txt"));
+        for name in ["../evil", "a/../../evil", "/etc/passwd", "\\\\server\\share", "C:\\evil", "a\\..\\..\\evil", ""] {
+            assert!(safe_path(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_link_targets_stay_inside() {
+        let no_links = |_: &Path| false;
+        assert!(check_link_target(Path::new("a/b/link"), Path::new("../c"), &no_links).is_ok());
+        assert!(check_link_target(Path::new("a/link"), Path::new("../c"), &no_links).is_ok());
+        assert!(check_link_target(Path::new("a/link"), Path::new("../../c"), &no_links).is_err());
+        assert!(check_link_target(Path::new("link"), Path::new("/etc"), &no_links).is_err());
+    }
+
+    #[test]
+    fn test_link_targets_cant_chain_out() {
+        let link = |name: &str, target: &str| item(name, EntryKind::Symlink(target.into()));
+        let mut checker = Checker::default();
+        checker.check(&link("a/up", "..")).unwrap();
+        // On disk `a/up/..` is the output directory's parent
+        let error = checker.check(&link("a/esc", "up/../..")).unwrap_err();
+        assert!(error.to_string().contains("goes through symlink 'a/up'"), "{}", error);
+        assert!(checker.check(&item("a/up/evil.txt", EntryKind::File)).is_err());
+        // Links to links are fine
+        checker.check(&link("lib/libz.so.1", "libz.so.1.3")).unwrap();
+        checker.check(&link("lib/libz.so", "libz.so.1")).unwrap();
+        checker.check(&link("z", "lib/libz.so")).unwrap();
+    }
+
+    fn item(name: &str, kind: EntryKind) -> Item {
+        Item { name: name.to_string(), kind, mode: None, modified: None }
+    }
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("extract-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        root
+    }
+
+    #[test]
+    fn test_overwrite_policies() {
+        let root = temp_root("overwrite");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.txt"), "old").unwrap();
+        fs::write(root.join("notes"), "old").unwrap();
+
+        let mut extractor = Extractor::new(&root, OverwritePolicy::Skip, false).unwrap();
+        extractor.entry(&item("a.txt", EntryKind::File), &mut "new".as_bytes()).unwrap();
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "old");
+        assert_eq!(extractor.finish().unwrap().skipped, 1);
+
+        let mut extractor = Extractor::new(&root, OverwritePolicy::Rename, false).unwrap();
+        extractor.entry(&item("a.txt", EntryKind::File), &mut "first".as_bytes()).unwrap();
+        extractor.entry(&item("a.txt", EntryKind::File), &mut "second".as_bytes()).unwrap();
+        extractor.entry(&item("notes", EntryKind::File), &mut "third".as_bytes()).unwrap();
+        assert_eq!(fs::read_to_string(root.join("a (1).txt")).unwrap(), "first");
+        assert_eq!(fs::read_to_string(root.join("a (2).txt")).unwrap(), "second");
+        assert_eq!(fs::read_to_string(root.join("notes (1)")).unwrap(), "third");
+        assert_eq!(extractor.finish().unwrap().renamed, 3);
+
+        let mut extractor = Extractor::new(&root, OverwritePolicy::Overwrite, false).unwrap();
+        extractor.entry(&item("a.txt", EntryKind::File), &mut "new".as_bytes()).unwrap();
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "new");
+        assert_eq!(extractor.finish().unwrap().extracted, 1);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_list_writes_nothing() {
+        let root = temp_root("list");
+        let mut extractor = Extractor::new(&root, OverwritePolicy::Skip, true).unwrap();
+        extractor.entry(&item("dir", EntryKind::Directory), &mut io::empty()).unwrap();
+        extractor.entry(&item("dir/a.txt", EntryKind::File), &mut "data".as_bytes()).unwrap();
+        extractor.entry(&item("dir/link", EntryKind::Symlink("a.txt".into())), &mut io::empty()).unwrap();
+
+        assert_eq!(extractor.finish().unwrap().extracted, 3);
+        assert!(fs::symlink_metadata(&root).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restores_mode_and_mtime() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::Duration;
+
+        let root = temp_root("metadata");
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let mut extractor = Extractor::new(&root, OverwritePolicy::Skip, false).unwrap();
+        let dir = Item { mode: Some(0o40750), modified: Some(modified), ..item("dir", EntryKind::Directory) };
+        // The setuid bit isn't restored
+        let file = Item { mode: Some(0o104640), modified: Some(modified), ..item("dir/a.txt", EntryKind::File) };
+        extractor.entry(&dir, &mut io::empty()).unwrap();
+        extractor.entry(&file, &mut "data".as_bytes()).unwrap();
+        extractor.finish().unwrap();
+
+        for (name, mode) in [("dir", 0o750), ("dir/a.txt", 0o640)] {
+            let metadata = fs::metadata(root.join(name)).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o7777, mode, "{}", name);
+            assert_eq!(metadata.modified().unwrap(), modified, "{}", name);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_refuses_to_write_through_symlinks() {
+        let root = temp_root("through-link");
+        let outside = temp_root("through-link-target");
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("out")).unwrap();
+
+        let mut extractor = Extractor::new(&root, OverwritePolicy::Overwrite, false).unwrap();
+        let result = extractor.entry(&item("out/evil.txt", EntryKind::File), &mut "data".as_bytes());
+        assert!(result.unwrap_err().to_string().contains("through symlink"));
+        assert!(fs::read_dir(&outside).unwrap().next().is_none());
+
+        // Nor chain a new link through one
+        fs::create_dir_all(root.join("a")).unwrap();
+        std::os::unix::fs::symlink("..", root.join("a/up")).unwrap();
+        let escape = item("a/esc", EntryKind::Symlink("up/../..".into()));
+        assert!(extractor.entry(&escape, &mut io::empty()).is_err());
+        assert!(fs::symlink_metadata(root.join("a/esc")).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+mod tar_archive;
+mod zip_archive;
+mod zip_raw;
+mod zip_update;
+
+use crate::analyze::Report;
+use crate::bench::Objective;
+use crate::extract::Extractor;
+use crate::progress::Progress;
+use crate::walk::Entry;
+use anyhow::{Result, bail};
+use clap::ValueEnum;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+use zip::CompressionMethod;
+
+pub use zip_update::{Compare, Rewrite};
+
+/// The container written by `compress` and read by `extract` and `analyze`. TAR has no compression of its own,
+/// so it comes wrapped in a compressed stream; ZIP compresses each entry.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+    TarZst,
+    TarXz,
+    TarBz2,
+}
+
+/// How ZIP entries are compressed: one method for all, or one picked per file by `--auto`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MethodChoice {
+    Fixed(CompressionMethod),
+    Auto(Objective),
+}
+
+/// Writes entries to an archive of one format.
+pub trait ArchiveWriter {
+    fn add(&mut self, entry: &Entry, progress: &Progress) -> Result<()>;
+
+    /// Adds entries in order; writers that can compress several at once override this.
+    fn add_all(&mut self, entries: &[&Entry], progress: &Progress) -> Result<()> {
+        for entry in entries {
+            self.add(entry, progress)?;
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// What a writer needs besides the ZIP compression method.
+pub struct WriterOptions {
+    /// Encrypts ZIP entries with AES-256
+    pub password: Option<Zeroizing<String>>,
+    /// Compress on the rayon thread pool
+    pub parallel: bool,
+    /// Where large ZIP entries compressed in parallel wait to be copied into the archive
+    pub spool_dir: PathBuf,
+}
+
+const EXTENSIONS: &[(&str, ArchiveFormat)] = &[
+    (".zip", ArchiveFormat::Zip),
+    (".tar", ArchiveFormat::Tar),
+    (".tar.gz", ArchiveFormat::TarGz),
+    (".tgz", ArchiveFormat::TarGz),
+    (".tar.zst", ArchiveFormat::TarZst),
+    (".tzst", ArchiveFormat::TarZst),
+    (".tar.xz", ArchiveFormat::TarXz),
+    (".txz", ArchiveFormat::TarXz),
+    (".tar.bz2", ArchiveFormat::TarBz2),
+    (".tbz2", ArchiveFormat::TarBz2),
+];
+
+// Offset of the "ustar" magic in a TAR header
+const USTAR_OFFSET: usize = 257;
+
+impl ArchiveFormat {
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        EXTENSIONS
+            .iter()
+            .find(|(extension, _)| name.ends_with(extension))
+            .map(|(_, format)| *format)
+    }
+
+    /// Identifies an archive by its first bytes. A plain TAR is recognized by its `ustar` header;
+    /// anything gzip, zstd, xz or bzip2 compressed is taken to be a compressed TAR.
+    pub fn detect(path: &Path) -> Result<Option<Self>> {
+        let mut start = Vec::with_capacity(USTAR_OFFSET + 5);
+        File::open(path)?.take(USTAR_OFFSET as u64 + 5).read_to_end(&mut start)?;
+
+        let format = if start.starts_with(b"PK\x03\x04") || start.starts_with(b"PK\x05\x06") {
+            Some(ArchiveFormat::Zip)
+        } else if start.starts_with(&[0x1f, 0x8b]) {
+            Some(ArchiveFormat::TarGz)
+        } else if start.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(ArchiveFormat::TarZst)
+        } else if start.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(ArchiveFormat::TarXz)
+        } else if start.starts_with(b"BZh") {
+            Some(ArchiveFormat::TarBz2)
+        } else if start.get(USTAR_OFFSET..USTAR_OFFSET + 5) == Some(b"ustar") {
+            Some(ArchiveFormat::Tar)
+        } else {
+            None
+        };
+        Ok(format)
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "ZIP",
+            ArchiveFormat::Tar => "TAR",
+            ArchiveFormat::TarGz => "TAR (gzip)",
+            ArchiveFormat::TarZst => "TAR (zstd)",
+            ArchiveFormat::TarXz => "TAR (xz)",
+            ArchiveFormat::TarBz2 => "TAR (bzip2)",
+        }
+    }
+
+    /// Only ZIP takes a compression method or encryption; TAR formats are compressed by the stream they're
+    /// written into.
+    pub fn check_options(self, choice: Option<MethodChoice>, encrypt: bool) -> Result<()> {
+        if encrypt && self != ArchiveFormat::Zip {
+            bail!("--encrypt only applies to ZIP; {} has no encryption", self.description());
+        }
+        match choice {
+            Some(MethodChoice::Fixed(_)) if self != ArchiveFormat::Zip => {
+                bail!("--compression-method only applies to ZIP; {} is compressed as a whole", self.description())
+            }
+            Some(MethodChoice::Auto(_)) if self != ArchiveFormat::Zip => {
+                bail!("--auto only applies to ZIP; {} is compressed as a whole", self.description())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// ZIP entries default to deflate.
+    pub fn writer(self, file: File, choice: Option<MethodChoice>, options: WriterOptions) -> Result<Box<dyn ArchiveWriter>> {
+        match self {
+            ArchiveFormat::Zip => Ok(Box::new(zip_archive::ZipArchiveWriter::new(
+                file,
+                choice.unwrap_or(MethodChoice::Fixed(CompressionMethod::Deflated)),
+                options,
+            ))),
+            _ => Ok(Box::new(tar_archive::TarArchiveWriter::new(self, file, options.parallel)?)),
+        }
+    }
+
+    /// `password` is only asked for if the archive has encrypted entries.
+    pub fn extract(self, path: &Path, extractor: &mut Extractor, password: &dyn Fn() -> Result<Zeroizing<String>>) -> Result<()> {
+        match self {
+            ArchiveFormat::Zip => zip_archive::extract(path, extractor, password),
+            _ => tar_archive::extract(self, path, extractor),
+        }
+    }
+
+    /// Fills in `report` with every entry of the archive, reading each one back to check it. Encrypted entries
+    /// are only checked given a password.
+    pub fn analyze(self, path: &Path, report: &mut Report, password: Option<&str>) -> Result<()> {
+        match self {
+            ArchiveFormat::Zip => zip_archive::analyze(path, report, password),
+            _ => tar_archive::analyze(self, path, report),
+        }
+    }
+}
+
+/// The format of an existing archive: from its contents, or its name when the contents don't tell.
+pub fn detect_or_guess(path: &Path) -> Result<ArchiveFormat> {
+    if let Some(format) = ArchiveFormat::detect(path)? {
+        return Ok(format);
+    }
+    match ArchiveFormat::from_extension(path) {
+        Some(format) => Ok(format),
+        None => bail!("'{}' is not a recognized archive", path.display()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_from_extension() {
+        assert_eq!(ArchiveFormat::from_extension(Path::new("out/a.ZIP")), Some(ArchiveFormat::Zip));
+        assert_eq!(ArchiveFormat::from_extension(Path::new("a.tar.gz")), Some(ArchiveFormat::TarGz));
+        assert_eq!(ArchiveFormat::from_extension(Path::new("a.tzst")), Some(ArchiveFormat::TarZst));
+        assert_eq!(ArchiveFormat::from_extension(Path::new("a.gz")), None);
+    }
+
+    #[test]
+    fn test_detect_recognizes_written_archives() {
+        let dir = std::env::temp_dir().join(format!("detect-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("data.txt");
+        std::fs::write(&source, "some text to archive").unwrap();
+        let entries = crate::walk::collect(std::slice::from_ref(&source), &crate::walk::Filter::default(), false).unwrap();
+
+        let formats = [
+            ArchiveFormat::Zip,
+            ArchiveFormat::Tar,
+            ArchiveFormat::TarGz,
+            ArchiveFormat::TarZst,
+            ArchiveFormat::TarXz,
+            ArchiveFormat::TarBz2,
+        ];
+        for format in formats {
+            // No extension, so only the contents tell
+            let path = dir.join(format!("{:?}", format));
+            let options = WriterOptions { password: None, parallel: false, spool_dir: dir.clone() };
+            let mut writer = format.writer(File::create(&path).unwrap(), None, options).unwrap();
+            writer.add_all(&entries.iter().collect::<Vec<_>>(), &Progress::new(0, false)).unwrap();
+            writer.finish().unwrap();
+            assert_eq!(ArchiveFormat::detect(&path).unwrap(), Some(format));
+        }
+
+        assert_eq!(ArchiveFormat::detect(&source).unwrap(), None);
+        std::fs::write(&source, "").unwrap();
+        assert_eq!(ArchiveFormat::detect(&source).unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
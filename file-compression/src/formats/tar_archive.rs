@@ -0,0 +1,344 @@
+use super::{ArchiveFormat, ArchiveWriter};
+use crate::analyze::{self, EntryReport, EntryStatus, Report};
+use crate::extract::{Checker, Extractor, Item};
+use crate::parallel::ChunkedDeflate;
+use crate::progress::{Progress, ProgressReader};
+use crate::walk::{Entry, EntryKind};
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+// Levels matching the command-line tools' defaults
+const ZSTD_LEVEL: i32 = 3;
+const XZ_LEVEL: u32 = 6;
+
+/// The compressed stream a TAR is written into.
+enum Encoder {
+    Plain(BufWriter<File>),
+    Gzip(flate2::write::GzEncoder<BufWriter<File>>),
+    ChunkedGzip(ChunkedDeflate<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+    Xz(xz2::write::XzEncoder<BufWriter<File>>),
+    Bzip2(bzip2::write::BzEncoder<BufWriter<File>>),
+}
+
+impl Encoder {
+    /// In parallel, gzip is written pigz-style and zstd and xz use their own worker threads, all as many as
+    /// the rayon pool has.
+    fn new(format: ArchiveFormat, file: File, parallel: bool) -> io::Result<Self> {
+        let file = BufWriter::new(file);
+        let threads = rayon::current_num_threads() as u32;
+        Ok(match format {
+            ArchiveFormat::TarGz if parallel => {
+                Encoder::ChunkedGzip(ChunkedDeflate::gzip(file, flate2::Compression::default())?)
+            }
+            ArchiveFormat::TarZst if parallel => {
+                let mut encoder = zstd::Encoder::new(file, ZSTD_LEVEL)?;
+                encoder.multithread(threads)?;
+                Encoder::Zstd(encoder)
+            }
+            ArchiveFormat::TarXz if parallel => {
+                let stream = xz2::stream::MtStreamBuilder::new().threads(threads).preset(XZ_LEVEL).encoder()?;
+                Encoder::Xz(xz2::write::XzEncoder::new_stream(file, stream))
+            }
+            ArchiveFormat::TarGz => Encoder::Gzip(flate2::write::GzEncoder::new(file, flate2::Compression::default())),
+            ArchiveFormat::TarZst => Encoder::Zstd(zstd::Encoder::new(file, ZSTD_LEVEL)?),
+            ArchiveFormat::TarXz => Encoder::Xz(xz2::write::XzEncoder::new(file, XZ_LEVEL)),
+            ArchiveFormat::TarBz2 => Encoder::Bzip2(bzip2::write::BzEncoder::new(file, bzip2::Compression::default())),
+            ArchiveFormat::Tar | ArchiveFormat::Zip => Encoder::Plain(file),
+        })
+    }
+
+    fn finish(self) -> io::Result<()> {
+        let mut file = match self {
+            Encoder::Plain(file) => file,
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::ChunkedGzip(encoder) => encoder.finish()?.0,
+            Encoder::Zstd(encoder) => encoder.finish()?,
+            Encoder::Xz(encoder) => encoder.finish()?,
+            Encoder::Bzip2(encoder) => encoder.finish()?,
+        };
+        file.flush()
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Plain(w) => w.write(buf),
+            Encoder::Gzip(w) => w.write(buf),
+            Encoder::ChunkedGzip(w) => w.write(buf),
+            Encoder::Zstd(w) => w.write(buf),
+            Encoder::Xz(w) => w.write(buf),
+            Encoder::Bzip2(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Plain(w) => w.flush(),
+            Encoder::Gzip(w) => w.flush(),
+            Encoder::ChunkedGzip(w) => w.flush(),
+            Encoder::Zstd(w) => w.flush(),
+            Encoder::Xz(w) => w.flush(),
+            Encoder::Bzip2(w) => w.flush(),
+        }
+    }
+}
+
+fn decoder(format: ArchiveFormat, file: File) -> io::Result<Box<dyn Read>> {
+    let file = BufReader::new(file);
+    Ok(match format {
+        // Multi-member decoders, so archives concatenated by `cat` or written by pigz/pbzip2 read whole
+        ArchiveFormat::TarGz => Box::new(flate2::read::MultiGzDecoder::new(file)),
+        ArchiveFormat::TarZst => Box::new(zstd::Decoder::with_buffer(file)?),
+        ArchiveFormat::TarXz => Box::new(xz2::read::XzDecoder::new_multi_decoder(file)),
+        ArchiveFormat::TarBz2 => Box::new(bzip2::read::MultiBzDecoder::new(file)),
+        ArchiveFormat::Tar | ArchiveFormat::Zip => Box::new(file),
+    })
+}
+
+fn open(format: ArchiveFormat, path: &Path) -> Result<tar::Archive<Box<dyn Read>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open '{}'", path.display()))?;
+    Ok(tar::Archive::new(decoder(format, file)?))
+}
+
+pub struct TarArchiveWriter {
+    builder: tar::Builder<Encoder>,
+}
+
+impl TarArchiveWriter {
+    pub fn new(format: ArchiveFormat, file: File, parallel: bool) -> Result<Self> {
+        Ok(TarArchiveWriter {
+            builder: tar::Builder::new(Encoder::new(format, file, parallel)?),
+        })
+    }
+}
+
+impl ArchiveWriter for TarArchiveWriter {
+    fn add(&mut self, entry: &Entry, progress: &Progress) -> Result<()> {
+        // GNU headers, so names longer than 100 bytes fit
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(entry.mode);
+        header.set_mtime(entry.modified.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs()));
+        header.set_size(0);
+
+        match &entry.kind {
+            EntryKind::Directory => {
+                header.set_entry_type(tar::EntryType::Directory);
+                self.builder.append_data(&mut header, &entry.name, io::empty())
+            }
+            EntryKind::Symlink(target) => {
+                header.set_entry_type(tar::EntryType::Symlink);
+                self.builder.append_link(&mut header, &entry.name, target)
+            }
+            EntryKind::File => {
+                let source = File::open(&entry.path)
+                    .with_context(|| format!("Failed to open source file '{}'", entry.path.display()))?;
+                // The header is written first, so take the size now and don't read past it if the file grows
+                let size = source.metadata()?.len();
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(size);
+                self.builder.append_data(&mut header, &entry.name, ProgressReader::new(source.take(size), progress))
+            }
+        }
+        .with_context(|| format!("Failed to add '{}' to TAR archive", entry.name))
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        let encoder = self.builder.into_inner().with_context(|| "Failed to finalize TAR archive")?;
+        encoder.finish().with_context(|| "Failed to finish compressed stream")?;
+        Ok(())
+    }
+}
+
+/// The extractable part of a TAR entry; hard links, devices and FIFOs have none.
+fn item<R: Read>(entry: &tar::Entry<R>) -> Result<Option<Item>> {
+    let header = entry.header();
+    let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+    let entry_type = header.entry_type();
+
+    let kind = if entry_type.is_file() {
+        EntryKind::File
+    } else if entry_type.is_dir() {
+        EntryKind::Directory
+    } else if entry_type.is_symlink() {
+        let target = entry.link_name()?.with_context(|| format!("Symlink '{}' has no target", name))?;
+        EntryKind::Symlink(target.into_owned())
+    } else {
+        eprintln!("Skipping '{}': only files, directories and symlinks are extracted", name);
+        return Ok(None);
+    };
+
+    Ok(Some(Item {
+        name,
+        kind,
+        mode: header.mode().ok(),
+        modified: header.mtime().ok().map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+    }))
+}
+
+pub fn extract(format: ArchiveFormat, path: &Path, extractor: &mut Extractor) -> Result<()> {
+    // A TAR can only be read front to back, so check every name in a first pass over the file
+    let mut checker = Checker::default();
+    for entry in open(format, path)?.entries()? {
+        if let Some(item) = item(&entry?)? {
+            checker.check(&item)?;
+        }
+    }
+
+    for entry in open(format, path)?.entries()? {
+        let mut entry = entry?;
+        if let Some(item) = item(&entry)? {
+            extractor.entry(&item, &mut entry)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn analyze(format: ArchiveFormat, path: &Path, report: &mut Report) -> Result<()> {
+    let mut archive = open(format, path)?;
+    let entries = match archive.entries() {
+        Ok(entries) => entries,
+        Err(e) => {
+            report.error = Some(e.to_string());
+            return Ok(());
+        }
+    };
+    // The TAR index is the entries themselves, so the archive counts as valid once one of them reads
+    report.valid = true;
+
+    for entry in entries {
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if report.entries.is_empty() => {
+                report.valid = false;
+                report.error = Some(e.to_string());
+                break;
+            }
+            Err(e) => {
+                report.error = Some(format!("Unreadable after {} entries: {}", report.entries.len(), e));
+                break;
+            }
+        };
+
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        let kind = if entry_type.is_file() {
+            "file"
+        } else if entry_type.is_dir() {
+            "directory"
+        } else if entry_type.is_symlink() {
+            "symlink"
+        } else if entry_type.is_hard_link() {
+            "hard link"
+        } else {
+            "other"
+        };
+        let mut item = EntryReport {
+            name: String::from_utf8_lossy(&entry.path_bytes()).into_owned(),
+            kind,
+            size: header.size().ok(),
+            modified: header
+                .mtime()
+                .ok()
+                .map(|secs| analyze::timestamp(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))),
+            mode: header.mode().ok().map(analyze::mode),
+            ..Default::default()
+        };
+
+        // A damaged stream can't be resynchronized, so stop at the first entry that doesn't read back
+        let result = io::copy(&mut entry, &mut io::sink());
+        if let Err(e) = result {
+            item.status = EntryStatus::Corrupt;
+            item.error = Some(e.to_string());
+            report.entries.push(item);
+            break;
+        }
+        report.entries.push(item);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::OverwritePolicy;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_round_trip() {
+        use crate::walk::{self, Filter};
+
+        const FORMATS: [ArchiveFormat; 5] = [
+            ArchiveFormat::Tar,
+            ArchiveFormat::TarGz,
+            ArchiveFormat::TarZst,
+            ArchiveFormat::TarXz,
+            ArchiveFormat::TarBz2,
+        ];
+        let dir = std::env::temp_dir().join(format!("tar-test-{}", std::process::id()));
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(tree.join("empty")).unwrap();
+        std::fs::write(tree.join("small.txt"), "hello tar").unwrap();
+        // Several chunks for pigz-style gzip
+        let large: Vec<u8> = (0..2_500_000u32).map(|i| (i % 251) as u8 ^ (i >> 13) as u8).collect();
+        std::fs::write(tree.join("large.bin"), &large).unwrap();
+        std::os::unix::fs::symlink("small.txt", tree.join("link")).unwrap();
+        let entries = walk::collect(std::slice::from_ref(&tree), &Filter::default(), false).unwrap();
+        let entries: Vec<&Entry> = entries.iter().collect();
+
+        for format in FORMATS {
+            for parallel in [false, true] {
+                let path = dir.join(format!("{:?}-{}.tar", format, parallel));
+                let mut writer = Box::new(TarArchiveWriter::new(format, File::create(&path).unwrap(), parallel).unwrap());
+                writer.add_all(&entries, &Progress::new(0, false)).unwrap();
+                writer.finish().unwrap();
+
+                let output = dir.join(format!("{:?}-{}", format, parallel));
+                let mut extractor = Extractor::new(&output, OverwritePolicy::Skip, false).unwrap();
+                extract(format, &path, &mut extractor).unwrap();
+                assert_eq!(extractor.finish().unwrap().extracted, 5, "{:?}", format);
+
+                let extracted = output.join("tree");
+                assert_eq!(std::fs::read_to_string(extracted.join("small.txt")).unwrap(), "hello tar");
+                assert!(std::fs::read(extracted.join("large.bin")).unwrap() == large, "{:?} {}", format, parallel);
+                assert_eq!(std::fs::read_link(extracted.join("link")).unwrap(), Path::new("small.txt"));
+                assert!(extracted.join("empty").is_dir());
+            }
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unsafe_entry_rejected_before_writing() {
+        let dir = std::env::temp_dir().join(format!("tar-evil-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("evil.tar");
+        let mut builder = tar::Builder::new(File::create(&path).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "good.txt", "good".as_bytes()).unwrap();
+        // `set_path` refuses `..`, so the name goes in raw
+        let mut header = tar::Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..7].copy_from_slice(b"../evil");
+        header.set_size(4);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append(&header, "evil".as_bytes()).unwrap();
+        builder.into_inner().unwrap();
+
+        let output = dir.join("out");
+        let mut extractor = Extractor::new(&output, OverwritePolicy::Skip, false).unwrap();
+        let error = extract(ArchiveFormat::Tar, &path, &mut extractor).unwrap_err();
+        assert!(error.to_string().contains("../evil"), "{}", error);
+        assert!(std::fs::read_dir(&output).unwrap().next().is_none());
+        assert!(!dir.join("evil").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,553 @@
+use super::zip_raw::{self, AES_EXTRA_FIELD, CentralHeader, ZIP64_EXTRA_FIELD};
+use super::{ArchiveWriter, MethodChoice, WriterOptions};
+use crate::analyze::{self, EntryReport, EntryStatus, Report};
+use crate::bench::{self, Candidate};
+use crate::extract::{Checker, Extractor, Item};
+use crate::parallel::{ChunkedDeflate, Spool};
+use crate::progress::{Progress, ProgressReader};
+use crate::walk::{Entry, EntryKind};
+use anyhow::{Context, Result, bail};
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use time::OffsetDateTime;
+use zeroize::Zeroizing;
+use zip::write::FileOptions;
+use zip::{AesMode, CompressionMethod};
+
+// Files from this size are compressed on all threads at once rather than alongside other entries
+const LARGE_FILE: u64 = 64 * 1024 * 1024;
+// Input per batch of small entries compressed side by side
+const BATCH_BYTES: u64 = 64 * 1024 * 1024;
+
+pub struct ZipArchiveWriter {
+    zip: zip::ZipWriter<BufWriter<File>>,
+    choice: MethodChoice,
+    password: Option<Zeroizing<String>>,
+    parallel: bool,
+    spool_dir: PathBuf,
+}
+
+impl ZipArchiveWriter {
+    pub fn new(file: File, choice: MethodChoice, options: WriterOptions) -> Self {
+        ZipArchiveWriter {
+            zip: zip::ZipWriter::new(BufWriter::new(file)),
+            choice,
+            password: options.password,
+            parallel: options.parallel,
+            spool_dir: options.spool_dir,
+        }
+    }
+
+    /// Compresses a batch of entries side by side, each into a single-entry ZIP, and merges those in order.
+    fn add_batch(&mut self, batch: &[&Entry], progress: &Progress) -> Result<()> {
+        let (choice, password, spool_dir) = (self.choice, self.password.as_deref().map(String::as_str), &self.spool_dir);
+        let spools: Vec<Result<Spool>> = batch
+            .par_iter()
+            .map(|entry| {
+                let mut zip = zip::ZipWriter::new(Spool::new(spool_dir, entry.size)?);
+                write_entry(&mut zip, entry, candidate(choice, entry)?, password, progress)?;
+                Ok(zip.finish()?)
+            })
+            .collect();
+
+        for spool in spools {
+            self.zip.merge_archive(zip::ZipArchive::new(spool?)?)?;
+        }
+        Ok(())
+    }
+
+    /// Compresses one large file on all threads: multi-threaded zstd, or deflate in chunks.
+    fn add_large(&mut self, entry: &Entry, progress: &Progress) -> Result<()> {
+        let candidate = candidate(self.choice, entry)?;
+        // AES and the other methods only come from the zip crate, which compresses an entry on one thread
+        let threaded = matches!(candidate.method, CompressionMethod::Deflated | CompressionMethod::Zstd);
+        if self.password.is_some() || !threaded {
+            return write_entry(&mut self.zip, entry, candidate, self.password.as_deref().map(String::as_str), progress);
+        }
+
+        let source = File::open(&entry.path)
+            .with_context(|| format!("Failed to open source file '{}'", entry.path.display()))?;
+        let mut source = ProgressReader::new(source, progress);
+        let spool = Spool::new(&self.spool_dir, entry.size)?;
+        let zip64 = entry.size >= zip_raw::ZIP64_LIMIT;
+        let spool = zip_raw::single_entry(spool, entry, candidate.method, zip64, |out| {
+            if candidate.method == CompressionMethod::Zstd {
+                let level = candidate.level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL as i64);
+                let mut source = flate2::CrcReader::new(source);
+                let mut encoder = zstd::Encoder::new(out, level as i32)?;
+                encoder.multithread(rayon::current_num_threads() as u32)?;
+                let size = io::copy(&mut source, &mut encoder)?;
+                encoder.finish()?;
+                Ok((source.crc().sum(), size))
+            } else {
+                let level = candidate.level.unwrap_or(6) as u32;
+                let mut encoder = ChunkedDeflate::new(out, flate2::Compression::new(level));
+                let size = io::copy(&mut source, &mut encoder)?;
+                let (_, crc) = encoder.finish()?;
+                Ok((crc, size))
+            }
+        })
+        .with_context(|| format!("Failed to compress '{}'", entry.path.display()))?;
+
+        self.zip
+            .merge_archive(zip::ZipArchive::new(spool)?)
+            .with_context(|| format!("Failed to add '{}' to ZIP archive", entry.name))?;
+        Ok(())
+    }
+
+    /// Copies entry `index` of `source` byte for byte, still compressed and encrypted as it was. `headers` is a
+    /// second handle on the same file.
+    pub fn copy_entry(&mut self, source: &mut zip::ZipArchive<File>, headers: &mut File, index: usize) -> Result<()> {
+        let entry = source.by_index_raw(index)?;
+        let name = entry.name().to_string();
+        let (header_start, data_end) = (entry.header_start(), entry.data_start() + entry.compressed_size());
+        let central_start = entry.central_header_start();
+        drop(entry);
+
+        let spool = CentralHeader::read(headers, central_start)
+            .and_then(|central| {
+                let spool = Spool::new(&self.spool_dir, data_end - header_start)?;
+                zip_raw::copy_entry(headers, header_start, data_end, central, spool)
+            })
+            .with_context(|| format!("Failed to copy '{}'", name))?;
+        self.zip
+            .merge_archive(zip::ZipArchive::new(spool)?)
+            .with_context(|| format!("Failed to add '{}' to ZIP archive", name))?;
+        Ok(())
+    }
+}
+
+impl ArchiveWriter for ZipArchiveWriter {
+    fn add(&mut self, entry: &Entry, progress: &Progress) -> Result<()> {
+        let candidate = candidate(self.choice, entry)?;
+        write_entry(&mut self.zip, entry, candidate, self.password.as_deref().map(String::as_str), progress)
+    }
+
+    /// In parallel, small entries are compressed in batches across the thread pool and large files one at a
+    /// time on all of it. The entries come out the same as written one by one, in the same order.
+    fn add_all(&mut self, entries: &[&Entry], progress: &Progress) -> Result<()> {
+        if !self.parallel {
+            for entry in entries {
+                self.add(entry, progress)?;
+            }
+            return Ok(());
+        }
+
+        let is_large = |entry: &Entry| entry.kind == EntryKind::File && entry.size >= LARGE_FILE;
+        let batch_entries = rayon::current_num_threads() * 4;
+        let mut rest = entries;
+        while let Some(first) = rest.first() {
+            if is_large(first) {
+                self.add_large(first, progress)?;
+                rest = &rest[1..];
+                continue;
+            }
+
+            let (mut len, mut bytes) = (0, 0);
+            while let Some(entry) = rest.get(len) {
+                if len == batch_entries || bytes >= BATCH_BYTES || is_large(entry) {
+                    break;
+                }
+                bytes += entry.size;
+                len += 1;
+            }
+            let (batch, tail) = rest.split_at(len);
+            self.add_batch(batch, progress)?;
+            rest = tail;
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        self.zip.finish().with_context(|| "Failed to finalize ZIP archive")?;
+        Ok(())
+    }
+}
+
+fn candidate(choice: MethodChoice, entry: &Entry) -> Result<Candidate> {
+    match choice {
+        MethodChoice::Fixed(method) => Ok(Candidate { method, level: None }),
+        MethodChoice::Auto(_) if entry.kind != EntryKind::File => Ok(Candidate { method: CompressionMethod::Stored, level: None }),
+        MethodChoice::Auto(objective) => {
+            let candidate = bench::choose(&entry.path, objective)?;
+            println!("{}: {}", entry.name, candidate.label());
+            Ok(candidate)
+        }
+    }
+}
+
+/// Adds one entry to `zip`, compressed by the zip crate on the calling thread.
+fn write_entry<W: Write + Seek>(
+    zip: &mut zip::ZipWriter<W>,
+    entry: &Entry,
+    candidate: Candidate,
+    password: Option<&str>,
+    progress: &Progress,
+) -> Result<()> {
+    let options: FileOptions<'_, ()> = FileOptions::default()
+        .compression_method(candidate.method)
+        .compression_level(candidate.level)
+        .unix_permissions(entry.mode)
+        .last_modified_time(zip_time(entry.modified))
+        .large_file(entry.size >= u32::MAX as u64);
+    let options = match password {
+        Some(password) => options.with_aes_encryption(AesMode::Aes256, password),
+        None => options,
+    };
+
+    match &entry.kind {
+        EntryKind::Directory => zip
+            .add_directory(entry.name.as_str(), options)
+            .with_context(|| format!("Failed to add directory '{}'", entry.name))?,
+        EntryKind::Symlink(target) => {
+            let target = target
+                .to_str()
+                .with_context(|| format!("Non-UTF-8 symlink target in '{}'", entry.name))?;
+            zip.add_symlink(entry.name.as_str(), target, options)
+                .with_context(|| format!("Failed to add symlink '{}'", entry.name))?
+        }
+        EntryKind::File => {
+            let source = File::open(&entry.path)
+                .with_context(|| format!("Failed to open source file '{}'", entry.path.display()))?;
+
+            // Stream the file into the archive instead of reading it whole
+            zip.start_file(entry.name.as_str(), options)
+                .with_context(|| format!("Failed to add '{}' to ZIP archive", entry.name))?;
+            io::copy(&mut ProgressReader::new(source, progress), zip)
+                .with_context(|| format!("Failed to write '{}' to ZIP archive", entry.name))?;
+        }
+    }
+    Ok(())
+}
+
+/// ZIP timestamps can't go before 1980; older files get the earliest one.
+pub fn zip_time(modified: SystemTime) -> zip::DateTime {
+    zip::DateTime::try_from(OffsetDateTime::from(modified)).unwrap_or_default()
+}
+
+/// ZIP stores modification times without a time zone; `compress` writes them in UTC.
+pub fn zip_modified(time: Option<zip::DateTime>) -> Option<SystemTime> {
+    time.and_then(|t| OffsetDateTime::try_from(t).ok()).map(SystemTime::from)
+}
+
+fn item(entry: &mut zip::read::ZipFile) -> Result<Item> {
+    let kind = if entry.is_symlink() {
+        let mut target = String::new();
+        entry.read_to_string(&mut target)?;
+        EntryKind::Symlink(target.into())
+    } else if entry.is_dir() {
+        EntryKind::Directory
+    } else {
+        EntryKind::File
+    };
+    Ok(Item {
+        name: entry.name().to_string(),
+        kind,
+        mode: entry.unix_mode(),
+        modified: zip_modified(entry.last_modified()),
+    })
+}
+
+/// Opens an entry for reading, decrypting it if it's encrypted and there's a password.
+fn open_entry<'a>(
+    archive: &'a mut zip::ZipArchive<File>,
+    index: usize,
+    password: Option<&str>,
+) -> Result<zip::read::ZipFile<'a>> {
+    let name = archive.name_for_index(index).unwrap_or_default().to_string();
+    let entry = match password {
+        Some(password) => archive.by_index_decrypt(index, password.as_bytes()),
+        None => archive.by_index(index),
+    };
+    entry.map_err(|e| match e {
+        zip::result::ZipError::InvalidPassword => anyhow::anyhow!("Wrong password for '{}'", name),
+        e => anyhow::Error::new(e).context(format!("Failed to read '{}'", name)),
+    })
+}
+
+pub fn extract(path: &Path, extractor: &mut Extractor, password: &dyn Fn() -> Result<Zeroizing<String>>) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("Failed to open '{}'", path.display()))?;
+    let mut archive = zip::ZipArchive::new(file)
+        .with_context(|| format!("'{}' is not a valid ZIP archive", path.display()))?;
+
+    let mut encrypted = false;
+    for i in 0..archive.len() {
+        encrypted |= archive.by_index_raw(i)?.encrypted();
+    }
+    let password = if encrypted { Some(password()?) } else { None };
+    let password = password.as_deref().map(String::as_str);
+
+    // Check every name before writing anything, so a hostile archive leaves nothing behind
+    let mut checker = Checker::default();
+    for i in 0..archive.len() {
+        checker.check(&item(&mut open_entry(&mut archive, i, password)?)?)?;
+    }
+
+    for i in 0..archive.len() {
+        let mut entry = open_entry(&mut archive, i, password)?;
+        let item = item(&mut entry)?;
+        extractor.entry(&item, &mut entry)?;
+    }
+    Ok(())
+}
+
+pub fn analyze(path: &Path, report: &mut Report, password: Option<&str>) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("Failed to open '{}'", path.display()))?;
+    let mut archive = match zip::ZipArchive::new(file) {
+        Ok(archive) => archive,
+        Err(e) => {
+            report.error = Some(e.to_string());
+            return Ok(());
+        }
+    };
+    report.valid = true;
+
+    // A second handle for reading central directory headers, which the zip crate doesn't expose whole
+    let mut headers = File::open(path)?;
+    for i in 0..archive.len() {
+        let item = analyze_entry(&mut archive, i, &mut headers, password)?;
+        report.entries.push(item);
+    }
+    Ok(())
+}
+
+fn analyze_entry(
+    archive: &mut zip::ZipArchive<File>,
+    index: usize,
+    headers: &mut File,
+    password: Option<&str>,
+) -> Result<EntryReport> {
+    let name = archive.name_for_index(index).unwrap_or_default().to_string();
+    let corrupt = |name: String, error: String| EntryReport {
+        name,
+        status: EntryStatus::Corrupt,
+        error: Some(error),
+        ..Default::default()
+    };
+
+    let mut item = match archive.by_index_raw(index) {
+        Ok(entry) => {
+            let fields = CentralHeader::read(headers, entry.central_header_start()).and_then(|header| {
+                let aes = header.field(AES_EXTRA_FIELD)?.map(<[u8]>::to_vec);
+                Ok((aes, header.field(ZIP64_EXTRA_FIELD)?.is_some()))
+            });
+            let (aes, zip64) = match fields {
+                Ok(fields) => fields,
+                Err(e) => return Ok(corrupt(name, format!("Bad central directory header: {}", e))),
+            };
+            EntryReport {
+                kind: if entry.is_dir() {
+                    "directory"
+                } else if entry.is_symlink() {
+                    "symlink"
+                } else {
+                    "file"
+                },
+                method: Some(format!("{:?}", entry.compression())),
+                size: Some(entry.size()),
+                compressed_size: Some(entry.compressed_size()),
+                ratio: analyze::ratio(entry.compressed_size(), entry.size()),
+                crc32: Some(format!("{:08x}", entry.crc32())),
+                modified: zip_modified(entry.last_modified()).map(analyze::timestamp),
+                mode: entry.unix_mode().map(analyze::mode),
+                encrypted: entry.encrypted(),
+                encryption: entry.encrypted().then(|| encryption(aes.as_deref())),
+                zip64,
+                name,
+                ..Default::default()
+            }
+        }
+        Err(e) => return Ok(corrupt(name, e.to_string())),
+    };
+
+    if item.encrypted && password.is_none() {
+        item.status = EntryStatus::Unverified;
+        return Ok(item);
+    }
+
+    // Reading to the end makes the zip crate compare the CRC32, and for AES the authentication code
+    let entry = match password {
+        Some(password) => archive.by_index_decrypt(index, password.as_bytes()),
+        None => archive.by_index(index),
+    };
+    let result = match entry {
+        Err(zip::result::ZipError::InvalidPassword) => bail!("Wrong password for '{}'", item.name),
+        entry => entry.map_err(io::Error::from).and_then(|mut entry| io::copy(&mut entry, &mut io::sink())),
+    };
+    if let Err(e) = result {
+        item.status = EntryStatus::Corrupt;
+        item.error = Some(match e.to_string().as_str() {
+            "Invalid checksum" => "CRC32 mismatch".to_string(),
+            message => message.to_string(),
+        });
+    }
+    Ok(item)
+}
+
+/// The name of an encrypted entry's cipher: WinZip AES records its key size in an extra field, anything else
+/// is the original PKWARE ZipCrypto.
+fn encryption(aes_field: Option<&[u8]>) -> String {
+    // Vendor version (2 bytes), vendor ID (2), key size (1), compression method (2)
+    match aes_field.map(|data| data.get(4)) {
+        Some(Some(1)) => "AES-128",
+        Some(Some(2)) => "AES-192",
+        Some(Some(3)) => "AES-256",
+        Some(_) => "AES",
+        None => "ZipCrypto",
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::walk::{self, Filter};
+    use zip::write::SimpleFileOptions;
+
+    fn report_for(bytes: &[u8], password: Option<&str>) -> Result<Report> {
+        let path = std::env::temp_dir().join(format!("analyze-test-{}-{}.zip", std::process::id(), bytes.len()));
+        std::fs::write(&path, bytes).unwrap();
+        let report = analyze::analyze(&path, password);
+        std::fs::remove_file(path).unwrap();
+        report
+    }
+
+    #[test]
+    fn test_analyze_detects_zip64_and_corruption() {
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        zip.start_file("small.txt", stored).unwrap();
+        zip.write_all(b"hello, world").unwrap();
+        zip.start_file("large.txt", stored.large_file(true)).unwrap();
+        zip.write_all(b"pretend this is big").unwrap();
+        let mut bytes = zip.finish().unwrap().into_inner();
+
+        let report = report_for(&bytes, None).unwrap();
+        assert_eq!(report.exit_code(), 0);
+        assert_eq!(report.entries.len(), 2);
+        assert!(!report.entries[0].zip64);
+        assert!(report.entries[1].zip64);
+        assert_eq!(report.entries[0].size, Some(12));
+
+        // Flip a byte of the stored contents of the first entry
+        let at = bytes.windows(5).position(|w| w == b"hello").unwrap();
+        bytes[at] = b'j';
+        let report = report_for(&bytes, None).unwrap();
+        assert_eq!(report.exit_code(), analyze::EXIT_CORRUPT);
+        assert_eq!(report.entries[0].status, EntryStatus::Corrupt);
+        assert_eq!(report.entries[0].error.as_deref(), Some("CRC32 mismatch"));
+        assert_eq!(report.entries[1].status, EntryStatus::Ok);
+
+        let report = report_for(&bytes[..bytes.len() - 10], None).unwrap();
+        assert_eq!(report.exit_code(), analyze::EXIT_INVALID);
+    }
+
+    #[test]
+    fn test_analyze_reports_and_checks_aes_entries() {
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default().with_aes_encryption(AesMode::Aes256, "hunter2");
+        zip.start_file("secret.txt", options).unwrap();
+        zip.write_all(b"customer export").unwrap();
+        let bytes = zip.finish().unwrap().into_inner();
+
+        let report = report_for(&bytes, None).unwrap();
+        assert_eq!(report.entries[0].encryption.as_deref(), Some("AES-256"));
+        assert_eq!(report.entries[0].method.as_deref(), Some("Deflated"));
+        assert_eq!(report.entries[0].status, EntryStatus::Unverified);
+        assert_eq!(report.exit_code(), 0);
+
+        let report = report_for(&bytes, Some("hunter2")).unwrap();
+        assert_eq!(report.entries[0].status, EntryStatus::Ok);
+        assert!(report_for(&bytes, Some("hunter3")).is_err());
+    }
+
+    fn write_archive(path: &Path, entries: &[Entry], choice: MethodChoice, parallel: bool) {
+        let options = WriterOptions {
+            password: None,
+            parallel,
+            spool_dir: std::env::temp_dir(),
+        };
+        let mut writer = ZipArchiveWriter::new(File::create(path).unwrap(), choice, options);
+        let progress = Progress::new(0, false);
+        // Small entries in one batch, then the large file on its own, chunked or multi-threaded in parallel
+        let (large, small): (Vec<&Entry>, Vec<&Entry>) = entries.iter().partition(|e| e.size > 1_000_000);
+        if parallel {
+            writer.add_batch(&small, &progress).unwrap();
+            writer.add_large(large[0], &progress).unwrap();
+        } else {
+            writer.add_all(&small, &progress).unwrap();
+            writer.add_all(&large, &progress).unwrap();
+        }
+        Box::new(writer).finish().unwrap();
+    }
+
+    #[test]
+    fn test_parallel_output_matches_sequential() {
+        let dir = std::env::temp_dir().join(format!("parallel-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        for i in 0..20 {
+            std::fs::write(dir.join("src").join(format!("{}.txt", i)), format!("line {}\n", i).repeat(i * 50)).unwrap();
+        }
+        let large: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8 ^ (i >> 14) as u8).collect();
+        std::fs::write(dir.join("large.bin"), &large).unwrap();
+        let entries = walk::collect(std::slice::from_ref(&dir), &Filter::default(), false).unwrap();
+
+        for method in [CompressionMethod::Deflated, CompressionMethod::Zstd] {
+            let (sequential, parallel) = (dir.with_extension("seq.zip"), dir.with_extension("par.zip"));
+            write_archive(&sequential, &entries, MethodChoice::Fixed(method), false);
+            write_archive(&parallel, &entries, MethodChoice::Fixed(method), true);
+
+            let mut sequential = zip::ZipArchive::new(File::open(sequential).unwrap()).unwrap();
+            let mut parallel = zip::ZipArchive::new(File::open(parallel).unwrap()).unwrap();
+            assert_eq!(sequential.len(), parallel.len());
+            for i in 0..sequential.len() {
+                let mut a = sequential.by_index(i).unwrap();
+                let mut b = parallel.by_index(i).unwrap();
+                assert_eq!((a.name(), a.crc32(), a.size(), a.compression()), (b.name(), b.crc32(), b.size(), b.compression()));
+                assert_eq!((a.unix_mode(), a.last_modified()), (b.unix_mode(), b.last_modified()));
+                if a.size() < 1_000_000 {
+                    assert_eq!(a.compressed_size(), b.compressed_size(), "{}", a.name());
+                }
+                let (mut data_a, mut data_b) = (Vec::new(), Vec::new());
+                a.read_to_end(&mut data_a).unwrap();
+                b.read_to_end(&mut data_b).unwrap();
+                assert!(data_a == data_b, "{}", a.name());
+            }
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_file(dir.with_extension("seq.zip")).unwrap();
+        std::fs::remove_file(dir.with_extension("par.zip")).unwrap();
+    }
+
+    #[test]
+    fn test_single_entry_zip64() {
+        let entry = Entry {
+            path: PathBuf::from("big.txt"),
+            name: "big.txt".to_string(),
+            kind: EntryKind::File,
+            size: 11,
+            mode: 0o640,
+            modified: SystemTime::now(),
+        };
+        let data = b"hello world";
+        let spool = zip_raw::single_entry(io::Cursor::new(Vec::new()), &entry, CompressionMethod::Deflated, true, |out| {
+            let mut encoder = ChunkedDeflate::new(out, flate2::Compression::default());
+            encoder.write_all(data)?;
+            Ok((encoder.finish()?.1, data.len() as u64))
+        })
+        .unwrap();
+
+        let bytes = spool.into_inner();
+        assert!(report_for(&bytes, None).unwrap().entries[0].zip64);
+        let mut archive = zip::ZipArchive::new(io::Cursor::new(bytes)).unwrap();
+        let mut file = archive.by_index(0).unwrap();
+        assert_eq!(file.unix_mode(), Some(0o100640));
+        let mut read = String::new();
+        file.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "hello world");
+    }
+}
@@ -0,0 +1,292 @@
+use crate::walk::Entry;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use zip::CompressionMethod;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP64_END_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const END_SIGNATURE: u32 = 0x06054b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const LOCAL_HEADER_SIZE: usize = 30;
+const CENTRAL_HEADER_SIZE: usize = 46;
+pub const ZIP64_EXTRA_FIELD: u16 = 0x0001;
+pub const AES_EXTRA_FIELD: u16 = 0x9901;
+// Version 4.5, which covers ZIP64 and is what the zip crate writes for zstd
+const ZIP64_VERSION: u16 = 45;
+const DEFLATE_VERSION: u16 = 20;
+// Made by: Unix
+const UNIX: u16 = 3 << 8;
+const DATA_DESCRIPTOR: u16 = 1 << 3;
+const UTF8_NAME: u16 = 1 << 11;
+const S_IFREG: u32 = 0o100000;
+/// Entries from this size get ZIP64 sizes, leaving room for data that grows a little when compressed.
+pub const ZIP64_LIMIT: u64 = 0xF000_0000;
+
+/// Writes a ZIP holding one file whose data is compressed by `compress` rather than the zip crate, so it can
+/// use all threads; `ZipWriter::merge_archive` then copies it into the real archive. `compress` writes the
+/// compressed data and returns the CRC32 and size of the original. `zip64` must be set for entries from
+/// `ZIP64_LIMIT` on.
+pub fn single_entry<W: Write + Seek>(
+    mut out: W,
+    entry: &Entry,
+    method: CompressionMethod,
+    zip64: bool,
+    compress: impl FnOnce(&mut W) -> io::Result<(u32, u64)>,
+) -> io::Result<W> {
+    let (method_id, method_version) = match method {
+        CompressionMethod::Deflated => (8, DEFLATE_VERSION),
+        CompressionMethod::Zstd => (93, ZIP64_VERSION),
+        _ => return Err(io::Error::other(format!("{:?} isn't compressed outside the zip crate", method))),
+    };
+    let version = if zip64 { ZIP64_VERSION } else { method_version };
+    let flags = if entry.name.is_ascii() { 0 } else { UTF8_NAME };
+    let modified = super::zip_archive::zip_time(entry.modified);
+    let name = entry.name.as_bytes();
+    let extra_len: u16 = if zip64 { 20 } else { 0 };
+
+    let mut header = Vec::new();
+    put32(&mut header, LOCAL_HEADER_SIGNATURE);
+    put16(&mut header, version);
+    put16(&mut header, flags);
+    put16(&mut header, method_id);
+    put16(&mut header, modified.timepart());
+    put16(&mut header, modified.datepart());
+    // CRC32 and sizes are filled in once the data is written
+    header.extend_from_slice(&[0; 12]);
+    put16(&mut header, name.len() as u16);
+    put16(&mut header, extra_len);
+    header.extend_from_slice(name);
+    if zip64 {
+        put16(&mut header, ZIP64_EXTRA_FIELD);
+        put16(&mut header, 16);
+        header.extend_from_slice(&[0; 16]);
+    }
+    out.write_all(&header)?;
+
+    let data_start = header.len() as u64;
+    let (crc, size) = compress(&mut out)?;
+    let data_end = out.stream_position()?;
+    let compressed_size = data_end - data_start;
+    if !zip64 && compressed_size.max(size) >= u32::MAX as u64 {
+        return Err(io::Error::other(format!("'{}' grew too large for its header", entry.name)));
+    }
+
+    // Back to the header for the CRC32 and sizes
+    let (size32, compressed32) = if zip64 { (u32::MAX, u32::MAX) } else { (size as u32, compressed_size as u32) };
+    let mut sizes = Vec::new();
+    put32(&mut sizes, crc);
+    put32(&mut sizes, compressed32);
+    put32(&mut sizes, size32);
+    out.seek(SeekFrom::Start(14))?;
+    out.write_all(&sizes)?;
+    if zip64 {
+        let mut zip64_sizes = Vec::new();
+        put64(&mut zip64_sizes, size);
+        put64(&mut zip64_sizes, compressed_size);
+        out.seek(SeekFrom::Start(30 + name.len() as u64 + 4))?;
+        out.write_all(&zip64_sizes)?;
+    }
+    out.seek(SeekFrom::Start(data_end))?;
+
+    let mut central = Vec::new();
+    put32(&mut central, CENTRAL_HEADER_SIGNATURE);
+    put16(&mut central, UNIX | ZIP64_VERSION);
+    put16(&mut central, version);
+    put16(&mut central, flags);
+    put16(&mut central, method_id);
+    put16(&mut central, modified.timepart());
+    put16(&mut central, modified.datepart());
+    central.extend_from_slice(&sizes);
+    put16(&mut central, name.len() as u16);
+    put16(&mut central, extra_len);
+    // Comment length, disk number, internal attributes
+    central.extend_from_slice(&[0; 6]);
+    put32(&mut central, (entry.mode | S_IFREG) << 16);
+    // The local header is at the start
+    put32(&mut central, 0);
+    central.extend_from_slice(name);
+    if zip64 {
+        put16(&mut central, ZIP64_EXTRA_FIELD);
+        put16(&mut central, 16);
+        put64(&mut central, size);
+        put64(&mut central, compressed_size);
+    }
+    out.write_all(&central)?;
+
+    write_end(&mut out, data_end, central.len() as u64, zip64)?;
+    Ok(out)
+}
+
+/// A central directory header as it is in the file: fixed fields, name, extra fields and comment.
+pub struct CentralHeader {
+    bytes: Vec<u8>,
+}
+
+impl CentralHeader {
+    pub fn read<R: Read + Seek>(file: &mut R, offset: u64) -> io::Result<Self> {
+        let mut bytes = vec![0u8; CENTRAL_HEADER_SIZE];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut bytes)?;
+        if u32_at(&bytes, 0) != CENTRAL_HEADER_SIGNATURE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "missing signature"));
+        }
+        let variable = u16_at(&bytes, 28) as usize + u16_at(&bytes, 30) as usize + u16_at(&bytes, 32) as usize;
+        bytes.resize(CENTRAL_HEADER_SIZE + variable, 0);
+        file.read_exact(&mut bytes[CENTRAL_HEADER_SIZE..])?;
+        Ok(CentralHeader { bytes })
+    }
+
+    /// The data of the first extra field with this ID. The zip crate drops the ZIP64 field once it has read
+    /// the sizes from it, so it's only seen here.
+    pub fn field(&self, id: u16) -> io::Result<Option<&[u8]>> {
+        let fields = self.fields()?;
+        Ok(fields.into_iter().find(|(field, _)| *field == id).map(|(_, range)| &self.bytes[range]))
+    }
+
+    fn fields(&self) -> io::Result<Vec<(u16, Range<usize>)>> {
+        let start = CENTRAL_HEADER_SIZE + u16_at(&self.bytes, 28) as usize;
+        extra_fields(&self.bytes, start..start + u16_at(&self.bytes, 30) as usize)
+    }
+
+    /// Points the header at a local header at offset 0.
+    fn move_to_start(&mut self) -> io::Result<()> {
+        if u32_at(&self.bytes, 42) != u32::MAX {
+            self.bytes[42..46].copy_from_slice(&[0; 4]);
+            return Ok(());
+        }
+        // The offset is in the ZIP64 field instead, after whichever sizes are there too
+        let (_, range) = self
+            .fields()?
+            .into_iter()
+            .find(|(id, _)| *id == ZIP64_EXTRA_FIELD)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "ZIP64 offset without a ZIP64 field"))?;
+        let sizes = [24, 20].iter().filter(|&&at| u32_at(&self.bytes, at) == u32::MAX).count();
+        let at = range.start + 8 * sizes;
+        self.bytes
+            .get_mut(at..at + 8)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "ZIP64 field too short"))?
+            .copy_from_slice(&[0; 8]);
+        Ok(())
+    }
+}
+
+/// Copies an entry of an existing archive byte for byte into a single-entry ZIP for
+/// `ZipWriter::merge_archive`: its local header, data and data descriptor, then its central header. Nothing is
+/// decompressed or decrypted, so the entry keeps its method, encryption and extra fields.
+pub fn copy_entry<R: Read + Seek, W: Write + Seek>(
+    source: &mut R,
+    header_start: u64,
+    data_end: u64,
+    mut central: CentralHeader,
+    mut out: W,
+) -> io::Result<W> {
+    let mut local = vec![0u8; LOCAL_HEADER_SIZE];
+    source.seek(SeekFrom::Start(header_start))?;
+    source.read_exact(&mut local)?;
+    if u32_at(&local, 0) != LOCAL_HEADER_SIGNATURE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "missing local header signature"));
+    }
+
+    let mut length = data_end - header_start;
+    if u16_at(&local, 6) & DATA_DESCRIPTOR != 0 {
+        // The descriptor has 8-byte sizes when the local header has a ZIP64 field, and may start with a signature
+        let (name_len, extra_len) = (u16_at(&local, 26) as usize, u16_at(&local, 28) as usize);
+        local.resize(LOCAL_HEADER_SIZE + name_len + extra_len, 0);
+        source.read_exact(&mut local[LOCAL_HEADER_SIZE..])?;
+        let extra = LOCAL_HEADER_SIZE + name_len..local.len();
+        let zip64 = extra_fields(&local, extra)?.iter().any(|(id, _)| *id == ZIP64_EXTRA_FIELD);
+
+        let mut signature = [0u8; 4];
+        source.seek(SeekFrom::Start(data_end))?;
+        source.read_exact(&mut signature)?;
+        length += 4 + if zip64 { 16 } else { 8 };
+        if u32::from_le_bytes(signature) == DATA_DESCRIPTOR_SIGNATURE {
+            length += 4;
+        }
+    }
+
+    source.seek(SeekFrom::Start(header_start))?;
+    let copied = io::copy(&mut source.take(length), &mut out)?;
+    if copied != length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "entry data is cut short"));
+    }
+
+    central.move_to_start()?;
+    out.write_all(&central.bytes)?;
+    write_end(&mut out, length, central.bytes.len() as u64, length >= u32::MAX as u64)?;
+    Ok(out)
+}
+
+/// The end of central directory record for one entry, preceded by the ZIP64 record and locator if `zip64`.
+fn write_end<W: Write>(out: &mut W, central_start: u64, central_len: u64, zip64: bool) -> io::Result<()> {
+    let mut end = Vec::new();
+    if zip64 {
+        put32(&mut end, ZIP64_END_SIGNATURE);
+        // Size of the rest of the record
+        put64(&mut end, 44);
+        put16(&mut end, UNIX | ZIP64_VERSION);
+        put16(&mut end, ZIP64_VERSION);
+        // This disk, the central directory's disk
+        put32(&mut end, 0);
+        put32(&mut end, 0);
+        // Entries on this disk and in total
+        put64(&mut end, 1);
+        put64(&mut end, 1);
+        put64(&mut end, central_len);
+        put64(&mut end, central_start);
+
+        put32(&mut end, ZIP64_LOCATOR_SIGNATURE);
+        put32(&mut end, 0);
+        put64(&mut end, central_start + central_len);
+        put32(&mut end, 1);
+    }
+    put32(&mut end, END_SIGNATURE);
+    // This disk, the central directory's disk
+    put16(&mut end, 0);
+    put16(&mut end, 0);
+    put16(&mut end, 1);
+    put16(&mut end, 1);
+    put32(&mut end, central_len as u32);
+    put32(&mut end, if zip64 { u32::MAX } else { central_start as u32 });
+    // Comment length
+    put16(&mut end, 0);
+    out.write_all(&end)
+}
+
+/// The extra fields in `bytes[range]`, as (ID, range of the data in `bytes`) pairs.
+fn extra_fields(bytes: &[u8], range: Range<usize>) -> io::Result<Vec<(u16, Range<usize>)>> {
+    let mut fields = Vec::new();
+    let mut at = range.start;
+    while at + 4 <= range.end {
+        let (id, len) = (u16_at(bytes, at), u16_at(bytes, at + 2) as usize);
+        let data = at + 4..at + 4 + len;
+        if data.end > range.end {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "extra field runs past the header"));
+        }
+        fields.push((id, data.clone()));
+        at = data.end;
+    }
+    Ok(fields)
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn put16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
@@ -0,0 +1,370 @@
+use super::zip_archive::{ZipArchiveWriter, zip_time};
+use super::{ArchiveWriter, MethodChoice, WriterOptions};
+use crate::progress::Progress;
+use crate::walk::{Entry, EntryKind, Filter};
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use glob::Pattern;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// How `--update` tells that a file already in the archive has changed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum Compare {
+    /// Size or modification time differ; ZIP keeps times to 2 seconds
+    #[default]
+    Mtime,
+    /// Size or CRC32 differ, which reads every file. AES entries keep no CRC32 and are compared by time
+    Hash,
+}
+
+enum Step<'a> {
+    /// Copy the existing entry at this index as it is
+    Copy(usize),
+    /// Compress the entry from disk
+    Write(&'a Entry),
+}
+
+/// A new version of an existing ZIP: its entries in order, each copied from the old archive or compressed from
+/// disk, and how many were added, replaced, kept or dropped.
+#[derive(Default)]
+pub struct Rewrite<'a> {
+    steps: Vec<Step<'a>>,
+    pub added: usize,
+    pub replaced: usize,
+    pub unchanged: usize,
+    pub deleted: usize,
+}
+
+impl<'a> Rewrite<'a> {
+    /// Brings the archive at `path` up to date with `entries`: changed ones are replaced where they are and new
+    /// ones appended. Everything else is kept, including entries with nothing on disk any more. An archive with
+    /// encrypted entries needs `password` to be theirs, so new entries are encrypted the same way.
+    pub fn update(path: &Path, entries: &[&'a Entry], compare: Compare, password: Option<&str>) -> Result<Self> {
+        let mut archive = open(path)?;
+        check_password(&mut archive, path, password)?;
+        let by_name: HashMap<&str, &Entry> = entries.iter().map(|entry| (entry.name.as_str(), *entry)).collect();
+        let mut rewrite = Rewrite::default();
+        let mut seen = HashSet::new();
+        for i in 0..archive.len() {
+            let existing = archive.by_index_raw(i)?;
+            // Directory entries end in `/`, walked names don't
+            let name = existing.name().trim_end_matches('/').to_string();
+            let entry = match by_name.get(name.as_str()) {
+                Some(&entry) if seen.insert(name) => entry,
+                _ => {
+                    rewrite.unchanged += 1;
+                    rewrite.steps.push(Step::Copy(i));
+                    continue;
+                }
+            };
+            if changed(&existing, entry, compare)? {
+                rewrite.replaced += 1;
+                rewrite.steps.push(Step::Write(entry));
+            } else {
+                rewrite.unchanged += 1;
+                rewrite.steps.push(Step::Copy(i));
+            }
+        }
+
+        for entry in entries.iter().filter(|entry| !seen.contains(&entry.name)) {
+            rewrite.added += 1;
+            rewrite.steps.push(Step::Write(entry));
+        }
+        Ok(rewrite)
+    }
+
+    /// Drops the entries of the archive at `path` matching any of `patterns`, and everything under matching
+    /// directories. Patterns follow `--exclude`; each has to match something.
+    pub fn delete(path: &Path, patterns: &[String]) -> Result<Self> {
+        let archive = open(path)?;
+        let patterns: Vec<Pattern> = patterns
+            .iter()
+            .map(|p| Pattern::new(p).with_context(|| format!("Invalid glob pattern '{}'", p)))
+            .collect::<Result<_>>()?;
+        let mut matched = vec![false; patterns.len()];
+        let mut rewrite = Rewrite::default();
+        for i in 0..archive.len() {
+            let name = archive.name_for_index(i).unwrap_or_default().trim_end_matches('/');
+            // The name itself and the directories it's in
+            let paths: Vec<&str> = name.match_indices('/').map(|(at, _)| &name[..at]).chain([name]).collect();
+            let mut deleted = false;
+            for (pattern, matched) in patterns.iter().zip(&mut matched) {
+                if paths.iter().any(|path| Filter::matches(pattern, path)) {
+                    *matched = true;
+                    deleted = true;
+                }
+            }
+            if deleted {
+                rewrite.deleted += 1;
+            } else {
+                rewrite.unchanged += 1;
+                rewrite.steps.push(Step::Copy(i));
+            }
+        }
+
+        if let Some((pattern, _)) = patterns.iter().zip(&matched).find(|(_, matched)| !**matched) {
+            bail!("No entries in '{}' match '{}'", path.display(), pattern);
+        }
+        Ok(rewrite)
+    }
+
+    /// Whether the new archive would differ from the old one.
+    pub fn changes(&self) -> bool {
+        self.added + self.replaced + self.deleted > 0
+    }
+
+    /// Bytes of the files to compress.
+    pub fn bytes(&self) -> u64 {
+        self.steps
+            .iter()
+            .map(|step| match step {
+                Step::Write(entry) => entry.size,
+                Step::Copy(_) => 0,
+            })
+            .sum()
+    }
+
+    /// Writes the new archive to `output`. Entries from the old one at `path` are copied without being
+    /// decompressed, so they stay compressed and encrypted as they were.
+    pub fn write(&self, path: &Path, output: File, choice: MethodChoice, options: WriterOptions, progress: &Progress) -> Result<()> {
+        let mut archive = open(path)?;
+        // A second handle for the raw headers, which the zip crate doesn't expose whole
+        let mut headers = File::open(path)?;
+        let mut writer = Box::new(ZipArchiveWriter::new(output, choice, options));
+
+        let mut rest = self.steps.as_slice();
+        while let Some(step) = rest.first() {
+            if let Step::Copy(index) = step {
+                writer.copy_entry(&mut archive, &mut headers, *index)?;
+                rest = &rest[1..];
+                continue;
+            }
+            // Runs of new entries go to `add_all` together so they're compressed side by side in parallel
+            let run: Vec<&Entry> = rest
+                .iter()
+                .map_while(|step| match step {
+                    Step::Write(entry) => Some(*entry),
+                    Step::Copy(_) => None,
+                })
+                .collect();
+            writer.add_all(&run, progress)?;
+            rest = &rest[run.len()..];
+        }
+        writer.finish()
+    }
+}
+
+/// Fails unless `password` opens the archive's encrypted entries, if it has any. Only the smallest one is read,
+/// since the password is the same for all of them.
+fn check_password(archive: &mut zip::ZipArchive<File>, path: &Path, password: Option<&str>) -> Result<()> {
+    let mut smallest = None;
+    for i in 0..archive.len() {
+        let entry = archive.by_index_raw(i)?;
+        if entry.encrypted() && smallest.is_none_or(|(_, size)| entry.size() < size) {
+            smallest = Some((i, entry.size()));
+        }
+    }
+    let Some((index, _)) = smallest else {
+        return Ok(());
+    };
+    let Some(password) = password else {
+        bail!("'{}' has encrypted entries; update it with --encrypt and the same password", path.display());
+    };
+
+    // Reading to the end checks the authentication code as well as the password verifier
+    let wrong = || anyhow::anyhow!("Wrong password for '{}'", path.display());
+    let mut entry = match archive.by_index_decrypt(index, password.as_bytes()) {
+        Err(zip::result::ZipError::InvalidPassword) => return Err(wrong()),
+        entry => entry?,
+    };
+    io::copy(&mut entry, &mut io::sink()).map_err(|_| wrong())?;
+    Ok(())
+}
+
+fn open(path: &Path) -> Result<zip::ZipArchive<File>> {
+    let file = File::open(path).with_context(|| format!("Failed to open '{}'", path.display()))?;
+    zip::ZipArchive::new(file).with_context(|| format!("'{}' is not a valid ZIP archive", path.display()))
+}
+
+/// Whether `entry` on disk differs from the archived `existing`. Directories hold nothing to compare, so only a
+/// change of kind replaces them.
+fn changed(existing: &zip::read::ZipFile, entry: &Entry, compare: Compare) -> Result<bool> {
+    let same_kind = match entry.kind {
+        EntryKind::Directory => existing.is_dir(),
+        EntryKind::Symlink(_) => existing.is_symlink(),
+        EntryKind::File => !existing.is_dir() && !existing.is_symlink(),
+    };
+    if !same_kind {
+        return Ok(true);
+    }
+
+    // A symlink is stored as a file holding its target
+    let target = match &entry.kind {
+        EntryKind::Directory => return Ok(false),
+        EntryKind::Symlink(target) => Some(target.to_string_lossy().into_owned()),
+        EntryKind::File => None,
+    };
+    let size = target.as_ref().map_or(entry.size, |target| target.len() as u64);
+    if existing.size() != size {
+        return Ok(true);
+    }
+    if compare == Compare::Mtime || existing.encrypted() {
+        // Compared as stored, since DOS times drop odd seconds
+        let modified = zip_time(entry.modified);
+        let stored = existing.last_modified().map(|t| (t.datepart(), t.timepart()));
+        return Ok(stored != Some((modified.datepart(), modified.timepart())));
+    }
+
+    let crc = match target {
+        Some(target) => crc32fast::hash(target.as_bytes()),
+        None => {
+            let source = File::open(&entry.path)
+                .with_context(|| format!("Failed to open source file '{}'", entry.path.display()))?;
+            let mut source = flate2::CrcReader::new(source);
+            io::copy(&mut source, &mut io::sink())
+                .with_context(|| format!("Failed to read '{}'", entry.path.display()))?;
+            source.crc().sum()
+        }
+    };
+    Ok(crc != existing.crc32())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::walk;
+    use std::io::{Read, Write};
+    use zip::CompressionMethod;
+    use zeroize::Zeroizing;
+    use zip::write::SimpleFileOptions;
+
+    fn write(rewrite: &Rewrite, path: &Path, output: &Path, method: CompressionMethod) {
+        write_encrypted(rewrite, path, output, method, None);
+    }
+
+    fn write_encrypted(rewrite: &Rewrite, path: &Path, output: &Path, method: CompressionMethod, password: Option<&str>) {
+        let options = WriterOptions {
+            password: password.map(|password| Zeroizing::new(password.to_string())),
+            parallel: false,
+            spool_dir: std::env::temp_dir(),
+        };
+        let progress = Progress::new(rewrite.bytes(), false);
+        rewrite
+            .write(path, File::create(output).unwrap(), MethodChoice::Fixed(method), options, &progress)
+            .unwrap();
+    }
+
+    fn raw_data(archive: &mut zip::ZipArchive<File>, name: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        archive.by_index_raw(archive.index_for_name(name).unwrap()).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_update_copies_unchanged_entries() {
+        let dir = std::env::temp_dir().join(format!("update-test-{}", std::process::id()));
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(&tree).unwrap();
+        std::fs::write(tree.join("same.txt"), "unchanged ".repeat(100)).unwrap();
+        std::fs::write(tree.join("edited.txt"), "before").unwrap();
+        let entries = walk::collect(std::slice::from_ref(&tree), &walk::Filter::default(), false).unwrap();
+        let (old, new) = (dir.join("old.zip"), dir.join("new.zip"));
+
+        let mut zip = ZipArchiveWriter::new(File::create(&old).unwrap(), MethodChoice::Fixed(CompressionMethod::Zstd), WriterOptions {
+            password: None,
+            parallel: false,
+            spool_dir: dir.clone(),
+        });
+        zip.add_all(&entries.iter().collect::<Vec<_>>(), &Progress::new(0, false)).unwrap();
+        Box::new(zip).finish().unwrap();
+
+        std::fs::write(tree.join("edited.txt"), "after, and longer").unwrap();
+        std::fs::write(tree.join("added.txt"), "new").unwrap();
+        let entries = walk::collect(std::slice::from_ref(&tree), &walk::Filter::default(), false).unwrap();
+        let entries: Vec<&Entry> = entries.iter().collect();
+        let rewrite = Rewrite::update(&old, &entries, Compare::Hash, None).unwrap();
+        assert_eq!((rewrite.added, rewrite.replaced, rewrite.unchanged), (1, 1, 2));
+        write(&rewrite, &old, &new, CompressionMethod::Deflated);
+
+        let mut before = zip::ZipArchive::new(File::open(&old).unwrap()).unwrap();
+        let mut after = zip::ZipArchive::new(File::open(&new).unwrap()).unwrap();
+        assert_eq!(after.len(), 4);
+        assert_eq!(after.name_for_index(3), Some("tree/added.txt"));
+        // Still zstd, and the same bytes
+        assert_eq!(after.by_name("tree/same.txt").unwrap().compression(), CompressionMethod::Zstd);
+        assert_eq!(raw_data(&mut before, "tree/same.txt"), raw_data(&mut after, "tree/same.txt"));
+        let mut edited = String::new();
+        after.by_name("tree/edited.txt").unwrap().read_to_string(&mut edited).unwrap();
+        assert_eq!(edited, "after, and longer");
+
+        let rewrite = Rewrite::update(&new, &entries, Compare::Mtime, None).unwrap();
+        assert!(!rewrite.changes());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_update_of_encrypted_archive_needs_its_password() {
+        let dir = std::env::temp_dir().join(format!("update-encrypted-test-{}", std::process::id()));
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(&tree).unwrap();
+        std::fs::write(tree.join("secret.txt"), "before").unwrap();
+        let entries = walk::collect(std::slice::from_ref(&tree), &walk::Filter::default(), false).unwrap();
+        let entries: Vec<&Entry> = entries.iter().collect();
+        let (old, new) = (dir.join("old.zip"), dir.join("new.zip"));
+        let mut zip = ZipArchiveWriter::new(File::create(&old).unwrap(), MethodChoice::Fixed(CompressionMethod::Deflated), WriterOptions {
+            password: Some(Zeroizing::new("hunter2".to_string())),
+            parallel: false,
+            spool_dir: dir.clone(),
+        });
+        zip.add_all(&entries, &Progress::new(0, false)).unwrap();
+        Box::new(zip).finish().unwrap();
+
+        std::fs::write(tree.join("added.txt"), "new").unwrap();
+        let entries = walk::collect(std::slice::from_ref(&tree), &walk::Filter::default(), false).unwrap();
+        let entries: Vec<&Entry> = entries.iter().collect();
+        let error = Rewrite::update(&old, &entries, Compare::Mtime, None).err().unwrap();
+        assert!(error.to_string().contains("--encrypt"), "{}", error);
+        let error = Rewrite::update(&old, &entries, Compare::Mtime, Some("hunter3")).err().unwrap();
+        assert!(error.to_string().contains("Wrong password"), "{}", error);
+
+        let rewrite = Rewrite::update(&old, &entries, Compare::Mtime, Some("hunter2")).unwrap();
+        assert_eq!((rewrite.added, rewrite.replaced, rewrite.unchanged), (1, 0, 2));
+        write_encrypted(&rewrite, &old, &new, CompressionMethod::Deflated, Some("hunter2"));
+        let mut archive = zip::ZipArchive::new(File::open(&new).unwrap()).unwrap();
+        assert!(archive.by_index_raw(archive.index_for_name("tree/added.txt").unwrap()).unwrap().encrypted());
+        let mut added = String::new();
+        archive.by_name_decrypt("tree/added.txt", b"hunter2").unwrap().read_to_string(&mut added).unwrap();
+        assert_eq!(added, "new");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_delete_removes_directory_contents() {
+        let path = std::env::temp_dir().join(format!("delete-test-{}.zip", std::process::id()));
+        let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+        let options = SimpleFileOptions::default();
+        zip.add_directory("docs/", options).unwrap();
+        for (name, options) in [("docs/a.txt", options), ("docs.txt", options), ("big.log", options.large_file(true))] {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(name.repeat(50).as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        assert!(Rewrite::delete(&path, &["docs".to_string(), "*.tmp".to_string()]).is_err());
+        let rewrite = Rewrite::delete(&path, &["docs".to_string(), "*.log".to_string()]).unwrap();
+        assert_eq!((rewrite.deleted, rewrite.unchanged), (3, 1));
+        let output = path.with_extension("new.zip");
+        write(&rewrite, &path, &output, CompressionMethod::Stored);
+
+        let mut archive = zip::ZipArchive::new(File::open(&output).unwrap()).unwrap();
+        assert_eq!(archive.file_names().collect::<Vec<_>>(), ["docs.txt"]);
+        let mut data = String::new();
+        archive.by_index(0).unwrap().read_to_string(&mut data).unwrap();
+        assert_eq!(data, "docs.txt".repeat(50));
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(output).unwrap();
+    }
+}
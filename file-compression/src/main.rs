@@ -1,13 +1,24 @@
+mod analyze;
+mod bench;
 mod compresor;
+mod extract;
+mod formats;
+mod parallel;
+mod progress;
+mod walk;
 
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use zeroize::Zeroizing;
+
+// Read instead of prompting when set, for scripts
+const PASSWORD_ENV: &str = "FILE_COMPRESSION_PASSWORD";
 
 #[derive(Parser)]
-#[command(name = "aes-encrypt")]
+#[command(name = "file-compression")]
 #[command(about = "Compress file(s) into archive with various compression methods")]
-struct CLI {
+struct Cli {
     #[command(subcommand)]
     command: Commands,
 }
@@ -15,54 +26,257 @@ struct CLI {
 #[derive(Subcommand)]
 enum Commands {
     Compress {
+        /// Files and directories to add; directories are added recursively
+        #[arg(short, long, num_args = 1.., required = true)]
+        input: Vec<PathBuf>,
+        #[arg(short, long)]
+        output: PathBuf,
+        /// ZIP entry compression: deflate (default), stored, bzip2 or zstd
+        #[arg(short = 'c', long, conflicts_with = "auto")]
+        compression_method: Option<String>,
+        /// Pick the method and level per file by compressing a sample of it, for the best ratio or speed
+        #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "ratio")]
+        auto: Option<bench::Objective>,
+        /// Archive format; guessed from the output extension when omitted, ZIP if that doesn't tell
+        #[arg(short, long, value_enum)]
+        format: Option<formats::ArchiveFormat>,
+        /// Only add files matching this glob; repeatable. Patterns without `/` match file names
+        #[arg(long)]
+        include: Vec<String>,
+        /// Skip files and directories matching this glob; repeatable
+        #[arg(long)]
+        exclude: Vec<String>,
+        /// Add what symlinks point to instead of the links themselves
+        #[arg(long)]
+        follow_symlinks: bool,
+        /// Encrypt ZIP entries with WinZip AES-256; the password is prompted for or read from
+        /// FILE_COMPRESSION_PASSWORD
+        #[arg(long)]
+        encrypt: bool,
+        /// Compress on this many threads, showing progress; 0 uses every core. Entries come out the same as
+        /// with one thread, though large files are compressed differently
+        #[arg(short, long, default_value_t = 1)]
+        jobs: usize,
+        /// Update an existing ZIP instead of recreating it: add new files and replace changed ones, copying
+        /// everything else without recompressing it
+        #[arg(short, long)]
+        update: bool,
+        /// How --update tells that a file has changed [default: mtime]
+        #[arg(long, value_enum, requires = "update")]
+        compare: Option<formats::Compare>,
+    },
+    /// Remove entries from a ZIP without recompressing the others
+    Delete {
         #[arg(short, long)]
         input: PathBuf,
+        /// Entry names or globs; a pattern without `/` matches file names anywhere, and deleting a directory
+        /// deletes what's in it
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+    /// Extract an archive into a directory, refusing entries that would land outside it
+    Extract {
         #[arg(short, long)]
+        input: PathBuf,
+        /// Directory to extract into; created if needed
+        #[arg(short, long, default_value = ".")]
         output: PathBuf,
-        #[arg(short = 'c', long, default_value = "deflate")]
-        compression_method: String,
+        /// What to do with entries whose destination already exists
+        #[arg(long, value_enum, default_value_t)]
+        overwrite: extract::OverwritePolicy,
+        /// Only print what would be extracted, skipped or renamed
+        #[arg(long)]
+        list: bool,
     },
+    /// Check every entry of an archive. Exits with 2 if the file isn't a readable archive, 3 if entries are corrupt
     Analyze {
         #[arg(short, long)]
         input: PathBuf,
+        #[arg(long, value_enum, default_value_t)]
+        output_format: OutputFormat,
+        /// Ask for the password, or read FILE_COMPRESSION_PASSWORD, to check encrypted entries too
+        #[arg(long)]
+        password: bool,
+    },
+    /// Measure ratio, throughput and memory of each compression method and level on some files
+    Bench {
+        /// Files and directories to use as the corpus
+        #[arg(short, long, num_args = 1.., required = true)]
+        input: Vec<PathBuf>,
+        #[arg(long, value_enum, default_value_t)]
+        output_format: OutputFormat,
+    },
+    /// Runs one measurement for `bench` in a fresh process
+    #[command(hide = true)]
+    BenchWorker {
+        #[arg(long)]
+        candidate: usize,
+        inputs: Vec<PathBuf>,
     },
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+enum OutputFormat {
+    #[default]
+    Table,
+    Json,
+}
+
 fn main() {
-    let cli = CLI::parse();
+    let cli = Cli::parse();
 
     match cli.command {
         Commands::Compress {
             input,
             output,
             compression_method,
+            auto,
+            format,
+            include,
+            exclude,
+            follow_symlinks,
+            encrypt,
+            jobs,
+            update,
+            compare,
         } => {
-            let input = input.to_str().ok_or("Invalid input path").unwrap();
+            let format = format
+                .or_else(|| formats::ArchiveFormat::from_extension(&output))
+                .unwrap_or(formats::ArchiveFormat::Zip);
             let output = output.to_str().ok_or("Invalid output path").unwrap();
 
-            let compression_method = match compression_method.as_str() {
+            let compression_method = compression_method.map(|method| match method.as_str() {
                 "deflate" => zip::CompressionMethod::Deflated,
                 "stored" => zip::CompressionMethod::Stored,
                 "bzip2" => zip::CompressionMethod::Bzip2,
                 "zstd" => zip::CompressionMethod::Zstd,
                 _ => {
-                    eprintln!("Invalid compression method: {}", compression_method);
+                    eprintln!("Invalid compression method: {}", method);
+                    std::process::exit(1);
+                }
+            });
+            let method = match auto {
+                Some(objective) => Some(formats::MethodChoice::Auto(objective)),
+                None => compression_method.map(formats::MethodChoice::Fixed),
+            };
+
+            // Check the options before asking for a password they can't use
+            let password = match format.check_options(method, encrypt) {
+                Ok(()) if encrypt => read_password("Password: ", true).map(Some),
+                result => result.map(|_| None),
+            };
+            let password = match password {
+                Ok(password) => password,
+                Err(e) => {
+                    eprintln!("Error: {:#}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            let entries = walk::Filter::new(&include, &exclude)
+                .and_then(|filter| walk::collect(&input, &filter, follow_symlinks));
+            let entries = match entries {
+                Ok(entries) => entries,
+                Err(e) => {
+                    eprintln!("Error: {:#}", e);
                     std::process::exit(1);
                 }
             };
 
-            if let Err(e) = compresor::compress(input, output, compression_method) {
-                eprintln!("Error: {}", e);
+            let result = if update {
+                compresor::update(&entries, output, format, method, password, jobs, compare.unwrap_or_default())
+            } else {
+                compresor::compress(&entries, output, format, method, password, jobs)
+            };
+            if let Err(e) = result {
+                eprintln!("Error: {:#}", e);
                 std::process::exit(1);
             }
         }
-        Commands::Analyze { input } => {
-            let input = input.to_str().ok_or("Invalid input path").unwrap();
-
-            if let Err(e) = compresor::analyze_zip_file(input) {
-                eprintln!("Error: {}", e);
+        Commands::Delete { input, names } => {
+            if let Err(e) = compresor::delete(&input, &names) {
+                eprintln!("Error: {:#}", e);
                 std::process::exit(1);
             }
         }
+        Commands::Extract {
+            input,
+            output,
+            overwrite,
+            list,
+        } => {
+            let result = extract::Extractor::new(&output, overwrite, list).and_then(|mut extractor| {
+                let password = || read_password("Password: ", false);
+                formats::detect_or_guess(&input)?.extract(&input, &mut extractor, &password)?;
+                extractor.finish()
+            });
+            match result {
+                Ok(summary) => println!(
+                    "{} entries extracted, {} skipped, {} renamed",
+                    summary.extracted, summary.skipped, summary.renamed
+                ),
+                Err(e) => {
+                    eprintln!("Error: {:#}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Analyze {
+            input,
+            output_format,
+            password,
+        } => {
+            let report = password
+                .then(|| read_password("Password: ", false))
+                .transpose()
+                .and_then(|password| analyze::analyze(&input, password.as_deref().map(String::as_str)));
+            match report {
+                Ok(report) => {
+                    match output_format {
+                        OutputFormat::Table => report.print(),
+                        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+                    }
+                    std::process::exit(report.exit_code());
+                }
+                Err(e) => {
+                    eprintln!("Error: {:#}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Bench { input, output_format } => match bench::bench(&input) {
+            Ok(measurements) => match output_format {
+                OutputFormat::Table => bench::print_table(&measurements),
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&measurements).unwrap()),
+            },
+            Err(e) => {
+                eprintln!("Error: {:#}", e);
+                std::process::exit(1);
+            }
+        },
+        Commands::BenchWorker { candidate, inputs } => {
+            if let Err(e) = bench::bench_worker(candidate, &inputs) {
+                eprintln!("Error: {:#}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn read_password(prompt: &str, confirm: bool) -> anyhow::Result<Zeroizing<String>> {
+    let password = match std::env::var(PASSWORD_ENV) {
+        Ok(password) => Zeroizing::new(password),
+        Err(_) => {
+            let password = Zeroizing::new(rpassword::prompt_password(prompt)?);
+            if confirm && *Zeroizing::new(rpassword::prompt_password("Confirm password: ")?) != *password {
+                anyhow::bail!("Passwords do not match");
+            }
+            password
+        }
+    };
+
+    if password.is_empty() {
+        anyhow::bail!("Password must not be empty");
     }
+    Ok(password)
 }
@@ -0,0 +1,215 @@
+use flate2::{Compress, Compression, FlushCompress, Status};
+use rayon::prelude::*;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Input per deflate chunk; one chunk per thread is compressed at a time
+const CHUNK: usize = 1024 * 1024;
+// Entries up to this size are spooled in memory, larger ones in a temporary file
+const SPOOL_IN_MEMORY: u64 = 16 * 1024 * 1024;
+
+/// pigz-style deflate: the input is cut into chunks compressed on the thread pool side by side, each ending in a
+/// sync flush so they join into one deflate stream that any inflater reads. Chunks don't share a dictionary,
+/// which costs a little ratio at each boundary. With `gzip`, the stream is framed as a single gzip member.
+pub struct ChunkedDeflate<W: Write> {
+    inner: W,
+    level: Compression,
+    gzip: bool,
+    buffer: Vec<u8>,
+    batch: usize,
+    crc: crc32fast::Hasher,
+    size: u64,
+}
+
+impl<W: Write> ChunkedDeflate<W> {
+    pub fn new(inner: W, level: Compression) -> Self {
+        let batch = CHUNK * rayon::current_num_threads();
+        ChunkedDeflate {
+            inner,
+            level,
+            gzip: false,
+            buffer: Vec::with_capacity(batch),
+            batch,
+            crc: crc32fast::Hasher::new(),
+            size: 0,
+        }
+    }
+
+    pub fn gzip(inner: W, level: Compression) -> io::Result<Self> {
+        let mut encoder = Self::new(inner, level);
+        encoder.gzip = true;
+        // Magic, deflate, no flags, no modification time, no extra flags, unknown OS
+        encoder.inner.write_all(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255])?;
+        Ok(encoder)
+    }
+
+    fn compress_buffer(&mut self, last: bool) -> io::Result<()> {
+        let chunks: Vec<&[u8]> = self.buffer.chunks(CHUNK).collect();
+        let count = chunks.len();
+        let level = self.level;
+        let compressed: Vec<io::Result<(Vec<u8>, crc32fast::Hasher)>> = chunks
+            .par_iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut crc = crc32fast::Hasher::new();
+                crc.update(chunk);
+                Ok((deflate_chunk(chunk, level, last && i == count - 1)?, crc))
+            })
+            .collect();
+
+        for result in compressed {
+            let (data, crc) = result?;
+            self.inner.write_all(&data)?;
+            self.crc.combine(&crc);
+        }
+        // A stream still needs its final block when the input ended on a batch boundary
+        if last && count == 0 {
+            self.inner.write_all(&deflate_chunk(&[], level, true)?)?;
+        }
+        self.size += self.buffer.len() as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Ends the stream, returning the inner writer and the CRC32 of everything written.
+    pub fn finish(mut self) -> io::Result<(W, u32)> {
+        self.compress_buffer(true)?;
+        let crc = self.crc.clone().finalize();
+        if self.gzip {
+            self.inner.write_all(&crc.to_le_bytes())?;
+            // The size is stored modulo 2^32
+            self.inner.write_all(&(self.size as u32).to_le_bytes())?;
+        }
+        Ok((self.inner, crc))
+    }
+}
+
+impl<W: Write> Write for ChunkedDeflate<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.batch - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == self.batch {
+            self.compress_buffer(false)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Raw deflate of one chunk, ending on a byte boundary with a sync flush, or with the final block if `last`.
+fn deflate_chunk(chunk: &[u8], level: Compression, last: bool) -> io::Result<Vec<u8>> {
+    let mut compress = Compress::new(level, false);
+    let flush = if last { FlushCompress::Finish } else { FlushCompress::Sync };
+    let mut out = Vec::with_capacity(chunk.len() / 2 + 64);
+    loop {
+        let status = compress
+            .compress_vec(&chunk[compress.total_in() as usize..], &mut out, flush)
+            .map_err(io::Error::other)?;
+        // A sync flush is complete once all input is in and there was room to spare for the output
+        let done = match status {
+            Status::StreamEnd => true,
+            _ => !last && compress.total_in() as usize == chunk.len() && out.len() < out.capacity(),
+        };
+        if done {
+            return Ok(out);
+        }
+        out.reserve(out.capacity().max(4096));
+    }
+}
+
+/// Where an entry is compressed before it's copied into the archive: memory for small entries, a temporary
+/// file next to the archive for large ones. The file is removed when the spool is dropped.
+pub enum Spool {
+    Memory(Cursor<Vec<u8>>),
+    File(File, PathBuf),
+}
+
+static SPOOL_FILES: AtomicUsize = AtomicUsize::new(0);
+
+impl Spool {
+    pub fn new(dir: &Path, size: u64) -> io::Result<Self> {
+        if size <= SPOOL_IN_MEMORY {
+            return Ok(Spool::Memory(Cursor::new(Vec::new())));
+        }
+        let name = format!(
+            ".file-compression-{}-{}.tmp",
+            std::process::id(),
+            SPOOL_FILES.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = dir.join(name);
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        Ok(Spool::File(file, path))
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if let Spool::File(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Read for Spool {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Spool::Memory(cursor) => cursor.read(buf),
+            Spool::File(file, _) => file.read(buf),
+        }
+    }
+}
+
+impl Write for Spool {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Spool::Memory(cursor) => cursor.write(buf),
+            Spool::File(file, _) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Spool::Memory(cursor) => cursor.flush(),
+            Spool::File(file, _) => file.flush(),
+        }
+    }
+}
+
+impl Seek for Spool {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Spool::Memory(cursor) => cursor.seek(pos),
+            Spool::File(file, _) => file.seek(pos),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunked_deflate_is_one_stream() {
+        let data: Vec<u8> = (0..5 * CHUNK as u32 / 2).map(|i| (i % 251) as u8 ^ (i >> 12) as u8).collect();
+
+        let mut encoder = ChunkedDeflate::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let (compressed, crc) = encoder.finish().unwrap();
+        assert_eq!(crc, crc32fast::hash(&data));
+        let mut inflated = Vec::new();
+        flate2::read::DeflateDecoder::new(compressed.as_slice()).read_to_end(&mut inflated).unwrap();
+        assert!(inflated == data);
+
+        let mut encoder = ChunkedDeflate::gzip(Vec::new(), Compression::default()).unwrap();
+        encoder.write_all(&data).unwrap();
+        let (compressed, _) = encoder.finish().unwrap();
+        let mut inflated = Vec::new();
+        flate2::read::GzDecoder::new(compressed.as_slice()).read_to_end(&mut inflated).unwrap();
+        assert!(inflated == data);
+    }
+}
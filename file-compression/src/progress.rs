@@ -0,0 +1,93 @@
+use std::io::{self, IsTerminal, Read, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// How often the progress line is redrawn
+const REDRAW: Duration = Duration::from_millis(200);
+
+/// Bytes read from input files so far, shared between compression threads. Redraws a progress line on stderr
+/// when that's a terminal and reporting is on.
+pub struct Progress {
+    done: AtomicU64,
+    total: u64,
+    start: Instant,
+    last_redraw: Option<Mutex<Instant>>,
+}
+
+impl Progress {
+    pub fn new(total: u64, report: bool) -> Self {
+        let start = Instant::now();
+        Progress {
+            done: AtomicU64::new(0),
+            total,
+            start,
+            last_redraw: (report && io::stderr().is_terminal()).then(|| Mutex::new(start)),
+        }
+    }
+
+    pub fn advance(&self, bytes: u64) {
+        let done = self.done.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        // Whichever thread gets the lock redraws; the others don't wait for it
+        if let Some(Ok(mut last)) = self.last_redraw.as_ref().map(Mutex::try_lock)
+            && last.elapsed() >= REDRAW
+        {
+            *last = Instant::now();
+            eprint!("\r{}", self.line(done));
+            let _ = io::stderr().flush();
+        }
+    }
+
+    pub fn done(&self) -> u64 {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    /// Bytes per second so far.
+    pub fn throughput(&self) -> f64 {
+        self.done() as f64 / self.start.elapsed().as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Clears the progress line, if one was drawn.
+    pub fn finish(&self) {
+        if self.last_redraw.is_some() {
+            eprint!("\r{:width$}\r", "", width = self.line(self.total).len());
+        }
+    }
+
+    fn line(&self, done: u64) -> String {
+        let percent = if self.total == 0 { 100.0 } else { done as f64 * 100.0 / self.total as f64 };
+        format!(
+            "{:.1} / {:.1} MiB ({:.0}%), {:.1} MB/s",
+            done as f64 / MIB,
+            self.total as f64 / MIB,
+            percent,
+            self.throughput() / 1e6
+        )
+    }
+}
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// Counts what's read through it towards a `Progress`.
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a Progress,
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    pub fn new(inner: R, progress: &'a Progress) -> Self {
+        ProgressReader { inner, progress }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.advance(n as u64);
+        Ok(n)
+    }
+}
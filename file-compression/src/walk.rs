@@ -0,0 +1,221 @@
+use anyhow::{Context, Result, bail};
+use glob::Pattern;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink(PathBuf),
+}
+
+/// Something to put in an archive: where it is on disk and the relative, `/`-separated name it gets.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub name: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mode: u32,
+    pub modified: SystemTime,
+}
+
+/// Include and exclude globs. A pattern without `/` matches the file name anywhere in the tree,
+/// one with `/` matches the whole relative path, like `.gitignore`.
+#[derive(Default)]
+pub struct Filter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl Filter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        let compile = |patterns: &[String]| -> Result<Vec<Pattern>> {
+            patterns
+                .iter()
+                .map(|p| Pattern::new(p).with_context(|| format!("Invalid glob pattern '{}'", p)))
+                .collect()
+        };
+        Ok(Filter {
+            include: compile(include)?,
+            exclude: compile(exclude)?,
+        })
+    }
+
+    pub fn matches(pattern: &Pattern, name: &str) -> bool {
+        if pattern.as_str().contains('/') {
+            pattern.matches(name)
+        } else {
+            pattern.matches(name.rsplit('/').next().unwrap_or(name))
+        }
+    }
+
+    /// Excluded entries are skipped, and excluded directories aren't walked into.
+    pub fn excludes(&self, name: &str) -> bool {
+        self.exclude.iter().any(|p| Self::matches(p, name))
+    }
+
+    /// Includes only narrow down files; directories are walked so their files can match, and kept only if
+    /// something in them did.
+    pub fn includes_file(&self, name: &str) -> bool {
+        self.include.is_empty() || self.include.iter().any(|p| Self::matches(p, name))
+    }
+}
+
+/// Walks the inputs recursively. Each input is stored under its own name, so `-i photos` gives
+/// `photos/...` entries. Directories come before their contents and siblings are sorted, so the
+/// same tree always produces the same archive.
+pub fn collect(inputs: &[PathBuf], filter: &Filter, follow_symlinks: bool) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut names = HashSet::new();
+
+    for input in inputs {
+        let name = input
+            .file_name()
+            .and_then(|n| n.to_str())
+            .with_context(|| format!("Can't name an entry after '{}'", input.display()))?
+            .to_string();
+        let mut ancestors = Vec::new();
+        walk(input, name, filter, follow_symlinks, &mut ancestors, &mut entries)?;
+    }
+
+    for entry in &entries {
+        if !names.insert(entry.name.as_str()) {
+            bail!("Two inputs would both be stored as '{}'", entry.name);
+        }
+    }
+    Ok(entries)
+}
+
+fn walk(
+    path: &Path,
+    name: String,
+    filter: &Filter,
+    follow_symlinks: bool,
+    ancestors: &mut Vec<PathBuf>,
+    entries: &mut Vec<Entry>,
+) -> Result<()> {
+    if filter.excludes(&name) {
+        return Ok(());
+    }
+
+    let link_metadata = fs::symlink_metadata(path)
+        .with_context(|| format!("Failed to read '{}'", path.display()))?;
+    let metadata = if link_metadata.file_type().is_symlink() && follow_symlinks {
+        fs::metadata(path).with_context(|| format!("Broken symlink '{}'", path.display()))?
+    } else {
+        link_metadata
+    };
+
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_symlink() {
+        EntryKind::Symlink(fs::read_link(path)?)
+    } else if file_type.is_dir() {
+        EntryKind::Directory
+    } else if file_type.is_file() {
+        EntryKind::File
+    } else {
+        eprintln!("Skipping '{}': not a file, directory or symlink", path.display());
+        return Ok(());
+    };
+
+    if kind != EntryKind::Directory && !filter.includes_file(&name) {
+        return Ok(());
+    }
+
+    let index = entries.len();
+    entries.push(Entry {
+        path: path.to_path_buf(),
+        name: name.clone(),
+        size: if kind == EntryKind::File { metadata.len() } else { 0 },
+        kind: kind.clone(),
+        mode: mode(&metadata),
+        modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+    });
+
+    if kind == EntryKind::Directory {
+        // Followed symlinks can point back up the tree
+        let canonical = fs::canonicalize(path)?;
+        if ancestors.contains(&canonical) {
+            bail!("Symlink loop at '{}'", path.display());
+        }
+        ancestors.push(canonical);
+
+        let mut children = fs::read_dir(path)
+            .with_context(|| format!("Failed to list '{}'", path.display()))?
+            .collect::<std::io::Result<Vec<_>>>()?;
+        children.sort_by_key(|child| child.file_name());
+        for child in children {
+            let child_name = child.file_name();
+            let child_name = child_name
+                .to_str()
+                .with_context(|| format!("Non-UTF-8 file name in '{}'", path.display()))?;
+            walk(&child.path(), format!("{}/{}", name, child_name), filter, follow_symlinks, ancestors, entries)?;
+        }
+
+        ancestors.pop();
+        if !filter.include.is_empty() && entries.len() == index + 1 {
+            entries.pop();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_matches_names_and_paths() {
+        let filter = Filter::new(&["*.rs".into()], &["target".into(), "src/gen/*".into()]).unwrap();
+
+        assert!(filter.includes_file("crate/src/main.rs"));
+        assert!(!filter.includes_file("crate/README.md"));
+        assert!(filter.excludes("crate/target"));
+        assert!(filter.excludes("src/gen/out.rs"));
+        assert!(!filter.excludes("crate/src/gen/out.rs"));
+        assert!(Filter::new(&["[".into()], &[]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_collect_walks_tree_in_order() {
+        let root = std::env::temp_dir().join(format!("walk-test-{}", std::process::id()));
+        let tree = root.join("tree");
+        fs::create_dir_all(tree.join("b/empty")).unwrap();
+        fs::create_dir_all(tree.join("skip")).unwrap();
+        fs::write(tree.join("b/file.txt"), "data").unwrap();
+        fs::write(tree.join("a.txt"), "data").unwrap();
+        fs::write(tree.join("skip/c.txt"), "data").unwrap();
+        std::os::unix::fs::symlink("a.txt", tree.join("link")).unwrap();
+
+        let filter = Filter::new(&[], &["skip".into()]).unwrap();
+        let entries = collect(std::slice::from_ref(&tree), &filter, false).unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["tree", "tree/a.txt", "tree/b", "tree/b/empty", "tree/b/file.txt", "tree/link"]);
+        assert_eq!(entries[5].kind, EntryKind::Symlink("a.txt".into()));
+
+        let followed = collect(std::slice::from_ref(&tree), &filter, true).unwrap();
+        assert_eq!((followed[5].kind.clone(), followed[5].size), (EntryKind::File, 4));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,23 +1,32 @@
+use std::sync::Arc;
 use std::time::Duration;
 
 use criterion::{criterion_group, criterion_main, Criterion};
-use redis::Client;
+use redis_strategy_pattern::backends;
 use redis_strategy_pattern::cache_context::CacheContext;
 use redis_strategy_pattern::cache_strategies::CacheStrategy;
+use redis_strategy_pattern::data_source::InMemoryDataSource;
 use redis_strategy_pattern::strategies::{
+    cache_aside::CacheAsideCache,
     lazy_invalidation::LazyInvalidationCache,
     read_through::ReadThroughCache,
+    tiered::TieredCache,
+    write_behind::WriteBehindCache,
     write_through::WriteThroughCache,
 };
 
 fn benchmark_cache_strategies(c: &mut Criterion) {
-    let redis_url = "redis://127.0.0.1/";
-    let client = Client::open(redis_url).unwrap();
+    // Set REDIS_URL to benchmark against a real server instead of the in-memory backend
+    let backend = backends::from_env();
+    let source = Arc::new(InMemoryDataSource::new());
 
     let strategies: Vec<(&str, Box<dyn CacheStrategy + Send + Sync>)> = vec![
-        ("WriteThrough", Box::new(WriteThroughCache::new(client.clone()))),
-        ("ReadThrough", Box::new(ReadThroughCache::new(client.clone()))),
-        ("LazyInvalidation", Box::new(LazyInvalidationCache::new(client.clone()))),
+        ("WriteThrough", Box::new(WriteThroughCache::new(backend.clone(), source.clone()))),
+        ("ReadThrough", Box::new(ReadThroughCache::new(backend.clone(), source.clone()))),
+        ("WriteBehind", Box::new(WriteBehindCache::new(backend.clone(), source.clone()))),
+        ("LazyInvalidation", Box::new(LazyInvalidationCache::new(backend.clone()))),
+        ("Tiered", Box::new(TieredCache::new(backend.clone()))),
+        ("CacheAside", Box::new(CacheAsideCache::new(backend.clone(), source.clone()))),
 ];
 
    let keys: Vec<String> = (0..100)
@@ -31,32 +40,49 @@ fn benchmark_cache_strategies(c: &mut Criterion) {
         c.bench_function(&format!("Set with {}", name), |b| {
             b.iter(|| {
                 for key in &keys {
-                    let _ = context.set(key, "value");
+                    context.set(key, "value");
                 }
             })
         });
 
         c.bench_function(&format!("Get with {}", name), |b| {
             for key in &keys {
-                let _ = context.set(key, "value");
+                context.set(key, "value");
             }
             b.iter(|| {
                 for key in &keys {
-                    let _ = context.get(key);
+                    context.get(key);
                 }
             })
         });
 
         c.bench_function(&format!("Delete with {}", name), |b| {
             for key in &keys {
-                let _ = context.set(key, "value");
+                context.set(key, "value");
             }
             b.iter(|| {
                 for key in &keys {
-                    let _ = context.delete(key);
+                    context.delete(key);
                 }
             })
         });
+
+        // Same 100 keys as above, but one MGET/MSET/DEL (or pipeline) instead of 100 round trips
+        let key_refs: Vec<&str> = keys.iter().map(String::as_str).collect();
+
+        c.bench_function(&format!("Batched set with {}", name), |b| {
+            b.iter(|| context.set_many(keys.iter().map(|key| (key.as_str(), "value"))))
+        });
+
+        c.bench_function(&format!("Batched get with {}", name), |b| {
+            context.set_many(keys.iter().map(|key| (key.as_str(), "value")));
+            b.iter(|| context.get_many(&key_refs))
+        });
+
+        c.bench_function(&format!("Batched delete with {}", name), |b| {
+            context.set_many(keys.iter().map(|key| (key.as_str(), "value")));
+            b.iter(|| context.delete_many(&key_refs))
+        });
     }
 }
 
@@ -0,0 +1,304 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::ControlFlow,
+    sync::{
+        Mutex,
+        mpsc::{self, RecvTimeoutError, Sender},
+    },
+    time::{Duration, Instant},
+};
+
+use redis::{ErrorKind, RedisError, RedisResult};
+
+use crate::cache_backend::{CacheBackend, ListenEvent};
+
+enum Value {
+    String(String),
+    Set(HashSet<String>),
+}
+
+struct Entry {
+    value: Value,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn is_live(&self, now: Instant) -> bool {
+        self.expires_at.is_none_or(|at| at > now)
+    }
+}
+
+fn wrong_type() -> RedisError {
+    RedisError::from((ErrorKind::TypeError, "WRONGTYPE Operation against a key holding the wrong kind of value"))
+}
+
+/// Keeps everything in process with Redis' GET/SET/DEL/EXPIRE, set and pub/sub semantics,
+/// so strategies can run in tests and benches without a server.
+#[derive(Default)]
+pub struct InMemoryBackend {
+    entries: Mutex<HashMap<String, Entry>>,
+    channels: Mutex<HashMap<String, Vec<Sender<String>>>>,
+}
+
+impl InMemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_live_entry<T>(&self, key: &str, f: impl FnOnce(Option<&mut Entry>) -> T) -> T {
+        let mut entries = self.entries.lock().unwrap();
+        // Expired keys are dropped lazily, the way Redis does on access
+        if entries.get(key).is_some_and(|entry| !entry.is_live(Instant::now())) {
+            entries.remove(key);
+        }
+        f(entries.get_mut(key))
+    }
+}
+
+impl CacheBackend for InMemoryBackend {
+    fn get(&self, key: &str) -> RedisResult<Option<String>> {
+        self.with_live_entry(key, |entry| match entry.map(|entry| &entry.value) {
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            Some(Value::Set(_)) => Err(wrong_type()),
+            None => Ok(None),
+        })
+    }
+
+    fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> RedisResult<()> {
+        let expires_at = ttl.map(|ttl| Instant::now() + ttl);
+        self.entries.lock().unwrap().insert(key.to_string(), Entry { value: Value::String(value.to_string()), expires_at });
+        Ok(())
+    }
+
+    fn set_nx(&self, key: &str, value: &str, ttl: Duration) -> RedisResult<bool> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        if entries.get(key).is_some_and(|entry| entry.is_live(now)) {
+            return Ok(false);
+        }
+        entries.insert(key.to_string(), Entry { value: Value::String(value.to_string()), expires_at: Some(now + ttl) });
+        Ok(true)
+    }
+
+    fn del(&self, key: &str) -> RedisResult<bool> {
+        let existed = self.with_live_entry(key, |entry| entry.is_some());
+        self.entries.lock().unwrap().remove(key);
+        Ok(existed)
+    }
+
+    fn expire(&self, key: &str, ttl: Duration) -> RedisResult<bool> {
+        Ok(self.with_live_entry(key, |entry| match entry {
+            Some(entry) => {
+                entry.expires_at = Some(Instant::now() + ttl);
+                true
+            }
+            None => false,
+        }))
+    }
+
+    fn ttl(&self, key: &str) -> RedisResult<Option<Duration>> {
+        Ok(self.with_live_entry(key, |entry| {
+            let expires_at = entry?.expires_at?;
+            Some(expires_at.saturating_duration_since(Instant::now()))
+        }))
+    }
+
+    fn sadd(&self, key: &str, members: &[&str]) -> RedisResult<usize> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.get(key).is_some_and(|entry| !entry.is_live(Instant::now())) {
+            entries.remove(key);
+        }
+        let entry =
+            entries.entry(key.to_string()).or_insert_with(|| Entry { value: Value::Set(HashSet::new()), expires_at: None });
+        let Value::Set(set) = &mut entry.value else {
+            return Err(wrong_type());
+        };
+        Ok(members.iter().filter(|member| set.insert(member.to_string())).count())
+    }
+
+    fn smembers(&self, key: &str) -> RedisResult<Vec<String>> {
+        self.with_live_entry(key, |entry| match entry.map(|entry| &entry.value) {
+            Some(Value::Set(set)) => Ok(set.iter().cloned().collect()),
+            Some(Value::String(_)) => Err(wrong_type()),
+            None => Ok(Vec::new()),
+        })
+    }
+
+    fn srem(&self, key: &str, members: &[&str]) -> RedisResult<usize> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.get(key).is_some_and(|entry| !entry.is_live(Instant::now())) {
+            entries.remove(key);
+        }
+        let Some(entry) = entries.get_mut(key) else {
+            return Ok(0);
+        };
+        let Value::Set(set) = &mut entry.value else {
+            return Err(wrong_type());
+        };
+        let removed = members.iter().filter(|member| set.remove(**member)).count();
+        if set.is_empty() {
+            entries.remove(key);
+        }
+        Ok(removed)
+    }
+
+    fn publish(&self, channel: &str, message: &str) -> RedisResult<()> {
+        if let Some(subscribers) = self.channels.lock().unwrap().get_mut(channel) {
+            // Listeners that stopped have dropped their receiver
+            subscribers.retain(|subscriber| subscriber.send(message.to_string()).is_ok());
+        }
+        Ok(())
+    }
+
+    // One lock for the whole batch, so readers never see half of it, like MSET
+    fn set_many(&self, entries: &[(&str, &str, Option<Duration>)]) -> RedisResult<()> {
+        let now = Instant::now();
+        let mut map = self.entries.lock().unwrap();
+        for (key, value, ttl) in entries {
+            let value = Value::String(value.to_string());
+            map.insert(key.to_string(), Entry { value, expires_at: ttl.map(|ttl| now + ttl) });
+        }
+        Ok(())
+    }
+
+    fn listen(
+        &self,
+        channel: &str,
+        poll_interval: Duration,
+        on_event: &mut dyn FnMut(ListenEvent<'_>) -> ControlFlow<()>,
+    ) -> RedisResult<()> {
+        let (sender, receiver) = mpsc::channel();
+        self.channels.lock().unwrap().entry(channel.to_string()).or_default().push(sender);
+
+        if on_event(ListenEvent::Subscribed).is_break() {
+            return Ok(());
+        }
+        loop {
+            let flow = match receiver.recv_timeout(poll_interval) {
+                Ok(message) => on_event(ListenEvent::Message(&message)),
+                Err(RecvTimeoutError::Timeout) => on_event(ListenEvent::Idle),
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            };
+            if flow.is_break() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    #[test]
+    fn test_batch_operations() {
+        let backend = InMemoryBackend::new();
+
+        backend.set_many(&[("a", "1", None), ("b", "2", Some(Duration::from_secs(60)))]).unwrap();
+        assert_eq!(backend.mget(&["a", "missing", "b"]).unwrap(), vec![Some("1".to_string()), None, Some("2".to_string())]);
+        assert!(backend.ttl("b").unwrap().is_some());
+
+        assert_eq!(backend.del_many(&["a", "b", "missing"]).unwrap(), 2);
+        assert_eq!(backend.mget(&["a", "b"]).unwrap(), vec![None, None]);
+    }
+
+    #[test]
+    fn test_sets_share_the_keyspace() {
+        let backend = InMemoryBackend::new();
+
+        assert_eq!(backend.sadd("tag:a", &["k1", "k2", "k1"]).unwrap(), 2);
+        let mut members = backend.smembers("tag:a").unwrap();
+        members.sort();
+        assert_eq!(members, vec!["k1".to_string(), "k2".to_string()]);
+        assert!(backend.get("tag:a").is_err());
+
+        assert!(backend.del("tag:a").unwrap());
+        assert!(backend.smembers("tag:a").unwrap().is_empty());
+
+        backend.sadd("tag:b", &["k1", "k2"]).unwrap();
+        assert_eq!(backend.srem("tag:b", &["k1", "k3"]).unwrap(), 1);
+        assert_eq!(backend.smembers("tag:b").unwrap(), vec!["k2".to_string()]);
+        assert_eq!(backend.srem("tag:b", &["k2"]).unwrap(), 1);
+        assert!(!backend.del("tag:b").unwrap());
+    }
+
+    #[test]
+    fn test_get_set_del() {
+        let backend = InMemoryBackend::new();
+
+        backend.set("user_1", "alice", None).unwrap();
+        assert_eq!(backend.get("user_1").unwrap(), Some("alice".to_string()));
+
+        assert!(backend.del("user_1").unwrap());
+        assert!(!backend.del("user_1").unwrap());
+        assert_eq!(backend.get("user_1").unwrap(), None);
+    }
+
+    #[test]
+    fn test_keys_expire() {
+        let backend = InMemoryBackend::new();
+
+        backend.set("user_1", "alice", Some(Duration::from_millis(20))).unwrap();
+        assert!(backend.ttl("user_1").unwrap().is_some());
+
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(backend.get("user_1").unwrap(), None);
+        assert_eq!(backend.ttl("user_1").unwrap(), None);
+    }
+
+    #[test]
+    fn test_expire_only_applies_to_existing_keys() {
+        let backend = InMemoryBackend::new();
+        backend.set("user_1", "alice", None).unwrap();
+
+        assert_eq!(backend.ttl("user_1").unwrap(), None);
+        assert!(backend.expire("user_1", Duration::from_secs(10)).unwrap());
+        assert!(backend.ttl("user_1").unwrap().unwrap() > Duration::from_secs(9));
+        assert!(!backend.expire("user_2", Duration::from_secs(10)).unwrap());
+    }
+
+    #[test]
+    fn test_set_nx_respects_live_keys_only() {
+        let backend = InMemoryBackend::new();
+
+        assert!(backend.set_nx("lock", "a", Duration::from_millis(20)).unwrap());
+        assert!(!backend.set_nx("lock", "b", Duration::from_millis(20)).unwrap());
+
+        thread::sleep(Duration::from_millis(40));
+        assert!(backend.set_nx("lock", "b", Duration::from_millis(20)).unwrap());
+        assert_eq!(backend.get("lock").unwrap(), Some("b".to_string()));
+    }
+
+    #[test]
+    fn test_published_messages_reach_listeners() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        let listener = {
+            let backend = backend.clone();
+            thread::spawn(move || {
+                let mut received = Vec::new();
+                backend
+                    .listen("events", Duration::from_millis(10), &mut |event| {
+                        match event {
+                            ListenEvent::Subscribed => ready_tx.send(()).unwrap(),
+                            ListenEvent::Message(message) => received.push(message.to_string()),
+                            ListenEvent::Idle => {}
+                        }
+                        if received.len() == 2 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+                    })
+                    .unwrap();
+                received
+            })
+        };
+
+        ready_rx.recv().unwrap();
+        backend.publish("events", "first").unwrap();
+        backend.publish("other", "ignored").unwrap();
+        backend.publish("events", "second").unwrap();
+
+        assert_eq!(listener.join().unwrap(), vec!["first".to_string(), "second".to_string()]);
+    }
+}
@@ -0,0 +1,14 @@
+pub mod in_memory;
+pub mod redis_backend;
+
+use std::sync::Arc;
+
+use crate::cache_backend::CacheBackend;
+
+/// Uses the Redis server at `REDIS_URL` when it is set, otherwise keeps everything in process.
+pub fn from_env() -> Arc<dyn CacheBackend + Send + Sync> {
+    match std::env::var("REDIS_URL") {
+        Ok(url) => Arc::new(redis_backend::RedisBackend::open(&url).expect("Failed to create Redis client")),
+        Err(_) => Arc::new(in_memory::InMemoryBackend::new()),
+    }
+}
@@ -0,0 +1,161 @@
+use std::{ops::ControlFlow, time::Duration};
+
+use redis::{Client, RedisResult};
+
+use crate::cache_backend::{CacheBackend, ListenEvent};
+
+pub struct RedisBackend {
+    redis: Client,
+}
+
+impl RedisBackend {
+    pub fn new(client: Client) -> Self {
+        Self { redis: client }
+    }
+
+    pub fn open(redis_url: &str) -> RedisResult<Self> {
+        Ok(Self::new(Client::open(redis_url)?))
+    }
+}
+
+// Redis rejects PX 0, so round sub-millisecond TTLs up
+fn millis(ttl: Duration) -> u64 {
+    (ttl.as_millis() as u64).max(1)
+}
+
+impl CacheBackend for RedisBackend {
+    fn get(&self, key: &str) -> RedisResult<Option<String>> {
+        let mut con = self.redis.get_connection()?;
+        redis::cmd("GET").arg(key).query(&mut con)
+    }
+
+    fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> RedisResult<()> {
+        let mut con = self.redis.get_connection()?;
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(key).arg(value);
+        if let Some(ttl) = ttl {
+            cmd.arg("PX").arg(millis(ttl));
+        }
+        cmd.query(&mut con)
+    }
+
+    fn set_nx(&self, key: &str, value: &str, ttl: Duration) -> RedisResult<bool> {
+        let mut con = self.redis.get_connection()?;
+        let written: Option<String> =
+            redis::cmd("SET").arg(key).arg(value).arg("NX").arg("PX").arg(millis(ttl)).query(&mut con)?;
+        Ok(written.is_some())
+    }
+
+    fn del(&self, key: &str) -> RedisResult<bool> {
+        let mut con = self.redis.get_connection()?;
+        let removed: i64 = redis::cmd("DEL").arg(key).query(&mut con)?;
+        Ok(removed > 0)
+    }
+
+    fn expire(&self, key: &str, ttl: Duration) -> RedisResult<bool> {
+        let mut con = self.redis.get_connection()?;
+        let updated: i64 = redis::cmd("PEXPIRE").arg(key).arg(millis(ttl)).query(&mut con)?;
+        Ok(updated == 1)
+    }
+
+    fn ttl(&self, key: &str) -> RedisResult<Option<Duration>> {
+        let mut con = self.redis.get_connection()?;
+        // -2 means the key is missing, -1 that it has no expiry
+        let remaining: i64 = redis::cmd("PTTL").arg(key).query(&mut con)?;
+        Ok((remaining >= 0).then(|| Duration::from_millis(remaining as u64)))
+    }
+
+    fn publish(&self, channel: &str, message: &str) -> RedisResult<()> {
+        let mut con = self.redis.get_connection()?;
+        redis::cmd("PUBLISH").arg(channel).arg(message).query(&mut con)
+    }
+
+    fn sadd(&self, key: &str, members: &[&str]) -> RedisResult<usize> {
+        let mut con = self.redis.get_connection()?;
+        redis::cmd("SADD").arg(key).arg(members).query(&mut con)
+    }
+
+    fn smembers(&self, key: &str) -> RedisResult<Vec<String>> {
+        let mut con = self.redis.get_connection()?;
+        redis::cmd("SMEMBERS").arg(key).query(&mut con)
+    }
+
+    fn srem(&self, key: &str, members: &[&str]) -> RedisResult<usize> {
+        if members.is_empty() {
+            return Ok(0);
+        }
+        let mut con = self.redis.get_connection()?;
+        redis::cmd("SREM").arg(key).arg(members).query(&mut con)
+    }
+
+    fn mget(&self, keys: &[&str]) -> RedisResult<Vec<Option<String>>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut con = self.redis.get_connection()?;
+        redis::cmd("MGET").arg(keys).query(&mut con)
+    }
+
+    fn set_many(&self, entries: &[(&str, &str, Option<Duration>)]) -> RedisResult<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let mut con = self.redis.get_connection()?;
+
+        // MSET can't carry expiries, so entries with a TTL go through a pipeline instead
+        if entries.iter().all(|(_, _, ttl)| ttl.is_none()) {
+            let mut cmd = redis::cmd("MSET");
+            for (key, value, _) in entries {
+                cmd.arg(key).arg(value);
+            }
+            return cmd.query(&mut con);
+        }
+
+        let mut pipe = redis::pipe();
+        for (key, value, ttl) in entries {
+            pipe.cmd("SET").arg(key).arg(value);
+            if let Some(ttl) = ttl {
+                pipe.arg("PX").arg(millis(*ttl));
+            }
+            pipe.ignore();
+        }
+        pipe.query(&mut con)
+    }
+
+    fn del_many(&self, keys: &[&str]) -> RedisResult<usize> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        let mut con = self.redis.get_connection()?;
+        redis::cmd("DEL").arg(keys).query(&mut con)
+    }
+
+    fn listen(
+        &self,
+        channel: &str,
+        poll_interval: Duration,
+        on_event: &mut dyn FnMut(ListenEvent<'_>) -> ControlFlow<()>,
+    ) -> RedisResult<()> {
+        let mut con = self.redis.get_connection()?;
+        con.set_read_timeout(Some(poll_interval))?;
+        let mut pubsub = con.as_pubsub();
+        pubsub.subscribe(channel)?;
+
+        if on_event(ListenEvent::Subscribed).is_break() {
+            return Ok(());
+        }
+        loop {
+            let flow = match pubsub.get_message() {
+                Ok(msg) => {
+                    let payload: String = msg.get_payload()?;
+                    on_event(ListenEvent::Message(&payload))
+                }
+                Err(e) if e.is_timeout() => on_event(ListenEvent::Idle),
+                Err(e) => return Err(e),
+            };
+            if flow.is_break() {
+                return Ok(());
+            }
+        }
+    }
+}
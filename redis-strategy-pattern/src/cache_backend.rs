@@ -0,0 +1,67 @@
+use std::{ops::ControlFlow, time::Duration};
+
+use redis::RedisResult;
+
+pub enum ListenEvent<'a> {
+    /// The subscription is active; anything published from now on is delivered
+    Subscribed,
+    Message(&'a str),
+    /// Nothing arrived within the poll interval
+    Idle,
+}
+
+/// The handful of Redis commands the strategies are built on. Each method behaves like
+/// the Redis command it is named after, so any implementation can stand in for a server.
+pub trait CacheBackend {
+    fn get(&self, key: &str) -> RedisResult<Option<String>>;
+    /// `SET key value [PX ttl]`
+    fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> RedisResult<()>;
+    /// `SET key value NX PX ttl`; returns whether the key was written
+    fn set_nx(&self, key: &str, value: &str, ttl: Duration) -> RedisResult<bool>;
+    /// Returns whether the key existed
+    fn del(&self, key: &str) -> RedisResult<bool>;
+    /// `PEXPIRE`; returns false if the key doesn't exist
+    fn expire(&self, key: &str, ttl: Duration) -> RedisResult<bool>;
+    /// `PTTL`; `None` for missing keys and keys that never expire
+    fn ttl(&self, key: &str) -> RedisResult<Option<Duration>>;
+    fn publish(&self, channel: &str, message: &str) -> RedisResult<()>;
+
+    /// `SADD`; returns how many members were new
+    fn sadd(&self, key: &str, members: &[&str]) -> RedisResult<usize>;
+
+    /// `SMEMBERS`; empty when the set doesn't exist
+    fn smembers(&self, key: &str) -> RedisResult<Vec<String>>;
+
+    /// `SREM`; returns how many members were removed. The set goes away with its last member.
+    fn srem(&self, key: &str, members: &[&str]) -> RedisResult<usize>;
+
+    /// `MGET`; the result lines up with `keys`
+    fn mget(&self, keys: &[&str]) -> RedisResult<Vec<Option<String>>> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    /// `MSET`, or a pipeline of `SET ... PX` when entries carry a TTL
+    fn set_many(&self, entries: &[(&str, &str, Option<Duration>)]) -> RedisResult<()> {
+        for (key, value, ttl) in entries {
+            self.set(key, value, *ttl)?;
+        }
+        Ok(())
+    }
+
+    /// `DEL key [key ...]`; returns how many keys existed
+    fn del_many(&self, keys: &[&str]) -> RedisResult<usize> {
+        let mut removed = 0;
+        for key in keys {
+            removed += self.del(key)? as usize;
+        }
+        Ok(removed)
+    }
+    /// Subscribes to `channel` and hands every event to `on_event` until it breaks.
+    /// `Idle` fires after each quiet `poll_interval`, so callers get a chance to stop.
+    fn listen(
+        &self,
+        channel: &str,
+        poll_interval: Duration,
+        on_event: &mut dyn FnMut(ListenEvent<'_>) -> ControlFlow<()>,
+    ) -> RedisResult<()>;
+}
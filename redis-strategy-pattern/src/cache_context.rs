@@ -1,23 +1,224 @@
-use crate::cache_strategies::CacheStrategy;
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-pub struct CacheContext {
+use arc_swap::ArcSwap;
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    cache_strategies::CacheStrategy,
+    codecs::Codec,
+    metrics::{CacheMetrics, Operation, StrategyMetrics},
+    typed_cache::TypedCache,
+};
+
+struct ActiveStrategy {
     strategy: Box<dyn CacheStrategy + Send + Sync>,
+    metrics: Arc<StrategyMetrics>,
+}
+
+impl ActiveStrategy {
+    fn new(strategy: Box<dyn CacheStrategy + Send + Sync>, metrics: &CacheMetrics) -> Self {
+        let metrics = metrics.activate(strategy.name());
+        Self { strategy, metrics }
+    }
+}
+
+/// Entry point for callers. Every call is timed and counted against the strategy that served it,
+/// and the strategy can be replaced while other threads are using the context.
+pub struct CacheContext {
+    active: ArcSwap<ActiveStrategy>,
+    metrics: CacheMetrics,
 }
 
 impl CacheContext {
     pub fn new(strategy: Box<dyn CacheStrategy + Send + Sync>) -> Self {
-        CacheContext { strategy }
+        let metrics = CacheMetrics::default();
+        let active = ActiveStrategy::new(strategy, &metrics);
+        CacheContext { active: ArcSwap::from_pointee(active), metrics }
+    }
+
+    /// Routes every later call to `strategy`. Calls already running finish on the old strategy,
+    /// which is dropped (flushing whatever it buffered) once the last of them returns.
+    pub fn swap_strategy(&self, strategy: Box<dyn CacheStrategy + Send + Sync>) {
+        self.active.store(Arc::new(ActiveStrategy::new(strategy, &self.metrics)));
+    }
+
+    /// Name of the strategy currently serving requests, as used in the metric labels.
+    pub fn strategy_name(&self) -> String {
+        self.active.load().strategy.name().to_string()
+    }
+
+    pub fn metrics(&self) -> &CacheMetrics {
+        &self.metrics
     }
 
     pub fn get(&self, key: &str) -> Option<String> {
-        self.strategy.get(key)
+        self.observe(Operation::Get, |active| {
+            active.metrics.record_key(key);
+            let value = active.strategy.get(key);
+            active.metrics.record_lookup(value.is_some());
+            value
+        })
     }
 
     pub fn set(&self, key: &str, value: impl Into<String>) {
-        self.strategy.set(key, value.into());
+        let value = value.into();
+        self.observe(Operation::Set, |active| {
+            active.metrics.record_key(key);
+            active.metrics.record_value(&value);
+            active.strategy.set(key, value);
+        })
+    }
+
+    pub fn set_with_ttl(&self, key: &str, value: impl Into<String>, ttl: Duration) {
+        let value = value.into();
+        self.observe(Operation::Set, |active| {
+            active.metrics.record_key(key);
+            active.metrics.record_value(&value);
+            active.strategy.set_with_ttl(key, value, ttl);
+        })
     }
 
     pub fn delete(&self, key: &str) {
-        self.strategy.delete(key);
+        self.observe(Operation::Delete, |active| {
+            active.metrics.record_key(key);
+            active.strategy.delete(key);
+        })
+    }
+
+    /// Looks up every key in one batch; the result lines up with `keys`.
+    pub fn get_many(&self, keys: &[&str]) -> Vec<Option<String>> {
+        self.observe(Operation::GetMany, |active| {
+            keys.iter().for_each(|key| active.metrics.record_key(key));
+            let values = active.strategy.get_many(keys);
+            values.iter().for_each(|value| active.metrics.record_lookup(value.is_some()));
+            values
+        })
+    }
+
+    pub fn set_many<K: Into<String>, V: Into<String>>(&self, entries: impl IntoIterator<Item = (K, V)>) {
+        let entries: Vec<(String, String)> = entries.into_iter().map(|(key, value)| (key.into(), value.into())).collect();
+        self.observe(Operation::SetMany, |active| {
+            for (key, value) in &entries {
+                active.metrics.record_key(key);
+                active.metrics.record_value(value);
+            }
+            active.strategy.set_many(entries);
+        })
+    }
+
+    pub fn delete_many(&self, keys: &[&str]) {
+        self.observe(Operation::DeleteMany, |active| {
+            keys.iter().for_each(|key| active.metrics.record_key(key));
+            active.strategy.delete_many(keys);
+        })
+    }
+
+    /// Views this context as a cache of `T` values encoded with `codec`.
+    pub fn typed<T: Serialize + DeserializeOwned, C: Codec>(&self, codec: C) -> TypedCache<'_, T, C> {
+        TypedCache::new(self, codec)
+    }
+
+    fn observe<T>(&self, operation: Operation, f: impl FnOnce(&ActiveStrategy) -> T) -> T {
+        // Hold our own reference so a concurrent swap can't drop the strategy mid-call
+        let active = self.active.load_full();
+        let started = Instant::now();
+        // Strategies report backend failures by panicking; count them before passing the panic on
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&active)));
+        active.metrics.record_latency(operation, started.elapsed());
+
+        result.unwrap_or_else(|payload| {
+            active.metrics.record_error(operation);
+            panic::resume_unwind(payload)
+        })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backends::in_memory::InMemoryBackend, metrics::MetricsSnapshot, strategies::tiered::TieredCache,
+        test_support::MapCache,
+    };
+
+    /// Fails every call, like a strategy whose backend went away.
+    struct BrokenCache;
+
+    impl CacheStrategy for BrokenCache {
+        fn get(&self, _key: &str) -> Option<String> {
+            panic!("Failed to get value from cache")
+        }
+
+        fn set(&self, _key: &str, _value: String) {
+            panic!("Failed to set value in cache")
+        }
+
+        fn set_with_ttl(&self, _key: &str, _value: String, _ttl: Duration) {
+            panic!("Failed to set value in cache")
+        }
+
+        fn delete(&self, _key: &str) {
+            panic!("Failed to delete key in cache")
+        }
+    }
+
+    #[test]
+    fn test_swap_routes_later_calls_to_new_strategy() {
+        let context = CacheContext::new(Box::new(MapCache::default()));
+        context.set("user_1", "alice");
+        assert_eq!(context.strategy_name(), "MapCache");
+
+        context.swap_strategy(Box::new(TieredCache::new(Arc::new(InMemoryBackend::new()))));
+
+        assert_eq!(context.strategy_name(), "TieredCache");
+        assert_eq!(context.get("user_1"), None);
+        assert_eq!(context.metrics().snapshot("MapCache"), Some(metrics(0, 0, 0, 1)));
+        assert_eq!(context.metrics().snapshot("TieredCache"), Some(metrics(0, 1, 0, 1)));
+    }
+
+    #[test]
+    fn test_hits_and_misses_are_counted_per_key() {
+        let context = CacheContext::new(Box::new(MapCache::default()));
+        context.set_many([("a", "1"), ("b", "2")]);
+
+        context.get("a");
+        context.get_many(&["a", "b", "c"]);
+
+        assert_eq!(context.metrics().snapshot("MapCache"), Some(metrics(3, 1, 0, 3)));
+    }
+
+    #[test]
+    fn test_failures_are_counted_and_still_surface() {
+        let context = CacheContext::new(Box::new(BrokenCache));
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| context.get("user_1")));
+
+        assert!(result.is_err());
+        assert_eq!(context.metrics().snapshot("BrokenCache").unwrap().errors, 1);
+        assert!(context.metrics().to_prometheus().contains(
+            "cache_errors_total{strategy=\"BrokenCache\",operation=\"get\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn test_prometheus_export_includes_latency_and_sizes() {
+        let context = CacheContext::new(Box::new(MapCache::default()));
+        context.set("user_1", "x".repeat(100));
+
+        let text = context.metrics().to_prometheus();
+
+        assert!(text.contains("# TYPE cache_operation_duration_seconds histogram\n"));
+        assert!(text.contains("cache_operation_duration_seconds_count{strategy=\"MapCache\",operation=\"set\"} 1\n"));
+        assert!(text.contains("cache_key_size_bytes_bucket{strategy=\"MapCache\",le=\"16\"} 1\n"));
+        assert!(text.contains("cache_value_size_bytes_bucket{strategy=\"MapCache\",le=\"64\"} 0\n"));
+        assert!(text.contains("cache_value_size_bytes_bucket{strategy=\"MapCache\",le=\"128\"} 1\n"));
+    }
+
+    fn metrics(hits: u64, misses: u64, errors: u64, operations: u64) -> MetricsSnapshot {
+        MetricsSnapshot { hits, misses, errors, operations }
+    }
+}
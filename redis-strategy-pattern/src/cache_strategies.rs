@@ -1,5 +1,33 @@
+use std::time::Duration;
+
 pub trait CacheStrategy {
     fn get(&self, key: &str) -> Option<String>;
     fn set(&self, key: &str, value: String);
+    fn set_with_ttl(&self, key: &str, value: String, ttl: Duration);
     fn delete(&self, key: &str);
-}
\ No newline at end of file
+
+    /// Label for metrics; defaults to the type name, e.g. `ReadThroughCache`.
+    fn name(&self) -> &str {
+        let path = std::any::type_name::<Self>();
+        let path = path.split('<').next().unwrap_or(path);
+        path.rsplit("::").next().unwrap_or(path)
+    }
+
+    /// Looks up several keys at once; the result lines up with `keys`.
+    /// Strategies whose backend can batch (MGET, pipelines) should override the batch methods.
+    fn get_many(&self, keys: &[&str]) -> Vec<Option<String>> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    fn set_many(&self, entries: Vec<(String, String)>) {
+        for (key, value) in entries {
+            self.set(&key, value);
+        }
+    }
+
+    fn delete_many(&self, keys: &[&str]) {
+        for key in keys {
+            self.delete(key);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::fmt;
+
+use serde::{Serialize, de::DeserializeOwned};
+
+#[derive(Debug)]
+pub enum CodecError {
+    Encode(String),
+    Decode(String),
+    Compression(std::io::Error),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Encode(e) => write!(f, "Failed to encode value: {}", e),
+            CodecError::Decode(e) => write!(f, "Failed to decode value: {}", e),
+            CodecError::Compression(e) => write!(f, "Compression error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Turns typed values into bytes and back.
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError>;
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(value).map_err(|e| CodecError::Encode(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(bytes).map_err(|e| CodecError::Decode(e.to_string()))
+    }
+}
+
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        bincode::serialize(value).map_err(|e| CodecError::Encode(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        bincode::deserialize(bytes).map_err(|e| CodecError::Decode(e.to_string()))
+    }
+}
+
+pub struct MessagePackCodec;
+
+impl Codec for MessagePackCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        // Named fields keep the payload readable by other MessagePack clients
+        rmp_serde::to_vec_named(value).map_err(|e| CodecError::Encode(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        rmp_serde::from_slice(bytes).map_err(|e| CodecError::Decode(e.to_string()))
+    }
+}
@@ -0,0 +1,49 @@
+use std::{collections::HashMap, sync::Mutex};
+
+/// The primary store that sits behind the cache, e.g. a database table.
+pub trait DataSource {
+    fn load(&self, key: &str) -> Option<String>;
+    fn store(&self, key: &str, value: &str);
+    fn remove(&self, key: &str);
+
+    /// Writes several records in one go. Sources that support bulk writes should override this.
+    fn store_many(&self, entries: &[(String, String)]) {
+        for (key, value) in entries {
+            self.store(key, value);
+        }
+    }
+}
+
+/// A `DataSource` backed by a `HashMap`, handy for examples and tests.
+#[derive(Default)]
+pub struct InMemoryDataSource {
+    records: Mutex<HashMap<String, String>>,
+}
+
+impl InMemoryDataSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl DataSource for InMemoryDataSource {
+    fn load(&self, key: &str) -> Option<String> {
+        self.records.lock().unwrap().get(key).cloned()
+    }
+
+    fn store(&self, key: &str, value: &str) {
+        self.records.lock().unwrap().insert(key.to_string(), value.to_string());
+    }
+
+    fn remove(&self, key: &str) {
+        self.records.lock().unwrap().remove(key);
+    }
+}
@@ -1,3 +1,13 @@
+pub mod backends;
+pub mod cache_backend;
 pub mod cache_context;
 pub mod cache_strategies;
+pub mod codecs;
+pub mod data_source;
+pub mod metrics;
 pub mod strategies;
+pub mod ttl;
+pub mod typed_cache;
+
+#[cfg(test)]
+mod test_support;
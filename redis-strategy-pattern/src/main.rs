@@ -1,20 +1,25 @@
-use redis::Client;
+use std::sync::Arc;
 
-use crate::{cache_context::CacheContext, strategies::write_through::WriteThroughCache};
-
-pub mod cache_context;
-pub mod cache_strategies;
-pub mod strategies;
+use redis_strategy_pattern::{
+    backends, cache_context::CacheContext, data_source::InMemoryDataSource, strategies::{tiered::TieredCache, write_through::WriteThroughCache},
+};
 
 fn main() {
-    let redis_url = "redis://127.0.0.1/";
-    let client = Client::open(redis_url).expect("Failed to create Redis client");
+    // Set REDIS_URL (e.g. redis://127.0.0.1/) to run against a real server
+    let backend = backends::from_env();
+    let source = Arc::new(InMemoryDataSource::new());
 
-    let strategy = WriteThroughCache::new(client.clone());
+    let strategy = WriteThroughCache::new(backend.clone(), source);
     let context = CacheContext::new(Box::new(strategy));
 
     context.set("user_123", "elizielx");
 
     let value = context.get("user_123");
     println!("Cached value: {:?}", value);
+
+    // Strategies can be swapped while the context is in use; metrics are kept per strategy
+    context.swap_strategy(Box::new(TieredCache::new(backend)));
+    println!("Cached value via {}: {:?}", context.strategy_name(), context.get("user_123"));
+
+    print!("{}", context.metrics().to_prometheus());
 }
@@ -0,0 +1,303 @@
+use std::{
+    fmt::Write,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+/// Upper bounds, in seconds, of the latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 12] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0];
+
+/// Upper bounds, in bytes, of the key and value size histogram buckets.
+const SIZE_BUCKETS: [f64; 10] = [16.0, 32.0, 64.0, 128.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 1048576.0];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Get,
+    GetMany,
+    Set,
+    SetMany,
+    Delete,
+    DeleteMany,
+}
+
+impl Operation {
+    const ALL: [Operation; 6] = [
+        Operation::Get,
+        Operation::GetMany,
+        Operation::Set,
+        Operation::SetMany,
+        Operation::Delete,
+        Operation::DeleteMany,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Operation::Get => "get",
+            Operation::GetMany => "get_many",
+            Operation::Set => "set",
+            Operation::SetMany => "set_many",
+            Operation::Delete => "delete",
+            Operation::DeleteMany => "delete_many",
+        }
+    }
+}
+
+/// A Prometheus-style cumulative histogram with fixed bucket bounds.
+struct Histogram {
+    bounds: &'static [f64],
+    // One count per bound; the implicit +Inf bucket is `count`
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    // Stored as f64 bits so the sum can be kept without a lock
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|&bound| value <= bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let _ = self.sum.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some((f64::from_bits(bits) + value).to_bits())
+        });
+    }
+
+    fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, cumulative);
+        }
+        let count = self.count();
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, f64::from_bits(self.sum.load(Ordering::Relaxed)));
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+struct OperationMetrics {
+    latency: Histogram,
+    errors: AtomicU64,
+}
+
+/// Everything recorded while one strategy was serving requests.
+pub(crate) struct StrategyMetrics {
+    strategy: String,
+    operations: Vec<OperationMetrics>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    key_sizes: Histogram,
+    value_sizes: Histogram,
+}
+
+impl StrategyMetrics {
+    fn new(strategy: &str) -> Self {
+        Self {
+            strategy: strategy.to_string(),
+            operations: Operation::ALL
+                .iter()
+                .map(|_| OperationMetrics { latency: Histogram::new(&LATENCY_BUCKETS), errors: AtomicU64::new(0) })
+                .collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            key_sizes: Histogram::new(&SIZE_BUCKETS),
+            value_sizes: Histogram::new(&SIZE_BUCKETS),
+        }
+    }
+
+    fn operation(&self, operation: Operation) -> &OperationMetrics {
+        &self.operations[operation as usize]
+    }
+
+    pub(crate) fn record_latency(&self, operation: Operation, elapsed: Duration) {
+        self.operation(operation).latency.observe(elapsed.as_secs_f64());
+    }
+
+    pub(crate) fn record_error(&self, operation: Operation) {
+        self.operation(operation).errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_lookup(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_key(&self, key: &str) {
+        self.key_sizes.observe(key.len() as f64);
+    }
+
+    pub(crate) fn record_value(&self, value: &str) {
+        self.value_sizes.observe(value.len() as f64);
+    }
+}
+
+/// Point-in-time counts for one strategy, mostly useful in tests and logs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub hits: u64,
+    pub misses: u64,
+    pub errors: u64,
+    pub operations: u64,
+}
+
+/// Latency, hit/miss, error and size metrics, kept per strategy so strategies that served the
+/// same context one after another can be compared. Exported in the Prometheus text format.
+#[derive(Default)]
+pub struct CacheMetrics {
+    strategies: Mutex<Vec<Arc<StrategyMetrics>>>,
+    active: Mutex<String>,
+}
+
+impl CacheMetrics {
+    /// Returns the series for `strategy`, creating them the first time it becomes active.
+    pub(crate) fn activate(&self, strategy: &str) -> Arc<StrategyMetrics> {
+        *self.active.lock().unwrap() = strategy.to_string();
+
+        let mut strategies = self.strategies.lock().unwrap();
+        if let Some(existing) = strategies.iter().find(|metrics| metrics.strategy == strategy) {
+            return existing.clone();
+        }
+        let metrics = Arc::new(StrategyMetrics::new(strategy));
+        strategies.push(metrics.clone());
+        metrics
+    }
+
+    pub fn snapshot(&self, strategy: &str) -> Option<MetricsSnapshot> {
+        let strategies = self.strategies.lock().unwrap();
+        let metrics = strategies.iter().find(|metrics| metrics.strategy == strategy)?;
+        Some(MetricsSnapshot {
+            hits: metrics.hits.load(Ordering::Relaxed),
+            misses: metrics.misses.load(Ordering::Relaxed),
+            errors: metrics.operations.iter().map(|op| op.errors.load(Ordering::Relaxed)).sum(),
+            operations: metrics.operations.iter().map(|op| op.latency.count()).sum(),
+        })
+    }
+
+    /// Renders every series in the Prometheus text exposition format, ready to serve from `/metrics`.
+    pub fn to_prometheus(&self) -> String {
+        let strategies = self.strategies.lock().unwrap();
+        let active = self.active.lock().unwrap();
+        let mut out = String::new();
+
+        header(&mut out, "cache_strategy_active", "gauge", "1 for the strategy currently serving requests.");
+        for metrics in strategies.iter() {
+            let value = u8::from(metrics.strategy == *active);
+            let _ = writeln!(out, "cache_strategy_active{{strategy=\"{}\"}} {}", escape(&metrics.strategy), value);
+        }
+
+        header(&mut out, "cache_operation_duration_seconds", "histogram", "Latency of cache operations.");
+        for metrics in strategies.iter() {
+            for operation in Operation::ALL {
+                let labels = operation_labels(&metrics.strategy, operation);
+                metrics.operation(operation).latency.render(&mut out, "cache_operation_duration_seconds", &labels);
+            }
+        }
+
+        header(&mut out, "cache_errors_total", "counter", "Cache operations that failed.");
+        for metrics in strategies.iter() {
+            for operation in Operation::ALL {
+                let errors = metrics.operation(operation).errors.load(Ordering::Relaxed);
+                let _ = writeln!(out, "cache_errors_total{{{}}} {}", operation_labels(&metrics.strategy, operation), errors);
+            }
+        }
+
+        header(&mut out, "cache_hits_total", "counter", "Keys looked up and found.");
+        for metrics in strategies.iter() {
+            let _ = writeln!(out, "cache_hits_total{{{}}} {}", strategy_labels(&metrics.strategy), metrics.hits.load(Ordering::Relaxed));
+        }
+
+        header(&mut out, "cache_misses_total", "counter", "Keys looked up and not found.");
+        for metrics in strategies.iter() {
+            let _ = writeln!(out, "cache_misses_total{{{}}} {}", strategy_labels(&metrics.strategy), metrics.misses.load(Ordering::Relaxed));
+        }
+
+        header(&mut out, "cache_key_size_bytes", "histogram", "Size of keys passed to the cache.");
+        for metrics in strategies.iter() {
+            metrics.key_sizes.render(&mut out, "cache_key_size_bytes", &strategy_labels(&metrics.strategy));
+        }
+
+        header(&mut out, "cache_value_size_bytes", "histogram", "Size of values written to the cache.");
+        for metrics in strategies.iter() {
+            metrics.value_sizes.render(&mut out, "cache_value_size_bytes", &strategy_labels(&metrics.strategy));
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn strategy_labels(strategy: &str) -> String {
+    format!("strategy=\"{}\"", escape(strategy))
+}
+
+fn operation_labels(strategy: &str, operation: Operation) -> String {
+    format!("strategy=\"{}\",operation=\"{}\"", escape(strategy), operation.as_str())
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new(&SIZE_BUCKETS);
+        histogram.observe(10.0);
+        histogram.observe(20.0);
+        histogram.observe(2_000_000.0);
+
+        let mut out = String::new();
+        histogram.render(&mut out, "sizes", "strategy=\"a\"");
+
+        assert!(out.contains("sizes_bucket{strategy=\"a\",le=\"16\"} 1\n"));
+        assert!(out.contains("sizes_bucket{strategy=\"a\",le=\"32\"} 2\n"));
+        assert!(out.contains("sizes_bucket{strategy=\"a\",le=\"1048576\"} 2\n"));
+        assert!(out.contains("sizes_bucket{strategy=\"a\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("sizes_sum{strategy=\"a\"} 2000030\n"));
+        assert!(out.contains("sizes_count{strategy=\"a\"} 3\n"));
+    }
+
+    #[test]
+    fn test_reactivating_a_strategy_keeps_its_series() {
+        let metrics = CacheMetrics::default();
+        metrics.activate("ReadThroughCache").record_lookup(true);
+        metrics.activate("TieredCache").record_lookup(false);
+        metrics.activate("ReadThroughCache").record_lookup(true);
+
+        assert_eq!(metrics.snapshot("ReadThroughCache").unwrap().hits, 2);
+        assert_eq!(metrics.snapshot("TieredCache").unwrap().misses, 1);
+
+        let text = metrics.to_prometheus();
+        assert!(text.contains("cache_strategy_active{strategy=\"ReadThroughCache\"} 1\n"));
+        assert!(text.contains("cache_strategy_active{strategy=\"TieredCache\"} 0\n"));
+    }
+
+    #[test]
+    fn test_labels_are_escaped() {
+        assert_eq!(escape("a\"b\\c"), "a\\\"b\\\\c");
+    }
+}
@@ -0,0 +1,285 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{cache_backend::CacheBackend, cache_strategies::CacheStrategy, data_source::DataSource, ttl::TtlPolicy};
+
+/// Cached in place of a value to remember that the source has no record for a key.
+/// The leading NUL keeps it from colliding with anything callers store.
+const NEGATIVE_ENTRY: &str = "\0cache-aside:missing";
+
+pub struct CacheAsideConfig {
+    /// Expiry for values loaded from the source
+    pub ttl: Option<TtlPolicy>,
+    /// How long a key the source doesn't have is remembered as missing. Keep it short:
+    /// a record created behind the cache's back stays invisible for this long.
+    pub negative_ttl: Duration,
+    /// Prefix of the Redis sets that hold the keys carrying each tag
+    pub tag_prefix: String,
+}
+
+impl Default for CacheAsideConfig {
+    fn default() -> Self {
+        Self { ttl: None, negative_ttl: Duration::from_secs(30), tag_prefix: "tag:".to_string() }
+    }
+}
+
+/// Reads check the cache and fall back to the data source, caching what they find and, for a
+/// short while, what they didn't find. Writes go to the source and evict the cached copy.
+/// Keys can be tagged when written so a whole group can be evicted with `invalidate_tag`.
+pub struct CacheAsideCache {
+    backend: Arc<dyn CacheBackend + Send + Sync>,
+    source: Arc<dyn DataSource + Send + Sync>,
+    config: CacheAsideConfig,
+}
+
+impl CacheAsideCache {
+    pub fn new(backend: Arc<dyn CacheBackend + Send + Sync>, source: Arc<dyn DataSource + Send + Sync>) -> Self {
+        Self::with_config(backend, source, CacheAsideConfig::default())
+    }
+
+    pub fn with_config(
+        backend: Arc<dyn CacheBackend + Send + Sync>,
+        source: Arc<dyn DataSource + Send + Sync>,
+        config: CacheAsideConfig,
+    ) -> Self {
+        Self { backend, source, config }
+    }
+
+    /// Like `set`, and also adds `key` to the set of every tag in `tags`.
+    pub fn set_tagged(&self, key: &str, value: String, tags: &[&str]) {
+        self.set(key, value);
+        self.tag(key, tags);
+    }
+
+    /// Like `set_with_ttl`, and also adds `key` to the set of every tag in `tags`.
+    pub fn set_with_ttl_tagged(&self, key: &str, value: String, ttl: Duration, tags: &[&str]) {
+        self.set_with_ttl(key, value, ttl);
+        self.tag(key, tags);
+    }
+
+    /// Evicts every key tagged with `tag` and takes them out of the tag. Returns how many cached entries were
+    /// removed. The data source is left alone; the next read of each key reloads it.
+    pub fn invalidate_tag(&self, tag: &str) -> usize {
+        let tag_key = self.tag_key(tag);
+        let members = self.backend.smembers(&tag_key).expect("Failed to read tag from cache");
+        let keys: Vec<&str> = members.iter().map(String::as_str).collect();
+
+        let removed = self.backend.del_many(&keys).expect("Failed to delete keys in cache");
+        // Only the members just evicted, so a key tagged in the meantime stays in the tag
+        self.backend.srem(&tag_key, &keys).expect("Failed to update tag in cache");
+        removed
+    }
+
+    /// Tag sets never expire: a key reloaded after its entry expired is cached again, and must still be
+    /// found by `invalidate_tag`. Keys leave their tags when the tag is invalidated.
+    fn tag(&self, key: &str, tags: &[&str]) {
+        for tag in tags {
+            self.backend.sadd(&self.tag_key(tag), &[key]).expect("Failed to tag key in cache");
+        }
+    }
+
+    fn tag_key(&self, tag: &str) -> String {
+        format!("{}{}", self.config.tag_prefix, tag)
+    }
+
+    /// Loads a key the cache doesn't have and caches the answer, found or not.
+    fn load(&self, key: &str) -> Option<String> {
+        let value = self.source.load(key);
+        let (cached, ttl) = self.cache_entry(value.as_deref());
+        self.backend.set(key, cached, ttl).expect("Failed to set value in cache");
+        value
+    }
+
+    fn cache_entry<'a>(&self, value: Option<&'a str>) -> (&'a str, Option<Duration>) {
+        match value {
+            Some(value) => (value, self.config.ttl.map(|policy| policy.next_ttl())),
+            None => (NEGATIVE_ENTRY, Some(self.config.negative_ttl)),
+        }
+    }
+}
+
+impl CacheStrategy for CacheAsideCache {
+    fn get(&self, key: &str) -> Option<String> {
+        match self.backend.get(key).expect("Failed to get value from cache") {
+            Some(value) if value == NEGATIVE_ENTRY => None,
+            Some(value) => Some(value),
+            None => self.load(key),
+        }
+    }
+
+    fn set(&self, key: &str, value: String) {
+        self.source.store(key, &value);
+        // Also clears a negative entry, so the new record is visible right away
+        self.backend.del(key).expect("Failed to delete key in cache");
+    }
+
+    /// Unlike `set`, an explicit TTL caches the new value right away.
+    fn set_with_ttl(&self, key: &str, value: String, ttl: Duration) {
+        self.source.store(key, &value);
+        self.backend.set(key, &value, Some(ttl)).expect("Failed to set value in cache");
+    }
+
+    fn delete(&self, key: &str) {
+        self.source.remove(key);
+        self.backend.del(key).expect("Failed to delete key in cache");
+    }
+
+    fn get_many(&self, keys: &[&str]) -> Vec<Option<String>> {
+        let cached = self.backend.mget(keys).expect("Failed to get values from cache");
+
+        let mut values = Vec::with_capacity(keys.len());
+        let mut loaded = Vec::new();
+        for (key, cached) in keys.iter().zip(cached) {
+            values.push(match cached {
+                Some(value) if value == NEGATIVE_ENTRY => None,
+                Some(value) => Some(value),
+                None => {
+                    let value = self.source.load(key);
+                    loaded.push((*key, values.len()));
+                    value
+                }
+            });
+        }
+
+        let batch: Vec<(&str, &str, Option<Duration>)> = loaded
+            .iter()
+            .map(|&(key, i)| {
+                let (cached, ttl) = self.cache_entry(values[i].as_deref());
+                (key, cached, ttl)
+            })
+            .collect();
+        self.backend.set_many(&batch).expect("Failed to set values in cache");
+        values
+    }
+
+    fn set_many(&self, entries: Vec<(String, String)>) {
+        self.source.store_many(&entries);
+        let keys: Vec<&str> = entries.iter().map(|(key, _)| key.as_str()).collect();
+        self.backend.del_many(&keys).expect("Failed to delete keys in cache");
+    }
+
+    fn delete_many(&self, keys: &[&str]) {
+        for key in keys {
+            self.source.remove(key);
+        }
+        self.backend.del_many(keys).expect("Failed to delete keys in cache");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{backends::in_memory::InMemoryBackend, data_source::InMemoryDataSource, test_support::CountingSource};
+
+    fn counting_source() -> Arc<CountingSource<InMemoryDataSource>> {
+        Arc::new(CountingSource::new(InMemoryDataSource::new()))
+    }
+
+    #[test]
+    fn test_miss_loads_from_source_once() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = counting_source();
+        source.inner.store("user_1", "alice");
+        let cache = CacheAsideCache::new(backend.clone(), source.clone());
+
+        assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+        assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+        assert_eq!(source.loads(), 1);
+    }
+
+    #[test]
+    fn test_missing_keys_are_negatively_cached() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = counting_source();
+        let cache = CacheAsideCache::new(backend.clone(), source.clone());
+
+        for _ in 0..3 {
+            assert_eq!(cache.get("user_404"), None);
+        }
+
+        assert_eq!(source.loads(), 1);
+        let ttl = backend.ttl("user_404").unwrap().unwrap();
+        assert!(ttl <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_negative_entries_expire() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = counting_source();
+        let config = CacheAsideConfig { negative_ttl: Duration::from_millis(50), ..Default::default() };
+        let cache = CacheAsideCache::with_config(backend.clone(), source.clone(), config);
+
+        assert_eq!(cache.get("user_1"), None);
+        source.inner.store("user_1", "alice");
+        assert_eq!(cache.get("user_1"), None);
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+    }
+
+    #[test]
+    fn test_set_clears_negative_entry() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let cache = CacheAsideCache::new(backend.clone(), counting_source());
+
+        assert_eq!(cache.get("user_1"), None);
+        cache.set("user_1", "alice".to_string());
+
+        assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+    }
+
+    #[test]
+    fn test_invalidate_tag_evicts_every_tagged_key() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = counting_source();
+        let cache = CacheAsideCache::new(backend.clone(), source.clone());
+
+        cache.set_tagged("user:123:profile", "alice".to_string(), &["user:123"]);
+        cache.set_tagged("user:123:settings", "dark".to_string(), &["user:123", "settings"]);
+        cache.set_tagged("user:456:profile", "bob".to_string(), &["user:456"]);
+        cache.get_many(&["user:123:profile", "user:123:settings", "user:456:profile"]);
+
+        assert_eq!(cache.invalidate_tag("user:123"), 2);
+
+        assert_eq!(backend.get("user:123:profile").unwrap(), None);
+        assert_eq!(backend.get("user:123:settings").unwrap(), None);
+        assert_eq!(backend.get("user:456:profile").unwrap(), Some("bob".to_string()));
+        assert_eq!(backend.smembers("tag:user:123").unwrap(), Vec::<String>::new());
+        assert_eq!(backend.smembers("tag:settings").unwrap(), vec!["user:123:settings".to_string()]);
+        // The source still has the records, so reads just reload them
+        assert_eq!(cache.get("user:123:profile"), Some("alice".to_string()));
+    }
+
+    #[test]
+    fn test_tags_outlive_expiring_entries() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = counting_source();
+        let config = CacheAsideConfig { ttl: Some(TtlPolicy::fixed(Duration::from_millis(50))), ..Default::default() };
+        let cache = CacheAsideCache::with_config(backend.clone(), source.clone(), config);
+
+        cache.set_tagged("user:1", "alice".to_string(), &["users"]);
+        cache.set_with_ttl_tagged("user:2", "bob".to_string(), Duration::from_millis(20), &["users"]);
+        assert_eq!(backend.ttl("tag:users").unwrap(), None);
+
+        // Reloaded long after the tagging write, and still found by the tag
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.get_many(&["user:1", "user:2"]), vec![Some("alice".to_string()), Some("bob".to_string())]);
+        source.inner.store("user:1", "carol");
+        assert_eq!(cache.invalidate_tag("users"), 2);
+        assert_eq!(cache.get("user:1"), Some("carol".to_string()));
+        assert_eq!(backend.smembers("tag:users").unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_get_many_caches_hits_and_misses_in_one_batch() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = counting_source();
+        source.inner.store("user_1", "alice");
+        let cache = CacheAsideCache::new(backend.clone(), source.clone());
+
+        assert_eq!(cache.get_many(&["user_1", "user_404"]), vec![Some("alice".to_string()), None]);
+        assert_eq!(cache.get_many(&["user_1", "user_404"]), vec![Some("alice".to_string()), None]);
+        assert_eq!(source.loads(), 2);
+    }
+}
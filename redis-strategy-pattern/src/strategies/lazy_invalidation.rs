@@ -1,42 +1,392 @@
-use std::{collections::HashMap, sync::Mutex};
+use std::{
+    collections::HashMap,
+    ops::ControlFlow,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
 
-use redis::Client;
+use crate::{
+    cache_backend::{CacheBackend, ListenEvent},
+    cache_strategies::CacheStrategy,
+    ttl::TtlPolicy,
+};
 
-use crate::cache_strategies::CacheStrategy;
+// How often the subscriber wakes up to check for shutdown and drop expired marks
+const SUBSCRIBER_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
+const INVALIDATE: &str = "invalidate";
+const REVALIDATE: &str = "revalidate";
+
+pub struct LazyInvalidationConfig {
+    /// Pub/sub channel shared by every instance that caches the same keys
+    pub channel: String,
+    /// How long an invalidation mark is kept. Invalidated keys are also expired in the cache
+    /// after this long, so no stale value outlives its mark.
+    pub mark_ttl: Duration,
+}
+
+impl Default for LazyInvalidationConfig {
+    fn default() -> Self {
+        Self { channel: "cache:invalidations".to_string(), mark_ttl: Duration::from_secs(60) }
+    }
+}
+
+/// Keys currently treated as invalid, each with the moment its mark lapses.
+#[derive(Default)]
+struct InvalidationMarks {
+    marks: Mutex<HashMap<String, Instant>>,
+}
+
+impl InvalidationMarks {
+    fn mark(&self, key: &str, ttl: Duration) {
+        self.marks.lock().unwrap().insert(key.to_string(), Instant::now() + ttl);
+    }
+
+    fn clear(&self, key: &str) {
+        self.marks.lock().unwrap().remove(key);
+    }
+
+    fn is_marked(&self, key: &str) -> bool {
+        let mut marks = self.marks.lock().unwrap();
+        match marks.get(key) {
+            Some(&until) if until > Instant::now() => true,
+            Some(_) => {
+                marks.remove(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn prune(&self) {
+        let now = Instant::now();
+        self.marks.lock().unwrap().retain(|_, until| *until > now);
+    }
+
+    fn len(&self) -> usize {
+        self.marks.lock().unwrap().len()
+    }
+}
+
+/// Listens on the invalidation channel and applies marks published by other instances.
+struct Subscriber {
+    backend: Arc<dyn CacheBackend + Send + Sync>,
+    channel: String,
+    instance_id: String,
+    mark_ttl: Duration,
+    marks: Arc<InvalidationMarks>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl Subscriber {
+    fn run(self, ready: mpsc::Sender<redis::RedisResult<()>>) {
+        let mut ready = Some(ready);
+
+        while !self.shutdown.load(Ordering::SeqCst) {
+            let result = self.listen(&mut ready);
+            if let Some(ready) = ready.take() {
+                // Never managed to subscribe; let the constructor report it
+                let _ = ready.send(result);
+                return;
+            }
+            // Invalidations published while reconnecting are lost; marks already held still apply
+            thread::sleep(SUBSCRIBER_POLL_INTERVAL);
+        }
+    }
+
+    fn listen(&self, ready: &mut Option<mpsc::Sender<redis::RedisResult<()>>>) -> redis::RedisResult<()> {
+        self.backend.listen(&self.channel, SUBSCRIBER_POLL_INTERVAL, &mut |event| {
+            match event {
+                ListenEvent::Subscribed => {
+                    if let Some(ready) = ready.take() {
+                        let _ = ready.send(Ok(()));
+                    }
+                }
+                ListenEvent::Message(payload) => self.apply(payload),
+                ListenEvent::Idle => self.marks.prune(),
+            }
+            if self.shutdown.load(Ordering::SeqCst) { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        })
+    }
+
+    fn apply(&self, payload: &str) {
+        let mut parts = payload.splitn(3, ':');
+        let (Some(op), Some(sender), Some(key)) = (parts.next(), parts.next(), parts.next()) else {
+            return;
+        };
+        // Our own changes were applied locally before they were published
+        if sender == self.instance_id {
+            return;
+        }
+
+        match op {
+            INVALIDATE => self.marks.mark(key, self.mark_ttl),
+            REVALIDATE => self.marks.clear(key),
+            _ => {}
+        }
+    }
+}
+
+/// Marks keys invalid instead of trusting what the cache returns for them. Invalidations are
+/// broadcast over pub/sub so every instance sharing the channel stops serving the key.
 pub struct LazyInvalidationCache {
-    redis: Client,
-    invalid_keys: Mutex<HashMap<String, bool>>
+    backend: Arc<dyn CacheBackend + Send + Sync>,
+    instance_id: String,
+    config: LazyInvalidationConfig,
+    marks: Arc<InvalidationMarks>,
+    default_ttl: Option<TtlPolicy>,
+    shutdown: Arc<AtomicBool>,
+    subscriber: Option<JoinHandle<()>>,
 }
 
 impl LazyInvalidationCache {
-    pub fn new(client: Client) -> Self {
-        Self { redis: client, invalid_keys: Mutex::new(HashMap::new()) }
+    pub fn new(backend: Arc<dyn CacheBackend + Send + Sync>) -> Self {
+        Self::with_config(backend, LazyInvalidationConfig::default())
+    }
+
+    pub fn with_config(backend: Arc<dyn CacheBackend + Send + Sync>, config: LazyInvalidationConfig) -> Self {
+        let instance_id = format!("{:016x}", rand::random::<u64>());
+        let marks = Arc::new(InvalidationMarks::default());
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let subscriber = Subscriber {
+            backend: backend.clone(),
+            channel: config.channel.clone(),
+            instance_id: instance_id.clone(),
+            mark_ttl: config.mark_ttl,
+            marks: marks.clone(),
+            shutdown: shutdown.clone(),
+        };
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let handle = thread::spawn(move || subscriber.run(ready_tx));
+
+        // Wait for the subscription, otherwise invalidations published right after construction are missed
+        ready_rx
+            .recv()
+            .expect("Invalidation subscriber exited early")
+            .expect("Failed to subscribe to invalidation channel");
+
+        Self { backend, instance_id, config, marks, default_ttl: None, shutdown, subscriber: Some(handle) }
+    }
+
+    /// Entries written without an explicit TTL expire according to `policy`.
+    pub fn with_default_ttl(mut self, policy: TtlPolicy) -> Self {
+        self.default_ttl = Some(policy);
+        self
+    }
+
+    /// Stops every instance from serving `key` without deleting it right away.
+    /// The cache drops the stale value once the mark lapses.
+    pub fn invalidate(&self, key: &str) {
+        self.marks.mark(key, self.config.mark_ttl);
+        self.backend.expire(key, self.config.mark_ttl).expect("Failed to set expiry in cache");
+        self.publish(INVALIDATE, key);
+    }
+
+    /// Number of keys currently marked invalid on this instance.
+    pub fn invalidated_keys(&self) -> usize {
+        self.marks.prune();
+        self.marks.len()
+    }
+
+    fn write(&self, key: &str, value: String, ttl: Option<Duration>) {
+        self.backend.set(key, &value, ttl).expect("Failed to set value in cache");
+        self.marks.clear(key);
+        self.publish(REVALIDATE, key);
+    }
+
+    fn publish(&self, op: &str, key: &str) {
+        let message = format!("{}:{}:{}", op, self.instance_id, key);
+        self.backend.publish(&self.config.channel, &message).expect("Failed to publish invalidation");
     }
 }
 
 impl CacheStrategy for LazyInvalidationCache {
     fn get(&self, key: &str) -> Option<String> {
-        let invalid = self.invalid_keys.lock().unwrap().get(key).copied().unwrap_or(false);
-        if invalid {
+        if self.marks.is_marked(key) {
             return None;
         }
 
-        let mut con = self.redis.get_connection().expect("Failed to connect to Redis");
-        let result: redis::RedisResult<String> = redis::cmd("GET").arg(key).query(&mut con);
-        result.ok()
+        self.backend.get(key).expect("Failed to get value from cache")
     }
 
     fn set(&self, key: &str, value: String) {
-        let mut con = self.redis.get_connection().expect("Failed to connect to Redis");
-        let _: () = redis::cmd("SET").arg(key).arg(value).query(&mut con).expect("Failed to set value in Redis");
-        self.invalid_keys.lock().unwrap().insert(key.to_string(), false);
+        self.write(key, value, self.default_ttl.map(|policy| policy.next_ttl()));
+    }
+
+    fn set_with_ttl(&self, key: &str, value: String, ttl: Duration) {
+        self.write(key, value, Some(ttl));
     }
 
     fn delete(&self, key: &str) {
-        self.invalid_keys.lock().unwrap().insert(key.to_string(), true);
-        let mut con = self.redis.get_connection().expect("Failed to connect to Redis");
-        let _: () = redis::cmd("DEL").arg(key).query(&mut con).expect("Failed to delete key in Redis");
+        self.marks.mark(key, self.config.mark_ttl);
+        self.backend.del(key).expect("Failed to delete key in cache");
+        self.publish(INVALIDATE, key);
+    }
+
+    fn get_many(&self, keys: &[&str]) -> Vec<Option<String>> {
+        // Marked keys aren't worth a round trip
+        let marked: Vec<bool> = keys.iter().map(|key| self.marks.is_marked(key)).collect();
+        let unmarked: Vec<&str> = keys.iter().zip(&marked).filter(|(_, marked)| !**marked).map(|(key, _)| *key).collect();
+        let mut fetched = self.backend.mget(&unmarked).expect("Failed to get values from cache").into_iter();
+
+        marked.into_iter().map(|marked| if marked { None } else { fetched.next().flatten() }).collect()
+    }
+
+    fn set_many(&self, entries: Vec<(String, String)>) {
+        let batch: Vec<(&str, &str, _)> = entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str(), self.default_ttl.map(|policy| policy.next_ttl())))
+            .collect();
+        self.backend.set_many(&batch).expect("Failed to set values in cache");
+        for (key, _) in &entries {
+            self.marks.clear(key);
+            self.publish(REVALIDATE, key);
+        }
+    }
+
+    fn delete_many(&self, keys: &[&str]) {
+        for key in keys {
+            self.marks.mark(key, self.config.mark_ttl);
+        }
+        self.backend.del_many(keys).expect("Failed to delete keys in cache");
+        for key in keys {
+            self.publish(INVALIDATE, key);
+        }
     }
 }
 
+impl Drop for LazyInvalidationCache {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Some(subscriber) = self.subscriber.take() {
+            let _ = subscriber.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::in_memory::InMemoryBackend;
+
+    fn wait_until(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn test_invalidation_reaches_other_instances() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let a = LazyInvalidationCache::new(backend.clone());
+        let b = LazyInvalidationCache::new(backend.clone());
+
+        a.set("user_1", "alice".to_string());
+        assert_eq!(b.get("user_1"), Some("alice".to_string()));
+
+        a.invalidate("user_1");
+
+        assert_eq!(a.get("user_1"), None);
+        assert!(wait_until(|| b.get("user_1").is_none()));
+        assert_eq!(backend.get("user_1").unwrap(), Some("alice".to_string()));
+    }
+
+    #[test]
+    fn test_set_revalidates_other_instances() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let a = LazyInvalidationCache::new(backend.clone());
+        let b = LazyInvalidationCache::new(backend.clone());
+
+        a.set("user_1", "alice".to_string());
+        b.invalidate("user_1");
+        assert!(wait_until(|| a.invalidated_keys() == 1));
+
+        b.set("user_1", "bob".to_string());
+
+        assert!(wait_until(|| a.get("user_1") == Some("bob".to_string())));
+    }
+
+    #[test]
+    fn test_instances_on_other_channels_are_unaffected() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let a = LazyInvalidationCache::new(backend.clone());
+        let other = LazyInvalidationCache::with_config(
+            backend.clone(),
+            LazyInvalidationConfig { channel: "other:invalidations".to_string(), ..Default::default() },
+        );
+
+        a.set("user_1", "alice".to_string());
+        a.invalidate("user_1");
+        thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(other.get("user_1"), Some("alice".to_string()));
+        assert_eq!(other.invalidated_keys(), 0);
+    }
+
+    #[test]
+    fn test_own_messages_do_not_undo_later_writes() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let a = LazyInvalidationCache::new(backend.clone());
+
+        a.set("user_1", "alice".to_string());
+        a.invalidate("user_1");
+        a.set("user_1", "bob".to_string());
+        thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(a.get("user_1"), Some("bob".to_string()));
+    }
+
+    #[test]
+    fn test_marks_expire_with_the_stale_value() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let config = LazyInvalidationConfig { mark_ttl: Duration::from_millis(50), ..Default::default() };
+        let a = LazyInvalidationCache::with_config(backend.clone(), config);
+
+        a.set("user_1", "alice".to_string());
+        a.invalidate("user_1");
+        assert_eq!(a.invalidated_keys(), 1);
+
+        thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(a.invalidated_keys(), 0);
+        assert_eq!(backend.get("user_1").unwrap(), None);
+        assert_eq!(a.get("user_1"), None);
+    }
+
+    #[test]
+    fn test_delete_removes_value_and_marks_key() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let a = LazyInvalidationCache::new(backend.clone());
+        let b = LazyInvalidationCache::new(backend.clone());
+
+        a.set("user_1", "alice".to_string());
+        a.delete("user_1");
+
+        assert_eq!(backend.get("user_1").unwrap(), None);
+        assert!(wait_until(|| b.invalidated_keys() == 1));
+    }
+
+    #[test]
+    fn test_get_many_skips_marked_keys() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let a = LazyInvalidationCache::new(backend.clone());
+        let b = LazyInvalidationCache::new(backend.clone());
+
+        a.set_many(vec![("user_1".to_string(), "alice".to_string()), ("user_2".to_string(), "bob".to_string())]);
+        a.invalidate("user_1");
+
+        assert_eq!(a.get_many(&["user_1", "user_2"]), vec![None, Some("bob".to_string())]);
+        assert!(wait_until(|| b.get_many(&["user_1", "user_2"]) == vec![None, Some("bob".to_string())]));
+    }
+}
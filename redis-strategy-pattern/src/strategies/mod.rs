@@ -1,3 +1,34 @@
 pub mod read_through;
 pub mod write_through;
-pub mod lazy_invalidation;
\ No newline at end of file
+pub mod write_behind;
+pub mod lazy_invalidation;
+pub mod tiered;
+pub mod stampede_protection;
+pub mod cache_aside;
+
+use std::sync::Arc;
+
+use crate::{cache_backend::CacheBackend, data_source::DataSource, ttl::TtlPolicy};
+
+/// Fills the misses in `values` from `source` and writes what it loaded back to the cache in one batch. Hits are
+/// left alone, so they keep their TTLs.
+pub(crate) fn fill_from_source(
+    backend: &(dyn CacheBackend + Send + Sync),
+    source: &Arc<dyn DataSource + Send + Sync>,
+    keys: &[&str],
+    values: &mut [Option<String>],
+    ttl: Option<TtlPolicy>,
+) {
+    let misses: Vec<usize> = values.iter().enumerate().filter(|(_, value)| value.is_none()).map(|(i, _)| i).collect();
+    for &i in &misses {
+        values[i] = source.load(keys[i]);
+    }
+
+    let loaded: Vec<(&str, &str, _)> = misses
+        .iter()
+        .filter_map(|&i| Some((keys[i], values[i].as_deref()?, ttl.map(|policy| policy.next_ttl()))))
+        .collect();
+    if !loaded.is_empty() {
+        backend.set_many(&loaded).expect("Failed to set values in cache");
+    }
+}
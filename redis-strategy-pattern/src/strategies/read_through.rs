@@ -1,31 +1,217 @@
-use redis::Client;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use crate::cache_strategies::CacheStrategy;
+use super::fill_from_source;
+use crate::{cache_backend::CacheBackend, cache_strategies::CacheStrategy, data_source::DataSource, ttl::TtlPolicy};
 
+/// Loads missing keys from the data source and populates the cache with them.
+/// Writes go to the data source and evict the cached copy, so the next read repopulates it.
 pub struct ReadThroughCache {
-    redis: Client
+    backend: Arc<dyn CacheBackend + Send + Sync>,
+    source: Arc<dyn DataSource + Send + Sync>,
+    // Serializes loads that repopulate the cache with writes, so a write can't land between a load and the SET
+    // that caches what it read
+    write_lock: Mutex<()>,
+    default_ttl: Option<TtlPolicy>,
 }
 
 impl ReadThroughCache {
-    pub fn new(client: Client) -> Self {
-        Self { redis: client }
+    pub fn new(backend: Arc<dyn CacheBackend + Send + Sync>, source: Arc<dyn DataSource + Send + Sync>) -> Self {
+        Self { backend, source, write_lock: Mutex::new(()), default_ttl: None }
+    }
+
+    /// Entries loaded from the source expire according to `policy`.
+    pub fn with_default_ttl(mut self, policy: TtlPolicy) -> Self {
+        self.default_ttl = Some(policy);
+        self
     }
 }
 
 impl CacheStrategy for ReadThroughCache {
     fn get(&self, key: &str) -> Option<String> {
-        let mut con = self.redis.get_connection().expect("Failed to connect to Redis");
-        let result: redis::RedisResult<String> = redis::cmd("GET").arg(key).query(&mut con);
-        result.ok()
+        if let Some(value) = self.backend.get(key).expect("Failed to get value from cache") {
+            return Some(value);
+        }
+
+        let _guard = self.write_lock.lock().unwrap();
+        let value = self.source.load(key)?;
+        let ttl = self.default_ttl.map(|policy| policy.next_ttl());
+        self.backend.set(key, &value, ttl).expect("Failed to set value in cache");
+        Some(value)
     }
 
     fn set(&self, key: &str, value: String) {
-        let mut con = self.redis.get_connection().expect("Failed to connect to Redis");
-        let _: () = redis::cmd("SET").arg(key).arg(value).query(&mut con).expect("Failed to set value in Redis");
+        let _guard = self.write_lock.lock().unwrap();
+        self.source.store(key, &value);
+        self.backend.del(key).expect("Failed to delete key in cache");
+    }
+
+    /// Unlike `set`, an explicit TTL caches the new value right away.
+    fn set_with_ttl(&self, key: &str, value: String, ttl: Duration) {
+        let _guard = self.write_lock.lock().unwrap();
+        self.source.store(key, &value);
+        self.backend.set(key, &value, Some(ttl)).expect("Failed to set value in cache");
     }
 
     fn delete(&self, key: &str) {
-        let mut con = self.redis.get_connection().expect("Failed to connect to Redis");
-        let _: () = redis::cmd("DEL").arg(key).query(&mut con).expect("Failed to delete key in Redis");
+        let _guard = self.write_lock.lock().unwrap();
+        self.source.remove(key);
+        self.backend.del(key).expect("Failed to delete key in cache");
+    }
+
+    fn get_many(&self, keys: &[&str]) -> Vec<Option<String>> {
+        let mut values = self.backend.mget(keys).expect("Failed to get values from cache");
+        if values.iter().any(Option::is_none) {
+            let _guard = self.write_lock.lock().unwrap();
+            fill_from_source(&*self.backend, &self.source, keys, &mut values, self.default_ttl);
+        }
+        values
+    }
+
+    fn set_many(&self, entries: Vec<(String, String)>) {
+        let _guard = self.write_lock.lock().unwrap();
+        self.source.store_many(&entries);
+        let keys: Vec<&str> = entries.iter().map(|(key, _)| key.as_str()).collect();
+        self.backend.del_many(&keys).expect("Failed to delete keys in cache");
+    }
+
+    fn delete_many(&self, keys: &[&str]) {
+        let _guard = self.write_lock.lock().unwrap();
+        for key in keys {
+            self.source.remove(key);
+        }
+        self.backend.del_many(keys).expect("Failed to delete keys in cache");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backends::in_memory::InMemoryBackend,
+        data_source::InMemoryDataSource,
+        test_support::{BlockingSource, CountingSource},
+    };
+
+    #[test]
+    fn test_miss_loads_from_source_and_populates_redis() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(CountingSource::new(InMemoryDataSource::new()));
+        source.inner.store("user_1", "alice");
+        let cache = ReadThroughCache::new(backend.clone(), source.clone());
+
+        assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+        assert_eq!(backend.get("user_1").unwrap(), Some("alice".to_string()));
+
+        assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+        assert_eq!(source.loads(), 1);
+    }
+
+    #[test]
+    fn test_missing_everywhere_returns_none() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let cache = ReadThroughCache::new(backend.clone(), Arc::new(InMemoryDataSource::new()));
+
+        assert_eq!(cache.get("nobody"), None);
+        assert_eq!(backend.get("nobody").unwrap(), None);
+    }
+
+    #[test]
+    fn test_set_writes_source_and_evicts_cached_copy() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(InMemoryDataSource::new());
+        let cache = ReadThroughCache::new(backend.clone(), source.clone());
+
+        cache.set("user_1", "alice".to_string());
+        assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+
+        cache.set("user_1", "bob".to_string());
+        assert_eq!(source.load("user_1"), Some("bob".to_string()));
+        assert_eq!(backend.get("user_1").unwrap(), None);
+        assert_eq!(cache.get("user_1"), Some("bob".to_string()));
+    }
+
+    #[test]
+    fn test_delete_removes_from_source_and_redis() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(InMemoryDataSource::new());
+        let cache = ReadThroughCache::new(backend.clone(), source.clone());
+
+        cache.set("user_1", "alice".to_string());
+        cache.get("user_1");
+        cache.delete("user_1");
+
+        assert_eq!(source.load("user_1"), None);
+        assert_eq!(cache.get("user_1"), None);
+    }
+
+    #[test]
+    fn test_loaded_values_use_the_default_ttl() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(InMemoryDataSource::new());
+        source.store("user_1", "alice");
+        let cache = ReadThroughCache::new(backend.clone(), source.clone())
+            .with_default_ttl(TtlPolicy::with_jitter(Duration::from_secs(60), Duration::from_secs(10)));
+
+        cache.get("user_1");
+
+        let ttl = backend.ttl("user_1").unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(55) && ttl <= Duration::from_secs(70));
+    }
+
+    #[test]
+    fn test_set_with_ttl_caches_until_expiry() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(InMemoryDataSource::new());
+        let cache = ReadThroughCache::new(backend.clone(), source.clone());
+
+        cache.set_with_ttl("user_1", "alice".to_string(), Duration::from_millis(50));
+        assert_eq!(backend.get("user_1").unwrap(), Some("alice".to_string()));
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(backend.get("user_1").unwrap(), None);
+        assert_eq!(source.load("user_1"), Some("alice".to_string()));
+    }
+
+    #[test]
+    fn test_get_many_loads_only_misses() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(CountingSource::new(InMemoryDataSource::new()));
+        source.inner.store("user_2", "bob");
+        let cache = ReadThroughCache::new(backend.clone(), source.clone());
+        backend.set("user_1", "alice", None).unwrap();
+
+        assert_eq!(cache.get_many(&["user_1", "user_2"]), vec![Some("alice".to_string()), Some("bob".to_string())]);
+        assert_eq!(source.loads(), 1);
+        assert_eq!(backend.get("user_2").unwrap(), Some("bob".to_string()));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_set_during_a_miss_isnt_overwritten_by_the_old_value() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let (source, entered, release) = BlockingSource::new();
+        source.inner.store("user_1", "alice");
+        let cache = Arc::new(ReadThroughCache::new(backend.clone(), source.clone()));
+
+        let reader = {
+            let cache = cache.clone();
+            std::thread::spawn(move || cache.get("user_1"))
+        };
+        entered.recv().unwrap();
+        source.set_blocking(false);
+        let writer = {
+            let cache = cache.clone();
+            std::thread::spawn(move || cache.set_with_ttl("user_1", "bob".to_string(), Duration::from_secs(60)))
+        };
+        // The write waits for the miss to finish caching what it read
+        std::thread::sleep(Duration::from_millis(50));
+        release.send(()).unwrap();
+
+        assert_eq!(reader.join().unwrap(), Some("alice".to_string()));
+        writer.join().unwrap();
+        assert_eq!(backend.get("user_1").unwrap(), Some("bob".to_string()));
+        assert_eq!(cache.get("user_1"), Some("bob".to_string()));
+    }
+}
@@ -0,0 +1,397 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use crate::{cache_backend::CacheBackend, cache_strategies::CacheStrategy, data_source::DataSource, ttl::TtlPolicy};
+
+// Once this many freshness records pile up, expired ones are dropped on the next insert
+const FRESHNESS_PRUNE_THRESHOLD: usize = 4096;
+
+/// One in-progress load that other callers for the same key wait on.
+#[derive(Default)]
+struct Flight {
+    result: Mutex<Option<Option<String>>>,
+    done: Condvar,
+}
+
+impl Flight {
+    fn wait(&self) -> Option<String> {
+        let result = self.result.lock().unwrap();
+        let result = self.done.wait_while(result, |r| r.is_none()).unwrap();
+        result.clone().flatten()
+    }
+}
+
+/// Completes the flight even if the load panics, so waiters never hang.
+struct FlightGuard<'a> {
+    cache: &'a StampedeProtectedCache,
+    key: &'a str,
+    flight: Arc<Flight>,
+    value: Option<String>,
+}
+
+impl Drop for FlightGuard<'_> {
+    fn drop(&mut self) {
+        self.cache.in_flight.lock().unwrap().remove(self.key);
+        *self.flight.result.lock().unwrap() = Some(self.value.take());
+        self.flight.done.notify_all();
+    }
+}
+
+/// What XFetch needs to decide on an early refresh: how long the last load took
+/// and when the value it produced expires.
+struct Freshness {
+    delta: Duration,
+    expires_at: Instant,
+}
+
+/// `SET lock:<key> <token> NX PX` based lock, so only one process loads a key at a time.
+pub struct RedisLock {
+    backend: Arc<dyn CacheBackend + Send + Sync>,
+    lock_ttl: Duration,
+    retry_interval: Duration,
+}
+
+impl RedisLock {
+    pub fn new(backend: Arc<dyn CacheBackend + Send + Sync>, lock_ttl: Duration) -> Self {
+        Self { backend, lock_ttl, retry_interval: Duration::from_millis(10) }
+    }
+
+    fn lock_key(key: &str) -> String {
+        format!("lock:{}", key)
+    }
+
+    fn try_acquire(&self, key: &str) -> Option<String> {
+        let token = format!("{:016x}", rand::random::<u64>());
+        let acquired =
+            self.backend.set_nx(&Self::lock_key(key), &token, self.lock_ttl).expect("Failed to acquire lock");
+        acquired.then_some(token)
+    }
+
+    fn release(&self, key: &str, token: &str) {
+        // GET + DEL isn't atomic, so a lock that expired in between could be released for its
+        // next holder. That only costs a duplicate load, never a wrong value.
+        let holder = self.backend.get(&Self::lock_key(key)).unwrap_or(None);
+        if holder.as_deref() == Some(token) {
+            self.backend.del(&Self::lock_key(key)).expect("Failed to release lock");
+        }
+    }
+}
+
+/// Wraps any `CacheStrategy` and protects the data source from cache stampedes:
+/// concurrent misses for a key share one load, hot keys are refreshed shortly before
+/// they expire (XFetch), and an optional Redis lock extends the coalescing across processes.
+pub struct StampedeProtectedCache {
+    inner: Box<dyn CacheStrategy + Send + Sync>,
+    source: Arc<dyn DataSource + Send + Sync>,
+    ttl: TtlPolicy,
+    beta: f64,
+    lock: Option<RedisLock>,
+    in_flight: Mutex<HashMap<String, Arc<Flight>>>,
+    freshness: Mutex<HashMap<String, Freshness>>,
+}
+
+impl StampedeProtectedCache {
+    /// Values loaded from `source` are cached in `inner` for `ttl`.
+    pub fn new(inner: Box<dyn CacheStrategy + Send + Sync>, source: Arc<dyn DataSource + Send + Sync>, ttl: TtlPolicy) -> Self {
+        Self {
+            inner,
+            source,
+            ttl,
+            beta: 1.0,
+            lock: None,
+            in_flight: Mutex::new(HashMap::new()),
+            freshness: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How eagerly to refresh before expiry. Above 1.0 favours earlier refreshes, 0.0 disables them.
+    pub fn with_beta(mut self, beta: f64) -> Self {
+        self.beta = beta;
+        self
+    }
+
+    pub fn with_redis_lock(mut self, lock: RedisLock) -> Self {
+        self.lock = Some(lock);
+        self
+    }
+
+    /// XFetch: refresh when `now - delta * beta * ln(rand)` reaches the expiry, which gets
+    /// more likely the closer the key is to expiring and the longer it takes to load.
+    fn should_refresh_early(&self, key: &str) -> bool {
+        if self.beta <= 0.0 {
+            return false;
+        }
+        let freshness = self.freshness.lock().unwrap();
+        let Some(entry) = freshness.get(key) else {
+            return false;
+        };
+
+        let remaining = entry.expires_at.saturating_duration_since(Instant::now());
+        let gap = entry.delta.as_secs_f64() * self.beta * -rand::rng().random::<f64>().ln();
+        gap >= remaining.as_secs_f64()
+    }
+
+    /// Runs `load` for `key` unless another caller already is, in which case waits for its result.
+    /// With `wait = false` an in-progress load returns `None` immediately instead.
+    fn single_flight(&self, key: &str, wait: bool) -> Option<Option<String>> {
+        let flight = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if let Some(flight) = in_flight.get(key) {
+                let flight = flight.clone();
+                drop(in_flight);
+                return wait.then(|| flight.wait());
+            }
+            let flight = Arc::new(Flight::default());
+            in_flight.insert(key.to_string(), flight.clone());
+            flight
+        };
+
+        let mut guard = FlightGuard { cache: self, key, flight, value: None };
+        guard.value = self.load(key);
+        Some(guard.value.clone())
+    }
+
+    fn load(&self, key: &str) -> Option<String> {
+        let Some(lock) = &self.lock else {
+            return self.recompute(key);
+        };
+
+        let deadline = Instant::now() + lock.lock_ttl;
+        loop {
+            if let Some(token) = lock.try_acquire(key) {
+                let value = self.recompute(key);
+                lock.release(key, &token);
+                return value;
+            }
+            // Another process is loading; its value shows up in the cache when it's done
+            thread::sleep(lock.retry_interval);
+            if let Some(value) = self.inner.get(key) {
+                return Some(value);
+            }
+            if Instant::now() >= deadline {
+                return self.recompute(key);
+            }
+        }
+    }
+
+    fn recompute(&self, key: &str) -> Option<String> {
+        let started = Instant::now();
+        let value = self.source.load(key)?;
+        let delta = started.elapsed();
+
+        let ttl = self.ttl.next_ttl();
+        self.inner.set_with_ttl(key, value.clone(), ttl);
+
+        let now = Instant::now();
+        let mut freshness = self.freshness.lock().unwrap();
+        if freshness.len() >= FRESHNESS_PRUNE_THRESHOLD {
+            freshness.retain(|_, entry| entry.expires_at > now);
+        }
+        freshness.insert(key.to_string(), Freshness { delta, expires_at: now + ttl });
+        Some(value)
+    }
+}
+
+impl CacheStrategy for StampedeProtectedCache {
+    fn get(&self, key: &str) -> Option<String> {
+        match self.inner.get(key) {
+            Some(value) if self.should_refresh_early(key) => {
+                // Whoever loses the race for the refresh keeps serving the current value
+                self.single_flight(key, false).flatten().or(Some(value))
+            }
+            Some(value) => Some(value),
+            None => self.single_flight(key, true).flatten(),
+        }
+    }
+
+    fn set(&self, key: &str, value: String) {
+        self.freshness.lock().unwrap().remove(key);
+        self.inner.set(key, value);
+    }
+
+    fn set_with_ttl(&self, key: &str, value: String, ttl: Duration) {
+        self.freshness.lock().unwrap().remove(key);
+        self.inner.set_with_ttl(key, value, ttl);
+    }
+
+    fn delete(&self, key: &str) {
+        self.freshness.lock().unwrap().remove(key);
+        self.inner.delete(key);
+    }
+
+    /// Hits come back from one batched lookup; misses and early refreshes still go through single-flight per key.
+    fn get_many(&self, keys: &[&str]) -> Vec<Option<String>> {
+        keys.iter()
+            .zip(self.inner.get_many(keys))
+            .map(|(key, cached)| match cached {
+                Some(value) if self.should_refresh_early(key) => self.single_flight(key, false).flatten().or(Some(value)),
+                Some(value) => Some(value),
+                None => self.single_flight(key, true).flatten(),
+            })
+            .collect()
+    }
+
+    fn set_many(&self, entries: Vec<(String, String)>) {
+        {
+            let mut freshness = self.freshness.lock().unwrap();
+            for (key, _) in &entries {
+                freshness.remove(key);
+            }
+        }
+        self.inner.set_many(entries);
+    }
+
+    fn delete_many(&self, keys: &[&str]) {
+        {
+            let mut freshness = self.freshness.lock().unwrap();
+            for key in keys {
+                freshness.remove(*key);
+            }
+        }
+        self.inner.delete_many(keys);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backends::in_memory::InMemoryBackend,
+        test_support::{MapCache, SlowSource},
+    };
+
+    fn slow_source() -> Arc<SlowSource> {
+        let source = Arc::new(SlowSource::new(Duration::from_millis(50)));
+        source.store("user_1", "alice");
+        source
+    }
+
+    fn ttl() -> TtlPolicy {
+        TtlPolicy::fixed(Duration::from_secs(60))
+    }
+
+    #[test]
+    fn test_concurrent_misses_share_one_load() {
+        let source = slow_source();
+        let cache = StampedeProtectedCache::new(Box::new(MapCache::default()), source.clone(), ttl());
+
+        thread::scope(|s| {
+            let handles: Vec<_> = (0..8).map(|_| s.spawn(|| cache.get("user_1"))).collect();
+            for handle in handles {
+                assert_eq!(handle.join().unwrap(), Some("alice".to_string()));
+            }
+        });
+
+        assert_eq!(source.inner.loads(), 1);
+    }
+
+    #[test]
+    fn test_missing_keys_are_coalesced_too() {
+        let source = slow_source();
+        let cache = StampedeProtectedCache::new(Box::new(MapCache::default()), source.clone(), ttl());
+
+        thread::scope(|s| {
+            let handles: Vec<_> = (0..4).map(|_| s.spawn(|| cache.get("nobody"))).collect();
+            for handle in handles {
+                assert_eq!(handle.join().unwrap(), None);
+            }
+        });
+
+        assert_eq!(source.inner.loads(), 1);
+    }
+
+    #[test]
+    fn test_loaded_values_are_cached_in_inner() {
+        let source = slow_source();
+        let inner = MapCache::default();
+        let cache = StampedeProtectedCache::new(Box::new(inner.clone()), source.clone(), ttl()).with_beta(0.0);
+
+        cache.get("user_1");
+        cache.get("user_1");
+
+        assert_eq!(inner.get("user_1"), Some("alice".to_string()));
+        assert_eq!(source.inner.loads(), 1);
+    }
+
+    #[test]
+    fn test_xfetch_refreshes_early_when_expiry_is_close() {
+        let source = slow_source();
+        // A huge beta makes every hit look like it's about to expire
+        let cache = StampedeProtectedCache::new(Box::new(MapCache::default()), source.clone(), ttl()).with_beta(1e9);
+
+        cache.get("user_1");
+        source.store("user_1", "bob");
+
+        assert_eq!(cache.get("user_1"), Some("bob".to_string()));
+        assert_eq!(source.inner.loads(), 2);
+    }
+
+    #[test]
+    fn test_waiters_are_released_when_the_load_panics() {
+        struct PanickingSource;
+        impl DataSource for PanickingSource {
+            fn load(&self, _key: &str) -> Option<String> {
+                thread::sleep(Duration::from_millis(50));
+                panic!("database is down");
+            }
+            fn store(&self, _key: &str, _value: &str) {}
+            fn remove(&self, _key: &str) {}
+        }
+        let cache = StampedeProtectedCache::new(Box::new(MapCache::default()), Arc::new(PanickingSource), ttl());
+
+        thread::scope(|s| {
+            let leader = s.spawn(|| cache.get("user_1"));
+            thread::sleep(Duration::from_millis(10));
+            let waiter = s.spawn(|| cache.get("user_1"));
+
+            assert!(leader.join().is_err());
+            // The waiter either saw the failed flight or became a leader and panicked itself
+            let _ = waiter.join();
+        });
+
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_redis_lock_coalesces_across_processes() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = slow_source();
+        let shared = MapCache::default();
+        let lock_ttl = Duration::from_secs(2);
+        let a = StampedeProtectedCache::new(Box::new(shared.clone()), source.clone(), ttl())
+            .with_redis_lock(RedisLock::new(backend.clone(), lock_ttl));
+        let b = StampedeProtectedCache::new(Box::new(shared.clone()), source.clone(), ttl())
+            .with_redis_lock(RedisLock::new(backend.clone(), lock_ttl));
+
+        thread::scope(|s| {
+            let first = s.spawn(|| a.get("user_1"));
+            let second = s.spawn(|| b.get("user_1"));
+            assert_eq!(first.join().unwrap(), Some("alice".to_string()));
+            assert_eq!(second.join().unwrap(), Some("alice".to_string()));
+        });
+
+        assert_eq!(source.inner.loads(), 1);
+        assert_eq!(backend.get("lock:user_1").unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_many_shares_loads_with_concurrent_gets() {
+        let source = slow_source();
+        let cache = StampedeProtectedCache::new(Box::new(MapCache::default()), source.clone(), ttl());
+
+        thread::scope(|s| {
+            let single = s.spawn(|| cache.get("user_1"));
+            let batch = s.spawn(|| cache.get_many(&["user_1", "user_2"]));
+            assert_eq!(single.join().unwrap(), Some("alice".to_string()));
+            assert_eq!(batch.join().unwrap(), vec![Some("alice".to_string()), None]);
+        });
+
+        assert_eq!(source.inner.loads(), 2);
+    }
+}
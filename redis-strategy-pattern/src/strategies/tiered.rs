@@ -0,0 +1,320 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+
+use crate::{cache_backend::CacheBackend, cache_strategies::CacheStrategy, ttl::TtlPolicy};
+
+pub struct TieredConfig {
+    /// Maximum number of entries kept in process; the least recently used one is evicted first
+    pub l1_capacity: NonZeroUsize,
+    /// How long an entry may be served from process memory before L2 is asked again.
+    /// This bounds how stale L1 can get when another instance changes the key.
+    pub l1_ttl: Duration,
+    /// Expiry for entries written to L2 without an explicit TTL
+    pub l2_ttl: Option<TtlPolicy>,
+}
+
+impl Default for TieredConfig {
+    fn default() -> Self {
+        Self { l1_capacity: NonZeroUsize::new(1024).unwrap(), l1_ttl: Duration::from_secs(5), l2_ttl: None }
+    }
+}
+
+/// Hit and miss counts per tier. An L1 miss is followed by an L2 lookup.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TierStats {
+    pub l1_hits: u64,
+    pub l1_misses: u64,
+    pub l2_hits: u64,
+    pub l2_misses: u64,
+}
+
+#[derive(Default)]
+struct TierCounters {
+    l1_hits: AtomicU64,
+    l1_misses: AtomicU64,
+    l2_hits: AtomicU64,
+    l2_misses: AtomicU64,
+}
+
+struct L1Entry {
+    value: String,
+    expires_at: Instant,
+}
+
+/// Keeps hot keys in a bounded in-process LRU (L1) in front of the shared backend, usually Redis (L2).
+pub struct TieredCache {
+    backend: Arc<dyn CacheBackend + Send + Sync>,
+    l1: Mutex<LruCache<String, L1Entry>>,
+    l1_ttl: Duration,
+    l2_ttl: Option<TtlPolicy>,
+    counters: TierCounters,
+}
+
+impl TieredCache {
+    pub fn new(backend: Arc<dyn CacheBackend + Send + Sync>) -> Self {
+        Self::with_config(backend, TieredConfig::default())
+    }
+
+    pub fn with_config(backend: Arc<dyn CacheBackend + Send + Sync>, config: TieredConfig) -> Self {
+        Self {
+            backend,
+            l1: Mutex::new(LruCache::new(config.l1_capacity)),
+            l1_ttl: config.l1_ttl,
+            l2_ttl: config.l2_ttl,
+            counters: TierCounters::default(),
+        }
+    }
+
+    pub fn stats(&self) -> TierStats {
+        TierStats {
+            l1_hits: self.counters.l1_hits.load(Ordering::Relaxed),
+            l1_misses: self.counters.l1_misses.load(Ordering::Relaxed),
+            l2_hits: self.counters.l2_hits.load(Ordering::Relaxed),
+            l2_misses: self.counters.l2_misses.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn l1_len(&self) -> usize {
+        self.l1.lock().unwrap().len()
+    }
+
+    fn get_l1(&self, key: &str) -> Option<String> {
+        let mut l1 = self.l1.lock().unwrap();
+        match l1.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.value.clone()),
+            Some(_) => {
+                l1.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn put_l1(&self, key: &str, value: String, ttl: Duration) {
+        let expires_at = Instant::now() + ttl.min(self.l1_ttl);
+        self.l1.lock().unwrap().put(key.to_string(), L1Entry { value, expires_at });
+    }
+
+    /// Caches an L2 hit in L1, for no longer than the L2 copy has left.
+    fn put_l1_from_l2(&self, key: &str, value: String) {
+        let left = self.backend.ttl(key).expect("Failed to get TTL from cache");
+        self.put_l1(key, value, left.unwrap_or(self.l1_ttl));
+    }
+
+    fn write(&self, key: &str, value: String, ttl: Option<Duration>) {
+        self.backend.set(key, &value, ttl).expect("Failed to set value in cache");
+        // L1 must never outlive the L2 copy
+        self.put_l1(key, value, ttl.unwrap_or(self.l1_ttl));
+    }
+}
+
+impl CacheStrategy for TieredCache {
+    fn get(&self, key: &str) -> Option<String> {
+        if let Some(value) = self.get_l1(key) {
+            self.counters.l1_hits.fetch_add(1, Ordering::Relaxed);
+            return Some(value);
+        }
+        self.counters.l1_misses.fetch_add(1, Ordering::Relaxed);
+
+        match self.backend.get(key).expect("Failed to get value from cache") {
+            Some(value) => {
+                self.counters.l2_hits.fetch_add(1, Ordering::Relaxed);
+                self.put_l1_from_l2(key, value.clone());
+                Some(value)
+            }
+            None => {
+                self.counters.l2_misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    fn set(&self, key: &str, value: String) {
+        self.write(key, value, self.l2_ttl.map(|policy| policy.next_ttl()));
+    }
+
+    fn set_with_ttl(&self, key: &str, value: String, ttl: Duration) {
+        self.write(key, value, Some(ttl));
+    }
+
+    // L2 goes first: a `get` between the two would otherwise copy the old value back into L1
+    fn delete(&self, key: &str) {
+        self.backend.del(key).expect("Failed to delete key in cache");
+        self.l1.lock().unwrap().pop(key);
+    }
+
+    fn get_many(&self, keys: &[&str]) -> Vec<Option<String>> {
+        let mut values: Vec<Option<String>> = keys.iter().map(|key| self.get_l1(key)).collect();
+        let hits = values.iter().filter(|value| value.is_some()).count() as u64;
+        self.counters.l1_hits.fetch_add(hits, Ordering::Relaxed);
+        self.counters.l1_misses.fetch_add(keys.len() as u64 - hits, Ordering::Relaxed);
+
+        // Everything L1 couldn't answer goes to L2 in a single MGET
+        let missing: Vec<usize> = (0..keys.len()).filter(|&i| values[i].is_none()).collect();
+        let missing_keys: Vec<&str> = missing.iter().map(|&i| keys[i]).collect();
+        let fetched = self.backend.mget(&missing_keys).expect("Failed to get values from cache");
+
+        for (i, value) in missing.into_iter().zip(fetched) {
+            match &value {
+                Some(value) => {
+                    self.counters.l2_hits.fetch_add(1, Ordering::Relaxed);
+                    self.put_l1_from_l2(keys[i], value.clone());
+                }
+                None => {
+                    self.counters.l2_misses.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            values[i] = value;
+        }
+        values
+    }
+
+    fn set_many(&self, entries: Vec<(String, String)>) {
+        let ttls: Vec<Option<Duration>> = entries.iter().map(|_| self.l2_ttl.map(|policy| policy.next_ttl())).collect();
+        let batch: Vec<(&str, &str, Option<Duration>)> = entries
+            .iter()
+            .zip(&ttls)
+            .map(|((key, value), ttl)| (key.as_str(), value.as_str(), *ttl))
+            .collect();
+        self.backend.set_many(&batch).expect("Failed to set values in cache");
+
+        for ((key, value), ttl) in entries.into_iter().zip(ttls) {
+            self.put_l1(&key, value, ttl.unwrap_or(self.l1_ttl));
+        }
+    }
+
+    fn delete_many(&self, keys: &[&str]) {
+        self.backend.del_many(keys).expect("Failed to delete keys in cache");
+        let mut l1 = self.l1.lock().unwrap();
+        for key in keys {
+            l1.pop(*key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::in_memory::InMemoryBackend;
+
+    fn config(capacity: usize, l1_ttl: Duration) -> TieredConfig {
+        TieredConfig { l1_capacity: NonZeroUsize::new(capacity).unwrap(), l1_ttl, l2_ttl: None }
+    }
+
+    #[test]
+    fn test_hot_keys_are_served_from_l1() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let cache = TieredCache::new(backend.clone());
+
+        cache.set("user_1", "alice".to_string());
+        for _ in 0..3 {
+            assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+        }
+
+        assert_eq!(cache.stats(), TierStats { l1_hits: 3, ..Default::default() });
+    }
+
+    #[test]
+    fn test_l1_miss_falls_back_to_redis() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let writer = TieredCache::new(backend.clone());
+        let reader = TieredCache::new(backend.clone());
+
+        writer.set("user_1", "alice".to_string());
+
+        assert_eq!(reader.get("user_1"), Some("alice".to_string()));
+        assert_eq!(reader.get("user_1"), Some("alice".to_string()));
+        assert_eq!(reader.get("user_2"), None);
+        assert_eq!(reader.stats(), TierStats { l1_hits: 1, l1_misses: 2, l2_hits: 1, l2_misses: 1 });
+    }
+
+    #[test]
+    fn test_l1_is_bounded() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let cache = TieredCache::with_config(backend.clone(), config(2, Duration::from_secs(5)));
+
+        cache.set("a", "1".to_string());
+        cache.set("b", "2".to_string());
+        cache.get("a");
+        cache.set("c", "3".to_string());
+
+        assert_eq!(cache.l1_len(), 2);
+        // "b" was least recently used, so only it has to come from Redis
+        assert_eq!(cache.get("b"), Some("2".to_string()));
+        assert_eq!(cache.stats().l2_hits, 1);
+    }
+
+    #[test]
+    fn test_l1_entries_expire() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let cache = TieredCache::with_config(backend.clone(), config(16, Duration::from_millis(30)));
+
+        cache.set("user_1", "alice".to_string());
+        std::thread::sleep(Duration::from_millis(60));
+
+        assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+        assert_eq!(cache.stats().l2_hits, 1);
+    }
+
+    #[test]
+    fn test_short_ttl_caps_l1_lifetime() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let cache = TieredCache::new(backend.clone());
+
+        cache.set_with_ttl("user_1", "alice".to_string(), Duration::from_millis(30));
+        std::thread::sleep(Duration::from_millis(60));
+
+        assert_eq!(cache.get("user_1"), None);
+    }
+
+    #[test]
+    fn test_l2_hits_stay_in_l1_no_longer_than_in_l2() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let cache = TieredCache::with_config(backend.clone(), config(10, Duration::from_secs(60)));
+        backend.set("user_1", "alice", Some(Duration::from_millis(30))).unwrap();
+        backend.set("user_2", "bob", Some(Duration::from_millis(30))).unwrap();
+
+        assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+        assert_eq!(cache.get_many(&["user_2"]), vec![Some("bob".to_string())]);
+        std::thread::sleep(Duration::from_millis(60));
+
+        assert_eq!(cache.get_many(&["user_1", "user_2"]), vec![None, None]);
+    }
+
+    #[test]
+    fn test_delete_invalidates_l1() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let cache = TieredCache::new(backend.clone());
+
+        cache.set("user_1", "alice".to_string());
+        cache.delete("user_1");
+
+        assert_eq!(cache.l1_len(), 0);
+        assert_eq!(cache.get("user_1"), None);
+        assert_eq!(backend.get("user_1").unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_many_only_asks_l2_for_l1_misses() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let cache = TieredCache::new(backend.clone());
+        cache.set("a", "1".to_string());
+        backend.set("b", "2", None).unwrap();
+
+        assert_eq!(cache.get_many(&["a", "b", "c"]), vec![Some("1".to_string()), Some("2".to_string()), None]);
+        assert_eq!(cache.stats(), TierStats { l1_hits: 1, l1_misses: 2, l2_hits: 1, l2_misses: 1 });
+        assert_eq!(cache.l1_len(), 2);
+
+        cache.delete_many(&["a", "b"]);
+        assert_eq!(cache.l1_len(), 0);
+        assert_eq!(backend.get("a").unwrap(), None);
+    }
+}
@@ -0,0 +1,494 @@
+use std::{
+    collections::HashMap,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{cache_backend::CacheBackend, cache_strategies::CacheStrategy, data_source::DataSource, ttl::TtlPolicy};
+
+pub struct WriteBehindConfig {
+    /// Flush as soon as this many keys are waiting
+    pub batch_size: usize,
+    /// Flush at least this often, even when the batch isn't full
+    pub flush_interval: Duration,
+}
+
+impl Default for WriteBehindConfig {
+    fn default() -> Self {
+        Self { batch_size: 100, flush_interval: Duration::from_millis(500) }
+    }
+}
+
+#[derive(Default)]
+struct Pending {
+    // `None` marks a delete; later writes to the same key replace earlier ones
+    writes: HashMap<String, Option<String>>,
+    // Taken by a flush but not yet confirmed by the source, so reads still find them
+    in_flight: HashMap<String, Option<String>>,
+    shutdown: bool,
+}
+
+impl Pending {
+    /// The newest write for `key` the source may not have yet.
+    fn queued(&self, key: &str) -> Option<Option<String>> {
+        self.writes.get(key).or_else(|| self.in_flight.get(key)).cloned()
+    }
+}
+
+struct Shared {
+    pending: Mutex<Pending>,
+    wake: Condvar,
+    // Held for the whole drain + apply so two flushes can't reach the source out of order
+    flush_lock: Mutex<()>,
+    failed_flushes: AtomicU64,
+    source: Arc<dyn DataSource + Send + Sync>,
+}
+
+impl Shared {
+    /// Pushes everything queued to the source. If the source panics, the writes stay in flight for the next
+    /// flush to retry and the panic is returned, so the flusher thread survives and the lock isn't poisoned.
+    fn flush(&self) -> thread::Result<()> {
+        let _guard = self.flush_lock.lock().unwrap();
+        let writes = {
+            let mut pending = self.pending.lock().unwrap();
+            // Whatever an earlier, failed flush left in flight goes again, under anything newer
+            let writes = std::mem::take(&mut pending.writes);
+            pending.in_flight.extend(writes);
+            pending.in_flight.clone()
+        };
+
+        let applied = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut stores = Vec::new();
+            for (key, value) in writes {
+                match value {
+                    Some(value) => stores.push((key, value)),
+                    None => self.source.remove(&key),
+                }
+            }
+            if !stores.is_empty() {
+                self.source.store_many(&stores);
+            }
+        }));
+        match applied {
+            Ok(()) => self.pending.lock().unwrap().in_flight.clear(),
+            Err(_) => {
+                self.failed_flushes.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        applied
+    }
+}
+
+/// Writes land in the cache immediately and are pushed to the data source in batches
+/// by a background thread. Dropping the cache flushes whatever is still queued.
+pub struct WriteBehindCache {
+    backend: Arc<dyn CacheBackend + Send + Sync>,
+    shared: Arc<Shared>,
+    batch_size: usize,
+    flusher: Option<JoinHandle<()>>,
+    default_ttl: Option<TtlPolicy>,
+}
+
+impl WriteBehindCache {
+    pub fn new(backend: Arc<dyn CacheBackend + Send + Sync>, source: Arc<dyn DataSource + Send + Sync>) -> Self {
+        Self::with_config(backend, source, WriteBehindConfig::default())
+    }
+
+    pub fn with_config(
+        backend: Arc<dyn CacheBackend + Send + Sync>,
+        source: Arc<dyn DataSource + Send + Sync>,
+        config: WriteBehindConfig,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            pending: Mutex::new(Pending::default()),
+            wake: Condvar::new(),
+            flush_lock: Mutex::new(()),
+            failed_flushes: AtomicU64::new(0),
+            source,
+        });
+
+        let worker = shared.clone();
+        let flusher = thread::spawn(move || {
+            loop {
+                let pending = worker.pending.lock().unwrap();
+                let (pending, _) = worker
+                    .wake
+                    .wait_timeout_while(pending, config.flush_interval, |p| {
+                        !p.shutdown && p.writes.len() < config.batch_size
+                    })
+                    .unwrap();
+                let shutdown = pending.shutdown;
+                drop(pending);
+
+                // A failed flush has already been counted and reported by the panic hook; the next one retries
+                let _ = worker.flush();
+                if shutdown {
+                    break;
+                }
+            }
+        });
+
+        Self { backend, shared, batch_size: config.batch_size, flusher: Some(flusher), default_ttl: None }
+    }
+
+    /// Entries written without an explicit TTL expire according to `policy`.
+    pub fn with_default_ttl(mut self, policy: TtlPolicy) -> Self {
+        self.default_ttl = Some(policy);
+        self
+    }
+
+    /// Pushes every queued write to the data source before returning. Panics, like the other operations, if the
+    /// source does; the writes stay queued for the next flush.
+    pub fn flush(&self) {
+        if let Err(payload) = self.shared.flush() {
+            panic::resume_unwind(payload);
+        }
+    }
+
+    /// How many flushes the data source has failed, in the background or through `flush`. Their writes are
+    /// retried, so a count that keeps growing means the source is down.
+    pub fn failed_flushes(&self) -> u64 {
+        self.shared.failed_flushes.load(Ordering::Relaxed)
+    }
+
+    /// Writes the source doesn't have yet, including any a flush is applying right now.
+    pub fn pending_writes(&self) -> usize {
+        let pending = self.shared.pending.lock().unwrap();
+        pending.writes.len() + pending.in_flight.keys().filter(|key| !pending.writes.contains_key(*key)).count()
+    }
+
+    fn write(&self, key: &str, value: String, ttl: Option<Duration>) {
+        self.backend.set(key, &value, ttl).expect("Failed to set value in cache");
+        self.enqueue(key, Some(value));
+    }
+
+    fn enqueue(&self, key: &str, value: Option<String>) {
+        self.enqueue_many(std::iter::once((key.to_string(), value)));
+    }
+
+    fn enqueue_many(&self, writes: impl IntoIterator<Item = (String, Option<String>)>) {
+        let mut pending = self.shared.pending.lock().unwrap();
+        pending.writes.extend(writes);
+        if pending.writes.len() >= self.batch_size {
+            self.shared.wake.notify_one();
+        }
+    }
+}
+
+impl CacheStrategy for WriteBehindCache {
+    fn get(&self, key: &str) -> Option<String> {
+        if let Some(value) = self.backend.get(key).expect("Failed to get value from cache") {
+            return Some(value);
+        }
+
+        // A queued write is newer than anything the source has
+        if let Some(queued) = self.shared.pending.lock().unwrap().queued(key) {
+            return queued;
+        }
+        self.shared.source.load(key)
+    }
+
+    fn set(&self, key: &str, value: String) {
+        self.write(key, value, self.default_ttl.map(|policy| policy.next_ttl()));
+    }
+
+    fn set_with_ttl(&self, key: &str, value: String, ttl: Duration) {
+        self.write(key, value, Some(ttl));
+    }
+
+    fn delete(&self, key: &str) {
+        self.backend.del(key).expect("Failed to delete key in cache");
+        self.enqueue(key, None);
+    }
+
+    fn get_many(&self, keys: &[&str]) -> Vec<Option<String>> {
+        let mut values = self.backend.mget(keys).expect("Failed to get values from cache");
+
+        // Queued writes are looked up under the lock, the source after it's released so writers don't wait on it
+        let queued: Vec<Option<Option<String>>> = {
+            let pending = self.shared.pending.lock().unwrap();
+            keys.iter().zip(&values).map(|(key, value)| if value.is_none() { pending.queued(key) } else { None }).collect()
+        };
+        for ((key, value), queued) in keys.iter().zip(values.iter_mut()).zip(queued) {
+            if value.is_none() {
+                *value = match queued {
+                    Some(queued) => queued,
+                    None => self.shared.source.load(key),
+                };
+            }
+        }
+        values
+    }
+
+    fn set_many(&self, entries: Vec<(String, String)>) {
+        let batch: Vec<(&str, &str, _)> = entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str(), self.default_ttl.map(|policy| policy.next_ttl())))
+            .collect();
+        self.backend.set_many(&batch).expect("Failed to set values in cache");
+        self.enqueue_many(entries.into_iter().map(|(key, value)| (key, Some(value))));
+    }
+
+    fn delete_many(&self, keys: &[&str]) {
+        self.backend.del_many(keys).expect("Failed to delete keys in cache");
+        self.enqueue_many(keys.iter().map(|key| (key.to_string(), None)));
+    }
+}
+
+impl Drop for WriteBehindCache {
+    fn drop(&mut self) {
+        self.shared.pending.lock().unwrap().shutdown = true;
+        self.shared.wake.notify_one();
+        if let Some(flusher) = self.flusher.take() {
+            let _ = flusher.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backends::in_memory::InMemoryBackend,
+        data_source::InMemoryDataSource,
+        test_support::{BlockingSource, CountingSource},
+    };
+
+    fn slow_config(batch_size: usize) -> WriteBehindConfig {
+        WriteBehindConfig { batch_size, flush_interval: Duration::from_secs(60) }
+    }
+
+    #[test]
+    fn test_set_is_visible_before_source_is_written() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(InMemoryDataSource::new());
+        let cache = WriteBehindCache::with_config(backend.clone(), source.clone(), slow_config(100));
+
+        cache.set("user_1", "alice".to_string());
+
+        assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+        assert_eq!(source.load("user_1"), None);
+        assert_eq!(cache.pending_writes(), 1);
+
+        cache.flush();
+        assert_eq!(source.load("user_1"), Some("alice".to_string()));
+        assert_eq!(cache.pending_writes(), 0);
+    }
+
+    #[test]
+    fn test_writes_are_coalesced_into_one_batch() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(CountingSource::new(InMemoryDataSource::new()));
+        let cache = WriteBehindCache::with_config(backend.clone(), source.clone(), slow_config(100));
+
+        for i in 0..10 {
+            cache.set(&format!("key_{}", i), "first".to_string());
+            cache.set(&format!("key_{}", i), "second".to_string());
+        }
+        cache.flush();
+
+        assert_eq!(source.batches(), 1);
+        assert_eq!(source.stores(), 10);
+        assert_eq!(source.inner.load("key_3"), Some("second".to_string()));
+    }
+
+    #[test]
+    fn test_full_batch_wakes_the_flusher() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(InMemoryDataSource::new());
+        let cache = WriteBehindCache::with_config(backend.clone(), source.clone(), slow_config(5));
+
+        for i in 0..5 {
+            cache.set(&format!("key_{}", i), "value".to_string());
+        }
+
+        for _ in 0..100 {
+            if source.len() == 5 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(source.len(), 5);
+    }
+
+    #[test]
+    fn test_delete_is_queued_for_the_source() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(InMemoryDataSource::new());
+        source.store("user_1", "alice");
+        let cache = WriteBehindCache::with_config(backend.clone(), source.clone(), slow_config(100));
+
+        cache.delete("user_1");
+        assert_eq!(cache.get("user_1"), None);
+        assert_eq!(source.load("user_1"), Some("alice".to_string()));
+
+        cache.flush();
+        assert_eq!(source.load("user_1"), None);
+    }
+
+    #[test]
+    fn test_drop_flushes_pending_writes() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(InMemoryDataSource::new());
+        let cache = WriteBehindCache::with_config(backend.clone(), source.clone(), slow_config(100));
+
+        cache.set("user_1", "alice".to_string());
+        drop(cache);
+
+        assert_eq!(source.load("user_1"), Some("alice".to_string()));
+    }
+
+    #[test]
+    fn test_set_with_ttl_still_reaches_the_source() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(InMemoryDataSource::new());
+        let cache = WriteBehindCache::with_config(backend.clone(), source.clone(), slow_config(100));
+
+        cache.set_with_ttl("user_1", "alice".to_string(), Duration::from_millis(50));
+        assert!(backend.ttl("user_1").unwrap().is_some());
+
+        cache.flush();
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(backend.get("user_1").unwrap(), None);
+        assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+    }
+
+    #[test]
+    fn test_batches_are_queued_together() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(CountingSource::new(InMemoryDataSource::new()));
+        let cache = WriteBehindCache::with_config(backend.clone(), source.clone(), slow_config(100));
+
+        cache.set_many((0..10).map(|i| (format!("key_{}", i), "value".to_string())).collect());
+        assert_eq!(cache.pending_writes(), 10);
+        assert_eq!(backend.get("key_7").unwrap(), Some("value".to_string()));
+
+        cache.delete_many(&["key_1", "key_2"]);
+        assert_eq!(cache.get_many(&["key_1", "key_3"]), vec![None, Some("value".to_string())]);
+
+        cache.flush();
+        assert_eq!(source.batches(), 1);
+        assert_eq!(source.stores(), 8);
+    }
+
+    #[test]
+    fn test_writes_being_flushed_stay_readable() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let (source, entered, release) = BlockingSource::new();
+        let cache = Arc::new(WriteBehindCache::with_config(backend.clone(), source.clone(), slow_config(100)));
+
+        cache.set("user_1", "alice".to_string());
+        // Evicted from the cache, so the read has to look further
+        backend.del("user_1").unwrap();
+        let flusher = {
+            let cache = cache.clone();
+            thread::spawn(move || cache.flush())
+        };
+        entered.recv().unwrap();
+
+        assert_eq!(source.inner.load("user_1"), None);
+        assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+        assert_eq!(cache.pending_writes(), 1);
+
+        release.send(()).unwrap();
+        flusher.join().unwrap();
+        assert_eq!(source.inner.load("user_1"), Some("alice".to_string()));
+        assert_eq!(cache.pending_writes(), 0);
+    }
+
+    #[test]
+    fn test_get_many_does_not_block_writers_on_the_source() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let (source, entered, release) = BlockingSource::new();
+        let cache = Arc::new(WriteBehindCache::with_config(backend.clone(), source, slow_config(100)));
+
+        let reader = {
+            let cache = cache.clone();
+            thread::spawn(move || cache.get_many(&["user_1"]))
+        };
+        entered.recv().unwrap();
+
+        let (done, finished) = std::sync::mpsc::channel();
+        let writer = {
+            let cache = cache.clone();
+            thread::spawn(move || {
+                cache.set("user_2", "bob".to_string());
+                done.send(()).unwrap();
+            })
+        };
+        assert!(finished.recv_timeout(Duration::from_secs(5)).is_ok(), "set waited for the source load");
+
+        release.send(()).unwrap();
+        assert_eq!(reader.join().unwrap(), vec![None]);
+        writer.join().unwrap();
+        // Lets the flush on drop through
+        drop(release);
+        drop(cache);
+    }
+
+    /// Panics in `store_many` while `failing` is set, like a database that's down.
+    #[derive(Default)]
+    struct FailingSource {
+        inner: InMemoryDataSource,
+        failing: std::sync::atomic::AtomicBool,
+    }
+
+    impl DataSource for FailingSource {
+        fn load(&self, key: &str) -> Option<String> {
+            self.inner.load(key)
+        }
+
+        fn store(&self, key: &str, value: &str) {
+            self.inner.store(key, value);
+        }
+
+        fn remove(&self, key: &str) {
+            self.inner.remove(key);
+        }
+
+        fn store_many(&self, entries: &[(String, String)]) {
+            if self.failing.load(Ordering::SeqCst) {
+                panic!("source unavailable");
+            }
+            for (key, value) in entries {
+                self.inner.store(key, value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_failed_flushes_are_counted_and_retried() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(FailingSource::default());
+        source.failing.store(true, Ordering::SeqCst);
+        let config = WriteBehindConfig { batch_size: 100, flush_interval: Duration::from_millis(10) };
+        let cache = WriteBehindCache::with_config(backend.clone(), source.clone(), config);
+
+        cache.set("user_1", "alice".to_string());
+        let result = panic::catch_unwind(AssertUnwindSafe(|| cache.flush()));
+        assert!(result.is_err());
+        assert!(cache.failed_flushes() >= 1);
+        assert_eq!(cache.pending_writes(), 1);
+        backend.del("user_1").unwrap();
+        assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+
+        // The flusher thread is still running and gets the write through once the source is back
+        source.failing.store(false, Ordering::SeqCst);
+        for _ in 0..100 {
+            if cache.pending_writes() == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(cache.pending_writes(), 0);
+        assert_eq!(source.inner.load("user_1"), Some("alice".to_string()));
+        cache.set("user_2", "bob".to_string());
+        cache.flush();
+        assert_eq!(source.inner.load("user_2"), Some("bob".to_string()));
+    }
+}
@@ -1,31 +1,260 @@
-use redis::Client;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use crate::cache_strategies::CacheStrategy;
+use super::fill_from_source;
+use crate::{cache_backend::CacheBackend, cache_strategies::CacheStrategy, data_source::DataSource, ttl::TtlPolicy};
 
+/// Writes go to the data source first and then to the cache, so the cache never holds a value
+/// the source doesn't. Reads that miss fall back to the source and repopulate the cache.
 pub struct WriteThroughCache {
-    redis: Client
+    backend: Arc<dyn CacheBackend + Send + Sync>,
+    source: Arc<dyn DataSource + Send + Sync>,
+    // Serializes source + cache writes, and the loads that repopulate the cache, so a write can't land between a
+    // load and the SET that caches what it read
+    write_lock: Mutex<()>,
+    default_ttl: Option<TtlPolicy>,
 }
 
 impl WriteThroughCache {
-    pub fn new(client: Client) -> Self {
-        Self { redis: client }
+    pub fn new(backend: Arc<dyn CacheBackend + Send + Sync>, source: Arc<dyn DataSource + Send + Sync>) -> Self {
+        Self { backend, source, write_lock: Mutex::new(()), default_ttl: None }
+    }
+
+    /// Entries written without an explicit TTL expire according to `policy`.
+    pub fn with_default_ttl(mut self, policy: TtlPolicy) -> Self {
+        self.default_ttl = Some(policy);
+        self
+    }
+
+    fn write(&self, key: &str, value: &str, ttl: Option<Duration>) {
+        let _guard = self.write_lock.lock().unwrap();
+        let previous = self.source.load(key);
+        self.source.store(key, value);
+
+        // Roll the source back so both sides keep agreeing when the cache rejects the write
+        if let Err(e) = self.backend.set(key, value, ttl) {
+            match previous {
+                Some(previous) => self.source.store(key, &previous),
+                None => self.source.remove(key),
+            }
+            panic!("Failed to set value in cache: {}", e);
+        }
+    }
+
+    fn write_many(&self, entries: &[(String, String)]) {
+        let _guard = self.write_lock.lock().unwrap();
+        let previous: Vec<Option<String>> = entries.iter().map(|(key, _)| self.source.load(key)).collect();
+        self.source.store_many(entries);
+
+        let batch: Vec<(&str, &str, _)> = entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str(), self.default_ttl.map(|policy| policy.next_ttl())))
+            .collect();
+        if let Err(e) = self.backend.set_many(&batch) {
+            for ((key, _), previous) in entries.iter().zip(previous) {
+                match previous {
+                    Some(previous) => self.source.store(key, &previous),
+                    None => self.source.remove(key),
+                }
+            }
+            panic!("Failed to set values in cache: {}", e);
+        }
     }
 }
 
 impl CacheStrategy for WriteThroughCache {
     fn get(&self, key: &str) -> Option<String> {
-        let mut con = self.redis.get_connection().expect("Failed to connect to Redis");
-        let result: redis::RedisResult<String> = redis::cmd("GET").arg(key).query(&mut con);
-        result.ok()
+        if let Some(value) = self.backend.get(key).expect("Failed to get value from cache") {
+            return Some(value);
+        }
+
+        let _guard = self.write_lock.lock().unwrap();
+        let value = self.source.load(key)?;
+        let ttl = self.default_ttl.map(|policy| policy.next_ttl());
+        self.backend.set(key, &value, ttl).expect("Failed to set value in cache");
+        Some(value)
     }
 
     fn set(&self, key: &str, value: String) {
-        let mut con = self.redis.get_connection().expect("Failed to connect to Redis");
-        let _: () = redis::cmd("SET").arg(key).arg(value).query(&mut con).expect("Failed to set value in Redis");
+        self.write(key, &value, self.default_ttl.map(|policy| policy.next_ttl()));
+    }
+
+    fn set_with_ttl(&self, key: &str, value: String, ttl: Duration) {
+        self.write(key, &value, Some(ttl));
     }
 
     fn delete(&self, key: &str) {
-        let mut con = self.redis.get_connection().expect("Failed to connect to Redis");
-        let _: () = redis::cmd("DEL").arg(key).query(&mut con).expect("Failed to delete key in Redis");
+        let _guard = self.write_lock.lock().unwrap();
+        self.source.remove(key);
+        self.backend.del(key).expect("Failed to delete key in cache");
+    }
+
+    fn get_many(&self, keys: &[&str]) -> Vec<Option<String>> {
+        let mut values = self.backend.mget(keys).expect("Failed to get values from cache");
+        if values.iter().any(Option::is_none) {
+            let _guard = self.write_lock.lock().unwrap();
+            fill_from_source(&*self.backend, &self.source, keys, &mut values, self.default_ttl);
+        }
+        values
+    }
+
+    fn set_many(&self, entries: Vec<(String, String)>) {
+        self.write_many(&entries);
+    }
+
+    fn delete_many(&self, keys: &[&str]) {
+        let _guard = self.write_lock.lock().unwrap();
+        for key in keys {
+            self.source.remove(key);
+        }
+        self.backend.del_many(keys).expect("Failed to delete keys in cache");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backends::{in_memory::InMemoryBackend, redis_backend::RedisBackend},
+        data_source::InMemoryDataSource,
+        test_support::{BlockingSource, CountingSource},
+    };
+
+    #[test]
+    fn test_set_writes_source_and_redis() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(InMemoryDataSource::new());
+        let cache = WriteThroughCache::new(backend.clone(), source.clone());
+
+        cache.set("user_1", "alice".to_string());
+
+        assert_eq!(source.load("user_1"), Some("alice".to_string()));
+        assert_eq!(backend.get("user_1").unwrap(), Some("alice".to_string()));
+    }
+
+    #[test]
+    fn test_get_after_set_is_served_from_redis() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(CountingSource::new(InMemoryDataSource::new()));
+        let cache = WriteThroughCache::new(backend.clone(), source.clone());
+
+        cache.set("user_1", "alice".to_string());
+        let loads_after_set = source.loads();
+
+        assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+        assert_eq!(source.loads(), loads_after_set);
+    }
+
+    #[test]
+    fn test_failed_cache_write_rolls_back_source() {
+        let source = Arc::new(InMemoryDataSource::new());
+        source.store("user_1", "alice");
+        // Nothing listens on the discard port, so every Redis call fails
+        let backend = RedisBackend::open("redis://127.0.0.1:9/").unwrap();
+        let cache = WriteThroughCache::new(Arc::new(backend), source.clone());
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cache.set("user_1", "bob".to_string())));
+
+        assert!(result.is_err());
+        assert_eq!(source.load("user_1"), Some("alice".to_string()));
+    }
+
+    #[test]
+    fn test_delete_removes_from_source_and_redis() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(InMemoryDataSource::new());
+        let cache = WriteThroughCache::new(backend.clone(), source.clone());
+
+        cache.set("user_1", "alice".to_string());
+        cache.delete("user_1");
+
+        assert_eq!(source.load("user_1"), None);
+        assert_eq!(backend.get("user_1").unwrap(), None);
+        assert_eq!(cache.get("user_1"), None);
+    }
+
+    #[test]
+    fn test_set_uses_the_default_ttl() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let cache = WriteThroughCache::new(backend.clone(), Arc::new(InMemoryDataSource::new()))
+            .with_default_ttl(TtlPolicy::fixed(Duration::from_secs(60)));
+
+        cache.set("user_1", "alice".to_string());
+
+        let ttl = backend.ttl("user_1").unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(55) && ttl <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_set_with_ttl_expires_cached_copy_only() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(InMemoryDataSource::new());
+        let cache = WriteThroughCache::new(backend.clone(), source.clone());
+
+        cache.set_with_ttl("user_1", "alice".to_string(), Duration::from_millis(50));
+        std::thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(backend.get("user_1").unwrap(), None);
+        assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+    }
+
+    #[test]
+    fn test_failed_batch_write_rolls_back_source() {
+        let source = Arc::new(InMemoryDataSource::new());
+        source.store("user_1", "alice");
+        let backend = RedisBackend::open("redis://127.0.0.1:9/").unwrap();
+        let cache = WriteThroughCache::new(Arc::new(backend), source.clone());
+
+        let entries = vec![("user_1".to_string(), "bob".to_string()), ("user_2".to_string(), "carol".to_string())];
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cache.set_many(entries)));
+
+        assert!(result.is_err());
+        assert_eq!(source.load("user_1"), Some("alice".to_string()));
+        assert_eq!(source.load("user_2"), None);
+    }
+
+    #[test]
+    fn test_get_many_keeps_ttl_of_hits() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let source = Arc::new(InMemoryDataSource::new());
+        source.store("user_2", "bob");
+        let cache = WriteThroughCache::new(backend.clone(), source.clone());
+
+        cache.set_with_ttl("user_1", "alice".to_string(), Duration::from_secs(5));
+        let values = cache.get_many(&["user_1", "user_2"]);
+
+        assert_eq!(values, vec![Some("alice".to_string()), Some("bob".to_string())]);
+        let ttl = backend.ttl("user_1").unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(4) && ttl <= Duration::from_secs(5));
+        assert_eq!(backend.get("user_2").unwrap(), Some("bob".to_string()));
+    }
+
+    #[test]
+    fn test_set_during_a_miss_isnt_overwritten_by_the_old_value() {
+        let backend = Arc::new(InMemoryBackend::new());
+        let (source, entered, release) = BlockingSource::new();
+        source.inner.store("user_1", "alice");
+        let cache = Arc::new(WriteThroughCache::new(backend.clone(), source.clone()));
+
+        let reader = {
+            let cache = cache.clone();
+            std::thread::spawn(move || cache.get("user_1"))
+        };
+        entered.recv().unwrap();
+        source.set_blocking(false);
+        let writer = {
+            let cache = cache.clone();
+            std::thread::spawn(move || cache.set_with_ttl("user_1", "bob".to_string(), Duration::from_secs(60)))
+        };
+        // The write waits for the miss to finish caching what it read
+        std::thread::sleep(Duration::from_millis(50));
+        release.send(()).unwrap();
+
+        assert_eq!(reader.join().unwrap(), Some("alice".to_string()));
+        writer.join().unwrap();
+        assert_eq!(backend.get("user_1").unwrap(), Some("bob".to_string()));
+        assert_eq!(cache.get("user_1"), Some("bob".to_string()));
     }
 }
@@ -0,0 +1,181 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cache_strategies::CacheStrategy,
+    data_source::{DataSource, InMemoryDataSource},
+};
+
+/// Wraps a `DataSource` and counts how often each operation reaches it.
+pub struct CountingSource<S> {
+    pub inner: S,
+    loads: AtomicUsize,
+    stores: AtomicUsize,
+    batches: AtomicUsize,
+}
+
+impl<S> CountingSource<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner, loads: AtomicUsize::new(0), stores: AtomicUsize::new(0), batches: AtomicUsize::new(0) }
+    }
+
+    pub fn loads(&self) -> usize {
+        self.loads.load(Ordering::SeqCst)
+    }
+
+    pub fn stores(&self) -> usize {
+        self.stores.load(Ordering::SeqCst)
+    }
+
+    pub fn batches(&self) -> usize {
+        self.batches.load(Ordering::SeqCst)
+    }
+}
+
+impl<S: DataSource> DataSource for CountingSource<S> {
+    fn load(&self, key: &str) -> Option<String> {
+        self.loads.fetch_add(1, Ordering::SeqCst);
+        self.inner.load(key)
+    }
+
+    fn store(&self, key: &str, value: &str) {
+        self.stores.fetch_add(1, Ordering::SeqCst);
+        self.inner.store(key, value);
+    }
+
+    fn remove(&self, key: &str) {
+        self.inner.remove(key);
+    }
+
+    fn store_many(&self, entries: &[(String, String)]) {
+        self.batches.fetch_add(1, Ordering::SeqCst);
+        for (key, value) in entries {
+            self.store(key, value);
+        }
+    }
+}
+
+/// A `CacheStrategy` kept entirely in process, for tests of code layered over a strategy.
+/// Clones share the same entries, like two processes talking to one Redis.
+#[derive(Clone, Default)]
+pub struct MapCache {
+    entries: Arc<Mutex<HashMap<String, MapEntry>>>,
+}
+
+type MapEntry = (String, Option<Instant>);
+
+impl CacheStrategy for MapCache {
+    fn get(&self, key: &str) -> Option<String> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((_, Some(expires_at))) if *expires_at <= Instant::now() => None,
+            Some((value, _)) => Some(value.clone()),
+            None => None,
+        }
+    }
+
+    fn set(&self, key: &str, value: String) {
+        self.entries.lock().unwrap().insert(key.to_string(), (value, None));
+    }
+
+    fn set_with_ttl(&self, key: &str, value: String, ttl: Duration) {
+        self.entries.lock().unwrap().insert(key.to_string(), (value, Some(Instant::now() + ttl)));
+    }
+
+    fn delete(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+}
+
+/// A `DataSource` that takes a while to answer, like a slow database query.
+pub struct SlowSource {
+    pub inner: CountingSource<InMemoryDataSource>,
+    delay: Duration,
+}
+
+impl SlowSource {
+    pub fn new(delay: Duration) -> Self {
+        Self { inner: CountingSource::new(InMemoryDataSource::new()), delay }
+    }
+}
+
+impl DataSource for SlowSource {
+    fn load(&self, key: &str) -> Option<String> {
+        thread::sleep(self.delay);
+        self.inner.load(key)
+    }
+
+    fn store(&self, key: &str, value: &str) {
+        self.inner.store(key, value);
+    }
+
+    fn remove(&self, key: &str) {
+        self.inner.remove(key);
+    }
+}
+
+/// Blocks in `load` or `store_many` until the test sends on `release` or drops it, announcing each call on
+/// `entered`, so a test can act while a strategy is between the source and the cache.
+pub struct BlockingSource {
+    pub inner: InMemoryDataSource,
+    blocking: AtomicBool,
+    entered: Mutex<Sender<()>>,
+    release: Mutex<Receiver<()>>,
+}
+
+impl BlockingSource {
+    /// The source, then the `entered` and `release` ends.
+    pub fn new() -> (Arc<Self>, Receiver<()>, Sender<()>) {
+        let (entered, entered_rx) = mpsc::channel();
+        let (release_tx, release) = mpsc::channel();
+        let source = Self {
+            inner: InMemoryDataSource::new(),
+            blocking: AtomicBool::new(true),
+            entered: Mutex::new(entered),
+            release: Mutex::new(release),
+        };
+        (Arc::new(source), entered_rx, release_tx)
+    }
+
+    /// Calls made while not blocking go straight through.
+    pub fn set_blocking(&self, blocking: bool) {
+        self.blocking.store(blocking, Ordering::SeqCst);
+    }
+
+    fn block(&self) {
+        if self.blocking.load(Ordering::SeqCst) {
+            let _ = self.entered.lock().unwrap().send(());
+            let _ = self.release.lock().unwrap().recv();
+        }
+    }
+}
+
+impl DataSource for BlockingSource {
+    fn load(&self, key: &str) -> Option<String> {
+        self.block();
+        self.inner.load(key)
+    }
+
+    fn store(&self, key: &str, value: &str) {
+        self.inner.store(key, value);
+    }
+
+    fn remove(&self, key: &str) {
+        self.inner.remove(key);
+    }
+
+    fn store_many(&self, entries: &[(String, String)]) {
+        self.block();
+        for (key, value) in entries {
+            self.inner.store(key, value);
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// How long cached entries live when the caller doesn't pass an explicit TTL.
+/// The random jitter spreads out expiry of keys written together, so they don't all miss at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TtlPolicy {
+    pub base: Duration,
+    pub jitter: Duration,
+}
+
+impl TtlPolicy {
+    pub fn fixed(base: Duration) -> Self {
+        Self { base, jitter: Duration::ZERO }
+    }
+
+    pub fn with_jitter(base: Duration, jitter: Duration) -> Self {
+        Self { base, jitter }
+    }
+
+    /// Picks a TTL in `base..=base + jitter`.
+    pub fn next_ttl(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.base;
+        }
+        let extra = rand::rng().random_range(0..=self.jitter.as_millis() as u64);
+        self.base + Duration::from_millis(extra)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_policy_has_no_jitter() {
+        let policy = TtlPolicy::fixed(Duration::from_secs(30));
+        assert_eq!(policy.next_ttl(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_jitter_stays_within_bounds() {
+        let policy = TtlPolicy::with_jitter(Duration::from_secs(30), Duration::from_secs(5));
+        for _ in 0..1000 {
+            let ttl = policy.next_ttl();
+            assert!(ttl >= Duration::from_secs(30) && ttl <= Duration::from_secs(35));
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::{marker::PhantomData, time::Duration};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    cache_context::CacheContext,
+    codecs::{Codec, CodecError},
+};
+
+// Every stored value starts with one of these markers so reads know how to unpack it
+const PLAIN: char = 'p';
+const BINARY: char = 'b';
+const COMPRESSED: char = 'z';
+
+/// Compresses encoded values with zstd once they reach `threshold` bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct Compression {
+    pub threshold: usize,
+    pub level: i32,
+}
+
+impl Compression {
+    pub fn above(threshold: usize) -> Self {
+        Self { threshold, level: zstd::DEFAULT_COMPRESSION_LEVEL }
+    }
+}
+
+/// Stores values of type `T` through a `CacheContext`, encoding them with `C`.
+pub struct TypedCache<'a, T, C> {
+    context: &'a CacheContext,
+    codec: C,
+    compression: Option<Compression>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<'a, T: Serialize + DeserializeOwned, C: Codec> TypedCache<'a, T, C> {
+    pub fn new(context: &'a CacheContext, codec: C) -> Self {
+        Self { context, codec, compression: None, _marker: PhantomData }
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<T>, CodecError> {
+        match self.context.get(key) {
+            Some(stored) => self.codec.decode(&unpack(&stored)?).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set(&self, key: &str, value: &T) -> Result<(), CodecError> {
+        self.context.set(key, self.pack(value)?);
+        Ok(())
+    }
+
+    pub fn set_with_ttl(&self, key: &str, value: &T, ttl: Duration) -> Result<(), CodecError> {
+        self.context.set_with_ttl(key, self.pack(value)?, ttl);
+        Ok(())
+    }
+
+    pub fn delete(&self, key: &str) {
+        self.context.delete(key);
+    }
+
+    fn pack(&self, value: &T) -> Result<String, CodecError> {
+        let bytes = self.codec.encode(value)?;
+
+        if let Some(compression) = self.compression.filter(|c| bytes.len() >= c.threshold) {
+            let compressed = zstd::encode_all(bytes.as_slice(), compression.level).map_err(CodecError::Compression)?;
+            return Ok(format!("{}{}", COMPRESSED, STANDARD.encode(compressed)));
+        }
+
+        match String::from_utf8(bytes) {
+            Ok(text) => Ok(format!("{}{}", PLAIN, text)),
+            Err(e) => Ok(format!("{}{}", BINARY, STANDARD.encode(e.into_bytes()))),
+        }
+    }
+}
+
+fn unpack(stored: &str) -> Result<Vec<u8>, CodecError> {
+    let mut chars = stored.chars();
+    let marker = chars.next();
+    let body = chars.as_str();
+
+    let decode_base64 = |body: &str| STANDARD.decode(body).map_err(|e| CodecError::Decode(e.to_string()));
+    match marker {
+        Some(PLAIN) => Ok(body.as_bytes().to_vec()),
+        Some(BINARY) => decode_base64(body),
+        Some(COMPRESSED) => zstd::decode_all(decode_base64(body)?.as_slice()).map_err(CodecError::Compression),
+        _ => Err(CodecError::Decode(format!("Unrecognized value format in '{}'", stored))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{
+        codecs::{BincodeCodec, JsonCodec, MessagePackCodec},
+        test_support::MapCache,
+    };
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct User {
+        id: u64,
+        name: String,
+        tags: Vec<String>,
+    }
+
+    fn user() -> User {
+        User { id: 123, name: "elizielx".to_string(), tags: vec!["admin".to_string(), "beta".to_string()] }
+    }
+
+    fn round_trip<C: Codec>(codec: C) {
+        let context = CacheContext::new(Box::new(MapCache::default()));
+        let cache = TypedCache::<User, _>::new(&context, codec);
+
+        cache.set("user_123", &user()).unwrap();
+
+        assert_eq!(cache.get("user_123").unwrap(), Some(user()));
+        assert_eq!(cache.get("user_456").unwrap(), None);
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        round_trip(JsonCodec);
+    }
+
+    #[test]
+    fn test_bincode_round_trip() {
+        round_trip(BincodeCodec);
+    }
+
+    #[test]
+    fn test_message_pack_round_trip() {
+        round_trip(MessagePackCodec);
+    }
+
+    #[test]
+    fn test_json_is_stored_readable() {
+        let context = CacheContext::new(Box::new(MapCache::default()));
+        let cache = TypedCache::<User, _>::new(&context, JsonCodec);
+
+        cache.set("user_123", &user()).unwrap();
+
+        let stored = context.get("user_123").unwrap();
+        assert!(stored.starts_with("p{\"id\":123"));
+    }
+
+    #[test]
+    fn test_large_values_are_compressed() {
+        let context = CacheContext::new(Box::new(MapCache::default()));
+        let cache = TypedCache::<Vec<String>, _>::new(&context, JsonCodec).with_compression(Compression::above(256));
+        let small = vec!["a".to_string()];
+        let large = vec!["repeated value".to_string(); 500];
+
+        cache.set("small", &small).unwrap();
+        cache.set("large", &large).unwrap();
+
+        assert!(context.get("small").unwrap().starts_with(PLAIN));
+        let stored = context.get("large").unwrap();
+        assert!(stored.starts_with(COMPRESSED));
+        assert!(stored.len() < serde_json::to_vec(&large).unwrap().len());
+        assert_eq!(cache.get("large").unwrap(), Some(large));
+    }
+
+    #[test]
+    fn test_undecodable_value_is_an_error() {
+        let context = CacheContext::new(Box::new(MapCache::default()));
+        context.set("user_123", "not a typed value");
+        let cache = TypedCache::<User, _>::new(&context, JsonCodec);
+
+        assert!(cache.get("user_123").is_err());
+    }
+
+    #[test]
+    fn test_set_with_ttl_is_forwarded() {
+        let context = CacheContext::new(Box::new(MapCache::default()));
+        let cache = TypedCache::<User, _>::new(&context, BincodeCodec);
+
+        cache.set_with_ttl("user_123", &user(), Duration::from_millis(20)).unwrap();
+        assert!(cache.get("user_123").unwrap().is_some());
+
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(cache.get("user_123").unwrap(), None);
+    }
+}
@@ -0,0 +1,239 @@
+mod common;
+
+use std::{
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::RedisStandIn;
+use redis_strategy_pattern::{
+    backends::{in_memory::InMemoryBackend, redis_backend::RedisBackend},
+    cache_backend::CacheBackend,
+    cache_strategies::CacheStrategy,
+    data_source::{DataSource, InMemoryDataSource},
+    strategies::{
+        cache_aside::CacheAsideCache, lazy_invalidation::LazyInvalidationCache, read_through::ReadThroughCache,
+        write_through::WriteThroughCache,
+    },
+};
+
+type Backend = Arc<dyn CacheBackend + Send + Sync>;
+
+fn wait_until(condition: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+fn read_through_loads_misses_from_source(backend: Backend) {
+    let source = Arc::new(InMemoryDataSource::new());
+    source.store("user_1", "alice");
+    let cache = ReadThroughCache::new(backend.clone(), source.clone());
+
+    assert_eq!(backend.get("user_1").unwrap(), None);
+    assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+    assert_eq!(backend.get("user_1").unwrap(), Some("alice".to_string()));
+    assert_eq!(cache.get("user_2"), None);
+}
+
+fn read_through_set_evicts_and_delete_removes(backend: Backend) {
+    let source = Arc::new(InMemoryDataSource::new());
+    let cache = ReadThroughCache::new(backend.clone(), source.clone());
+
+    cache.set("user_1", "alice".to_string());
+    assert_eq!(backend.get("user_1").unwrap(), None);
+    assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+
+    cache.delete("user_1");
+    assert_eq!(source.load("user_1"), None);
+    assert_eq!(backend.get("user_1").unwrap(), None);
+}
+
+fn write_through_writes_source_and_cache(backend: Backend) {
+    let source = Arc::new(InMemoryDataSource::new());
+    let cache = WriteThroughCache::new(backend.clone(), source.clone());
+
+    cache.set("user_1", "alice".to_string());
+
+    assert_eq!(source.load("user_1"), Some("alice".to_string()));
+    assert_eq!(backend.get("user_1").unwrap(), Some("alice".to_string()));
+    assert_eq!(backend.ttl("user_1").unwrap(), None);
+
+    cache.delete("user_1");
+    assert_eq!(source.load("user_1"), None);
+    assert_eq!(cache.get("user_1"), None);
+}
+
+fn write_through_ttl_expires_cached_copy(backend: Backend) {
+    let source = Arc::new(InMemoryDataSource::new());
+    let cache = WriteThroughCache::new(backend.clone(), source.clone());
+
+    cache.set_with_ttl("user_1", "alice".to_string(), Duration::from_millis(50));
+    assert!(backend.ttl("user_1").unwrap().is_some());
+
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(backend.get("user_1").unwrap(), None);
+    assert_eq!(cache.get("user_1"), Some("alice".to_string()));
+}
+
+fn lazy_invalidation_reaches_other_instances(backend: Backend) {
+    let a = LazyInvalidationCache::new(backend.clone());
+    let b = LazyInvalidationCache::new(backend.clone());
+
+    a.set("user_1", "alice".to_string());
+    assert_eq!(b.get("user_1"), Some("alice".to_string()));
+
+    a.invalidate("user_1");
+    assert!(wait_until(|| b.get("user_1").is_none()));
+    assert!(backend.ttl("user_1").unwrap().is_some());
+
+    b.set("user_1", "bob".to_string());
+    assert!(wait_until(|| a.get("user_1") == Some("bob".to_string())));
+}
+
+fn lazy_invalidation_delete_removes_value(backend: Backend) {
+    let a = LazyInvalidationCache::new(backend.clone());
+    let b = LazyInvalidationCache::new(backend.clone());
+
+    a.set("user_1", "alice".to_string());
+    a.delete("user_1");
+
+    assert_eq!(backend.get("user_1").unwrap(), None);
+    assert!(wait_until(|| b.invalidated_keys() == 1));
+}
+
+fn backend_batches_keep_per_key_ttls(backend: Backend) {
+    backend.set_many(&[("a", "1", None), ("b", "2", None)]).unwrap();
+    backend.set_many(&[("c", "3", Some(Duration::from_secs(60))), ("d", "4", None)]).unwrap();
+
+    assert_eq!(
+        backend.mget(&["a", "b", "missing", "c", "d"]).unwrap(),
+        vec![Some("1".to_string()), Some("2".to_string()), None, Some("3".to_string()), Some("4".to_string())]
+    );
+    assert!(backend.ttl("c").unwrap().is_some());
+    assert_eq!(backend.ttl("d").unwrap(), None);
+
+    assert_eq!(backend.del_many(&["a", "c", "missing"]).unwrap(), 2);
+    assert_eq!(backend.mget(&[]).unwrap(), Vec::<Option<String>>::new());
+    assert_eq!(backend.del_many(&[]).unwrap(), 0);
+}
+
+fn read_through_get_many_fills_misses(backend: Backend) {
+    let source = Arc::new(InMemoryDataSource::new());
+    source.store("user_2", "bob");
+    let cache = ReadThroughCache::new(backend.clone(), source.clone());
+    backend.set("user_1", "alice", None).unwrap();
+
+    assert_eq!(
+        cache.get_many(&["user_1", "user_2", "user_3"]),
+        vec![Some("alice".to_string()), Some("bob".to_string()), None]
+    );
+    assert_eq!(backend.get("user_2").unwrap(), Some("bob".to_string()));
+}
+
+fn write_through_batches_reach_source_and_cache(backend: Backend) {
+    let source = Arc::new(InMemoryDataSource::new());
+    let cache = WriteThroughCache::new(backend.clone(), source.clone());
+
+    cache.set_many(vec![("user_1".to_string(), "alice".to_string()), ("user_2".to_string(), "bob".to_string())]);
+    assert_eq!(source.len(), 2);
+    assert_eq!(backend.mget(&["user_1", "user_2"]).unwrap(), vec![Some("alice".to_string()), Some("bob".to_string())]);
+
+    cache.delete_many(&["user_1", "user_2"]);
+    assert!(source.is_empty());
+    assert_eq!(cache.get_many(&["user_1", "user_2"]), vec![None, None]);
+}
+
+fn cache_aside_remembers_missing_keys(backend: Backend) {
+    let source = Arc::new(InMemoryDataSource::new());
+    let cache = CacheAsideCache::new(backend.clone(), source.clone());
+
+    assert_eq!(cache.get("user_404"), None);
+    assert!(backend.get("user_404").unwrap().is_some());
+    assert!(backend.ttl("user_404").unwrap().is_some());
+
+    cache.set("user_404", "found".to_string());
+    assert_eq!(cache.get("user_404"), Some("found".to_string()));
+}
+
+fn cache_aside_invalidates_tags(backend: Backend) {
+    let source = Arc::new(InMemoryDataSource::new());
+    let cache = CacheAsideCache::new(backend.clone(), source.clone());
+
+    cache.set_tagged("user:123:profile", "alice".to_string(), &["user:123"]);
+    cache.set_tagged("user:123:orders", "[]".to_string(), &["user:123"]);
+    cache.get_many(&["user:123:profile", "user:123:orders"]);
+
+    let mut members = backend.smembers("tag:user:123").unwrap();
+    members.sort();
+    assert_eq!(members, vec!["user:123:orders".to_string(), "user:123:profile".to_string()]);
+
+    assert_eq!(cache.invalidate_tag("user:123"), 2);
+    assert_eq!(backend.mget(&["user:123:profile", "user:123:orders"]).unwrap(), vec![None, None]);
+    assert!(backend.smembers("tag:user:123").unwrap().is_empty());
+
+    // Tags outlive the entries written under them
+    cache.set_with_ttl_tagged("session:1", "a".to_string(), Duration::from_millis(20), &["sessions"]);
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(backend.get("session:1").unwrap(), None);
+    assert_eq!(backend.smembers("tag:sessions").unwrap(), vec!["session:1".to_string()]);
+    assert_eq!(cache.get("session:1"), Some("a".to_string()));
+    assert_eq!(cache.invalidate_tag("sessions"), 1);
+    assert!(backend.smembers("tag:sessions").unwrap().is_empty());
+}
+
+macro_rules! run_against {
+    ($backend:ident, $make:expr, [$($test:ident),* $(,)?]) => {
+        mod $backend {
+            $(
+                #[test]
+                fn $test() {
+                    super::$test($make());
+                }
+            )*
+        }
+    };
+}
+
+fn in_memory() -> Backend {
+    Arc::new(InMemoryBackend::new())
+}
+
+fn redis() -> Backend {
+    let server = RedisStandIn::start();
+    Arc::new(RedisBackend::new(server.client()))
+}
+
+run_against!(in_memory, super::in_memory, [
+    read_through_loads_misses_from_source,
+    read_through_set_evicts_and_delete_removes,
+    write_through_writes_source_and_cache,
+    write_through_ttl_expires_cached_copy,
+    lazy_invalidation_reaches_other_instances,
+    lazy_invalidation_delete_removes_value,
+    backend_batches_keep_per_key_ttls,
+    read_through_get_many_fills_misses,
+    write_through_batches_reach_source_and_cache,
+    cache_aside_remembers_missing_keys,
+    cache_aside_invalidates_tags,
+]);
+
+run_against!(redis, super::redis, [
+    read_through_loads_misses_from_source,
+    read_through_set_evicts_and_delete_removes,
+    write_through_writes_source_and_cache,
+    write_through_ttl_expires_cached_copy,
+    lazy_invalidation_reaches_other_instances,
+    lazy_invalidation_delete_removes_value,
+    backend_batches_keep_per_key_ttls,
+    read_through_get_many_fills_misses,
+    write_through_batches_reach_source_and_cache,
+    cache_aside_remembers_missing_keys,
+    cache_aside_invalidates_tags,
+]);
@@ -0,0 +1,327 @@
+// A tiny RESP server that understands just enough commands to run `RedisBackend`
+// in the integration tests without a live Redis instance.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use redis::Client;
+
+enum Value {
+    Bytes(Vec<u8>),
+    Set(BTreeSet<Vec<u8>>),
+}
+
+struct Entry {
+    value: Value,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= Instant::now())
+    }
+}
+
+#[derive(Default)]
+struct Store {
+    entries: HashMap<String, Entry>,
+}
+
+impl Store {
+    fn get(&mut self, key: &str) -> Option<&Entry> {
+        if self.entries.get(key).is_some_and(Entry::is_expired) {
+            self.entries.remove(key);
+        }
+        self.entries.get(key)
+    }
+}
+
+enum Reply {
+    Ok,
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Error(String),
+    Array(Vec<Reply>),
+    // Several top-level replies to one command, as SUBSCRIBE sends one per channel
+    Many(Vec<Reply>),
+}
+
+impl Reply {
+    fn text(s: &str) -> Self {
+        Reply::Bulk(s.as_bytes().to_vec())
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Ok => out.extend_from_slice(b"+OK\r\n"),
+            Reply::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(bytes) => {
+                out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Nil => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Error(message) => out.extend_from_slice(format!("-ERR {}\r\n", message).as_bytes()),
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                items.iter().for_each(|item| item.write_to(out));
+            }
+            Reply::Many(replies) => replies.iter().for_each(|reply| reply.write_to(out)),
+        }
+    }
+}
+
+type Writer = Arc<Mutex<TcpStream>>;
+
+#[derive(Default)]
+struct Server {
+    store: Mutex<Store>,
+    // channel -> (connection id, writer) of every subscriber
+    channels: Mutex<HashMap<String, Vec<(usize, Writer)>>>,
+}
+
+pub struct RedisStandIn {
+    port: u16,
+}
+
+impl RedisStandIn {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind stand-in server");
+        let port = listener.local_addr().unwrap().port();
+        let server = Arc::new(Server::default());
+
+        thread::spawn(move || {
+            for (id, stream) in listener.incoming().flatten().enumerate() {
+                let server = server.clone();
+                thread::spawn(move || serve(id, stream, server));
+            }
+        });
+
+        Self { port }
+    }
+
+    pub fn client(&self) -> Client {
+        Client::open(format!("redis://127.0.0.1:{}/", self.port)).unwrap()
+    }
+}
+
+fn serve(id: usize, stream: TcpStream, server: Arc<Server>) {
+    let writer = Arc::new(Mutex::new(stream.try_clone().unwrap()));
+    let mut reader = BufReader::new(stream);
+
+    while let Some(args) = read_command(&mut reader) {
+        let reply = execute(&args, &server, id, &writer);
+        let mut out = Vec::new();
+        reply.write_to(&mut out);
+        if writer.lock().unwrap().write_all(&out).is_err() {
+            break;
+        }
+    }
+
+    unsubscribe_all(&server, id);
+}
+
+fn read_line(reader: &mut impl BufRead) -> Option<String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim_end_matches("\r\n").to_string()),
+    }
+}
+
+fn read_command(reader: &mut impl BufRead) -> Option<Vec<Vec<u8>>> {
+    let header = read_line(reader)?;
+    let count: usize = header.strip_prefix('*')?.parse().ok()?;
+
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len: usize = read_line(reader)?.strip_prefix('$')?.parse().ok()?;
+        let mut buf = vec![0u8; len + 2];
+        reader.read_exact(&mut buf).ok()?;
+        buf.truncate(len);
+        args.push(buf);
+    }
+    Some(args)
+}
+
+fn unsubscribe_all(server: &Server, id: usize) -> Vec<String> {
+    let mut channels = server.channels.lock().unwrap();
+    let mut left = Vec::new();
+    for (channel, subscribers) in channels.iter_mut() {
+        let before = subscribers.len();
+        subscribers.retain(|(subscriber, _)| *subscriber != id);
+        if subscribers.len() != before {
+            left.push(channel.clone());
+        }
+    }
+    left
+}
+
+fn execute(args: &[Vec<u8>], server: &Server, id: usize, writer: &Writer) -> Reply {
+    let text = |i: usize| String::from_utf8_lossy(&args[i]).into_owned();
+    let name = text(0).to_ascii_uppercase();
+
+    match name.as_str() {
+        "PING" | "CLIENT" => Reply::Ok,
+        "SUBSCRIBE" => {
+            let mut channels = server.channels.lock().unwrap();
+            let replies = (1..args.len())
+                .map(|i| {
+                    channels.entry(text(i)).or_default().push((id, writer.clone()));
+                    let count = channels.values().filter(|subs| subs.iter().any(|(s, _)| *s == id)).count();
+                    Reply::Array(vec![Reply::text("subscribe"), Reply::text(&text(i)), Reply::Integer(count as i64)])
+                })
+                .collect();
+            Reply::Many(replies)
+        }
+        "UNSUBSCRIBE" | "PUNSUBSCRIBE" => {
+            let kind = Reply::text(&name.to_ascii_lowercase());
+            let left = if name == "UNSUBSCRIBE" { unsubscribe_all(server, id) } else { Vec::new() };
+            if left.is_empty() {
+                return Reply::Array(vec![kind, Reply::Nil, Reply::Integer(0)]);
+            }
+            let replies = left
+                .iter()
+                .enumerate()
+                .map(|(i, channel)| {
+                    let remaining = (left.len() - i - 1) as i64;
+                    Reply::Array(vec![Reply::text(&name.to_ascii_lowercase()), Reply::text(channel), Reply::Integer(remaining)])
+                })
+                .collect();
+            Reply::Many(replies)
+        }
+        "PUBLISH" if args.len() == 3 => {
+            let channel = text(1);
+            let subscribers = server.channels.lock().unwrap().get(&channel).cloned().unwrap_or_default();
+            let mut message = Vec::new();
+            Reply::Array(vec![Reply::text("message"), Reply::text(&channel), Reply::Bulk(args[2].clone())])
+                .write_to(&mut message);
+            for (_, subscriber) in &subscribers {
+                let _ = subscriber.lock().unwrap().write_all(&message);
+            }
+            Reply::Integer(subscribers.len() as i64)
+        }
+        _ => execute_keyspace(&name, args, &mut server.store.lock().unwrap()),
+    }
+}
+
+fn execute_keyspace(name: &str, args: &[Vec<u8>], store: &mut Store) -> Reply {
+    let text = |i: usize| String::from_utf8_lossy(&args[i]).into_owned();
+
+    match name {
+        "GET" if args.len() == 2 => match store.get(&text(1)).map(|entry| &entry.value) {
+            Some(Value::Bytes(value)) => Reply::Bulk(value.clone()),
+            Some(Value::Set(_)) => Reply::Error("WRONGTYPE".to_string()),
+            None => Reply::Nil,
+        },
+        "MGET" if args.len() >= 2 => Reply::Array(
+            (1..args.len())
+                .map(|i| match store.get(&text(i)).map(|entry| &entry.value) {
+                    Some(Value::Bytes(value)) => Reply::Bulk(value.clone()),
+                    _ => Reply::Nil,
+                })
+                .collect(),
+        ),
+        "MSET" if args.len() >= 3 && args.len() % 2 == 1 => {
+            for i in (1..args.len()).step_by(2) {
+                store.entries.insert(text(i), Entry { value: Value::Bytes(args[i + 1].clone()), expires_at: None });
+            }
+            Reply::Ok
+        }
+        "SET" if args.len() >= 3 => {
+            let key = text(1);
+            let mut expires_at = None;
+            let mut only_if_absent = false;
+            let mut i = 3;
+            while i < args.len() {
+                match text(i).to_ascii_uppercase().as_str() {
+                    "EX" => {
+                        let secs: u64 = text(i + 1).parse().unwrap_or(0);
+                        expires_at = Some(Instant::now() + Duration::from_secs(secs));
+                        i += 1;
+                    }
+                    "PX" => {
+                        let millis: u64 = text(i + 1).parse().unwrap_or(0);
+                        expires_at = Some(Instant::now() + Duration::from_millis(millis));
+                        i += 1;
+                    }
+                    "NX" => only_if_absent = true,
+                    other => return Reply::Error(format!("unsupported SET option '{}'", other)),
+                }
+                i += 1;
+            }
+
+            if only_if_absent && store.get(&key).is_some() {
+                return Reply::Nil;
+            }
+            store.entries.insert(key, Entry { value: Value::Bytes(args[2].clone()), expires_at });
+            Reply::Ok
+        }
+        "SADD" if args.len() >= 3 => {
+            let key = text(1);
+            store.get(&key);
+            let entry =
+                store.entries.entry(key).or_insert_with(|| Entry { value: Value::Set(BTreeSet::new()), expires_at: None });
+            let Value::Set(members) = &mut entry.value else {
+                return Reply::Error("WRONGTYPE".to_string());
+            };
+            let added = args[2..].iter().filter(|member| members.insert(member.to_vec())).count();
+            Reply::Integer(added as i64)
+        }
+        "SMEMBERS" if args.len() == 2 => match store.get(&text(1)).map(|entry| &entry.value) {
+            Some(Value::Set(members)) => Reply::Array(members.iter().map(|member| Reply::Bulk(member.clone())).collect()),
+            Some(Value::Bytes(_)) => Reply::Error("WRONGTYPE".to_string()),
+            None => Reply::Array(Vec::new()),
+        },
+        "SREM" if args.len() >= 3 => {
+            let key = text(1);
+            store.get(&key);
+            let Some(entry) = store.entries.get_mut(&key) else {
+                return Reply::Integer(0);
+            };
+            let Value::Set(members) = &mut entry.value else {
+                return Reply::Error("WRONGTYPE".to_string());
+            };
+            let removed = args[2..].iter().filter(|member| members.remove(member.as_slice())).count();
+            if members.is_empty() {
+                store.entries.remove(&key);
+            }
+            Reply::Integer(removed as i64)
+        }
+        "DEL" => {
+            let removed = (1..args.len())
+                .filter(|&i| {
+                    let key = text(i);
+                    store.get(&key).is_some() && store.entries.remove(&key).is_some()
+                })
+                .count();
+            Reply::Integer(removed as i64)
+        }
+        "PEXPIRE" if args.len() == 3 => {
+            let millis: u64 = text(2).parse().unwrap_or(0);
+            match store.entries.get_mut(&text(1)).filter(|entry| !entry.is_expired()) {
+                Some(entry) => {
+                    entry.expires_at = Some(Instant::now() + Duration::from_millis(millis));
+                    Reply::Integer(1)
+                }
+                None => Reply::Integer(0),
+            }
+        }
+        "PTTL" if args.len() == 2 => match store.get(&text(1)) {
+            Some(Entry { expires_at: Some(at), .. }) => {
+                Reply::Integer(at.saturating_duration_since(Instant::now()).as_millis() as i64)
+            }
+            Some(_) => Reply::Integer(-1),
+            None => Reply::Integer(-2),
+        },
+        _ => Reply::Error(format!("unknown command '{}'", name)),
+    }
+}
+
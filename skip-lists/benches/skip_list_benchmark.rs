@@ -4,36 +4,43 @@ use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use skip_lists::SkipList;
 
 fn benchmark_skip_list(c: &mut Criterion) {
-    c.bench_function("append 1000 items", |b| {
+    c.bench_function("insert 1000 items", |b| {
         b.iter(|| {
-            let mut list = SkipList {
-                head: None,
-                tails: vec![None; 32],
-                max_level: 32,
-                length: 0,
-            };
+            let mut list = SkipList::new();
 
-            for i in 0..1000 {
-                list.append(i, format!("command {}", i));
+            for i in 0..1000u64 {
+                list.insert(i, format!("command {}", i));
             }
         })
     });
 
-    c.bench_function("find 1000 items", |b| {
-        let mut list = SkipList {
-            head: None,
-            tails: vec![None; 32],
-            max_level: 32,
-            length: 0,
-        };
+    c.bench_function("insert 1000 items out of order", |b| {
+        b.iter(|| {
+            let mut list = SkipList::new();
+
+            for i in 0..1000u64 {
+                list.insert(i * 7919 % 1000, format!("command {}", i));
+            }
+        })
+    });
 
-        for i in 0..1000 {
-            list.append(i, format!("command {}", i));
-        }
+    let mut list = SkipList::new();
+    for i in 0..1000u64 {
+        list.insert(i, format!("command {}", i));
+    }
 
+    c.bench_function("get 1000 items", |b| {
         b.iter(|| {
             for i in 0..1000 {
-                black_box(list.find(i));
+                black_box(list.get(&i));
+            }
+        })
+    });
+
+    c.bench_function("iterate 100 items from 500", |b| {
+        b.iter(|| {
+            for entry in list.iter_from(&500).take(100) {
+                black_box(entry);
             }
         })
     });
@@ -1,28 +1,57 @@
 // skip lists are a data structure that allow for fast search, insert, and delete operations
 
-use std::{cell::RefCell, rc::Rc};
+use std::ops::{Bound, RangeBounds};
 
-type Link = Option<Rc<RefCell<Node>>>;
+const MAX_LEVEL: usize = 32;
 
-#[derive(Clone)]
-pub struct Node {
-    pub next: Vec<Link>,
-    pub offset: u64,
-    pub command: String,
+// Nodes live in a Vec and link to each other by index, so lookups can hand out plain references
+struct Node<K, V> {
+    key: K,
+    value: V,
+    next: Vec<Option<usize>>,
 }
 
-pub struct SkipList {
-    pub head: Link,
-    pub tails: Vec<Link>,
-    pub max_level: usize,
-    pub length: usize,
+/// An ordered map kept in sorted key order. Each node is linked on a random number of levels, so searches
+/// skip most of the list and take O(log n) on average.
+pub struct SkipList<K, V> {
+    nodes: Vec<Option<Node<K, V>>>,
+    // Slots in `nodes` left by removed entries, reused by `insert`
+    free: Vec<usize>,
+    // The first node on each level
+    head: Vec<Option<usize>>,
+    max_level: usize,
+    length: usize,
 }
 
 // A time associated with a value
 // To be able to quickly jump to an arbitary time
-// To start iterating from that time
+// To start iterating from that time: `iter_from`
+
+impl<K: Ord, V> SkipList<K, V> {
+    pub fn new() -> Self {
+        Self::with_max_level(MAX_LEVEL)
+    }
+
+    /// Nodes get at most `max_level` levels; about 2^max_level entries still search in O(log n).
+    pub fn with_max_level(max_level: usize) -> Self {
+        assert!(max_level > 0, "a skip list needs at least one level");
+        SkipList {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            head: vec![None; max_level],
+            max_level,
+            length: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
 
-impl SkipList {
     pub fn get_level(&self) -> usize {
         let mut level = 1;
         while rand::random::<bool>() && level < self.max_level {
@@ -31,71 +60,191 @@ impl SkipList {
         level
     }
 
-    pub fn append(&mut self, offset: u64, command: String) {
-        let level = 1 + if self.head.is_none() {
-            self.max_level
-        } else {
-            self.get_level()
+    /// Adds `value` under `key`, anywhere in the list. An existing value for `key` is replaced and returned.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let before = self.predecessors(&key);
+        if let Some(index) = self.next(before[0], 0) {
+            let node = self.node_mut(index);
+            if node.key == key {
+                return Some(std::mem::replace(&mut node.value, value));
+            }
+        }
+
+        let level = self.get_level();
+        let next = (0..level).map(|i| self.next(before[i], i)).collect();
+        let node = Some(Node { key, value, next });
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
         };
+        for (i, &prev) in before.iter().enumerate().take(level) {
+            self.set_next(prev, i, Some(index));
+        }
 
-        // Ensure level does not exceed max_level
-        let level = level.min(self.max_level);
+        self.length += 1;
+        None
+    }
 
-        let new_node = Rc::new(RefCell::new(Node {
-            next: vec![None; level],
-            offset,
-            command,
-        }));
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let before = self.predecessors(key);
+        let index = self.next(before[0], 0).filter(|&index| self.node(index).key == *key)?;
 
-        for i in 0..level {
-            if let Some(old) = self.tails[i].take() {
-                let next = &mut old.borrow_mut().next;
-                next[i] = Some(new_node.clone());
-            }
-            self.tails[i] = Some(new_node.clone());
+        let node = self.nodes[index].take()?;
+        for (i, next) in node.next.into_iter().enumerate() {
+            self.set_next(before[i], i, next);
         }
+        self.free.push(index);
+        self.length -= 1;
+        Some(node.value)
+    }
 
-        if self.head.is_none() {
-            self.head = Some(new_node.clone());
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let index = self.lower_bound(key)?;
+        let node = self.node(index);
+        (node.key == *key).then_some(&node.value)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// The entry with the smallest key.
+    pub fn first(&self) -> Option<(&K, &V)> {
+        self.head[0].map(|index| self.entry(index))
+    }
+
+    /// The entry with the largest key.
+    pub fn last(&self) -> Option<(&K, &V)> {
+        let mut last = None;
+        for level in (0..self.max_level).rev() {
+            while let Some(next) = self.next(last, level) {
+                last = Some(next);
+            }
         }
+        last.map(|index| self.entry(index))
+    }
 
-        self.length += 1;
+    /// All entries in key order.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter { list: self, next: self.head[0] }
     }
 
-    pub fn find(&self, offset: u64) -> Option<String> {
-        match self.head {
-            Some(ref head) => {
-                let mut start_level = self.max_level - 1;
-                let node = head.clone();
-                let mut result = None;
+    /// Entries in key order, starting from the first one at or after `key`.
+    pub fn iter_from(&self, key: &K) -> Iter<'_, K, V> {
+        Iter { list: self, next: self.lower_bound(key) }
+    }
 
-                loop {
-                    if node.borrow().next[start_level].is_none() {
-                        break;
-                    }
-                    start_level -= 1;
+    /// Entries with keys in `range`, in key order.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> impl Iterator<Item = (&K, &V)> {
+        let next = match range.start_bound() {
+            Bound::Included(start) => self.lower_bound(start),
+            Bound::Excluded(start) => self
+                .lower_bound(start)
+                .and_then(|index| if self.node(index).key == *start { self.node(index).next[0] } else { Some(index) }),
+            Bound::Unbounded => self.head[0],
+        };
+        Iter { list: self, next }.take_while(move |(key, _)| match range.end_bound() {
+            Bound::Included(end) => *key <= end,
+            Bound::Excluded(end) => *key < end,
+            Bound::Unbounded => true,
+        })
+    }
+
+    /// For each level, the last node with a key below `key`, or `None` where that's the head.
+    fn predecessors(&self, key: &K) -> Vec<Option<usize>> {
+        let mut before = vec![None; self.max_level];
+        let mut node = None;
+        for level in (0..self.max_level).rev() {
+            while let Some(next) = self.next(node, level) {
+                if self.node(next).key >= *key {
+                    break;
                 }
+                node = Some(next);
+            }
+            before[level] = node;
+        }
+        before
+    }
 
-                let mut n = node;
-                for level in (0..=start_level).rev() {
-                    loop {
-                        let next = n.clone();
-                        match next.borrow().next[level] {
-                            Some(ref next) if next.borrow().offset <= offset => n = next.clone(),
-                            _ => break,
-                        };
-                    }
-
-                    if n.borrow().offset == offset {
-                        let tmp = n.borrow();
-                        result = Some(tmp.command.clone());
-                        break;
-                    }
+    /// The first node with a key at or after `key`.
+    fn lower_bound(&self, key: &K) -> Option<usize> {
+        let mut node = None;
+        for level in (0..self.max_level).rev() {
+            while let Some(next) = self.next(node, level) {
+                if self.node(next).key >= *key {
+                    break;
                 }
-                result
+                node = Some(next);
             }
-            None => None,
         }
+        self.next(node, 0)
+    }
+}
+
+impl<K, V> SkipList<K, V> {
+    fn node(&self, index: usize) -> &Node<K, V> {
+        self.nodes[index].as_ref().expect("linked node was removed")
+    }
+
+    fn node_mut(&mut self, index: usize) -> &mut Node<K, V> {
+        self.nodes[index].as_mut().expect("linked node was removed")
+    }
+
+    fn entry(&self, index: usize) -> (&K, &V) {
+        let node = self.node(index);
+        (&node.key, &node.value)
+    }
+
+    /// What follows `node` on `level`, where `None` is the head.
+    fn next(&self, node: Option<usize>, level: usize) -> Option<usize> {
+        match node {
+            Some(index) => self.node(index).next[level],
+            None => self.head[level],
+        }
+    }
+
+    fn set_next(&mut self, node: Option<usize>, level: usize, next: Option<usize>) {
+        match node {
+            Some(index) => self.node_mut(index).next[level] = next,
+            None => self.head[level] = next,
+        }
+    }
+}
+
+impl<K: Ord, V> Default for SkipList<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Walks the bottom level, which links every entry in key order.
+pub struct Iter<'a, K, V> {
+    list: &'a SkipList<K, V>,
+    next: Option<usize>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.next?;
+        self.next = self.list.node(index).next[0];
+        Some(self.list.entry(index))
+    }
+}
+
+impl<'a, K: Ord, V> IntoIterator for &'a SkipList<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
     }
 }
 
@@ -104,23 +253,67 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_append_and_find() {
-        let mut list = SkipList {
-            head: None,
-            tails: vec![None; 32],
-            max_level: 32,
-            length: 0,
-        };
+    fn test_insert_and_get() {
+        let mut list = SkipList::new();
+
+        list.insert(3, "foo".to_string());
+        list.insert(1, "hello".to_string());
+        list.insert(4, "bar".to_string());
+        list.insert(2, "world".to_string());
+
+        assert_eq!(list.get(&1), Some(&"hello".to_string()));
+        assert_eq!(list.get(&2), Some(&"world".to_string()));
+        assert_eq!(list.get(&3), Some(&"foo".to_string()));
+        assert_eq!(list.get(&4), Some(&"bar".to_string()));
+        assert_eq!(list.get(&5), None);
+
+        assert_eq!(list.insert(2, "there".to_string()), Some("world".to_string()));
+        assert_eq!(list.get(&2), Some(&"there".to_string()));
+        assert_eq!(list.len(), 4);
+    }
 
-        list.append(1, "hello".to_string());
-        list.append(2, "world".to_string());
-        list.append(3, "foo".to_string());
-        list.append(4, "bar".to_string());
+    #[test]
+    fn test_remove() {
+        let mut list = SkipList::with_max_level(4);
+        for key in [5, 1, 9, 3, 7] {
+            list.insert(key, key * 10);
+        }
+
+        assert_eq!(list.remove(&3), Some(30));
+        assert_eq!(list.remove(&3), None);
+        assert_eq!(list.remove(&9), Some(90));
+        assert_eq!(list.get(&3), None);
+        assert_eq!(list.iter().map(|(k, _)| *k).collect::<Vec<_>>(), [1, 5, 7]);
+        assert_eq!(list.last(), Some((&7, &70)));
+
+        // Freed slots are reused
+        list.insert(4, 40);
+        assert_eq!(list.iter().map(|(k, _)| *k).collect::<Vec<_>>(), [1, 4, 5, 7]);
+        for key in [1, 4, 5, 7] {
+            list.remove(&key);
+        }
+        assert!(list.is_empty());
+        assert_eq!((list.first(), list.last()), (None, None));
+    }
+
+    #[test]
+    fn test_range_and_iter_from() {
+        let mut list = SkipList::new();
+        // Commands logged at offsets 0, 10, ..., 90, inserted out of order
+        for offset in (0..10).rev() {
+            list.insert(offset * 10, format!("command {}", offset));
+        }
 
-        assert_eq!(list.find(1), Some("hello".to_string()));
-        assert_eq!(list.find(2), Some("world".to_string()));
-        assert_eq!(list.find(3), Some("foo".to_string()));
-        assert_eq!(list.find(4), Some("bar".to_string()));
-        assert_eq!(list.find(5), None);
+        let keys = |iter: &mut dyn Iterator<Item = (&u64, &String)>| iter.map(|(k, _)| *k).collect::<Vec<_>>();
+        assert_eq!(keys(&mut list.range(20..50)), [20, 30, 40]);
+        assert_eq!(keys(&mut list.range(15..=50)), [20, 30, 40, 50]);
+        assert_eq!(keys(&mut list.range((Bound::Excluded(20), Bound::Included(40)))), [30, 40]);
+        assert_eq!(keys(&mut list.range(..20)), [0, 10]);
+        assert_eq!(keys(&mut list.range(95..)), []);
+        assert_eq!(keys(&mut list.iter_from(&75)), [80, 90]);
+        assert_eq!(list.iter_from(&30).next(), Some((&30, &"command 3".to_string())));
+        assert_eq!(list.first(), Some((&0, &"command 0".to_string())));
+        assert_eq!(list.last(), Some((&90, &"command 9".to_string())));
+        assert_eq!((&list).into_iter().count(), 10);
     }
 }